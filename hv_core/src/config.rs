@@ -0,0 +1,290 @@
+//! This module defines the hypervisor-wide configuration consulted by every
+//! logical processor. `hypervisor::amd::config` holds the current one.
+
+use alloc::vec::Vec;
+
+use log::LevelFilter;
+use x86::cpuid::CpuIdResult;
+
+use crate::intercept::{CrReadIntercepts, CrWriteIntercepts, DrReadIntercepts, DrWriteIntercepts};
+
+/// How writes to the Interrupt Command Register (ICR) of the local APIC are
+/// intercepted. Intercepting ICR writes is how INIT and SIPI sent by the guest
+/// are emulated, but it requires making the whole local APIC page read-only,
+/// so every APIC write, including EOI, causes #VMEXIT(NPF).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IcrInterceptPolicy {
+    /// ICR writes are never intercepted. The guest must not send INIT or SIPI
+    /// to virtualized processors.
+    Disabled,
+    /// ICR writes are intercepted until no application processor waits for
+    /// SIPI. Processors running when they are virtualized are started already,
+    /// so nothing is intercepted when the hypervisor is loaded after boot.
+    /// INIT and SIPI sent once the interception stopped are not emulated.
+    UntilApsStarted,
+    /// ICR writes are intercepted for the lifetime of the hypervisor.
+    Always,
+}
+
+/// What the hypervisor does after a guest triple fault.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShutdownAction {
+    /// Resets the system, as the processor does without the hypervisor.
+    Reset,
+    /// Bug checks the system with `HYPERVISOR_ERROR` (0x20001). The parameters
+    /// are the processor ID, EXITINTINFO, the guest RIP and the address of the
+    /// `hypervisor::amd::crash::CrashReport`.
+    BugCheck,
+    /// Halts the processor. The other processors are not stopped, but the
+    /// system usually hangs once one of them waits on the halted processor,
+    /// such as for an IPI or a lock it held. Meant for attaching a debugger.
+    Halt,
+}
+
+/// How many PAUSE instructions the guest may execute before #VMEXIT(PAUSE).
+/// See: 15.14.4 Pause Intercept Filtering
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PauseFilter {
+    /// The number of PAUSE instructions that cause #VMEXIT(PAUSE).
+    pub count: u16,
+    /// The maximum number of cycles between two PAUSE instructions for them to
+    /// be counted as the same spin loop. Zero disables the threshold. Ignored
+    /// if the processor does not support it.
+    pub threshold: u16,
+}
+
+/// How the extended processor state is switched between the guest and the
+/// host. XMM0-XMM15 and MXCSR are always switched.
+/// See: 11.5 XSAVE/XRSTOR Instructions
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExtendedStateSwitch {
+    /// Nothing else is switched. Host code must not use more than SSE.
+    Sse,
+    /// The state enabled in XCR0 and IA32_XSS is switched with XSAVE and
+    /// XRSTOR on every world switch.
+    Eager,
+    /// The state enabled in XCR0 and IA32_XSS is saved only when the host asks
+    /// for it with `hypervisor::amd::VCpu::extended_state`, and restored on the
+    /// next VMRUN.
+    Lazy,
+}
+
+/// Whether a mitigation is applied.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mitigation {
+    /// Applied if the processor reports being vulnerable.
+    Auto,
+    /// Applied if the processor supports it.
+    Enabled,
+    Disabled,
+}
+
+/// The mitigations against speculative execution attacks applied on the world
+/// switch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SpeculationMitigations {
+    /// Whether the SPEC_CTRL MSR is switched between the guest and the host
+    /// values. Processors with SPEC_CTRL virtualization always switch it.
+    /// [`Mitigation::Auto`] switches it if it exists.
+    pub spec_ctrl: Mitigation,
+    /// Whether the return stack buffer is filled after #VMEXIT.
+    /// [`Mitigation::Auto`] fills it unless the host runs with automatic IBRS.
+    pub rsb_fill: Mitigation,
+    /// Whether IBPB is issued after every #VMEXIT. [`Mitigation::Auto`] issues
+    /// it if the processor is vulnerable to Speculative Return Stack Overflow.
+    pub ibpb_on_exit: Mitigation,
+}
+
+impl SpeculationMitigations {
+    pub const fn new() -> Self {
+        Self {
+            spec_ctrl: Mitigation::Auto,
+            rsb_fill: Mitigation::Auto,
+            ibpb_on_exit: Mitigation::Disabled,
+        }
+    }
+}
+
+impl Default for SpeculationMitigations {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Where the driver writes log messages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogSink {
+    /// The kernel debugger, with DbgPrint.
+    Debugger,
+    /// The serial port at the I/O port `port`.
+    Serial { port: u16 },
+}
+
+/// The processors `hypervisor::amd::virtualize_system` virtualizes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProcessorSet {
+    All,
+    /// By system processor index. The index counts the active processors of
+    /// every processor group, in the order of `KeGetProcessorNumberFromIndex`.
+    Only(Vec<usize>),
+    /// By processor group and number in the group. A group not listed is not
+    /// virtualized.
+    Affinity(Vec<GroupAffinity>),
+}
+
+impl ProcessorSet {
+    /// Returns whether the processor at the system processor index `index`,
+    /// which is `number` in the processor group `group`, is in the set.
+    pub fn contains(&self, index: usize, group: u16, number: u8) -> bool {
+        match self {
+            Self::All => true,
+            Self::Only(indexes) => indexes.contains(&index),
+            Self::Affinity(affinities) => affinities
+                .iter()
+                .any(|affinity| affinity.group == group && number < 64 && affinity.mask & (1 << number) != 0),
+        }
+    }
+}
+
+/// The processors of a processor group, as the `GROUP_AFFINITY` structure.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GroupAffinity {
+    pub group: u16,
+    /// Bit n selects the processor numbered n in the group.
+    pub mask: u64,
+}
+
+/// A register returned by CPUID.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CpuidRegister {
+    Eax,
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+/// Bits changed in a register the guest reads with CPUID, after the changes the
+/// hypervisor makes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CpuidOverride {
+    pub leaf: u32,
+    /// The sub-leaf, or `None` for every sub-leaf.
+    pub sub_leaf: Option<u32>,
+    pub register: CpuidRegister,
+    /// The bits cleared. They are cleared before `set` is applied.
+    pub clear: u32,
+    /// The bits set.
+    pub set: u32,
+}
+
+impl CpuidOverride {
+    /// Applies the override to `result` if it is for `leaf` and `sub_leaf`.
+    pub fn apply(&self, leaf: u32, sub_leaf: u32, result: &mut CpuIdResult) {
+        if leaf != self.leaf || self.sub_leaf.is_some_and(|expected| expected != sub_leaf) {
+            return;
+        }
+        let register = match self.register {
+            CpuidRegister::Eax => &mut result.eax,
+            CpuidRegister::Ebx => &mut result.ebx,
+            CpuidRegister::Ecx => &mut result.ecx,
+            CpuidRegister::Edx => &mut result.edx,
+        };
+        *register = *register & !self.clear | self.set;
+    }
+}
+
+bitflags::bitflags! {
+    /// Where the hypervisor breaks into the kernel debugger with INT3. Without
+    /// a debugger attached, a break bug checks the system.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct DebugBreaks: u32 {
+        /// Before each processor is virtualized.
+        const VIRTUALIZE = 1 << 0;
+        /// Before nested paging is enabled on each processor.
+        const NESTED_PAGING = 1 << 1;
+        /// After each CPUID is emulated.
+        const CPUID = 1 << 2;
+        /// On each #VMEXIT(NPF).
+        const NESTED_PAGE_FAULT = 1 << 3;
+    }
+}
+
+/// The configuration of the hypervisor.
+#[derive(Clone, Debug)]
+pub struct HypervisorConfig {
+    pub icr_intercept: IcrInterceptPolicy,
+    /// The control registers whose reads and writes are intercepted, emulated
+    /// and reported to `hypervisor::amd::hooks::VmExitHooks::cr_access`. Only CR0,
+    /// CR3, CR4 and CR8 are supported. With any other, the register intercepts
+    /// are ignored with an error logged. Read when a processor is virtualized.
+    pub cr_read_intercepts: CrReadIntercepts,
+    pub cr_write_intercepts: CrWriteIntercepts,
+    /// The debug registers whose reads and writes are intercepted, emulated
+    /// and reported to `hypervisor::amd::hooks::VmExitHooks::dr_access`. Read when
+    /// a processor is virtualized.
+    pub dr_read_intercepts: DrReadIntercepts,
+    pub dr_write_intercepts: DrWriteIntercepts,
+    /// The XCR0 state components hidden from the guest. They are cleared from
+    /// XCR0 and CPUID when a processor is virtualized, and XSETBV enabling them
+    /// causes #GP. Hiding SSE hides AVX and hiding AVX hides AVX-512, along
+    /// with the features using them. x87 state cannot be hidden. As extended
+    /// state already saved with a hidden component cannot be restored, set it
+    /// only for a driver loaded at boot.
+    pub hidden_xcr0: u64,
+    /// The filter PAUSE instructions go through before they are intercepted,
+    /// or `None` not to intercept PAUSE. PAUSE is not intercepted either if
+    /// the processor does not support pause filtering. Read when a processor
+    /// is virtualized.
+    pub pause_filter: Option<PauseFilter>,
+    /// Whether HLT is intercepted. The processor does not halt while HLT is
+    /// intercepted, so an idle guest spins through #VMEXITs instead; this is
+    /// meant for tracing only. Read when a processor is virtualized.
+    pub intercept_hlt: bool,
+    /// What to do after a guest triple fault, once the crash report is logged.
+    pub shutdown_action: ShutdownAction,
+    /// How the extended state is switched. Read when a processor is
+    /// virtualized. [`ExtendedStateSwitch::Sse`] is used if the processor does
+    /// not support XSAVE.
+    pub extended_state_switch: ExtendedStateSwitch,
+    /// The mitigations applied on the world switch. Read when a processor is
+    /// virtualized.
+    pub speculation: SpeculationMitigations,
+    /// The maximum level of log messages and where they are written. Read by
+    /// the driver when it is loaded.
+    pub log_level: LevelFilter,
+    pub log_sink: LogSink,
+    /// The processors virtualized by `hypervisor::amd::virtualize_system`.
+    pub processors: ProcessorSet,
+    /// The changes made to CPUID results, in order.
+    pub cpuid_overrides: Vec<CpuidOverride>,
+    pub debug_breaks: DebugBreaks,
+}
+
+impl HypervisorConfig {
+    pub const fn new() -> Self {
+        Self {
+            icr_intercept: IcrInterceptPolicy::Disabled,
+            cr_read_intercepts: CrReadIntercepts::empty(),
+            cr_write_intercepts: CrWriteIntercepts::empty(),
+            dr_read_intercepts: DrReadIntercepts::empty(),
+            dr_write_intercepts: DrWriteIntercepts::empty(),
+            hidden_xcr0: 0,
+            pause_filter: None,
+            intercept_hlt: false,
+            shutdown_action: ShutdownAction::Reset,
+            extended_state_switch: ExtendedStateSwitch::Sse,
+            speculation: SpeculationMitigations::new(),
+            log_level: LevelFilter::Trace,
+            log_sink: LogSink::Debugger,
+            processors: ProcessorSet::All,
+            cpuid_overrides: Vec::new(),
+            debug_breaks: DebugBreaks::all(),
+        }
+    }
+}
+
+impl Default for HypervisorConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
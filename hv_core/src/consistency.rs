@@ -0,0 +1,358 @@
+//! This module implements the consistency checks VMRUN performs on the VMCB,
+//! so that the cause of #VMEXIT(INVALID) can be reported.
+//!
+//! "The VMRUN instruction performs consistency checks on the guest state (...).
+//!  Illegal guest state combinations cause a #VMEXIT with error code
+//!  VMEXIT_INVALID."
+//! See: 15.5.1 Basic Operation
+//!
+//! Only the conditions listed there are checked, so that every reported
+//! violation is one VMRUN rejects.
+//!
+//! The checks are pure functions of [`VmcbFields`] and of [`CpuLimits`], so
+//! they can be run on any host.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use bit_field::BitField;
+use x86::cpuid::cpuid;
+
+use crate::intercept::{Misc1Intercepts, Misc2Intercepts};
+
+/// The VMCB fields the checks read, as they are in the VMCB.
+/// See: Appendix B Layout of VMCB
+#[derive(Clone, Copy, Debug, Default)]
+pub struct VmcbFields {
+    pub intercept_misc1: u32,
+    pub intercept_misc2: u32,
+    pub iopm_base_pa: u64,
+    pub msrpm_base_pa: u64,
+    pub guest_asid: u32,
+    pub event_inj: u64,
+    pub es_attrib: u16,
+    pub cs_attrib: u16,
+    pub ss_attrib: u16,
+    pub ds_attrib: u16,
+    pub fs_attrib: u16,
+    pub gs_attrib: u16,
+    pub ldtr_attrib: u16,
+    pub tr_attrib: u16,
+    pub efer: u64,
+    pub cr4: u64,
+    pub cr3: u64,
+    pub cr0: u64,
+    pub dr7: u64,
+    pub dr6: u64,
+}
+
+/// The processor properties some checks depend on.
+#[derive(Clone, Copy, Debug)]
+pub struct CpuLimits {
+    /// The number of physical address bits supported.
+    pub physical_address_bits: u8,
+}
+
+impl CpuLimits {
+    /// Returns the limits of the current processor.
+    /// See: E.4.7 Function 8000_0008h—Processor Capacity Parameters and Extended Feature Identification
+    pub fn current() -> Self {
+        Self {
+            physical_address_bits: cpuid!(0x8000_0008).eax.get_bits(0..=7) as u8,
+        }
+    }
+}
+
+/// A VMCB field holding a value VMRUN rejects.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VmcbViolation {
+    /// The name of the field, or fields, in the VMCB.
+    pub field: &'static str,
+    /// The value of the field.
+    pub value: u64,
+    /// The rule the value violates.
+    pub rule: &'static str,
+}
+
+impl fmt::Display for VmcbViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} = {:#x}: {}", self.field, self.value, self.rule)
+    }
+}
+
+const EFER_LME: u64 = 1 << 8;
+const EFER_SVME: u64 = 1 << 12;
+// SCE, LME, LMA, NXE, SVME, LMSLE, FFXSR, TCE, MCOMMIT, INTWB, UAIE and AIBRSE.
+// See: 3.1.7 Extended Feature Enable Register (EFER)
+const EFER_VALID_BITS: u64 = 1 << 0 | 1 << 8 | 0b11_1111 << 10 | 0b11 << 17 | 0b11 << 20;
+
+const CR0_PE: u64 = 1 << 0;
+const CR0_NW: u64 = 1 << 29;
+const CR0_CD: u64 = 1 << 30;
+const CR0_PG: u64 = 1 << 31;
+
+const CR4_PAE: u64 = 1 << 5;
+// VME through LA57, FSGSBASE, PCIDE, OSXSAVE, SMEP, SMAP, PKE, CET and PKS.
+// See: 3.1.3 CR4 Register
+pub const CR4_VALID_BITS: u64 = 0x1fff | 0b111 << 16 | 0b1_1111 << 20;
+
+// Bits 63:52 of CR3 are reserved in long mode.
+// See: 3.1.2 CR3 Register
+const CR3_LONG_MODE_RESERVED_BITS: u64 = 0xfff << 52;
+
+const SEGMENT_ATTRIB_L: u16 = 1 << 9;
+const SEGMENT_ATTRIB_DB: u16 = 1 << 10;
+// The attribute field holds descriptor bits 47:40 and 55:52.
+// See: 15.5.1 Basic Operation, Segment State in the VMCB
+const SEGMENT_ATTRIB_RESERVED_BITS: u16 = 0xf000;
+
+// See: 15.10.1 I/O Permissions Map and 15.11 MSR Intercepts
+const IOPM_SIZE: u64 = 0x3000;
+const MSRPM_SIZE: u64 = 0x2000;
+
+const EVENT_VALID: u64 = 1 << 31;
+const EVENT_TYPE_EXCEPTION: u64 = 3;
+const NMI_VECTOR: u64 = 2;
+
+/// Returns every field of `vmcb` that makes VMRUN fail with #VMEXIT(INVALID).
+/// An empty result means the VMCB passes all checks known to this module.
+pub fn check(vmcb: &VmcbFields, limits: &CpuLimits) -> Vec<VmcbViolation> {
+    let mut violations = Vec::new();
+    check_state(vmcb, &mut violations);
+    check_control(vmcb, limits, &mut violations);
+    violations
+}
+
+fn check_state(vmcb: &VmcbFields, violations: &mut Vec<VmcbViolation>) {
+    let mut violation = |field, value, rule| {
+        violations.push(VmcbViolation { field, value, rule });
+    };
+
+    if vmcb.efer & EFER_SVME == 0 {
+        violation("EFER", vmcb.efer, "EFER.SVME is zero");
+    }
+    if vmcb.efer & !EFER_VALID_BITS != 0 {
+        violation("EFER", vmcb.efer, "a reserved bit of EFER is set");
+    }
+    if vmcb.cr0 & CR0_CD == 0 && vmcb.cr0 & CR0_NW != 0 {
+        violation("CR0", vmcb.cr0, "CR0.CD is zero and CR0.NW is set");
+    }
+    if vmcb.cr0.get_bits(32..) != 0 {
+        violation("CR0", vmcb.cr0, "CR0[63:32] are not zero");
+    }
+    if vmcb.cr4 & !CR4_VALID_BITS != 0 {
+        violation("CR4", vmcb.cr4, "a reserved bit of CR4 is set");
+    }
+    if vmcb.dr6.get_bits(32..) != 0 {
+        violation("DR6", vmcb.dr6, "DR6[63:32] are not zero");
+    }
+    if vmcb.dr7.get_bits(32..) != 0 {
+        violation("DR7", vmcb.dr7, "DR7[63:32] are not zero");
+    }
+
+    let long_mode = vmcb.efer & EFER_LME != 0 && vmcb.cr0 & CR0_PG != 0;
+    if long_mode {
+        if vmcb.cr4 & CR4_PAE == 0 {
+            violation("CR4", vmcb.cr4, "EFER.LME and CR0.PG are set and CR4.PAE is zero");
+        }
+        if vmcb.cr0 & CR0_PE == 0 {
+            violation("CR0", vmcb.cr0, "EFER.LME and CR0.PG are set and CR0.PE is zero");
+        }
+        if vmcb.cr3 & CR3_LONG_MODE_RESERVED_BITS != 0 {
+            violation("CR3", vmcb.cr3, "a reserved bit of CR3 is set in long mode");
+        }
+        if vmcb.cr4 & CR4_PAE != 0
+            && vmcb.cs_attrib & SEGMENT_ATTRIB_L != 0
+            && vmcb.cs_attrib & SEGMENT_ATTRIB_DB != 0
+        {
+            violation(
+                "CS.ATTRIB",
+                u64::from(vmcb.cs_attrib),
+                "EFER.LME, CR0.PG, CR4.PAE, CS.L and CS.D are all set",
+            );
+        }
+    }
+
+    let segments = [
+        ("ES.ATTRIB", vmcb.es_attrib),
+        ("CS.ATTRIB", vmcb.cs_attrib),
+        ("SS.ATTRIB", vmcb.ss_attrib),
+        ("DS.ATTRIB", vmcb.ds_attrib),
+        ("FS.ATTRIB", vmcb.fs_attrib),
+        ("GS.ATTRIB", vmcb.gs_attrib),
+        ("LDTR.ATTRIB", vmcb.ldtr_attrib),
+        ("TR.ATTRIB", vmcb.tr_attrib),
+    ];
+    for (field, attrib) in segments {
+        if attrib & SEGMENT_ATTRIB_RESERVED_BITS != 0 {
+            violation(field, u64::from(attrib), "a reserved bit of the segment attributes is set");
+        }
+    }
+
+}
+
+fn check_control(vmcb: &VmcbFields, limits: &CpuLimits, violations: &mut Vec<VmcbViolation>) {
+    let mut violation = |field, value, rule| {
+        violations.push(VmcbViolation { field, value, rule });
+    };
+
+    if vmcb.intercept_misc2 & Misc2Intercepts::VMRUN.bits() == 0 {
+        violation("INTERCEPT_MISC2", u64::from(vmcb.intercept_misc2), "the VMRUN intercept bit is clear");
+    }
+    if vmcb.guest_asid == 0 {
+        violation("GUEST_ASID", 0, "the ASID is zero");
+    }
+
+    // "The MSR or IOIO intercept tables extend to a physical address that is
+    //  greater than or equal to the maximum supported physical address."
+    let max_pa = 1u64
+        .checked_shl(u32::from(limits.physical_address_bits))
+        .unwrap_or(u64::MAX);
+    let extends_beyond = |base: u64, size: u64| {
+        (base & !0xfff).checked_add(size).is_none_or(|end| end > max_pa)
+    };
+    if vmcb.intercept_misc1 & Misc1Intercepts::IOIO_PROT.bits() != 0
+        && extends_beyond(vmcb.iopm_base_pa, IOPM_SIZE)
+    {
+        violation("IOPM_BASE_PA", vmcb.iopm_base_pa, "the IOPM extends beyond the maximum physical address");
+    }
+    if vmcb.intercept_misc1 & Misc1Intercepts::MSR_PROT.bits() != 0
+        && extends_beyond(vmcb.msrpm_base_pa, MSRPM_SIZE)
+    {
+        violation("MSRPM_BASE_PA", vmcb.msrpm_base_pa, "the MSRPM extends beyond the maximum physical address");
+    }
+
+    // See: 15.20 Event Injection
+    if vmcb.event_inj & EVENT_VALID != 0 {
+        let type_ = vmcb.event_inj.get_bits(8..=10);
+        let vector = vmcb.event_inj.get_bits(0..=7);
+        match type_ {
+            0 | 2 | 4 => {}
+            EVENT_TYPE_EXCEPTION if vector == NMI_VECTOR || vector > 31 => {
+                violation("EVENTINJ", vmcb.event_inj, "an exception is injected with an illegal vector");
+            }
+            EVENT_TYPE_EXCEPTION => {}
+            _ => violation("EVENTINJ", vmcb.event_inj, "the event type is reserved"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EFER_LMA: u64 = 1 << 10;
+
+    const LIMITS: CpuLimits = CpuLimits {
+        physical_address_bits: 48,
+    };
+
+    /// Returns a VMCB in 64-bit mode that passes every check.
+    fn valid_vmcb() -> VmcbFields {
+        VmcbFields {
+            intercept_misc2: Misc2Intercepts::VMRUN.bits(),
+            guest_asid: 1,
+            efer: EFER_SVME | EFER_LME | EFER_LMA,
+            cr0: CR0_PG | CR0_PE | 1 << 16,
+            cr3: 0x1ad000,
+            cr4: CR4_PAE | 1 << 7,
+            cs_attrib: 0x29b,
+            ss_attrib: 0x493,
+            dr6: 0xffff0ff0,
+            dr7: 0x400,
+            ..Default::default()
+        }
+    }
+
+    fn fields(vmcb: &VmcbFields) -> Vec<&'static str> {
+        check(vmcb, &LIMITS).iter().map(|violation| violation.field).collect()
+    }
+
+    #[test]
+    fn accepts_valid_vmcb() {
+        assert_eq!(check(&valid_vmcb(), &LIMITS), []);
+    }
+
+    #[test]
+    fn rejects_missing_svme() {
+        let mut vmcb = valid_vmcb();
+        vmcb.efer &= !EFER_SVME;
+        assert_eq!(fields(&vmcb), ["EFER"]);
+    }
+
+    #[test]
+    fn rejects_cr0_nw_without_cd() {
+        let mut vmcb = valid_vmcb();
+        vmcb.cr0 |= CR0_NW;
+        assert_eq!(fields(&vmcb), ["CR0"]);
+        vmcb.cr0 |= CR0_CD;
+        assert_eq!(fields(&vmcb), [] as [&str; 0]);
+    }
+
+    #[test]
+    fn rejects_long_mode_without_pae_or_pe() {
+        let mut vmcb = valid_vmcb();
+        vmcb.cr4 &= !CR4_PAE;
+        vmcb.cr0 &= !CR0_PE;
+        assert_eq!(fields(&vmcb), ["CR4", "CR0"]);
+    }
+
+    #[test]
+    fn rejects_cs_l_and_d() {
+        let mut vmcb = valid_vmcb();
+        vmcb.cs_attrib |= SEGMENT_ATTRIB_DB;
+        assert_eq!(fields(&vmcb), ["CS.ATTRIB"]);
+    }
+
+    #[test]
+    fn rejects_reserved_bits() {
+        let mut vmcb = valid_vmcb();
+        vmcb.efer |= 1 << 9;
+        vmcb.cr0 |= 1 << 32;
+        vmcb.cr3 |= 1 << 63;
+        vmcb.cr4 |= 1 << 13;
+        vmcb.dr6 |= 1 << 40;
+        vmcb.dr7 |= 1 << 40;
+        vmcb.tr_attrib = 0x808b;
+        assert_eq!(fields(&vmcb), ["EFER", "CR0", "CR4", "DR6", "DR7", "CR3", "TR.ATTRIB"]);
+    }
+
+    #[test]
+    fn rejects_zero_asid_and_missing_vmrun_intercept() {
+        let mut vmcb = valid_vmcb();
+        vmcb.guest_asid = 0;
+        vmcb.intercept_misc2 = 0;
+        assert_eq!(fields(&vmcb), ["INTERCEPT_MISC2", "GUEST_ASID"]);
+    }
+
+    #[test]
+    fn rejects_permission_maps_beyond_max_physical_address() {
+        let mut vmcb = valid_vmcb();
+        vmcb.iopm_base_pa = (1 << 48) - 0x1000;
+        vmcb.msrpm_base_pa = (1 << 48) - 0x1000;
+        assert_eq!(fields(&vmcb), [] as [&str; 0], "ignored unless intercepted");
+
+        vmcb.intercept_misc1 = (Misc1Intercepts::IOIO_PROT | Misc1Intercepts::MSR_PROT).bits();
+        assert_eq!(fields(&vmcb), ["IOPM_BASE_PA", "MSRPM_BASE_PA"]);
+
+        vmcb.iopm_base_pa = (1 << 48) - IOPM_SIZE;
+        vmcb.msrpm_base_pa = (1 << 48) - MSRPM_SIZE;
+        assert_eq!(fields(&vmcb), [] as [&str; 0]);
+    }
+
+    #[test]
+    fn rejects_illegal_event_injection() {
+        let mut vmcb = valid_vmcb();
+        vmcb.event_inj = EVENT_VALID | EVENT_TYPE_EXCEPTION << 8 | 14;
+        assert_eq!(fields(&vmcb), [] as [&str; 0]);
+
+        vmcb.event_inj = EVENT_VALID | EVENT_TYPE_EXCEPTION << 8 | NMI_VECTOR;
+        assert_eq!(fields(&vmcb), ["EVENTINJ"]);
+
+        vmcb.event_inj = EVENT_VALID | 1 << 8;
+        assert_eq!(fields(&vmcb), ["EVENTINJ"]);
+
+        vmcb.event_inj = 1 << 8;
+        assert_eq!(fields(&vmcb), [] as [&str; 0], "ignored unless valid");
+    }
+}
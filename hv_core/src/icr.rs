@@ -0,0 +1,249 @@
+//! This module implements decoding of the Interrupt Command Register (ICR) and
+//! resolution of its destination into the set of local APICs that accept the
+//! IPI.
+//!
+//! See: 16.5 Interprocessor Interrupts (IPI)
+
+use bit_field::BitField;
+
+/// Offsets of the local APIC registers the hypervisor cares about.
+/// See: Table 16-2. APIC Registers
+pub const APIC_REG_LDR: u64 = 0xd0;
+pub const APIC_REG_DFR: u64 = 0xe0;
+pub const APIC_REG_ICR_LOW: u64 = 0x300;
+pub const APIC_REG_ICR_HIGH: u64 = 0x310;
+
+/// The value of DFR after reset, that is, the flat model.
+pub const APIC_DFR_RESET_VALUE: u32 = u32::MAX;
+
+/// The decoded value of ICR.
+/// See: Figure 16-18. Interrupt Command Register (APIC Offset 300h–310h)
+#[derive(Clone, Copy, Debug)]
+pub struct InterruptCommand {
+    low: u32,
+    high: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeliveryMode {
+    Fixed,
+    LowestPriority,
+    Smi,
+    RemoteRead,
+    Nmi,
+    Init,
+    Startup,
+    ExtInt,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DestinationMode {
+    Physical,
+    Logical,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DestinationShorthand {
+    Destination,
+    SelfOnly,
+    AllIncludingSelf,
+    AllExcludingSelf,
+}
+
+impl InterruptCommand {
+    pub fn new(low: u32, high: u32) -> Self {
+        Self { low, high }
+    }
+
+    pub fn vector(&self) -> u8 {
+        self.low.get_bits(0..=7) as u8
+    }
+
+    pub fn delivery_mode(&self) -> DeliveryMode {
+        match self.low.get_bits(8..=10) {
+            0b000 => DeliveryMode::Fixed,
+            0b001 => DeliveryMode::LowestPriority,
+            0b010 => DeliveryMode::Smi,
+            0b011 => DeliveryMode::RemoteRead,
+            0b100 => DeliveryMode::Nmi,
+            0b101 => DeliveryMode::Init,
+            0b110 => DeliveryMode::Startup,
+            _ => DeliveryMode::ExtInt,
+        }
+    }
+
+    pub fn destination_mode(&self) -> DestinationMode {
+        if self.low.get_bit(11) {
+            DestinationMode::Logical
+        } else {
+            DestinationMode::Physical
+        }
+    }
+
+    pub fn shorthand(&self) -> DestinationShorthand {
+        match self.low.get_bits(18..=19) {
+            0b00 => DestinationShorthand::Destination,
+            0b01 => DestinationShorthand::SelfOnly,
+            0b10 => DestinationShorthand::AllIncludingSelf,
+            _ => DestinationShorthand::AllExcludingSelf,
+        }
+    }
+
+    pub fn destination(&self) -> u8 {
+        self.high.get_bits(24..=31) as u8
+    }
+
+    /// Returns whether this is the legacy INIT level de-assert message, which
+    /// does not reset the destination processors.
+    pub fn is_init_level_deassert(&self) -> bool {
+        // Level (bit 14) is de-assert and Trigger Mode (bit 15) is level.
+        self.delivery_mode() == DeliveryMode::Init && !self.low.get_bit(14) && self.low.get_bit(15)
+    }
+
+    /// Returns the APIC ID of the only local APIC that can accept the IPI when
+    /// it is sent by the local APIC with `sender_apic_id`, or `None` if any
+    /// number of them can, as with a broadcast or a logical destination.
+    pub fn single_destination(&self, sender_apic_id: u8) -> Option<u8> {
+        match self.shorthand() {
+            DestinationShorthand::SelfOnly => Some(sender_apic_id),
+            DestinationShorthand::Destination
+                if self.destination_mode() == DestinationMode::Physical && self.destination() != 0xff =>
+            {
+                Some(self.destination())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The state of a local APIC that decides whether it accepts an IPI.
+#[derive(Clone, Copy, Debug)]
+pub struct ApicDestination {
+    pub apic_id: u8,
+    /// The value of the Logical Destination Register (LDR).
+    pub ldr: u32,
+    /// The value of the Destination Format Register (DFR).
+    pub dfr: u32,
+}
+
+impl ApicDestination {
+    /// Returns whether this local APIC accepts the IPI described by `command`
+    /// when it is sent by the local APIC with `sender_apic_id`.
+    pub fn accepts(&self, command: &InterruptCommand, sender_apic_id: u8) -> bool {
+        match command.shorthand() {
+            DestinationShorthand::SelfOnly => self.apic_id == sender_apic_id,
+            DestinationShorthand::AllIncludingSelf => true,
+            DestinationShorthand::AllExcludingSelf => self.apic_id != sender_apic_id,
+            DestinationShorthand::Destination => match command.destination_mode() {
+                DestinationMode::Physical => {
+                    command.destination() == 0xff || command.destination() == self.apic_id
+                }
+                DestinationMode::Logical => self.accepts_logical(command.destination()),
+            },
+        }
+    }
+
+    // See: 16.6.2 Logical Destination Mode
+    fn accepts_logical(&self, mda: u8) -> bool {
+        let logical_id = self.ldr.get_bits(24..=31) as u8;
+        match self.dfr.get_bits(28..=31) {
+            // Flat model. Each bit of the message destination address (MDA)
+            // selects a local APIC.
+            0b1111 => logical_id & mda != 0,
+            // Cluster model. MDA[7:4] selects the cluster, where 0xf means all
+            // clusters, and MDA[3:0] selects local APICs within the cluster.
+            0b0000 => {
+                let cluster = mda >> 4;
+                (cluster == 0xf || cluster == logical_id >> 4) && (mda & logical_id & 0xf) != 0
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FLAT: u32 = APIC_DFR_RESET_VALUE;
+    const CLUSTER: u32 = 0x0fff_ffff;
+
+    fn apic(apic_id: u8, logical_id: u8, dfr: u32) -> ApicDestination {
+        ApicDestination {
+            apic_id,
+            ldr: (logical_id as u32) << 24,
+            dfr,
+        }
+    }
+
+    /// Returns a fixed IPI with the given shorthand, destination mode and
+    /// destination.
+    fn command(shorthand: u32, logical: bool, destination: u8) -> InterruptCommand {
+        InterruptCommand::new(shorthand << 18 | (logical as u32) << 11 | 0x41, (destination as u32) << 24)
+    }
+
+    #[test]
+    fn decodes_command() {
+        let command = InterruptCommand::new(0x000c_4600 | 0x9a, 0x0300_0000);
+        assert_eq!(command.vector(), 0x9a);
+        assert_eq!(command.delivery_mode(), DeliveryMode::Startup);
+        assert_eq!(command.destination_mode(), DestinationMode::Physical);
+        assert_eq!(command.shorthand(), DestinationShorthand::AllExcludingSelf);
+        assert_eq!(command.destination(), 3);
+        assert!(!command.is_init_level_deassert());
+        assert!(InterruptCommand::new(0x8500, 0).is_init_level_deassert());
+        assert!(!InterruptCommand::new(0xc500, 0).is_init_level_deassert());
+    }
+
+    #[test]
+    fn accepts_physical() {
+        let target = apic(3, 0, FLAT);
+        assert!(target.accepts(&command(0b00, false, 3), 0));
+        assert!(!target.accepts(&command(0b00, false, 4), 0));
+        assert!(target.accepts(&command(0b00, false, 0xff), 0));
+    }
+
+    #[test]
+    fn accepts_logical_flat() {
+        let target = apic(3, 0b0100, FLAT);
+        assert!(target.accepts(&command(0b00, true, 0b0110), 0));
+        assert!(!target.accepts(&command(0b00, true, 0b1011), 0));
+        assert!(target.accepts(&command(0b00, true, 0xff), 0));
+        // The logical ID is not an APIC ID.
+        assert!(!apic(3, 0, FLAT).accepts(&command(0b00, true, 3), 0));
+    }
+
+    #[test]
+    fn accepts_logical_cluster() {
+        let target = apic(3, 0x24, CLUSTER);
+        assert!(target.accepts(&command(0b00, true, 0x26), 0));
+        assert!(!target.accepts(&command(0b00, true, 0x34), 0));
+        assert!(!target.accepts(&command(0b00, true, 0x23), 0));
+        assert!(target.accepts(&command(0b00, true, 0xf4), 0));
+        // A reserved model accepts nothing.
+        assert!(!apic(3, 0x24, 0x7fff_ffff).accepts(&command(0b00, true, 0xff), 0));
+    }
+
+    #[test]
+    fn accepts_shorthands() {
+        let sender = apic(1, 0, FLAT);
+        let other = apic(2, 0, FLAT);
+        // The destination field is ignored with a shorthand.
+        assert!(sender.accepts(&command(0b01, false, 2), 1));
+        assert!(!other.accepts(&command(0b01, false, 2), 1));
+        assert!(sender.accepts(&command(0b10, false, 0), 1));
+        assert!(other.accepts(&command(0b10, false, 0), 1));
+        assert!(!sender.accepts(&command(0b11, false, 1), 1));
+        assert!(other.accepts(&command(0b11, false, 1), 1));
+    }
+
+    #[test]
+    fn resolves_single_destination() {
+        assert_eq!(command(0b00, false, 3).single_destination(1), Some(3));
+        assert_eq!(command(0b01, false, 3).single_destination(1), Some(1));
+        assert_eq!(command(0b00, false, 0xff).single_destination(1), None);
+        assert_eq!(command(0b00, true, 0b0100).single_destination(1), None);
+        assert_eq!(command(0b10, false, 3).single_destination(1), None);
+        assert_eq!(command(0b11, false, 3).single_destination(1), None);
+    }
+}
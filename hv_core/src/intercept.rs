@@ -0,0 +1,257 @@
+//! This module defines the intercept vectors of the VMCB control area, one
+//! bitflags type per vector. `hypervisor::amd::intercept` builds the intercepts
+//! of a vCPU from them.
+//!
+//! See: 15.9 Instruction Intercepts and Appendix B Layout of VMCB
+
+bitflags::bitflags! {
+    /// Reads of control registers, VMCB offset 0x000.
+    #[repr(transparent)]
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct CrReadIntercepts: u16 {
+        const CR0 = 1 << 0;
+        const CR1 = 1 << 1;
+        const CR2 = 1 << 2;
+        const CR3 = 1 << 3;
+        const CR4 = 1 << 4;
+        const CR5 = 1 << 5;
+        const CR6 = 1 << 6;
+        const CR7 = 1 << 7;
+        const CR8 = 1 << 8;
+        const CR9 = 1 << 9;
+        const CR10 = 1 << 10;
+        const CR11 = 1 << 11;
+        const CR12 = 1 << 12;
+        const CR13 = 1 << 13;
+        const CR14 = 1 << 14;
+        const CR15 = 1 << 15;
+    }
+
+    /// Writes to control registers, VMCB offset 0x002.
+    #[repr(transparent)]
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct CrWriteIntercepts: u16 {
+        const CR0 = 1 << 0;
+        const CR1 = 1 << 1;
+        const CR2 = 1 << 2;
+        const CR3 = 1 << 3;
+        const CR4 = 1 << 4;
+        const CR5 = 1 << 5;
+        const CR6 = 1 << 6;
+        const CR7 = 1 << 7;
+        const CR8 = 1 << 8;
+        const CR9 = 1 << 9;
+        const CR10 = 1 << 10;
+        const CR11 = 1 << 11;
+        const CR12 = 1 << 12;
+        const CR13 = 1 << 13;
+        const CR14 = 1 << 14;
+        const CR15 = 1 << 15;
+    }
+
+    /// Reads of debug registers, VMCB offset 0x004.
+    #[repr(transparent)]
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct DrReadIntercepts: u16 {
+        const DR0 = 1 << 0;
+        const DR1 = 1 << 1;
+        const DR2 = 1 << 2;
+        const DR3 = 1 << 3;
+        const DR4 = 1 << 4;
+        const DR5 = 1 << 5;
+        const DR6 = 1 << 6;
+        const DR7 = 1 << 7;
+        const DR8 = 1 << 8;
+        const DR9 = 1 << 9;
+        const DR10 = 1 << 10;
+        const DR11 = 1 << 11;
+        const DR12 = 1 << 12;
+        const DR13 = 1 << 13;
+        const DR14 = 1 << 14;
+        const DR15 = 1 << 15;
+    }
+
+    /// Writes to debug registers, VMCB offset 0x006.
+    #[repr(transparent)]
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct DrWriteIntercepts: u16 {
+        const DR0 = 1 << 0;
+        const DR1 = 1 << 1;
+        const DR2 = 1 << 2;
+        const DR3 = 1 << 3;
+        const DR4 = 1 << 4;
+        const DR5 = 1 << 5;
+        const DR6 = 1 << 6;
+        const DR7 = 1 << 7;
+        const DR8 = 1 << 8;
+        const DR9 = 1 << 9;
+        const DR10 = 1 << 10;
+        const DR11 = 1 << 11;
+        const DR12 = 1 << 12;
+        const DR13 = 1 << 13;
+        const DR14 = 1 << 14;
+        const DR15 = 1 << 15;
+    }
+
+    /// Exceptions, one bit per vector, VMCB offset 0x008.
+    /// See: 8.2 Vectors
+    #[repr(transparent)]
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct ExceptionIntercepts: u32 {
+        /// Divide-by-zero error.
+        const DE = 1 << 0;
+        /// Debug.
+        const DB = 1 << 1;
+        /// Non-maskable interrupt.
+        const NMI = 1 << 2;
+        /// Breakpoint.
+        const BP = 1 << 3;
+        /// Overflow.
+        const OF = 1 << 4;
+        /// Bound range.
+        const BR = 1 << 5;
+        /// Invalid opcode.
+        const UD = 1 << 6;
+        /// Device not available.
+        const NM = 1 << 7;
+        /// Double fault.
+        const DF = 1 << 8;
+        /// Invalid TSS.
+        const TS = 1 << 10;
+        /// Segment not present.
+        const NP = 1 << 11;
+        /// Stack.
+        const SS = 1 << 12;
+        /// General protection.
+        const GP = 1 << 13;
+        /// Page fault.
+        const PF = 1 << 14;
+        /// x87 floating-point exception pending.
+        const MF = 1 << 16;
+        /// Alignment check.
+        const AC = 1 << 17;
+        /// Machine check.
+        const MC = 1 << 18;
+        /// SIMD floating-point.
+        const XF = 1 << 19;
+        /// Control protection.
+        const CP = 1 << 21;
+        /// Hypervisor injection.
+        const HV = 1 << 28;
+        /// VMM communication.
+        const VC = 1 << 29;
+        /// Security.
+        const SX = 1 << 30;
+    }
+
+    /// Intercept vector 3, VMCB offset 0x00c.
+    #[repr(transparent)]
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct Misc1Intercepts: u32 {
+        const INTR = 1 << 0;
+        const NMI = 1 << 1;
+        const SMI = 1 << 2;
+        const INIT = 1 << 3;
+        const VINTR = 1 << 4;
+        /// Writes to CR0 that change bits other than CR0.TS or CR0.MP.
+        const CR0_SEL_WRITE = 1 << 5;
+        const IDTR_READ = 1 << 6;
+        const GDTR_READ = 1 << 7;
+        const LDTR_READ = 1 << 8;
+        const TR_READ = 1 << 9;
+        const IDTR_WRITE = 1 << 10;
+        const GDTR_WRITE = 1 << 11;
+        const LDTR_WRITE = 1 << 12;
+        const TR_WRITE = 1 << 13;
+        const RDTSC = 1 << 14;
+        const RDPMC = 1 << 15;
+        const PUSHF = 1 << 16;
+        const POPF = 1 << 17;
+        const CPUID = 1 << 18;
+        const RSM = 1 << 19;
+        const IRET = 1 << 20;
+        const INTN = 1 << 21;
+        const INVD = 1 << 22;
+        const PAUSE = 1 << 23;
+        const HLT = 1 << 24;
+        const INVLPG = 1 << 25;
+        const INVLPGA = 1 << 26;
+        /// IN and OUT accesses selected by the I/O permissions map.
+        const IOIO_PROT = 1 << 27;
+        /// RDMSR and WRMSR accesses selected by the MSR permissions map.
+        const MSR_PROT = 1 << 28;
+        const TASK_SWITCH = 1 << 29;
+        const FERR_FREEZE = 1 << 30;
+        const SHUTDOWN = 1 << 31;
+    }
+
+    /// Intercept vector 4, VMCB offset 0x010.
+    #[repr(transparent)]
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct Misc2Intercepts: u32 {
+        /// Must always be set.
+        const VMRUN = 1 << 0;
+        const VMMCALL = 1 << 1;
+        const VMLOAD = 1 << 2;
+        const VMSAVE = 1 << 3;
+        const STGI = 1 << 4;
+        const CLGI = 1 << 5;
+        const SKINIT = 1 << 6;
+        const RDTSCP = 1 << 7;
+        const ICEBP = 1 << 8;
+        /// WBINVD and WBNOINVD.
+        const WBINVD = 1 << 9;
+        /// MONITOR and MONITORX.
+        const MONITOR = 1 << 10;
+        /// MWAIT and MWAITX, unconditionally.
+        const MWAIT = 1 << 11;
+        /// MWAIT and MWAITX, if the monitor hardware is armed.
+        const MWAIT_CONDITIONAL = 1 << 12;
+        const XSETBV = 1 << 13;
+        const RDPRU = 1 << 14;
+        /// Writes to EFER, after the write completes.
+        const EFER_WRITE_TRAP = 1 << 15;
+        /// Writes to CR0 through CR15, after the write completes.
+        const CR0_WRITE_TRAP = 1 << 16;
+        const CR1_WRITE_TRAP = 1 << 17;
+        const CR2_WRITE_TRAP = 1 << 18;
+        const CR3_WRITE_TRAP = 1 << 19;
+        const CR4_WRITE_TRAP = 1 << 20;
+        const CR5_WRITE_TRAP = 1 << 21;
+        const CR6_WRITE_TRAP = 1 << 22;
+        const CR7_WRITE_TRAP = 1 << 23;
+        const CR8_WRITE_TRAP = 1 << 24;
+        const CR9_WRITE_TRAP = 1 << 25;
+        const CR10_WRITE_TRAP = 1 << 26;
+        const CR11_WRITE_TRAP = 1 << 27;
+        const CR12_WRITE_TRAP = 1 << 28;
+        const CR13_WRITE_TRAP = 1 << 29;
+        const CR14_WRITE_TRAP = 1 << 30;
+        const CR15_WRITE_TRAP = 1 << 31;
+    }
+
+    /// Intercept vector 5, VMCB offset 0x014.
+    #[repr(transparent)]
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct Misc3Intercepts: u32 {
+        /// INVLPGB, all forms.
+        const INVLPGB = 1 << 0;
+        /// INVLPGB with illegally specified operands only.
+        const INVLPGB_ILLEGAL = 1 << 1;
+        const INVPCID = 1 << 2;
+        const MCOMMIT = 1 << 3;
+        const TLBSYNC = 1 << 4;
+        /// Bus lock when the bus lock threshold counter is zero.
+        const BUS_LOCK = 1 << 5;
+        /// HLT, if no virtual interrupt is pending.
+        const IDLE_HLT = 1 << 6;
+    }
+}
+
+impl ExceptionIntercepts {
+    /// Returns the intercept of the exception `vector`. Vectors above 31 yield
+    /// no intercept.
+    pub const fn from_vector(vector: u8) -> Self {
+        if vector < 32 { Self::from_bits_retain(1 << vector) } else { Self::empty() }
+    }
+}
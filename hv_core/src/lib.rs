@@ -0,0 +1,17 @@
+//! This crate holds the parts of the hypervisor that depend neither on the WDK
+//! nor on running in the kernel, so that they can be tested on any host: the
+//! configuration and its parsing from the registry, the intercept vectors, the
+//! decoding of IPIs and the VMCB consistency checks.
+
+#![no_std]
+
+extern crate alloc;
+
+pub mod config;
+pub mod consistency;
+pub mod icr;
+pub mod intercept;
+pub mod registry;
+pub mod registry_value;
+
+pub use registry_value::RegistryValue;
@@ -0,0 +1,559 @@
+//! This module implements the parsing of [`HypervisorConfig`] from the values
+//! of the `Parameters` key of the driver's service key.
+//!
+//! Every value is optional, and an absent value leaves the default from
+//! [`HypervisorConfig::new`]. Names are case-insensitive. Numbers in strings are
+//! decimal, or hexadecimal with a `0x` prefix.
+//!
+//! | Name                  | Type         | Value                                                     |
+//! |-----------------------|--------------|-----------------------------------------------------------|
+//! | LogLevel              | REG_SZ       | `off`, `error`, `warn`, `info`, `debug` or `trace`        |
+//! | LogSink               | REG_SZ       | `debugger`, or `serial[:PORT]` where PORT defaults to 0x3f8 |
+//! | Processors            | REG_SZ       | `all`, indexes such as `0-3,8`, or `GROUP:MASK` entries   |
+//! | CpuidOverrides        | REG_MULTI_SZ | `LEAF[:SUBLEAF] REGISTER [clear=MASK] [set=MASK]` each    |
+//! | IcrIntercept          | REG_SZ       | `disabled`, `until-aps-started` or `always`               |
+//! | CrReadIntercepts      | REG_DWORD    | Bit n intercepts CRn. Only CR0, CR3, CR4 and CR8          |
+//! | CrWriteIntercepts     | REG_DWORD    | As CrReadIntercepts                                       |
+//! | DrReadIntercepts      | REG_DWORD    | Bit n intercepts DRn. Only DR0-DR7                        |
+//! | DrWriteIntercepts     | REG_DWORD    | As DrReadIntercepts                                       |
+//! | HiddenXcr0            | REG_QWORD    | XCR0 components hidden from the guest, except x87         |
+//! | PauseFilterCount      | REG_DWORD    | PAUSE count before #VMEXIT(PAUSE), 0 not to intercept     |
+//! | PauseFilterThreshold  | REG_DWORD    | Requires PauseFilterCount                                 |
+//! | InterceptHlt          | REG_DWORD    | 0 or 1                                                    |
+//! | ShutdownAction        | REG_SZ       | `reset`, `bug-check` or `halt`                            |
+//! | ExtendedStateSwitch   | REG_SZ       | `sse`, `eager` or `lazy`                                  |
+//! | SpecCtrl              | REG_SZ       | `auto`, `enabled` or `disabled`                           |
+//! | RsbFill               | REG_SZ       | As SpecCtrl                                               |
+//! | IbpbOnExit            | REG_SZ       | As SpecCtrl                                               |
+//! | DebugBreaks           | REG_DWORD    | The bits of [`DebugBreaks`]                               |
+//!
+//! In `Processors`, `GROUP:MASK` selects the processors of the processor group
+//! GROUP whose number is set in MASK, so `0:0xff,1:0x3` selects processors 0-7
+//! of group 0 and 0-1 of group 1. Indexes must be below
+//! [`MAX_PROCESSORS`].
+//!
+//! REG_DWORD is accepted for REG_QWORD values too. Any other value, type or
+//! content is rejected with a [`ConfigError`] naming the value.
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::str::FromStr;
+
+use hv_interface::MAX_PROCESSORS;
+use log::LevelFilter;
+
+use crate::config::{
+    CpuidOverride, CpuidRegister, DebugBreaks, ExtendedStateSwitch, GroupAffinity,
+    HypervisorConfig, IcrInterceptPolicy, LogSink, Mitigation, PauseFilter, ProcessorSet,
+    ShutdownAction,
+};
+use crate::intercept::{
+    CrReadIntercepts, CrWriteIntercepts, DrReadIntercepts, DrWriteIntercepts,
+};
+use crate::RegistryValue;
+
+const DEFAULT_SERIAL_PORT: u16 = 0x3f8;
+const SUPPORTED_CRS: u32 = 1 << 0 | 1 << 3 | 1 << 4 | 1 << 8;
+const SUPPORTED_DRS: u32 = 0xff;
+const XCR0_X87: u64 = 1 << 0;
+
+#[derive(thiserror_no_std::Error, Clone, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    #[error("unknown value `{name}`")]
+    UnknownValue { name: String },
+
+    #[error("`{name}` must be a {expected}")]
+    InvalidType {
+        name: String,
+        expected: &'static str,
+    },
+
+    #[error("`{name}` is invalid: {reason}")]
+    InvalidValue { name: String, reason: String },
+}
+
+/// Parses the configuration from the name and data of each registry value.
+pub fn parse<'a>(
+    values: impl IntoIterator<Item = (&'a str, &'a RegistryValue)>,
+) -> Result<HypervisorConfig, ConfigError> {
+    let mut config = HypervisorConfig::new();
+    let mut pause_count = None;
+    let mut pause_threshold = None;
+
+    for (name, value) in values {
+        let value = Value { name, value };
+        match name.to_ascii_lowercase().as_str() {
+            "loglevel" => config.log_level = value.parse_str(|s| LevelFilter::from_str(s).ok())?,
+            "logsink" => config.log_sink = value.parse_str(parse_log_sink)?,
+            "processors" => config.processors = value.parse_str(parse_processors)?,
+            "cpuidoverrides" => config.cpuid_overrides = value.cpuid_overrides()?,
+            "icrintercept" => {
+                config.icr_intercept = value.parse_str(|s| match s {
+                    "disabled" => Some(IcrInterceptPolicy::Disabled),
+                    "until-aps-started" => Some(IcrInterceptPolicy::UntilApsStarted),
+                    "always" => Some(IcrInterceptPolicy::Always),
+                    _ => None,
+                })?
+            }
+            "crreadintercepts" => {
+                config.cr_read_intercepts =
+                    CrReadIntercepts::from_bits_retain(value.mask(SUPPORTED_CRS)? as u16)
+            }
+            "crwriteintercepts" => {
+                config.cr_write_intercepts =
+                    CrWriteIntercepts::from_bits_retain(value.mask(SUPPORTED_CRS)? as u16)
+            }
+            "drreadintercepts" => {
+                config.dr_read_intercepts =
+                    DrReadIntercepts::from_bits_retain(value.mask(SUPPORTED_DRS)? as u16)
+            }
+            "drwriteintercepts" => {
+                config.dr_write_intercepts =
+                    DrWriteIntercepts::from_bits_retain(value.mask(SUPPORTED_DRS)? as u16)
+            }
+            "hiddenxcr0" => {
+                let hidden = value.qword()?;
+                if hidden & XCR0_X87 != 0 {
+                    return Err(value.invalid("x87 state cannot be hidden"));
+                }
+                config.hidden_xcr0 = hidden;
+            }
+            "pausefiltercount" => {
+                pause_count = Some(value.dword_in(0..=u32::from(u16::MAX))? as u16)
+            }
+            "pausefilterthreshold" => {
+                pause_threshold = Some(value.dword_in(0..=u32::from(u16::MAX))? as u16)
+            }
+            "intercepthlt" => config.intercept_hlt = value.dword_in(0..=1)? == 1,
+            "shutdownaction" => {
+                config.shutdown_action = value.parse_str(|s| match s {
+                    "reset" => Some(ShutdownAction::Reset),
+                    "bug-check" => Some(ShutdownAction::BugCheck),
+                    "halt" => Some(ShutdownAction::Halt),
+                    _ => None,
+                })?
+            }
+            "extendedstateswitch" => {
+                config.extended_state_switch = value.parse_str(|s| match s {
+                    "sse" => Some(ExtendedStateSwitch::Sse),
+                    "eager" => Some(ExtendedStateSwitch::Eager),
+                    "lazy" => Some(ExtendedStateSwitch::Lazy),
+                    _ => None,
+                })?
+            }
+            "specctrl" => config.speculation.spec_ctrl = value.parse_str(parse_mitigation)?,
+            "rsbfill" => config.speculation.rsb_fill = value.parse_str(parse_mitigation)?,
+            "ibpbonexit" => config.speculation.ibpb_on_exit = value.parse_str(parse_mitigation)?,
+            "debugbreaks" => {
+                config.debug_breaks =
+                    DebugBreaks::from_bits_retain(value.mask(DebugBreaks::all().bits())?)
+            }
+            _ => return Err(ConfigError::UnknownValue { name: name.into() }),
+        }
+    }
+
+    config.pause_filter = match (pause_count, pause_threshold) {
+        (Some(0) | None, Some(_)) => {
+            return Err(ConfigError::InvalidValue {
+                name: "PauseFilterThreshold".into(),
+                reason: "PauseFilterCount is not set".into(),
+            });
+        }
+        (Some(0) | None, None) => None,
+        (Some(count), threshold) => Some(PauseFilter {
+            count,
+            threshold: threshold.unwrap_or(0),
+        }),
+    };
+    Ok(config)
+}
+
+/// A registry value being parsed.
+struct Value<'a> {
+    name: &'a str,
+    value: &'a RegistryValue,
+}
+
+impl Value<'_> {
+    fn invalid(&self, reason: impl ToString) -> ConfigError {
+        ConfigError::InvalidValue {
+            name: self.name.into(),
+            reason: reason.to_string(),
+        }
+    }
+
+    fn invalid_type(&self, expected: &'static str) -> ConfigError {
+        ConfigError::InvalidType {
+            name: self.name.into(),
+            expected,
+        }
+    }
+
+    /// Parses a REG_SZ value with `parse`, which is given the trimmed string in
+    /// lowercase.
+    fn parse_str<T>(&self, parse: impl FnOnce(&str) -> Option<T>) -> Result<T, ConfigError> {
+        let RegistryValue::String(string) = self.value else {
+            return Err(self.invalid_type("REG_SZ"));
+        };
+        parse(&string.trim().to_ascii_lowercase())
+            .ok_or_else(|| self.invalid(format!("`{string}` is not recognized")))
+    }
+
+    fn dword(&self) -> Result<u32, ConfigError> {
+        match self.value {
+            RegistryValue::Dword(value) => Ok(*value),
+            _ => Err(self.invalid_type("REG_DWORD")),
+        }
+    }
+
+    fn dword_in(&self, range: core::ops::RangeInclusive<u32>) -> Result<u32, ConfigError> {
+        let value = self.dword()?;
+        if !range.contains(&value) {
+            return Err(self.invalid(format!(
+                "{value} is not in {}..={}",
+                range.start(),
+                range.end()
+            )));
+        }
+        Ok(value)
+    }
+
+    /// Returns a REG_DWORD whose bits must all be in `supported`.
+    fn mask(&self, supported: u32) -> Result<u32, ConfigError> {
+        let value = self.dword()?;
+        if value & !supported != 0 {
+            return Err(self.invalid(format!("bits {:#x} are not supported", value & !supported)));
+        }
+        Ok(value)
+    }
+
+    fn qword(&self) -> Result<u64, ConfigError> {
+        match self.value {
+            RegistryValue::Qword(value) => Ok(*value),
+            RegistryValue::Dword(value) => Ok(u64::from(*value)),
+            _ => Err(self.invalid_type("REG_QWORD")),
+        }
+    }
+
+    fn cpuid_overrides(&self) -> Result<Vec<CpuidOverride>, ConfigError> {
+        let RegistryValue::MultiString(strings) = self.value else {
+            return Err(self.invalid_type("REG_MULTI_SZ"));
+        };
+        strings
+            .iter()
+            .map(|string| {
+                parse_cpuid_override(string)
+                    .ok_or_else(|| self.invalid(format!("`{string}` is not recognized")))
+            })
+            .collect()
+    }
+}
+
+fn parse_number<T: TryFrom<u64>>(s: &str) -> Option<T> {
+    let value = match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => s.parse().ok()?,
+    };
+    T::try_from(value).ok()
+}
+
+fn parse_log_sink(s: &str) -> Option<LogSink> {
+    match s.split_once(':') {
+        None if s == "debugger" => Some(LogSink::Debugger),
+        None if s == "serial" => Some(LogSink::Serial {
+            port: DEFAULT_SERIAL_PORT,
+        }),
+        Some(("serial", port)) => Some(LogSink::Serial {
+            port: parse_number(port.trim())?,
+        }),
+        _ => None,
+    }
+}
+
+fn parse_processors(s: &str) -> Option<ProcessorSet> {
+    if s == "all" {
+        return Some(ProcessorSet::All);
+    }
+    if s.contains(':') {
+        return parse_group_affinities(s);
+    }
+    let mut indexes = Vec::new();
+    for range in s.split(',').map(str::trim) {
+        let (start, end): (usize, usize) = match range.split_once('-') {
+            Some((start, end)) => (parse_number(start.trim())?, parse_number(end.trim())?),
+            None => {
+                let index = parse_number(range)?;
+                (index, index)
+            }
+        };
+        if start > end || end >= MAX_PROCESSORS {
+            return None;
+        }
+        indexes.extend(start..=end);
+    }
+    indexes.sort_unstable();
+    indexes.dedup();
+    Some(ProcessorSet::Only(indexes))
+}
+
+/// Parses `GROUP:MASK` entries. Entries of the same group are merged.
+fn parse_group_affinities(s: &str) -> Option<ProcessorSet> {
+    let mut affinities: Vec<GroupAffinity> = Vec::new();
+    for entry in s.split(',').map(str::trim) {
+        let (group, mask) = entry.split_once(':')?;
+        let group = parse_number(group.trim())?;
+        let mask = parse_number(mask.trim())?;
+        match affinities.iter_mut().find(|affinity| affinity.group == group) {
+            Some(affinity) => affinity.mask |= mask,
+            None => affinities.push(GroupAffinity { group, mask }),
+        }
+    }
+    affinities.sort_unstable_by_key(|affinity| affinity.group);
+    Some(ProcessorSet::Affinity(affinities))
+}
+
+fn parse_mitigation(s: &str) -> Option<Mitigation> {
+    match s {
+        "auto" => Some(Mitigation::Auto),
+        "enabled" => Some(Mitigation::Enabled),
+        "disabled" => Some(Mitigation::Disabled),
+        _ => None,
+    }
+}
+
+/// Parses `LEAF[:SUBLEAF] REGISTER [clear=MASK] [set=MASK]`.
+fn parse_cpuid_override(s: &str) -> Option<CpuidOverride> {
+    let mut tokens = s.split_whitespace();
+    let leaf = tokens.next()?;
+    let (leaf, sub_leaf) = match leaf.split_once(':') {
+        Some((leaf, sub_leaf)) => (parse_number(leaf)?, Some(parse_number(sub_leaf)?)),
+        None => (parse_number(leaf)?, None),
+    };
+    let register = match tokens.next()?.to_ascii_lowercase().as_str() {
+        "eax" => CpuidRegister::Eax,
+        "ebx" => CpuidRegister::Ebx,
+        "ecx" => CpuidRegister::Ecx,
+        "edx" => CpuidRegister::Edx,
+        _ => return None,
+    };
+    let mut cpuid_override = CpuidOverride {
+        leaf,
+        sub_leaf,
+        register,
+        clear: 0,
+        set: 0,
+    };
+    for token in tokens {
+        match token.split_once('=')? {
+            ("clear", mask) => cpuid_override.clear = parse_number(mask)?,
+            ("set", mask) => cpuid_override.set = parse_number(mask)?,
+            _ => return None,
+        }
+    }
+    if cpuid_override.clear == 0 && cpuid_override.set == 0 {
+        return None;
+    }
+    Some(cpuid_override)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn parse_values(values: &[(&str, RegistryValue)]) -> Result<HypervisorConfig, ConfigError> {
+        parse(values.iter().map(|(name, value)| (*name, value)))
+    }
+
+    fn string(s: &str) -> RegistryValue {
+        RegistryValue::String(s.into())
+    }
+
+    #[test]
+    fn defaults_without_values() {
+        let config = parse_values(&[]).unwrap();
+        assert_eq!(config.log_level, LevelFilter::Trace);
+        assert_eq!(config.processors, ProcessorSet::All);
+        assert_eq!(config.debug_breaks, DebugBreaks::all());
+        assert!(config.cpuid_overrides.is_empty());
+    }
+
+    #[test]
+    fn parses_every_value() {
+        let config = parse_values(&[
+            ("LogLevel", string("info")),
+            ("logsink", string("serial:0x2f8")),
+            ("Processors", string("0-2, 5, 1")),
+            (
+                "CpuidOverrides",
+                RegistryValue::MultiString(vec![
+                    "0x7:0 ebx clear=0x20".into(),
+                    "0x40000000 EAX set=1".into(),
+                ]),
+            ),
+            ("IcrIntercept", string("until-aps-started")),
+            ("CrReadIntercepts", RegistryValue::Dword(0x9)),
+            ("CrWriteIntercepts", RegistryValue::Dword(0x110)),
+            ("DrReadIntercepts", RegistryValue::Dword(0x80)),
+            ("DrWriteIntercepts", RegistryValue::Dword(0x1)),
+            ("HiddenXcr0", RegistryValue::Qword(0xe0)),
+            ("PauseFilterCount", RegistryValue::Dword(3000)),
+            ("PauseFilterThreshold", RegistryValue::Dword(100)),
+            ("InterceptHlt", RegistryValue::Dword(1)),
+            ("ShutdownAction", string("bug-check")),
+            ("ExtendedStateSwitch", string("lazy")),
+            ("SpecCtrl", string("disabled")),
+            ("RsbFill", string("enabled")),
+            ("IbpbOnExit", string("auto")),
+            ("DebugBreaks", RegistryValue::Dword(0)),
+        ])
+        .unwrap();
+
+        assert_eq!(config.log_level, LevelFilter::Info);
+        assert_eq!(config.log_sink, LogSink::Serial { port: 0x2f8 });
+        assert_eq!(config.processors, ProcessorSet::Only(vec![0, 1, 2, 5]));
+        assert_eq!(
+            config.cpuid_overrides,
+            [
+                CpuidOverride {
+                    leaf: 7,
+                    sub_leaf: Some(0),
+                    register: CpuidRegister::Ebx,
+                    clear: 0x20,
+                    set: 0
+                },
+                CpuidOverride {
+                    leaf: 0x4000_0000,
+                    sub_leaf: None,
+                    register: CpuidRegister::Eax,
+                    clear: 0,
+                    set: 1
+                },
+            ]
+        );
+        assert_eq!(config.icr_intercept, IcrInterceptPolicy::UntilApsStarted);
+        assert_eq!(
+            config.cr_read_intercepts,
+            CrReadIntercepts::CR0 | CrReadIntercepts::CR3
+        );
+        assert_eq!(
+            config.cr_write_intercepts,
+            CrWriteIntercepts::CR4 | CrWriteIntercepts::CR8
+        );
+        assert_eq!(config.dr_read_intercepts, DrReadIntercepts::DR7);
+        assert_eq!(config.dr_write_intercepts, DrWriteIntercepts::DR0);
+        assert_eq!(config.hidden_xcr0, 0xe0);
+        assert_eq!(
+            config.pause_filter,
+            Some(PauseFilter {
+                count: 3000,
+                threshold: 100
+            })
+        );
+        assert!(config.intercept_hlt);
+        assert_eq!(config.shutdown_action, ShutdownAction::BugCheck);
+        assert_eq!(config.extended_state_switch, ExtendedStateSwitch::Lazy);
+        assert_eq!(config.speculation.spec_ctrl, Mitigation::Disabled);
+        assert_eq!(config.speculation.rsb_fill, Mitigation::Enabled);
+        assert_eq!(config.speculation.ibpb_on_exit, Mitigation::Auto);
+        assert_eq!(config.debug_breaks, DebugBreaks::empty());
+    }
+
+    #[test]
+    fn disables_pause_filter_with_zero_count() {
+        let config = parse_values(&[("PauseFilterCount", RegistryValue::Dword(0))]).unwrap();
+        assert_eq!(config.pause_filter, None);
+        assert!(matches!(
+            parse_values(&[("PauseFilterThreshold", RegistryValue::Dword(10))]),
+            Err(ConfigError::InvalidValue { name, .. }) if name == "PauseFilterThreshold"
+        ));
+    }
+
+    #[test]
+    fn parses_group_affinities() {
+        let config = parse_values(&[("Processors", string("1:0x3, 0:0xf0,1:0x8"))]).unwrap();
+        assert_eq!(
+            config.processors,
+            ProcessorSet::Affinity(vec![
+                GroupAffinity {
+                    group: 0,
+                    mask: 0xf0
+                },
+                GroupAffinity {
+                    group: 1,
+                    mask: 0xb
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_names() {
+        assert_eq!(
+            parse_values(&[("LogLevl", string("info"))]).unwrap_err(),
+            ConfigError::UnknownValue {
+                name: "LogLevl".into()
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_types() {
+        assert_eq!(
+            parse_values(&[("InterceptHlt", string("1"))]).unwrap_err(),
+            ConfigError::InvalidType {
+                name: "InterceptHlt".into(),
+                expected: "REG_DWORD"
+            }
+        );
+        assert_eq!(
+            parse_values(&[("LogLevel", RegistryValue::Dword(1))]).unwrap_err(),
+            ConfigError::InvalidType {
+                name: "LogLevel".into(),
+                expected: "REG_SZ"
+            }
+        );
+        assert!(parse_values(&[("HiddenXcr0", RegistryValue::Dword(0x4))]).is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let invalid = [
+            ("LogLevel", string("verbose")),
+            ("LogSink", string("serial:port")),
+            ("Processors", string("3-1")),
+            ("Processors", string("")),
+            ("Processors", string("0:0xff,3")),
+            ("Processors", string("0x10000:1")),
+            ("Processors", string("0-0xffffffffffffffff")),
+            ("Processors", string("1,1000")),
+            (
+                "CpuidOverrides",
+                RegistryValue::MultiString(vec!["0x1 esi set=1".into()]),
+            ),
+            (
+                "CpuidOverrides",
+                RegistryValue::MultiString(vec!["0x1 ecx".into()]),
+            ),
+            (
+                "CpuidOverrides",
+                RegistryValue::MultiString(vec!["0x1 ecx set=0x100000000".into()]),
+            ),
+            ("CrReadIntercepts", RegistryValue::Dword(1 << 2)),
+            ("DrWriteIntercepts", RegistryValue::Dword(1 << 8)),
+            ("HiddenXcr0", RegistryValue::Qword(0x1)),
+            ("PauseFilterCount", RegistryValue::Dword(0x10000)),
+            ("InterceptHlt", RegistryValue::Dword(2)),
+            ("ShutdownAction", string("reboot")),
+            ("DebugBreaks", RegistryValue::Dword(1 << 31)),
+        ];
+        for (name, value) in invalid {
+            let error = parse_values(&[(name, value.clone())]).unwrap_err();
+            assert!(
+                matches!(&error, ConfigError::InvalidValue { name: invalid, .. } if invalid == name),
+                "{name} = {value:?}: {error}"
+            );
+        }
+    }
+}
@@ -0,0 +1,97 @@
+//! This module implements the decoding of registry value data. It does not
+//! depend on the WDK, so that configuration parsing can be tested off-target.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+// The value types of winnt.h.
+const REG_SZ: u32 = 1;
+const REG_EXPAND_SZ: u32 = 2;
+const REG_DWORD: u32 = 4;
+const REG_MULTI_SZ: u32 = 7;
+const REG_QWORD: u32 = 11;
+
+/// The data of a registry value.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RegistryValue {
+    Dword(u32),
+    Qword(u64),
+    /// REG_SZ or REG_EXPAND_SZ, without expanding environment variables.
+    String(String),
+    MultiString(Vec<String>),
+    /// A value of any other type, such as REG_BINARY.
+    Other {
+        value_type: u32,
+    },
+}
+
+impl RegistryValue {
+    /// Decodes the data of a value of `value_type`. Data shorter than the type
+    /// requires is decoded as [`RegistryValue::Other`].
+    pub fn from_raw(value_type: u32, data: &[u8]) -> Self {
+        match value_type {
+            REG_DWORD if data.len() >= 4 => {
+                Self::Dword(u32::from_le_bytes(data[..4].try_into().unwrap()))
+            }
+            REG_QWORD if data.len() >= 8 => {
+                Self::Qword(u64::from_le_bytes(data[..8].try_into().unwrap()))
+            }
+            REG_SZ | REG_EXPAND_SZ => {
+                let string = utf16(data);
+                Self::String(string.split('\0').next().unwrap_or_default().into())
+            }
+            REG_MULTI_SZ => Self::MultiString(
+                utf16(data)
+                    .split('\0')
+                    .take_while(|string| !string.is_empty())
+                    .map(String::from)
+                    .collect(),
+            ),
+            _ => Self::Other { value_type },
+        }
+    }
+}
+
+/// Decodes little-endian UTF-16, replacing invalid code units.
+pub fn utf16(bytes: &[u8]) -> String {
+    let units = bytes
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]));
+    char::decode_utf16(units)
+        .map(|unit| unit.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn utf16_bytes(string: &str) -> Vec<u8> {
+        string.encode_utf16().flat_map(u16::to_le_bytes).collect()
+    }
+
+    #[test]
+    fn decodes_numbers() {
+        assert_eq!(RegistryValue::from_raw(REG_DWORD, &[0x78, 0x56, 0x34, 0x12]), RegistryValue::Dword(0x1234_5678));
+        assert_eq!(RegistryValue::from_raw(REG_QWORD, &[1, 0, 0, 0, 0, 0, 0, 0x80]), RegistryValue::Qword(0x8000_0000_0000_0001));
+        assert_eq!(RegistryValue::from_raw(REG_DWORD, &[1, 0]), RegistryValue::Other { value_type: REG_DWORD });
+    }
+
+    #[test]
+    fn decodes_strings() {
+        assert_eq!(RegistryValue::from_raw(REG_SZ, &utf16_bytes("Debugger\0")), RegistryValue::String("Debugger".into()));
+        assert_eq!(RegistryValue::from_raw(REG_EXPAND_SZ, &utf16_bytes("%SystemRoot%")), RegistryValue::String("%SystemRoot%".into()));
+        assert_eq!(
+            RegistryValue::from_raw(REG_MULTI_SZ, &utf16_bytes("0x1 ecx\u{0}0x7 ebx\0\0")),
+            RegistryValue::MultiString(vec!["0x1 ecx".into(), "0x7 ebx".into()])
+        );
+        assert_eq!(RegistryValue::from_raw(REG_MULTI_SZ, &utf16_bytes("\0")), RegistryValue::MultiString(vec![]));
+    }
+
+    #[test]
+    fn keeps_other_types() {
+        const REG_BINARY: u32 = 3;
+        assert_eq!(RegistryValue::from_raw(REG_BINARY, &[1, 2, 3]), RegistryValue::Other { value_type: REG_BINARY });
+    }
+}
@@ -0,0 +1,326 @@
+//! This crate decodes the hypervisor data the driver adds to crash dumps.
+//!
+//! The format is documented in `hypervisor::amd::dump`, and its layout is
+//! shared through `hv_interface::dump`. The data is located in
+//! a dump file by its header, which holds both [`DUMP_MAGIC`] and
+//! [`DUMP_GUID`], so the dump file format itself does not need to be parsed.
+
+use std::fmt::{self, Write};
+
+use hv_interface::dump::{
+    DUMP_FLAG_TRUNCATED, DUMP_HEADER_SIZE, DUMP_RECORD_SIZE, HEADER_BUG_CHECK_PROCESSOR,
+    HEADER_FLAGS, HEADER_GUID, HEADER_HEADER_SIZE, HEADER_MAGIC, HEADER_RECORD_COUNT,
+    HEADER_RECORD_SIZE, HEADER_TSC, HEADER_VERSION, RECENT_EXIT_COUNT, RECORD_COUNTERS,
+    RECORD_EXIT_COUNTS, RECORD_GPRS, RECORD_PROCESSOR_ID, RECORD_RECENT_COUNT, RECORD_RECENT_EXITS,
+    RECORD_RFLAGS, RECORD_RIP, RECORD_TOTAL_EXITS, RECORD_VMCB, TRACE_ENTRY_SIZE, VMCB_SIZE,
+};
+use hv_interface::EXIT_SLOT_COUNT;
+use hv_trace::{exit_name, Action, TraceEntry};
+
+pub use hv_interface::dump::{DUMP_GUID, DUMP_MAGIC, DUMP_VERSION};
+
+const GPR_NAMES: [&str; 16] = [
+    "RAX", "RCX", "RDX", "RBX", "RSP", "RBP", "RSI", "RDI", "R8", "R9", "R10", "R11", "R12", "R13",
+    "R14", "R15",
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Dump {
+    pub version: u16,
+    /// Whether some virtualized processors did not fit into the data.
+    pub truncated: bool,
+    /// The processor that bug checked, if known.
+    pub bug_check_processor: Option<u32>,
+    pub tsc: u64,
+    pub processors: Vec<ProcessorRecord>,
+}
+
+/// The state of a virtualized processor as of its last #VMEXIT.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProcessorRecord {
+    pub processor_id: u32,
+    /// The number of #VMEXITs recorded since the trace started.
+    pub total_exits: u64,
+    pub guest_cycles: u64,
+    pub host_cycles: u64,
+    pub injected_events: u64,
+    /// The general purpose registers in the ModRM order.
+    pub gprs: [u64; 16],
+    pub rip: u64,
+    pub rflags: u64,
+    /// The number of #VMEXITs per exit reason. See [`exit_code_of_slot`].
+    pub exit_counts: Vec<u64>,
+    /// The most recent #VMEXITs, oldest first.
+    pub recent_exits: Vec<TraceEntry>,
+    /// The guest VMCB.
+    pub vmcb: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    NotFound,
+    BadMagic([u8; 4]),
+    UnsupportedVersion(u16),
+    BadHeaderSize(u16),
+    BadRecordSize(u32),
+    Truncated { size: usize, required: usize },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound => f.write_str("no hypervisor data found"),
+            Self::BadMagic(magic) => write!(f, "not hypervisor data: bad magic {magic:02x?}"),
+            Self::UnsupportedVersion(version) => write!(f, "unsupported version {version}"),
+            Self::BadHeaderSize(size) => {
+                write!(f, "header size {size} is smaller than {DUMP_HEADER_SIZE}")
+            }
+            Self::BadRecordSize(size) => {
+                write!(f, "record size {size} is smaller than {DUMP_RECORD_SIZE}")
+            }
+            Self::Truncated { size, required } => {
+                write!(
+                    f,
+                    "truncated: {size} bytes but {required} bytes are required"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// Returns the offset of the hypervisor data in `bytes`, such as the contents
+/// of a dump file, if any.
+pub fn find(bytes: &[u8]) -> Option<usize> {
+    bytes.windows(DUMP_HEADER_SIZE).position(|header| {
+        header[HEADER_MAGIC..HEADER_MAGIC + 4] == DUMP_MAGIC
+            && header[HEADER_GUID..HEADER_GUID + 16] == DUMP_GUID
+    })
+}
+
+/// Finds and decodes the hypervisor data in `bytes`, such as the contents of a
+/// dump file.
+pub fn extract(bytes: &[u8]) -> Result<Dump, DecodeError> {
+    let offset = find(bytes).ok_or(DecodeError::NotFound)?;
+    decode(&bytes[offset..])
+}
+
+/// Decodes the hypervisor data at the start of `bytes`. Bytes after the last
+/// record are ignored.
+pub fn decode(bytes: &[u8]) -> Result<Dump, DecodeError> {
+    if bytes.len() < DUMP_HEADER_SIZE {
+        return Err(DecodeError::Truncated {
+            size: bytes.len(),
+            required: DUMP_HEADER_SIZE,
+        });
+    }
+
+    let magic: [u8; 4] = bytes[HEADER_MAGIC..HEADER_MAGIC + 4].try_into().unwrap();
+    if magic != DUMP_MAGIC {
+        return Err(DecodeError::BadMagic(magic));
+    }
+    let version = u16_at(bytes, HEADER_VERSION);
+    if version != DUMP_VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+    let header_size = u16_at(bytes, HEADER_HEADER_SIZE);
+    if (header_size as usize) < DUMP_HEADER_SIZE {
+        return Err(DecodeError::BadHeaderSize(header_size));
+    }
+    let record_size = u32_at(bytes, HEADER_RECORD_SIZE);
+    if (record_size as usize) < DUMP_RECORD_SIZE {
+        return Err(DecodeError::BadRecordSize(record_size));
+    }
+    let count = u32_at(bytes, HEADER_RECORD_COUNT) as usize;
+    let flags = u32_at(bytes, HEADER_FLAGS);
+    let bug_check_processor =
+        Some(u32_at(bytes, HEADER_BUG_CHECK_PROCESSOR)).filter(|&id| id != u32::MAX);
+    let tsc = u64_at(bytes, HEADER_TSC);
+
+    let required = header_size as usize + count * record_size as usize;
+    if bytes.len() < required {
+        return Err(DecodeError::Truncated {
+            size: bytes.len(),
+            required,
+        });
+    }
+
+    let processors = bytes[header_size as usize..required]
+        .chunks_exact(record_size as usize)
+        .map(decode_record)
+        .collect();
+
+    Ok(Dump {
+        version,
+        truncated: flags & DUMP_FLAG_TRUNCATED != 0,
+        bug_check_processor,
+        tsc,
+        processors,
+    })
+}
+
+fn decode_record(record: &[u8]) -> ProcessorRecord {
+    let recent_count = (u32_at(record, RECORD_RECENT_COUNT) as usize).min(RECENT_EXIT_COUNT);
+    let recent_exits = record
+        [RECORD_RECENT_EXITS..RECORD_RECENT_EXITS + recent_count * TRACE_ENTRY_SIZE]
+        .chunks_exact(TRACE_ENTRY_SIZE)
+        .map(|entry| TraceEntry {
+            sequence: u64_at(entry, 0x00),
+            tsc: u64_at(entry, 0x08),
+            exit_code: u64_at(entry, 0x10),
+            exit_info1: u64_at(entry, 0x18),
+            exit_info2: u64_at(entry, 0x20),
+            rip: u64_at(entry, 0x28),
+            cr3: u64_at(entry, 0x30),
+            action: Action::from(u32_at(entry, 0x38)),
+        })
+        .collect();
+
+    ProcessorRecord {
+        processor_id: u32_at(record, RECORD_PROCESSOR_ID),
+        total_exits: u64_at(record, RECORD_TOTAL_EXITS),
+        guest_cycles: u64_at(record, RECORD_COUNTERS),
+        host_cycles: u64_at(record, RECORD_COUNTERS + 8),
+        injected_events: u64_at(record, RECORD_COUNTERS + 16),
+        gprs: std::array::from_fn(|index| u64_at(record, RECORD_GPRS + index * 8)),
+        rip: u64_at(record, RECORD_RIP),
+        rflags: u64_at(record, RECORD_RFLAGS),
+        exit_counts: (0..EXIT_SLOT_COUNT)
+            .map(|slot| u64_at(record, RECORD_EXIT_COUNTS + slot * 8))
+            .collect(),
+        recent_exits,
+        vmcb: record[RECORD_VMCB..RECORD_VMCB + VMCB_SIZE].to_vec(),
+    }
+}
+
+/// Returns the exit code counted in `slot` of [`ProcessorRecord::exit_counts`],
+/// or `None` for the slot counting unknown exit codes and unused slots.
+pub fn exit_code_of_slot(slot: usize) -> Option<u64> {
+    match slot {
+        0x00..=0xa6 => Some(slot as u64),
+        0xa8..=0xab => Some(0x400 + (slot - 0xa8) as u64),
+        0xac => Some(u64::MAX),
+        _ => None,
+    }
+}
+
+impl ProcessorRecord {
+    fn vmcb_u64(&self, offset: usize) -> u64 {
+        u64_at(&self.vmcb, offset)
+    }
+
+    /// EXITCODE, EXITINFO1, EXITINFO2 and EXITINTINFO of the last #VMEXIT.
+    pub fn exit(&self) -> [u64; 4] {
+        [0x70, 0x78, 0x80, 0x88].map(|offset| self.vmcb_u64(offset))
+    }
+
+    /// CR0, CR2, CR3, CR4 and EFER from the state save area of the VMCB.
+    pub fn control_registers(&self) -> [u64; 5] {
+        [0x558, 0x640, 0x550, 0x548, 0x4d0].map(|offset| self.vmcb_u64(offset))
+    }
+}
+
+impl Dump {
+    /// Formats the data as text.
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        let bug_check_processor = self
+            .bug_check_processor
+            .map_or("an unknown processor".to_string(), |id| {
+                format!("processor {id}")
+            });
+        writeln!(
+            text,
+            "bug check on {bug_check_processor} at tsc={:#x}, {} virtualized processors{}",
+            self.tsc,
+            self.processors.len(),
+            if self.truncated { " (truncated)" } else { "" }
+        )
+        .unwrap();
+
+        for processor in &self.processors {
+            writeln!(text).unwrap();
+            writeln!(
+                text,
+                "processor {}: {} exits, guest={:#x} host={:#x} cycles, {} injected",
+                processor.processor_id,
+                processor.total_exits,
+                processor.guest_cycles,
+                processor.host_cycles,
+                processor.injected_events
+            )
+            .unwrap();
+            let [exit_code, exit_info1, exit_info2, exit_int_info] = processor.exit();
+            writeln!(
+                text,
+                "last exit {}({exit_code:#x}) info1={exit_info1:#x} info2={exit_info2:#x} intinfo={exit_int_info:#x}",
+                exit_name(exit_code)
+            )
+            .unwrap();
+            writeln!(
+                text,
+                "RIP {:#018x} RFLAGS {:#018x}",
+                processor.rip, processor.rflags
+            )
+            .unwrap();
+            for (pair, names) in processor.gprs.chunks(2).zip(GPR_NAMES.chunks(2)) {
+                writeln!(
+                    text,
+                    "{:<3} {:#018x} {:<3} {:#018x}",
+                    names[0], pair[0], names[1], pair[1]
+                )
+                .unwrap();
+            }
+            let [cr0, cr2, cr3, cr4, efer] = processor.control_registers();
+            writeln!(text, "CR0 {cr0:#018x} CR2 {cr2:#018x} CR3 {cr3:#018x}").unwrap();
+            writeln!(text, "CR4 {cr4:#018x} EFER {efer:#018x}").unwrap();
+
+            writeln!(text, "exit counts:").unwrap();
+            for (slot, count) in processor
+                .exit_counts
+                .iter()
+                .enumerate()
+                .filter(|(_, &count)| count != 0)
+            {
+                match exit_code_of_slot(slot) {
+                    Some(exit_code) => {
+                        writeln!(text, "  {}({exit_code:#x}) {count}", exit_name(exit_code))
+                    }
+                    None => writeln!(text, "  UNKNOWN {count}"),
+                }
+                .unwrap();
+            }
+            writeln!(text, "recent exits:").unwrap();
+            for entry in &processor.recent_exits {
+                writeln!(
+                    text,
+                    "  #{} tsc={:#x} {}({:#x}) info1={:#x} info2={:#x} rip={:#x} cr3={:#x} {}",
+                    entry.sequence,
+                    entry.tsc,
+                    exit_name(entry.exit_code),
+                    entry.exit_code,
+                    entry.exit_info1,
+                    entry.exit_info2,
+                    entry.rip,
+                    entry.cr3,
+                    entry.action,
+                )
+                .unwrap();
+            }
+        }
+        text
+    }
+}
@@ -0,0 +1,48 @@
+//! Extracts and decodes the hypervisor data from a crash dump file.
+//!
+//! Usage: hv_dump [--vmcb <directory>] <file>
+//!
+//! With `--vmcb`, the guest VMCB of each processor is also written to
+//! `<directory>/vmcb<processor ID>.bin`.
+
+use std::path::Path;
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (vmcb_directory, path) = match args.as_slice() {
+        [path] => (None, path),
+        [flag, directory, path] if flag == "--vmcb" => (Some(Path::new(directory)), path),
+        _ => {
+            eprintln!("usage: hv_dump [--vmcb <directory>] <file>");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            eprintln!("{path}: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let dump = match hv_dump::extract(&bytes) {
+        Ok(dump) => dump,
+        Err(err) => {
+            eprintln!("{path}: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    print!("{}", dump.to_text());
+    if let Some(directory) = vmcb_directory {
+        for processor in &dump.processors {
+            let vmcb_path = directory.join(format!("vmcb{}.bin", processor.processor_id));
+            if let Err(err) = std::fs::write(&vmcb_path, &processor.vmcb) {
+                eprintln!("{}: {err}", vmcb_path.display());
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    ExitCode::SUCCESS
+}
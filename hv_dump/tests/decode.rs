@@ -0,0 +1,184 @@
+use hv_dump::{decode, exit_code_of_slot, extract, find, DecodeError};
+use hv_interface::dump::{
+    dump_size, DumpHeader, RecordWriter, DUMP_FLAG_TRUNCATED, DUMP_HEADER_SIZE, DUMP_RECORD_SIZE,
+};
+use hv_trace::Action;
+
+fn fixture(name: &str) -> Vec<u8> {
+    let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name);
+    std::fs::read(&path).unwrap_or_else(|err| panic!("{}: {err}", path.display()))
+}
+
+fn fixture_text(name: &str) -> String {
+    String::from_utf8(fixture(name)).unwrap()
+}
+
+#[test]
+fn decodes_records() {
+    let dump = decode(&fixture("small.bin")).unwrap();
+    assert_eq!(dump.version, 1);
+    assert!(!dump.truncated);
+    assert_eq!(dump.bug_check_processor, Some(2));
+    assert_eq!(dump.tsc, 0x1_2345_6789);
+    assert_eq!(dump.processors.len(), 2);
+
+    let processor = &dump.processors[0];
+    assert_eq!(processor.processor_id, 0);
+    assert_eq!(processor.total_exits, 3);
+    assert_eq!(processor.guest_cycles, 0x1000);
+    assert_eq!(processor.host_cycles, 0x200);
+    assert_eq!(processor.injected_events, 1);
+    assert_eq!(processor.gprs[4], 0x44);
+    assert_eq!(processor.rip, 0xffff_f803_1234_5678);
+    assert_eq!(processor.exit_counts[0x72], 2);
+    assert_eq!(processor.exit_counts[0xa8], 1);
+    assert_eq!(processor.recent_exits.len(), 3);
+    assert_eq!(processor.recent_exits[1].exit_code, 0x400);
+    assert_eq!(processor.recent_exits[1].exit_info2, 0xfee0_0300);
+    assert_eq!(processor.recent_exits[2].action, Action::Pending);
+    assert_eq!(processor.exit(), [0x72, 0, 0, 0]);
+    assert_eq!(
+        processor.control_registers(),
+        [0x8005_0033, 0x1f0, 0x1a_d000, 0x35_0ef8, 0xd01]
+    );
+    assert_eq!(processor.vmcb.len(), 0x1000);
+
+    assert_eq!(dump.processors[1].processor_id, 2);
+    assert!(dump.processors[1].recent_exits.is_empty());
+}
+
+#[test]
+fn decodes_producer_layout() {
+    let mut bytes = vec![0; dump_size(2)];
+    DumpHeader {
+        record_count: 2,
+        flags: DUMP_FLAG_TRUNCATED,
+        bug_check_processor: 1,
+        tsc: 0xabcd,
+    }
+    .write(&mut bytes);
+
+    let mut record = RecordWriter::new(&mut bytes[DUMP_HEADER_SIZE..], 1);
+    record.set_trace(2, 40);
+    record.set_counters([0x3000, 0x400, 5]);
+    record.set_gprs(std::array::from_fn(|index| index as u64 * 0x11));
+    record.set_rip(0xffff_f803_0000_1000);
+    record.set_rflags(0x246);
+    record.set_exit_count(0x7b, 9);
+    record.set_exit_count(0xac, 1);
+    record.set_exit_count(0x200, 1);
+    let entry = &mut record.recent_exits()[64..];
+    entry[0x00..0x08].copy_from_slice(&39u64.to_le_bytes());
+    entry[0x10..0x18].copy_from_slice(&0x7bu64.to_le_bytes());
+    entry[0x38..0x3c].copy_from_slice(&2u32.to_le_bytes());
+    record.vmcb()[0x70] = 0x7b;
+    RecordWriter::new(&mut bytes[DUMP_HEADER_SIZE + DUMP_RECORD_SIZE..], 3).set_trace(0, 0);
+
+    let dump = decode(&bytes).unwrap();
+    assert!(dump.truncated);
+    assert_eq!(dump.bug_check_processor, Some(1));
+    assert_eq!(dump.tsc, 0xabcd);
+    assert_eq!(dump.processors.len(), 2);
+
+    let processor = &dump.processors[0];
+    assert_eq!(processor.processor_id, 1);
+    assert_eq!(processor.total_exits, 40);
+    assert_eq!(
+        [
+            processor.guest_cycles,
+            processor.host_cycles,
+            processor.injected_events
+        ],
+        [0x3000, 0x400, 5]
+    );
+    assert_eq!(processor.gprs[15], 0xff);
+    assert_eq!(processor.rip, 0xffff_f803_0000_1000);
+    assert_eq!(processor.rflags, 0x246);
+    assert_eq!(processor.exit_counts[0x7b], 9);
+    assert_eq!(processor.exit_counts[0xac], 1);
+    assert_eq!(processor.exit_counts.iter().sum::<u64>(), 10);
+    assert_eq!(processor.recent_exits.len(), 2);
+    assert_eq!(processor.recent_exits[1].sequence, 39);
+    assert_eq!(processor.recent_exits[1].exit_code, 0x7b);
+    assert_eq!(processor.recent_exits[1].action, Action::from(2));
+    assert_eq!(processor.exit()[0], 0x7b);
+
+    assert_eq!(dump.processors[1].processor_id, 3);
+    assert!(dump.processors[1].recent_exits.is_empty());
+}
+
+#[test]
+fn formats_text() {
+    let dump = decode(&fixture("small.bin")).unwrap();
+    assert_eq!(dump.to_text(), fixture_text("small.txt"));
+}
+
+#[test]
+fn extracts_from_dump_file() {
+    let bytes = fixture("dump.bin");
+    // The file holds the magic alone before the data.
+    assert_eq!(find(&bytes), Some(0x808 + 0x44));
+    assert_eq!(extract(&bytes), decode(&fixture("small.bin")));
+    assert_eq!(
+        extract(&fixture("small.bin")[1..]),
+        Err(DecodeError::NotFound)
+    );
+}
+
+#[test]
+fn ignores_appended_fields() {
+    // The header and the records are larger than in version 1, as if written
+    // by a newer producer.
+    let dump = decode(&fixture("extended.bin")).unwrap();
+    assert!(dump.truncated);
+    assert_eq!(dump.bug_check_processor, None);
+    assert_eq!(dump.processors.len(), 1);
+    let processor = &dump.processors[0];
+    assert_eq!(processor.processor_id, 5);
+    assert_eq!(processor.exit_counts[0], 7);
+    assert_eq!(processor.recent_exits[0].sequence, 300);
+    assert_eq!(processor.recent_exits[0].action, Action::Unknown(9));
+    assert_eq!(processor.exit()[0], 0x7f);
+}
+
+#[test]
+fn rejects_truncated() {
+    assert_eq!(
+        decode(&fixture("truncated.bin")),
+        Err(DecodeError::Truncated {
+            size: 0x1b70,
+            required: 0x30 + 2 * 0x1a40
+        })
+    );
+    assert!(matches!(
+        decode(&fixture("small.bin")[..16]),
+        Err(DecodeError::Truncated { .. })
+    ));
+}
+
+#[test]
+fn rejects_mismatches() {
+    let mut bytes = fixture("small.bin");
+    bytes[4] = 2;
+    assert_eq!(decode(&bytes), Err(DecodeError::UnsupportedVersion(2)));
+
+    let mut bytes = fixture("small.bin");
+    bytes[0x08] = 0;
+    assert_eq!(decode(&bytes), Err(DecodeError::BadRecordSize(0x1a00)));
+
+    let mut bytes = fixture("small.bin");
+    bytes[3] = b'X';
+    assert_eq!(decode(&bytes), Err(DecodeError::BadMagic(*b"HVDX")));
+}
+
+#[test]
+fn maps_exit_slots() {
+    assert_eq!(exit_code_of_slot(0x72), Some(0x72));
+    assert_eq!(exit_code_of_slot(0xa7), None);
+    assert_eq!(exit_code_of_slot(0xa8), Some(0x400));
+    assert_eq!(exit_code_of_slot(0xab), Some(0x403));
+    assert_eq!(exit_code_of_slot(0xac), Some(u64::MAX));
+    assert_eq!(exit_code_of_slot(0xad), None);
+}
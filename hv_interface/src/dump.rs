@@ -0,0 +1,163 @@
+//! The layout of the hypervisor data the driver adds to crash dumps, shared by
+//! the producer, `hypervisor::amd::dump`, where the format is documented, and
+//! the decoder, `hv_dump`.
+//!
+//! [`DumpHeader::write`] and [`RecordWriter`] lay out the current version, so
+//! that the decoder can be tested against what the producer writes.
+
+use crate::EXIT_SLOT_COUNT;
+
+pub const DUMP_MAGIC: [u8; 4] = *b"HVDD";
+pub const DUMP_VERSION: u16 = 1;
+/// The sizes of the header and a record in version 1. Newer producers may make
+/// them larger by appending fields.
+pub const DUMP_HEADER_SIZE: usize = 0x30;
+pub const DUMP_RECORD_SIZE: usize = 0x1a40;
+/// The number of most recent #VMEXITs kept per processor.
+pub const RECENT_EXIT_COUNT: usize = 16;
+/// The size of a recent #VMEXIT, an entry of the trace export format.
+pub const TRACE_ENTRY_SIZE: usize = 64;
+pub const VMCB_SIZE: usize = 0x1000;
+/// Set when some virtualized processors did not fit into the data.
+pub const DUMP_FLAG_TRUNCATED: u32 = 1 << 0;
+/// {e0706aac-5bfa-4584-85b2-28a3c2209d6e} in the memory layout of a GUID.
+pub const DUMP_GUID: [u8; 16] = [
+    0xac, 0x6a, 0x70, 0xe0, 0xfa, 0x5b, 0x84, 0x45, 0x85, 0xb2, 0x28, 0xa3, 0xc2, 0x20, 0x9d, 0x6e,
+];
+
+// Offsets of the fields within the header.
+pub const HEADER_MAGIC: usize = 0x00;
+pub const HEADER_VERSION: usize = 0x04;
+pub const HEADER_HEADER_SIZE: usize = 0x06;
+pub const HEADER_RECORD_SIZE: usize = 0x08;
+pub const HEADER_RECORD_COUNT: usize = 0x0c;
+pub const HEADER_FLAGS: usize = 0x10;
+pub const HEADER_BUG_CHECK_PROCESSOR: usize = 0x14;
+pub const HEADER_TSC: usize = 0x18;
+pub const HEADER_GUID: usize = 0x20;
+
+// Offsets of the fields within a record.
+pub const RECORD_PROCESSOR_ID: usize = 0x000;
+pub const RECORD_RECENT_COUNT: usize = 0x004;
+pub const RECORD_TOTAL_EXITS: usize = 0x008;
+/// The guest cycles, the host cycles and the number of injected events.
+pub const RECORD_COUNTERS: usize = 0x010;
+pub const RECORD_GPRS: usize = 0x030;
+pub const RECORD_RIP: usize = 0x0b0;
+pub const RECORD_RFLAGS: usize = 0x0b8;
+pub const RECORD_EXIT_COUNTS: usize = 0x0c0;
+pub const RECORD_RECENT_EXITS: usize = 0x630;
+pub const RECORD_VMCB: usize = 0xa40;
+
+const _: () = assert!(HEADER_GUID + DUMP_GUID.len() == DUMP_HEADER_SIZE);
+const _: () = assert!(RECORD_EXIT_COUNTS + 8 * EXIT_SLOT_COUNT == RECORD_RECENT_EXITS);
+const _: () = assert!(RECORD_RECENT_EXITS + TRACE_ENTRY_SIZE * RECENT_EXIT_COUNT <= RECORD_VMCB);
+const _: () = assert!(RECORD_VMCB + VMCB_SIZE == DUMP_RECORD_SIZE);
+
+/// Returns the size of the data for `record_count` records.
+pub const fn dump_size(record_count: usize) -> usize {
+    DUMP_HEADER_SIZE + DUMP_RECORD_SIZE * record_count
+}
+
+/// The fields of the header that are not constant.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DumpHeader {
+    pub record_count: u32,
+    pub flags: u32,
+    /// The processor ID that bug checked, or `u32::MAX`.
+    pub bug_check_processor: u32,
+    pub tsc: u64,
+}
+
+impl DumpHeader {
+    /// Writes the header into the first [`DUMP_HEADER_SIZE`] bytes of
+    /// `buffer`, which must be large enough.
+    pub fn write(&self, buffer: &mut [u8]) {
+        let header = &mut buffer[..DUMP_HEADER_SIZE];
+        header.fill(0);
+        header[HEADER_MAGIC..HEADER_MAGIC + 4].copy_from_slice(&DUMP_MAGIC);
+        header[HEADER_VERSION..HEADER_VERSION + 2].copy_from_slice(&DUMP_VERSION.to_le_bytes());
+        header[HEADER_HEADER_SIZE..HEADER_HEADER_SIZE + 2]
+            .copy_from_slice(&(DUMP_HEADER_SIZE as u16).to_le_bytes());
+        header[HEADER_RECORD_SIZE..HEADER_RECORD_SIZE + 4]
+            .copy_from_slice(&(DUMP_RECORD_SIZE as u32).to_le_bytes());
+        header[HEADER_RECORD_COUNT..HEADER_RECORD_COUNT + 4]
+            .copy_from_slice(&self.record_count.to_le_bytes());
+        header[HEADER_FLAGS..HEADER_FLAGS + 4].copy_from_slice(&self.flags.to_le_bytes());
+        header[HEADER_BUG_CHECK_PROCESSOR..HEADER_BUG_CHECK_PROCESSOR + 4]
+            .copy_from_slice(&self.bug_check_processor.to_le_bytes());
+        header[HEADER_TSC..HEADER_TSC + 8].copy_from_slice(&self.tsc.to_le_bytes());
+        header[HEADER_GUID..HEADER_GUID + 16].copy_from_slice(&DUMP_GUID);
+    }
+}
+
+/// Writes the fields of a record. Fields that are not written are zero.
+pub struct RecordWriter<'a> {
+    record: &'a mut [u8],
+}
+
+impl<'a> RecordWriter<'a> {
+    /// Starts the record of `processor_id` in the first [`DUMP_RECORD_SIZE`]
+    /// bytes of `buffer`, which must be large enough.
+    pub fn new(buffer: &'a mut [u8], processor_id: u32) -> Self {
+        let record = &mut buffer[..DUMP_RECORD_SIZE];
+        record.fill(0);
+        record[RECORD_PROCESSOR_ID..RECORD_PROCESSOR_ID + 4]
+            .copy_from_slice(&processor_id.to_le_bytes());
+        Self { record }
+    }
+
+    /// Sets the number of valid entries in [`RecordWriter::recent_exits`] and
+    /// the number of #VMEXITs since the trace started.
+    pub fn set_trace(&mut self, recent_count: u32, total_exits: u64) {
+        self.record[RECORD_RECENT_COUNT..RECORD_RECENT_COUNT + 4]
+            .copy_from_slice(&recent_count.to_le_bytes());
+        self.put(RECORD_TOTAL_EXITS, total_exits);
+    }
+
+    /// Sets the guest cycles, the host cycles and the number of injected
+    /// events.
+    pub fn set_counters(&mut self, counters: [u64; 3]) {
+        for (index, value) in counters.into_iter().enumerate() {
+            self.put(RECORD_COUNTERS + index * 8, value);
+        }
+    }
+
+    /// Sets the general purpose registers, in the ModRM order.
+    pub fn set_gprs(&mut self, gprs: [u64; 16]) {
+        for (index, value) in gprs.into_iter().enumerate() {
+            self.put(RECORD_GPRS + index * 8, value);
+        }
+    }
+
+    pub fn set_rip(&mut self, rip: u64) {
+        self.put(RECORD_RIP, rip);
+    }
+
+    pub fn set_rflags(&mut self, rflags: u64) {
+        self.put(RECORD_RFLAGS, rflags);
+    }
+
+    /// Sets the number of #VMEXITs counted in `slot`. Slots from
+    /// [`EXIT_SLOT_COUNT`] on are ignored.
+    pub fn set_exit_count(&mut self, slot: usize, count: u64) {
+        if slot < EXIT_SLOT_COUNT {
+            self.put(RECORD_EXIT_COUNTS + slot * 8, count);
+        }
+    }
+
+    /// Returns the space for [`RECENT_EXIT_COUNT`] trace entries.
+    pub fn recent_exits(&mut self) -> &mut [u8] {
+        &mut self.record
+            [RECORD_RECENT_EXITS..RECORD_RECENT_EXITS + TRACE_ENTRY_SIZE * RECENT_EXIT_COUNT]
+    }
+
+    /// Returns the space for the guest VMCB.
+    pub fn vmcb(&mut self) -> &mut [u8] {
+        &mut self.record[RECORD_VMCB..RECORD_VMCB + VMCB_SIZE]
+    }
+
+    fn put(&mut self, offset: usize, value: u64) {
+        self.record[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+}
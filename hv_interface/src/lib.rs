@@ -0,0 +1,433 @@
+//! This crate defines the interface of the control device the driver creates,
+//! so that the driver and user-mode clients agree on it.
+//!
+//! A client opens [`USER_MODE_PATH`] and issues one of the `IOCTL_*` codes with
+//! `DeviceIoControl`. Every request and response starts with a [`Header`] whose
+//! version is [`INTERFACE_VERSION`] and whose size is the size of the whole
+//! structure. The driver rejects requests of another version or size, so a
+//! structure changes only together with the version.
+//!
+//! | IOCTL                     | Input                       | Output                    |
+//! |---------------------------|-----------------------------|---------------------------|
+//! | [`IOCTL_QUERY_STATUS`]      | none                        | [`StatusResponse`]        |
+//! | [`IOCTL_QUERY_STATISTICS`]  | [`StatisticsRequest`]       | [`StatisticsResponse`]    |
+//! | [`IOCTL_EXPORT_TRACE`]      | [`TraceRequest`]            | the trace, see `hv_trace` |
+//! | [`IOCTL_CHANGE_INTERCEPTS`] | [`ChangeInterceptsRequest`] | none                      |
+//! | [`IOCTL_DEVIRTUALIZE`]      | [`DevirtualizeRequest`]     | none                      |
+//! | [`IOCTL_VIRTUALIZE`]        | [`VirtualizeRequest`]       | none                      |
+//!
+//! The device is accessible to SYSTEM and Administrators only.
+
+#![no_std]
+
+pub mod dump;
+
+use core::fmt;
+use core::mem::size_of;
+
+pub const INTERFACE_VERSION: u32 = 2;
+
+/// The name of the device object.
+pub const DEVICE_NAME: &str = r"\Device\Bluepill";
+/// The name of the symbolic link to the device object.
+pub const SYMBOLIC_LINK_NAME: &str = r"\DosDevices\Bluepill";
+/// The path user-mode clients open.
+pub const USER_MODE_PATH: &str = r"\\.\Bluepill";
+
+/// The device type. Values from 0x8000 are for vendors.
+pub const FILE_DEVICE_HYPERVISOR: u32 = 0x8000;
+
+pub const METHOD_BUFFERED: u32 = 0;
+pub const FILE_READ_DATA: u32 = 1;
+pub const FILE_WRITE_DATA: u32 = 2;
+
+/// Builds an I/O control code as the `CTL_CODE` macro of the Windows headers.
+pub const fn ctl_code(device_type: u32, function: u32, method: u32, access: u32) -> u32 {
+    (device_type << 16) | (access << 14) | (function << 2) | method
+}
+
+pub const IOCTL_QUERY_STATUS: u32 = ctl_code(
+    FILE_DEVICE_HYPERVISOR,
+    0x800,
+    METHOD_BUFFERED,
+    FILE_READ_DATA,
+);
+pub const IOCTL_QUERY_STATISTICS: u32 = ctl_code(
+    FILE_DEVICE_HYPERVISOR,
+    0x801,
+    METHOD_BUFFERED,
+    FILE_READ_DATA,
+);
+pub const IOCTL_EXPORT_TRACE: u32 = ctl_code(
+    FILE_DEVICE_HYPERVISOR,
+    0x802,
+    METHOD_BUFFERED,
+    FILE_READ_DATA,
+);
+pub const IOCTL_CHANGE_INTERCEPTS: u32 = ctl_code(
+    FILE_DEVICE_HYPERVISOR,
+    0x803,
+    METHOD_BUFFERED,
+    FILE_WRITE_DATA,
+);
+pub const IOCTL_DEVIRTUALIZE: u32 = ctl_code(
+    FILE_DEVICE_HYPERVISOR,
+    0x804,
+    METHOD_BUFFERED,
+    FILE_WRITE_DATA,
+);
+pub const IOCTL_VIRTUALIZE: u32 = ctl_code(
+    FILE_DEVICE_HYPERVISOR,
+    0x805,
+    METHOD_BUFFERED,
+    FILE_WRITE_DATA,
+);
+
+/// The processor to pass to target every processor.
+pub const ALL_PROCESSORS: u32 = u32::MAX;
+/// The number of processors the bitmaps of [`StatusResponse`] can describe.
+pub const MAX_PROCESSORS: usize = 256;
+/// The number of exit reasons statistics are kept for. See
+/// `hypervisor::amd::stats::exit_slot` for how exit codes map to them.
+pub const EXIT_SLOT_COUNT: usize = 0xae;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub version: u32,
+    /// The size of the structure, including the header.
+    pub size: u32,
+}
+
+impl Header {
+    /// Returns the header of a `T` of the current version.
+    pub const fn of<T: Message>() -> Self {
+        Self {
+            version: INTERFACE_VERSION,
+            size: size_of::<T>() as u32,
+        }
+    }
+}
+
+/// A request or response.
+///
+/// # Safety
+///
+/// The type must be `repr(C)`, start with a [`Header`], and every bit pattern
+/// must be a valid value.
+pub unsafe trait Message: Copy {
+    fn header(&self) -> &Header;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InterfaceError {
+    BufferTooSmall { size: usize, required: usize },
+    VersionMismatch { version: u32 },
+    SizeMismatch { size: u32 },
+}
+
+impl fmt::Display for InterfaceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BufferTooSmall { size, required } => {
+                write!(f, "buffer of {size} bytes is smaller than {required} bytes")
+            }
+            Self::VersionMismatch { version } => {
+                write!(f, "version {version} is not {INTERFACE_VERSION}")
+            }
+            Self::SizeMismatch { size } => write!(f, "size {size} does not match the version"),
+        }
+    }
+}
+
+/// Reads a `T` from the start of `bytes` and checks its header.
+pub fn read<T: Message>(bytes: &[u8]) -> Result<T, InterfaceError> {
+    if bytes.len() < size_of::<T>() {
+        return Err(InterfaceError::BufferTooSmall {
+            size: bytes.len(),
+            required: size_of::<T>(),
+        });
+    }
+
+    // Safety: the buffer is large enough and every bit pattern is a valid `T`.
+    let value = unsafe { bytes.as_ptr().cast::<T>().read_unaligned() };
+    let header = value.header();
+    if header.version != INTERFACE_VERSION {
+        return Err(InterfaceError::VersionMismatch {
+            version: header.version,
+        });
+    }
+    if header.size as usize != size_of::<T>() {
+        return Err(InterfaceError::SizeMismatch { size: header.size });
+    }
+    Ok(value)
+}
+
+/// Writes `value` to the start of `buffer` and returns the number of bytes
+/// written.
+pub fn write<T: Message>(value: &T, buffer: &mut [u8]) -> Result<usize, InterfaceError> {
+    if buffer.len() < size_of::<T>() {
+        return Err(InterfaceError::BufferTooSmall {
+            size: buffer.len(),
+            required: size_of::<T>(),
+        });
+    }
+
+    // Safety: the buffer is large enough.
+    unsafe { buffer.as_mut_ptr().cast::<T>().write_unaligned(*value) };
+    Ok(size_of::<T>())
+}
+
+macro_rules! impl_message {
+    ($($ty:ty),*) => {
+        $(unsafe impl Message for $ty {
+            fn header(&self) -> &Header {
+                &self.header
+            }
+        })*
+    };
+}
+
+impl_message!(
+    StatusResponse,
+    StatisticsRequest,
+    StatisticsResponse,
+    TraceRequest,
+    ChangeInterceptsRequest,
+    DevirtualizeRequest,
+    VirtualizeRequest
+);
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StatusResponse {
+    pub header: Header,
+    /// The version of the driver.
+    pub major: u16,
+    pub minor: u16,
+    pub patch: u16,
+    pub reserved: u16,
+    /// The number of active processors.
+    pub processor_count: u32,
+    pub virtualized_count: u32,
+    /// A bitmap of the virtualized processors, by processor index.
+    pub virtualized: [u64; MAX_PROCESSORS / 64],
+    /// A bitmap of the processors added after the driver was loaded, by
+    /// processor index.
+    pub hot_added: [u64; MAX_PROCESSORS / 64],
+}
+
+impl StatusResponse {
+    /// Returns whether the processor at `index` is virtualized.
+    pub fn is_virtualized(&self, index: usize) -> bool {
+        is_set(&self.virtualized, index)
+    }
+
+    pub fn set_virtualized(&mut self, index: usize) {
+        set(&mut self.virtualized, index);
+    }
+
+    /// Returns whether the processor at `index` was added at runtime.
+    pub fn is_hot_added(&self, index: usize) -> bool {
+        is_set(&self.hot_added, index)
+    }
+
+    pub fn set_hot_added(&mut self, index: usize) {
+        set(&mut self.hot_added, index);
+    }
+}
+
+fn is_set(bitmap: &[u64; MAX_PROCESSORS / 64], index: usize) -> bool {
+    index < MAX_PROCESSORS && bitmap[index / 64] & (1 << (index % 64)) != 0
+}
+
+fn set(bitmap: &mut [u64; MAX_PROCESSORS / 64], index: usize) {
+    bitmap[index / 64] |= 1 << (index % 64);
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StatisticsRequest {
+    pub header: Header,
+    /// The processor index, or [`ALL_PROCESSORS`] for the sum over all
+    /// processors.
+    pub processor: u32,
+    pub reserved: u32,
+}
+
+/// The #VMEXIT statistics and the memory the hypervisor uses. The times are in
+/// TSC ticks.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StatisticsResponse {
+    pub header: Header,
+    /// The number of processors the statistics cover.
+    pub processor_count: u32,
+    pub reserved: u32,
+    pub guest_cycles: u64,
+    pub host_cycles: u64,
+    pub injected_events: u64,
+    pub exit_counts: [u64; EXIT_SLOT_COUNT],
+    pub max_exit_cycles: [u64; EXIT_SLOT_COUNT],
+    pub vmcb_bytes: u64,
+    pub npt_bytes: u64,
+    pub host_stack_bytes: u64,
+}
+
+/// The output is the trace of the processor in the format `hv_trace` decodes.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceRequest {
+    pub header: Header,
+    pub processor: u32,
+    pub reserved: u32,
+}
+
+/// The raw value of each intercept vector of the VMCB.
+/// See: 15.9 Instruction Intercepts and Appendix B Layout of VMCB
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct InterceptVectors {
+    pub cr_read: u16,
+    pub cr_write: u16,
+    pub dr_read: u16,
+    pub dr_write: u16,
+    pub exceptions: u32,
+    pub misc1: u32,
+    pub misc2: u32,
+    pub misc3: u32,
+}
+
+/// Only the intercepts the hypervisor handles at runtime can be changed, and
+/// the driver rejects the request otherwise.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChangeInterceptsRequest {
+    pub header: Header,
+    /// The processor index, or [`ALL_PROCESSORS`] for every virtualized
+    /// processor.
+    pub processor: u32,
+    pub reserved: u32,
+    pub enable: InterceptVectors,
+    /// Applied after `enable`, so an intercept in both ends up disabled.
+    pub disable: InterceptVectors,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DevirtualizeRequest {
+    pub header: Header,
+    /// The processor index, or [`ALL_PROCESSORS`] for every virtualized
+    /// processor.
+    pub processor: u32,
+    pub reserved: u32,
+}
+
+/// Virtualizes a processor that is not virtualized.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VirtualizeRequest {
+    pub header: Header,
+    /// The processor index, or [`ALL_PROCESSORS`] for every processor the
+    /// driver is configured to virtualize.
+    pub processor: u32,
+    pub reserved: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ctl_codes() {
+        assert_eq!(IOCTL_QUERY_STATUS, 0x8000_6000);
+        assert_eq!(IOCTL_QUERY_STATISTICS, 0x8000_6004);
+        assert_eq!(IOCTL_CHANGE_INTERCEPTS, 0x8000_a00c);
+        assert_eq!(IOCTL_DEVIRTUALIZE, 0x8000_a010);
+        assert_eq!(IOCTL_VIRTUALIZE, 0x8000_a014);
+    }
+
+    #[test]
+    fn layouts() {
+        assert_eq!(size_of::<StatusResponse>(), 0x58);
+        assert_eq!(size_of::<StatisticsResponse>(), 0x40 + 16 * EXIT_SLOT_COUNT);
+        assert_eq!(size_of::<InterceptVectors>(), 0x18);
+        assert_eq!(size_of::<ChangeInterceptsRequest>(), 0x40);
+    }
+
+    #[test]
+    fn round_trip() {
+        let request = ChangeInterceptsRequest {
+            header: Header::of::<ChangeInterceptsRequest>(),
+            processor: 3,
+            reserved: 0,
+            enable: InterceptVectors {
+                misc1: 1 << 14,
+                ..Default::default()
+            },
+            disable: InterceptVectors::default(),
+        };
+        let mut buffer = [0u8; 0x48];
+        assert_eq!(write(&request, &mut buffer[1..]), Ok(0x40));
+        assert_eq!(read::<ChangeInterceptsRequest>(&buffer[1..]), Ok(request));
+    }
+
+    #[test]
+    fn rejects_mismatches() {
+        let mut request = DevirtualizeRequest {
+            header: Header::of::<DevirtualizeRequest>(),
+            processor: ALL_PROCESSORS,
+            reserved: 0,
+        };
+        let mut buffer = [0u8; 0x10];
+        assert_eq!(
+            read::<DevirtualizeRequest>(&buffer[..0xf]),
+            Err(InterfaceError::BufferTooSmall {
+                size: 0xf,
+                required: 0x10
+            })
+        );
+
+        request.header.version = INTERFACE_VERSION + 1;
+        write(&request, &mut buffer).unwrap();
+        assert_eq!(
+            read::<DevirtualizeRequest>(&buffer),
+            Err(InterfaceError::VersionMismatch {
+                version: INTERFACE_VERSION + 1
+            })
+        );
+
+        request.header = Header::of::<TraceRequest>();
+        request.header.size += 8;
+        write(&request, &mut buffer).unwrap();
+        assert_eq!(
+            read::<DevirtualizeRequest>(&buffer),
+            Err(InterfaceError::SizeMismatch { size: 0x18 })
+        );
+    }
+
+    #[test]
+    fn virtualized_bitmap() {
+        let mut status = StatusResponse {
+            header: Header::of::<StatusResponse>(),
+            major: 0,
+            minor: 0,
+            patch: 0,
+            reserved: 0,
+            processor_count: 130,
+            virtualized_count: 2,
+            virtualized: [0; 4],
+            hot_added: [0; 4],
+        };
+        status.set_virtualized(1);
+        status.set_virtualized(129);
+        status.set_hot_added(129);
+        assert_eq!(status.virtualized, [0b10, 0, 0b10, 0]);
+        assert_eq!(status.hot_added, [0, 0, 0b10, 0]);
+        assert!(status.is_virtualized(129));
+        assert!(!status.is_virtualized(128));
+        assert!(!status.is_virtualized(MAX_PROCESSORS));
+        assert!(status.is_hot_added(129));
+        assert!(!status.is_hot_added(1));
+    }
+}
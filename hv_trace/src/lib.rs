@@ -0,0 +1,266 @@
+//! This crate decodes the #VMEXIT trace exported by the hypervisor.
+//!
+//! The format is documented in `hypervisor::amd::trace`. This crate does not
+//! depend on the hypervisor so that it builds and runs on any host.
+
+use std::fmt::{self, Write};
+
+pub const TRACE_MAGIC: [u8; 4] = *b"HVTR";
+pub const TRACE_VERSION: u16 = 1;
+
+// The sizes of the header and an entry in version 1. Newer producers may make
+// them larger by appending fields.
+const HEADER_SIZE: usize = 32;
+const ENTRY_SIZE: usize = 64;
+
+/// What the hypervisor did in response to a #VMEXIT.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Pending,
+    Resumed,
+    Emulated,
+    Injected,
+    Unknown(u32),
+}
+
+impl From<u32> for Action {
+    fn from(value: u32) -> Self {
+        match value {
+            0 => Self::Pending,
+            1 => Self::Resumed,
+            2 => Self::Emulated,
+            3 => Self::Injected,
+            _ => Self::Unknown(value),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Pending => f.write_str("pending"),
+            Self::Resumed => f.write_str("resumed"),
+            Self::Emulated => f.write_str("emulated"),
+            Self::Injected => f.write_str("injected"),
+            Self::Unknown(value) => write!(f, "unknown({value})"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub sequence: u64,
+    pub tsc: u64,
+    pub exit_code: u64,
+    pub exit_info1: u64,
+    pub exit_info2: u64,
+    pub rip: u64,
+    pub cr3: u64,
+    pub action: Action,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Trace {
+    pub version: u16,
+    pub processor_id: u32,
+    /// The number of #VMEXITs recorded since the trace started. `entries` holds
+    /// the last of them, oldest first.
+    pub total_exits: u64,
+    pub entries: Vec<TraceEntry>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    BadMagic([u8; 4]),
+    UnsupportedVersion(u16),
+    BadHeaderSize(u16),
+    BadEntrySize(u16),
+    Truncated { size: usize, required: usize },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadMagic(magic) => write!(f, "not a trace: bad magic {magic:02x?}"),
+            Self::UnsupportedVersion(version) => write!(f, "unsupported version {version}"),
+            Self::BadHeaderSize(size) => write!(f, "header size {size} is smaller than {HEADER_SIZE}"),
+            Self::BadEntrySize(size) => write!(f, "entry size {size} is smaller than {ENTRY_SIZE}"),
+            Self::Truncated { size, required } => {
+                write!(f, "truncated: {size} bytes but {required} bytes are required")
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// Decodes an exported trace. Bytes after the last entry are ignored.
+pub fn decode(bytes: &[u8]) -> Result<Trace, DecodeError> {
+    if bytes.len() < HEADER_SIZE {
+        return Err(DecodeError::Truncated {
+            size: bytes.len(),
+            required: HEADER_SIZE,
+        });
+    }
+
+    let magic: [u8; 4] = bytes[0x00..0x04].try_into().unwrap();
+    if magic != TRACE_MAGIC {
+        return Err(DecodeError::BadMagic(magic));
+    }
+    let version = u16_at(bytes, 0x04);
+    if version != TRACE_VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+    let header_size = u16_at(bytes, 0x06);
+    if (header_size as usize) < HEADER_SIZE {
+        return Err(DecodeError::BadHeaderSize(header_size));
+    }
+    let entry_size = u16_at(bytes, 0x08);
+    if (entry_size as usize) < ENTRY_SIZE {
+        return Err(DecodeError::BadEntrySize(entry_size));
+    }
+    let processor_id = u32_at(bytes, 0x0c);
+    let count = u32_at(bytes, 0x10) as usize;
+    let total_exits = u64_at(bytes, 0x18);
+
+    let required = header_size as usize + count * entry_size as usize;
+    if bytes.len() < required {
+        return Err(DecodeError::Truncated {
+            size: bytes.len(),
+            required,
+        });
+    }
+
+    let entries = bytes[header_size as usize..required]
+        .chunks_exact(entry_size as usize)
+        .map(|entry| TraceEntry {
+            sequence: u64_at(entry, 0x00),
+            tsc: u64_at(entry, 0x08),
+            exit_code: u64_at(entry, 0x10),
+            exit_info1: u64_at(entry, 0x18),
+            exit_info2: u64_at(entry, 0x20),
+            rip: u64_at(entry, 0x28),
+            cr3: u64_at(entry, 0x30),
+            action: Action::from(u32_at(entry, 0x38)),
+        })
+        .collect();
+
+    Ok(Trace {
+        version,
+        processor_id,
+        total_exits,
+        entries,
+    })
+}
+
+/// Returns the name of the exit code without the `VMEXIT_` prefix.
+/// See: Appendix C SVM Intercept Exit Codes
+pub fn exit_name(exit_code: u64) -> String {
+    const NAMES: [&str; 0x47] = [
+        "INTR", "NMI", "SMI", "INIT", "VINTR", "CR0_SEL_WRITE", "IDTR_READ", "GDTR_READ",
+        "LDTR_READ", "TR_READ", "IDTR_WRITE", "GDTR_WRITE", "LDTR_WRITE", "TR_WRITE", "RDTSC",
+        "RDPMC", "PUSHF", "POPF", "CPUID", "RSM", "IRET", "SWINT", "INVD", "PAUSE", "HLT",
+        "INVLPG", "INVLPGA", "IOIO", "MSR", "TASK_SWITCH", "FERR_FREEZE", "SHUTDOWN", "VMRUN",
+        "VMMCALL", "VMLOAD", "VMSAVE", "STGI", "CLGI", "SKINIT", "RDTSCP", "ICEBP", "WBINVD",
+        "MONITOR", "MWAIT", "MWAIT_CONDITIONAL", "XSETBV", "RDPRU", "EFER_WRITE_TRAP",
+        "CR0_WRITE_TRAP", "CR1_WRITE_TRAP", "CR2_WRITE_TRAP", "CR3_WRITE_TRAP", "CR4_WRITE_TRAP",
+        "CR5_WRITE_TRAP", "CR6_WRITE_TRAP", "CR7_WRITE_TRAP", "CR8_WRITE_TRAP", "CR9_WRITE_TRAP",
+        "CR10_WRITE_TRAP", "CR11_WRITE_TRAP", "CR12_WRITE_TRAP", "CR13_WRITE_TRAP",
+        "CR14_WRITE_TRAP", "CR15_WRITE_TRAP", "INVLPGB", "INVLPGB_ILLEGAL", "INVPCID", "MCOMMIT",
+        "TLBSYNC", "BUSLOCK", "IDLE_HLT",
+    ];
+
+    match exit_code {
+        0x00..=0x0f => format!("CR{}_READ", exit_code),
+        0x10..=0x1f => format!("CR{}_WRITE", exit_code - 0x10),
+        0x20..=0x2f => format!("DR{}_READ", exit_code - 0x20),
+        0x30..=0x3f => format!("DR{}_WRITE", exit_code - 0x30),
+        0x40..=0x5f => format!("EXCP{}", exit_code - 0x40),
+        0x60..=0xa6 => NAMES[exit_code as usize - 0x60].to_string(),
+        0x400 => "NPF".to_string(),
+        0x401 => "AVIC_INCOMPLETE_IPI".to_string(),
+        0x402 => "AVIC_NOACCEL".to_string(),
+        0x403 => "VMGEXIT".to_string(),
+        u64::MAX => "INVALID".to_string(),
+        _ => "UNKNOWN".to_string(),
+    }
+}
+
+impl Trace {
+    /// Formats the trace as one line per entry.
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        writeln!(
+            text,
+            "processor {}: last {} of {} exits",
+            self.processor_id,
+            self.entries.len(),
+            self.total_exits
+        )
+        .unwrap();
+        for entry in &self.entries {
+            writeln!(
+                text,
+                "#{} tsc={:#x} {}({:#x}) info1={:#x} info2={:#x} rip={:#x} cr3={:#x} {}",
+                entry.sequence,
+                entry.tsc,
+                exit_name(entry.exit_code),
+                entry.exit_code,
+                entry.exit_info1,
+                entry.exit_info2,
+                entry.rip,
+                entry.cr3,
+                entry.action,
+            )
+            .unwrap();
+        }
+        text
+    }
+
+    /// Formats the trace as a JSON object. 64-bit values are written as hex
+    /// strings as JSON numbers cannot represent them exactly.
+    pub fn to_json(&self) -> String {
+        let mut json = String::new();
+        write!(
+            json,
+            "{{\"version\":{},\"processor_id\":{},\"total_exits\":{},\"entries\":[",
+            self.version, self.processor_id, self.total_exits
+        )
+        .unwrap();
+        for (i, entry) in self.entries.iter().enumerate() {
+            if i != 0 {
+                json.push(',');
+            }
+            write!(
+                json,
+                "{{\"sequence\":{},\"tsc\":\"{:#x}\",\"exit_code\":\"{:#x}\",\"exit_name\":\"{}\",\
+                 \"exit_info1\":\"{:#x}\",\"exit_info2\":\"{:#x}\",\"rip\":\"{:#x}\",\
+                 \"cr3\":\"{:#x}\",\"action\":\"{}\"}}",
+                entry.sequence,
+                entry.tsc,
+                entry.exit_code,
+                exit_name(entry.exit_code),
+                entry.exit_info1,
+                entry.exit_info2,
+                entry.rip,
+                entry.cr3,
+                entry.action,
+            )
+            .unwrap();
+        }
+        json.push_str("]}\n");
+        json
+    }
+}
@@ -0,0 +1,39 @@
+//! Decodes a #VMEXIT trace exported by the hypervisor.
+//!
+//! Usage: hv_trace [--json] <file>
+
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (json, path) = match args.as_slice() {
+        [path] => (false, path),
+        [flag, path] if flag == "--json" => (true, path),
+        _ => {
+            eprintln!("usage: hv_trace [--json] <file>");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            eprintln!("{path}: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let trace = match hv_trace::decode(&bytes) {
+        Ok(trace) => trace,
+        Err(err) => {
+            eprintln!("{path}: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if json {
+        print!("{}", trace.to_json());
+    } else {
+        print!("{}", trace.to_text());
+    }
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,93 @@
+use hv_trace::{decode, exit_name, Action, DecodeError};
+
+fn fixture(name: &str) -> Vec<u8> {
+    let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name);
+    std::fs::read(&path).unwrap_or_else(|err| panic!("{}: {err}", path.display()))
+}
+
+fn fixture_text(name: &str) -> String {
+    String::from_utf8(fixture(name)).unwrap()
+}
+
+#[test]
+fn decodes_entries() {
+    let trace = decode(&fixture("small.bin")).unwrap();
+    assert_eq!(trace.version, 1);
+    assert_eq!(trace.processor_id, 2);
+    assert_eq!(trace.total_exits, 3);
+    assert_eq!(trace.entries.len(), 3);
+
+    let npf = &trace.entries[2];
+    assert_eq!(npf.sequence, 3);
+    assert_eq!(npf.exit_code, 0x400);
+    assert_eq!(npf.exit_info1, 0x7);
+    assert_eq!(npf.exit_info2, 0xfee0_0300);
+    assert_eq!(npf.rip, 0xffff_f803_1234_5800);
+    assert_eq!(npf.cr3, 0x1a_d000);
+    assert_eq!(npf.action, Action::Pending);
+}
+
+#[test]
+fn formats_text() {
+    let trace = decode(&fixture("small.bin")).unwrap();
+    assert_eq!(trace.to_text(), fixture_text("small.txt"));
+}
+
+#[test]
+fn ignores_appended_fields() {
+    // The header and the entries are larger than in version 1, as if written
+    // by a newer producer.
+    let trace = decode(&fixture("extended.bin")).unwrap();
+    assert_eq!(trace.total_exits, 300);
+    let sequences: Vec<u64> = trace.entries.iter().map(|entry| entry.sequence).collect();
+    assert_eq!(sequences, [298, 299, 300]);
+    assert_eq!(trace.entries[2].action, Action::Unknown(9));
+    assert_eq!(trace.to_json(), fixture_text("extended.json"));
+}
+
+#[test]
+fn rejects_bad_magic() {
+    assert_eq!(
+        decode(&fixture("bad_magic.bin")),
+        Err(DecodeError::BadMagic(*b"HVTX"))
+    );
+}
+
+#[test]
+fn rejects_truncated() {
+    assert_eq!(
+        decode(&fixture("truncated.bin")),
+        Err(DecodeError::Truncated {
+            size: 170,
+            required: 224
+        })
+    );
+    assert!(matches!(
+        decode(&fixture("small.bin")[..16]),
+        Err(DecodeError::Truncated { .. })
+    ));
+}
+
+#[test]
+fn rejects_unknown_version() {
+    let mut bytes = fixture("small.bin");
+    bytes[4] = 2;
+    assert_eq!(decode(&bytes), Err(DecodeError::UnsupportedVersion(2)));
+}
+
+#[test]
+fn names_exit_codes() {
+    assert_eq!(exit_name(0x00), "CR0_READ");
+    assert_eq!(exit_name(0x13), "CR3_WRITE");
+    assert_eq!(exit_name(0x37), "DR7_WRITE");
+    assert_eq!(exit_name(0x5e), "EXCP30");
+    assert_eq!(exit_name(0x72), "CPUID");
+    assert_eq!(exit_name(0x7f), "SHUTDOWN");
+    assert_eq!(exit_name(0x8d), "XSETBV");
+    assert_eq!(exit_name(0xa6), "IDLE_HLT");
+    assert_eq!(exit_name(0x400), "NPF");
+    assert_eq!(exit_name(u64::MAX), "INVALID");
+    assert_eq!(exit_name(0xa7), "UNKNOWN");
+}
@@ -0,0 +1,90 @@
+//! This module implements a benchmark of the world switch with each way of
+//! switching the extended state.
+//!
+//! The benchmark runs in the guest on the current processor, which must be
+//! virtualized with [`ExtendedStateSwitch::Eager`] or
+//! [`ExtendedStateSwitch::Lazy`]. It times round trips of a VMMCALL the
+//! hypervisor rejects without doing anything, first with each mode, then
+//! restores the configured mode. Trace logging of the hypervisor takes longer
+//! than the world switch, so it should be filtered out while benchmarking.
+
+use core::arch::asm;
+
+use x86::bits64::rflags::{self, RFlags};
+
+use crate::amd::config;
+use crate::amd::{ExtendedStateSwitch, HypercallCommand, HypercallStatus};
+
+/// A command the hypervisor rejects with [`HypercallStatus::InvalidCommand`].
+const NO_COMMAND: u64 = 0;
+
+/// The result of [`world_switch`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WorldSwitchBenchmark {
+    pub iterations: u32,
+    /// The average TSC ticks of a round trip to the host with
+    /// [`ExtendedStateSwitch::Eager`].
+    pub eager_cycles: u64,
+    /// The average TSC ticks of a round trip to the host with
+    /// [`ExtendedStateSwitch::Lazy`], when the host does not use the state.
+    pub lazy_cycles: u64,
+}
+
+/// Measures `iterations` round trips to the host with each mode on the current
+/// processor. Interrupts are disabled meanwhile.
+pub fn world_switch(iterations: u32) -> Result<WorldSwitchBenchmark, HypercallStatus> {
+    let interrupts = rflags::read().contains(RFlags::FLAGS_IF);
+    unsafe { x86::irq::disable() };
+
+    let result = measure(ExtendedStateSwitch::Eager, iterations).and_then(|eager_cycles| {
+        Ok(WorldSwitchBenchmark {
+            iterations,
+            eager_cycles,
+            lazy_cycles: measure(ExtendedStateSwitch::Lazy, iterations)?,
+        })
+    });
+    let restored = set_extended_state_switch(config::read().extended_state_switch);
+
+    if interrupts {
+        unsafe { x86::irq::enable() };
+    }
+    restored.and(result)
+}
+
+/// Returns the average TSC ticks of a round trip with `switch`.
+fn measure(switch: ExtendedStateSwitch, iterations: u32) -> Result<u64, HypercallStatus> {
+    set_extended_state_switch(switch)?;
+
+    let start = unsafe { x86::time::rdtsc() };
+    for _ in 0..iterations {
+        vmmcall(NO_COMMAND, 0);
+    }
+    let end = unsafe { x86::time::rdtsc() };
+    Ok(end.wrapping_sub(start) / u64::from(iterations.max(1)))
+}
+
+fn set_extended_state_switch(switch: ExtendedStateSwitch) -> Result<(), HypercallStatus> {
+    let mode = match switch {
+        ExtendedStateSwitch::Sse => 0,
+        ExtendedStateSwitch::Eager => 1,
+        ExtendedStateSwitch::Lazy => 2,
+    };
+    match vmmcall(HypercallCommand::SetExtendedStateSwitch as u64, mode) {
+        status if status == HypercallStatus::Success as u64 => Ok(()),
+        _ => Err(HypercallStatus::InvalidParameter),
+    }
+}
+
+/// Issues a hypercall without an output buffer and returns the status.
+fn vmmcall(command: u64, argument: u64) -> u64 {
+    let status: u64;
+    unsafe {
+        asm!(
+        "vmmcall",
+        in("rcx") command, in("rdx") 0, in("r8") 0, in("r9") argument,
+        lateout("rax") status,
+        options(nostack),
+        )
+    };
+    status
+}
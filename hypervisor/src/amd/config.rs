@@ -0,0 +1,33 @@
+//! This module implements the hypervisor-wide configuration consulted by every
+//! logical processor. The configuration types are defined in `hv_core`.
+
+use core::arch::asm;
+
+use spin::{RwLock, RwLockReadGuard};
+
+pub use hv_core::config::*;
+
+static CONFIG: RwLock<HypervisorConfig> = RwLock::new(HypervisorConfig::new());
+
+/// Replaces the current configuration. Takes effect for every subsequent
+/// #VMEXIT on all processors.
+pub fn set(config: HypervisorConfig) {
+    *CONFIG.write() = config;
+}
+
+/// Returns the current configuration.
+pub fn get() -> HypervisorConfig {
+    CONFIG.read().clone()
+}
+
+pub(crate) fn read() -> RwLockReadGuard<'static, HypervisorConfig> {
+    CONFIG.read()
+}
+
+/// Breaks into the kernel debugger if `point` is enabled in
+/// [`HypervisorConfig::debug_breaks`].
+pub(crate) fn debug_break(point: DebugBreaks) {
+    if read().debug_breaks.contains(point) {
+        unsafe { asm!("int 3") };
+    }
+}
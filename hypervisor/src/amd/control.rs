@@ -0,0 +1,233 @@
+//! This module implements the control of virtualized processors from the
+//! guest, such as from the driver's IOCTL handlers.
+//!
+//! Each function runs on the targeted processors one by one, issuing a
+//! hypercall there unless it virtualizes them, so it must be called at
+//! PASSIVE_LEVEL, after
+//! [`crate::amd::virtualize_system`]. Processors are identified by their system
+//! processor index, which is also the processor ID used by
+//! [`crate::amd::stats`] and [`crate::amd::trace`]. The functions are
+//! serialized, so that a processor is never virtualized or devirtualized twice
+//! at once.
+//!
+//! Processors lose their SVM state when the system sleeps or hibernates, and
+//! the firmware starts them without the hypervisor on resume. [`suspend`] and
+//! [`resume`] devirtualize them before and virtualize them again after. The
+//! configuration and [`crate::amd::stats`] are global, so they are kept.
+
+use alloc::boxed::Box;
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use kernelutils::nt::mutex::GuardedMutex;
+use kernelutils::nt::platform_ops;
+
+use crate::amd::guest::support::apic_id;
+use crate::amd::intercept::InterceptChange;
+use crate::amd::{config, is_virtualized, processors, HypercallCommand, HypercallStatus};
+
+/// Serializes the functions. They switch processors while holding it, so it
+/// must not be a spin lock.
+static LOCK: GuardedMutex<()> = GuardedMutex::new(());
+/// Whether the system is between [`suspend`] and [`resume`].
+static SUSPENDED: AtomicBool = AtomicBool::new(false);
+
+#[derive(thiserror_no_std::Error, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControlError {
+    #[error("processor `{processor_id}` does not exist")]
+    InvalidProcessor { processor_id: usize },
+
+    #[error("processor `{processor_id}` is not virtualized")]
+    NotVirtualized { processor_id: usize },
+
+    #[error("processor `{processor_id}` is already virtualized")]
+    AlreadyVirtualized { processor_id: usize },
+
+    #[error("processor `{processor_id}` rejected the hypercall with {status:?}")]
+    Rejected { processor_id: usize, status: HypercallStatus },
+
+    #[error("the system is leaving the working state")]
+    Suspended,
+}
+
+/// Returns the number of active processors.
+pub fn processor_count() -> usize {
+    platform_ops::get().processor_count() as usize
+}
+
+/// Returns the number of processors that can be controlled: the active ones,
+/// up to [`processors::MAX_PROCESSORS`].
+fn controllable_count() -> usize {
+    processor_count().min(processors::MAX_PROCESSORS)
+}
+
+/// Virtualizes `processor_id`, or every processor of
+/// [`crate::amd::HypervisorConfig::processors`] that is not virtualized if
+/// `None`. Processors from [`processors::MAX_PROCESSORS`] on cannot be
+/// virtualized.
+pub fn virtualize(processor_id: Option<usize>) -> Result<(), ControlError> {
+    let _lock = LOCK.lock();
+    if SUSPENDED.load(Ordering::Relaxed) {
+        return Err(ControlError::Suspended);
+    }
+    let ops = platform_ops::get();
+    let count = controllable_count();
+    let targets = match processor_id {
+        Some(processor_id) if processor_id >= count => {
+            return Err(ControlError::InvalidProcessor { processor_id });
+        }
+        Some(processor_id) if is_virtualized(processor_id) => {
+            return Err(ControlError::AlreadyVirtualized { processor_id });
+        }
+        Some(processor_id) => processor_id..processor_id + 1,
+        None => 0..count,
+    };
+
+    for index in targets {
+        let number = ops.processor_number(index as u32);
+        let selected = config::read().processors.contains(index, number.group, number.number);
+        if is_virtualized(index) || (processor_id.is_none() && !selected) {
+            continue;
+        }
+        ops.run_on_processor(index as u32, &mut crate::amd::virtualize_current_processor);
+    }
+    Ok(())
+}
+
+/// Starts tracking `processor_id`, which was added to the system after
+/// [`crate::amd::virtualize_system`], and virtualizes it if
+/// [`crate::amd::HypervisorConfig::processors`] includes it. Does nothing if
+/// the processor is already tracked, so that it can be called for every
+/// active processor.
+pub fn add_processor(processor_id: usize) -> Result<(), ControlError> {
+    let _lock = LOCK.lock();
+    let ops = platform_ops::get();
+    if processor_id >= controllable_count() {
+        return Err(ControlError::InvalidProcessor { processor_id });
+    }
+    if apic_id::apic_id_of(processor_id).is_some() {
+        return Ok(());
+    }
+
+    let mut registered = false;
+    ops.run_on_processor(processor_id as u32, &mut || {
+        registered = apic_id::register_current(processor_id);
+    });
+    if !registered {
+        return Err(ControlError::InvalidProcessor { processor_id });
+    }
+    processors::set_hot_added(processor_id);
+    log::info!("Processor {processor_id} was added");
+
+    let number = ops.processor_number(processor_id as u32);
+    let selected = config::read().processors.contains(processor_id, number.group, number.number);
+    if !SUSPENDED.load(Ordering::Relaxed) && selected {
+        ops.run_on_processor(processor_id as u32, &mut crate::amd::virtualize_current_processor);
+    }
+    Ok(())
+}
+
+/// Changes the intercepts of `processor_id`, or of every virtualized processor
+/// if `None`. Only the intercepts in
+/// [`crate::amd::InterceptVectors::RUNTIME`] can be changed. With `None`, the
+/// processors before the first one that rejects the change keep it.
+pub fn change_intercepts(processor_id: Option<usize>, change: &InterceptChange) -> Result<(), ControlError> {
+    // The hypervisor reads the change from its linear address, so it is copied
+    // to the heap, which is nonpaged. See `HypercallCommand::ChangeIntercepts`.
+    let change = Box::new(*change);
+    let address = &*change as *const InterceptChange as u64;
+    for_each_processor(processor_id, || vmmcall(HypercallCommand::ChangeIntercepts, address))
+}
+
+/// Devirtualizes `processor_id`, or every virtualized processor if `None`.
+/// With `None`, the processors before the first one that fails stay
+/// devirtualized and the others stay virtualized.
+pub fn devirtualize(processor_id: Option<usize>) -> Result<(), ControlError> {
+    for_each_processor(processor_id, || vmmcall(HypercallCommand::Devirtualize, 0))
+}
+
+/// Devirtualizes every virtualized processor before the system leaves the
+/// working state, and remembers them for [`resume`]. Processors are not
+/// virtualized again until then. A processor that fails to devirtualize does
+/// not stop the others; the first failure is returned.
+pub fn suspend() -> Result<(), ControlError> {
+    let _lock = LOCK.lock();
+    SUSPENDED.store(true, Ordering::Relaxed);
+    let mut result = Ok(());
+    for processor_id in (0..controllable_count()).filter(|&id| is_virtualized(id)) {
+        processors::set_suspended(processor_id, true);
+        let devirtualized = run_hypercall(processor_id, &mut || vmmcall(HypercallCommand::Devirtualize, 0));
+        if result.is_ok() {
+            result = devirtualized;
+        }
+    }
+    result
+}
+
+/// Virtualizes the processors devirtualized by [`suspend`] once the system is
+/// back in the working state.
+pub fn resume() {
+    let _lock = LOCK.lock();
+    let ops = platform_ops::get();
+    for processor_id in (0..controllable_count()).filter(|&id| processors::is_suspended(id)) {
+        processors::set_suspended(processor_id, false);
+        if !is_virtualized(processor_id) {
+            ops.run_on_processor(processor_id as u32, &mut crate::amd::virtualize_current_processor);
+        }
+    }
+    SUSPENDED.store(false, Ordering::Relaxed);
+}
+
+/// Runs `hypercall` on `processor_id`, which must be virtualized, or on every
+/// virtualized processor if `None`, and stops at the first failure. Nothing is
+/// rolled back, so the processors before the failing one keep the effect of
+/// the hypercall.
+fn for_each_processor(
+    processor_id: Option<usize>,
+    mut hypercall: impl FnMut() -> u64,
+) -> Result<(), ControlError> {
+    let _lock = LOCK.lock();
+    let count = controllable_count();
+    let targets = match processor_id {
+        Some(processor_id) if processor_id >= count => {
+            return Err(ControlError::InvalidProcessor { processor_id });
+        }
+        Some(processor_id) if !is_virtualized(processor_id) => {
+            return Err(ControlError::NotVirtualized { processor_id });
+        }
+        Some(processor_id) => processor_id..processor_id + 1,
+        None => 0..count,
+    };
+
+    for processor_id in targets.filter(|&id| is_virtualized(id)) {
+        run_hypercall(processor_id, &mut hypercall)?;
+    }
+    Ok(())
+}
+
+/// Runs `hypercall` on `processor_id` and converts the status.
+fn run_hypercall(processor_id: usize, hypercall: &mut impl FnMut() -> u64) -> Result<(), ControlError> {
+    let mut status = HypercallStatus::Success as u64;
+    platform_ops::get().run_on_processor(processor_id as u32, &mut || status = hypercall());
+    let status = match status {
+        status if status == HypercallStatus::Success as u64 => return Ok(()),
+        status if status == HypercallStatus::InvalidCommand as u64 => HypercallStatus::InvalidCommand,
+        status if status == HypercallStatus::BufferTooSmall as u64 => HypercallStatus::BufferTooSmall,
+        _ => HypercallStatus::InvalidParameter,
+    };
+    Err(ControlError::Rejected { processor_id, status })
+}
+
+/// Issues a hypercall without an output buffer and returns the status.
+fn vmmcall(command: HypercallCommand, argument: u64) -> u64 {
+    let status: u64;
+    unsafe {
+        asm!(
+        "vmmcall",
+        in("rcx") command as u64, in("rdx") 0, in("r8") 0, in("r9") argument,
+        lateout("rax") status,
+        options(nostack),
+        )
+    };
+    status
+}
@@ -0,0 +1,253 @@
+//! This module implements the crash reports captured when a guest shuts down
+//! on a triple fault.
+//!
+//! A report holds the guest state at #VMEXIT(SHUTDOWN), including the event
+//! that was being delivered from EXITINTINFO, and is kept for the lifetime of
+//! the system since the processor never runs the guest again. Each report is
+//! logged when captured and can be read with [`report`].
+//!
+//! [`CrashReport`] is `repr(C)` and its layout is stable within a
+//! [`CRASH_REPORT_VERSION`], so that it can be copied out as bytes:
+//!
+//! | Offset | Size  | Field                                               |
+//! |--------|-------|-----------------------------------------------------|
+//! | 0x000  | 4     | magic, "HVCR"                                       |
+//! | 0x004  | 2     | version                                             |
+//! | 0x006  | 2     | size of the report in bytes                         |
+//! | 0x008  | 4     | processor ID                                        |
+//! | 0x00c  | 4     | reserved                                            |
+//! | 0x010  | 8     | TSC at #VMEXIT                                      |
+//! | 0x018  | 8 x 4 | EXITCODE, EXITINFO1, EXITINFO2, EXITINTINFO         |
+//! | 0x038  | 8 x 16| RAX, RCX, RDX, RBX, RSP, RBP, RSI, RDI, R8-R15      |
+//! | 0x0b8  | 8 x 2 | RIP, RFLAGS                                         |
+//! | 0x0c8  | 8 x 6 | CR0, CR2, CR3, CR4, CR8, EFER                       |
+//! | 0x0f8  | 8 x 6 | DR0-DR3, DR6, DR7                                   |
+//! | 0x128  | 8 x 2 | GDTR base, IDTR base                                |
+//! | 0x138  | 4 x 2 | GDTR limit, IDTR limit                              |
+//! | 0x140  | 16 x 8| ES, CS, SS, DS, FS, GS, LDTR, TR                    |
+//! | 0x1c0  | 8     | KernelGsBase                                        |
+//!
+//! Each segment is the selector (2 bytes), the attributes (2 bytes), the limit
+//! (4 bytes) and the base (8 bytes). All values are little-endian.
+
+use core::fmt::{self, Write};
+
+use bit_field::BitField;
+use spin::Once;
+
+use crate::amd::processors::MAX_PROCESSORS;
+
+/// "HVCR" in little-endian.
+pub const CRASH_REPORT_MAGIC: u32 = u32::from_le_bytes(*b"HVCR");
+pub const CRASH_REPORT_VERSION: u16 = 1;
+pub const CRASH_REPORT_SIZE: usize = core::mem::size_of::<CrashReport>();
+
+pub use crate::amd::guest::state::SegmentState;
+
+/// The guest state at #VMEXIT(SHUTDOWN).
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CrashReport {
+    pub magic: u32,
+    pub version: u16,
+    pub size: u16,
+    pub processor_id: u32,
+    pub reserved: u32,
+    pub tsc: u64,
+    pub exit_code: u64,
+    pub exit_info1: u64,
+    pub exit_info2: u64,
+    pub exit_int_info: u64,
+    /// The general purpose registers in the ModRM order.
+    pub gprs: [u64; 16],
+    pub rip: u64,
+    pub rflags: u64,
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub cr8: u64,
+    pub efer: u64,
+    /// DR0-DR3, DR6 and DR7.
+    pub dr: [u64; 6],
+    pub gdtr_base: u64,
+    pub idtr_base: u64,
+    pub gdtr_limit: u32,
+    pub idtr_limit: u32,
+    /// ES, CS, SS, DS, FS, GS, LDTR and TR.
+    pub segments: [SegmentState; 8],
+    pub kernel_gs_base: u64,
+}
+
+const _: () = assert!(core::mem::offset_of!(CrashReport, gprs) == 0x38);
+const _: () = assert!(core::mem::offset_of!(CrashReport, segments) == 0x140);
+const _: () = assert!(core::mem::size_of::<CrashReport>() == 0x1c8);
+
+const SEGMENT_NAMES: [&str; 8] = ["ES", "CS", "SS", "DS", "FS", "GS", "LDTR", "TR"];
+const GPR_NAMES: [&str; 16] = [
+    "RAX", "RCX", "RDX", "RBX", "RSP", "RBP", "RSI", "RDI", "R8", "R9", "R10", "R11", "R12", "R13",
+    "R14", "R15",
+];
+
+/// An event from EXITINTINFO or EVENTINJ.
+/// See: 15.20 Event Injection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Event {
+    pub vector: u8,
+    /// 0: external interrupt, 2: NMI, 3: exception, 4: software interrupt.
+    pub event_type: u8,
+    pub error_code: Option<u32>,
+}
+
+impl Event {
+    /// Decodes `value`, or returns `None` if it does not hold a valid event.
+    pub fn decode(value: u64) -> Option<Self> {
+        if !value.get_bit(31) {
+            return None;
+        }
+        Some(Self {
+            vector: value.get_bits(0..8) as u8,
+            event_type: value.get_bits(8..11) as u8,
+            error_code: value.get_bit(11).then(|| value.get_bits(32..) as u32),
+        })
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.event_type {
+            0 => "interrupt",
+            2 => "NMI",
+            3 => "exception",
+            4 => "software interrupt",
+            _ => "event",
+        };
+        write!(f, "{kind} {:#x}", self.vector)?;
+        if let Some(error_code) = self.error_code {
+            write!(f, " with error code {error_code:#x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for CrashReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Guest shutdown on processor {} at TSC {:#x}", self.processor_id, self.tsc)?;
+        match Event::decode(self.exit_int_info) {
+            Some(event) => writeln!(f, "While delivering: {event}")?,
+            None => writeln!(f, "While delivering: nothing")?,
+        }
+        writeln!(f, "RIP {:#018x} RFLAGS {:#018x}", self.rip, self.rflags)?;
+        for (pair, names) in self.gprs.chunks(2).zip(GPR_NAMES.chunks(2)) {
+            writeln!(f, "{:<3} {:#018x} {:<3} {:#018x}", names[0], pair[0], names[1], pair[1])?;
+        }
+        writeln!(f, "CR0 {:#018x} CR2 {:#018x} CR3 {:#018x}", self.cr0, self.cr2, self.cr3)?;
+        writeln!(f, "CR4 {:#018x} CR8 {:#018x} EFER {:#018x}", self.cr4, self.cr8, self.efer)?;
+        writeln!(
+            f,
+            "DR0 {:#x} DR1 {:#x} DR2 {:#x} DR3 {:#x} DR6 {:#x} DR7 {:#x}",
+            self.dr[0], self.dr[1], self.dr[2], self.dr[3], self.dr[4], self.dr[5]
+        )?;
+        writeln!(f, "GDTR {:#018x}:{:#x} IDTR {:#018x}:{:#x}", self.gdtr_base, self.gdtr_limit, self.idtr_base, self.idtr_limit)?;
+        for (segment, name) in self.segments.iter().zip(SEGMENT_NAMES) {
+            writeln!(
+                f,
+                "{name:<4} {:#06x} attrib {:#06x} limit {:#010x} base {:#018x}",
+                segment.selector, segment.attrib, segment.limit, segment.base
+            )?;
+        }
+        write!(f, "KernelGsBase {:#018x}", self.kernel_gs_base)
+    }
+}
+
+static REPORTS: [Once<CrashReport>; MAX_PROCESSORS] = [const { Once::new() }; MAX_PROCESSORS];
+
+/// Stores the report of its processor and logs it. Only the first report of
+/// each processor is kept.
+pub(crate) fn record(report: CrashReport) -> &'static CrashReport {
+    let report = REPORTS[report.processor_id as usize].call_once(|| report);
+    // The guest may have crashed in the allocator, so the report is logged
+    // through a fixed buffer.
+    let mut writer = LineWriter::new();
+    let _ = write!(writer, "{report}");
+    writer.flush();
+    report
+}
+
+/// Logs what is written to it line by line. Lines longer than the buffer are
+/// split.
+struct LineWriter {
+    buffer: [u8; 128],
+    len: usize,
+}
+
+impl LineWriter {
+    const fn new() -> Self {
+        Self { buffer: [0; 128], len: 0 }
+    }
+
+    fn flush(&mut self) {
+        if self.len != 0 {
+            // The report is ASCII, so splitting a long line cuts no character.
+            let line = core::str::from_utf8(&self.buffer[..self.len]).unwrap_or("<invalid UTF-8>");
+            log::error!("{line}");
+            self.len = 0;
+        }
+    }
+}
+
+impl fmt::Write for LineWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if byte == b'\n' {
+                self.flush();
+                continue;
+            }
+            if self.len == self.buffer.len() {
+                self.flush();
+            }
+            self.buffer[self.len] = byte;
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the crash report of the processor, if its guest has shut down.
+pub fn report(processor_id: usize) -> Option<&'static CrashReport> {
+    REPORTS.get(processor_id)?.get()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_event() {
+        // #PF with error code 2.
+        assert_eq!(
+            Event::decode(0x0000_0002_8000_0b0e),
+            Some(Event { vector: 0xe, event_type: 3, error_code: Some(2) })
+        );
+        // An external interrupt without error code ignores bits 63:32.
+        assert_eq!(
+            Event::decode(0xdead_beef_8000_0041),
+            Some(Event { vector: 0x41, event_type: 0, error_code: None })
+        );
+        assert_eq!(Event::decode(0x8000_0202), Some(Event { vector: 2, event_type: 2, error_code: None }));
+    }
+
+    #[test]
+    fn rejects_invalid_event() {
+        assert_eq!(Event::decode(0), None);
+        assert_eq!(Event::decode(0x0000_0002_8000_0b0e & !(1 << 31)), None);
+    }
+
+    #[test]
+    fn displays_event() {
+        let event = Event::decode(0x0000_0002_8000_0b0e).unwrap();
+        assert_eq!(alloc::format!("{event}"), "exception 0xe with error code 0x2");
+        let event = Event::decode(0x8000_0441).unwrap();
+        assert_eq!(alloc::format!("{event}"), "software interrupt 0x41");
+    }
+}
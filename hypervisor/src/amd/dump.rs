@@ -0,0 +1,133 @@
+//! This module implements the hypervisor data added to crash dumps.
+//!
+//! When the system bug checks, the driver asks [`write`] for a snapshot of
+//! every virtualized processor and adds it to the dump as secondary data
+//! tagged with [`DUMP_GUID`]. The bug check runs in the guest, so the snapshot
+//! holds the guest state as of the last #VMEXIT of each processor. Nothing is
+//! allocated or locked while writing it.
+//!
+//! # Format (version 1)
+//!
+//! All values are little endian. The data starts with a 48 byte header:
+//!
+//! | Offset | Type       | Field                                             |
+//! |--------|------------|---------------------------------------------------|
+//! | 0x00   | `[u8; 4]`  | Magic, `b"HVDD"`                                  |
+//! | 0x04   | `u16`      | Format version, 1                                 |
+//! | 0x06   | `u16`      | Header size in bytes, 48                          |
+//! | 0x08   | `u32`      | Record size in bytes, 0x1a40                      |
+//! | 0x0c   | `u32`      | Number of records following the header            |
+//! | 0x10   | `u32`      | Flags, see [`DUMP_FLAG_TRUNCATED`]                |
+//! | 0x14   | `u32`      | Processor ID that bug checked, or `u32::MAX`      |
+//! | 0x18   | `u64`      | TSC when the data was written                     |
+//! | 0x20   | `[u8; 16]` | [`DUMP_GUID`]                                     |
+//!
+//! A record per virtualized processor follows, in processor ID order:
+//!
+//! | Offset | Type          | Field                                          |
+//! |--------|---------------|------------------------------------------------|
+//! | 0x000  | `u32`         | Processor ID                                   |
+//! | 0x004  | `u32`         | Number of valid recent #VMEXITs                |
+//! | 0x008  | `u64`         | Number of #VMEXITs since the trace started     |
+//! | 0x010  | `u64`         | TSC ticks spent running the guest              |
+//! | 0x018  | `u64`         | TSC ticks spent in the host                    |
+//! | 0x020  | `u64`         | Number of events injected                      |
+//! | 0x028  | `u64`         | Reserved                                       |
+//! | 0x030  | `[u64; 16]`   | RAX-R15 in the ModRM order                     |
+//! | 0x0b0  | `u64`         | RIP                                            |
+//! | 0x0b8  | `u64`         | RFLAGS                                         |
+//! | 0x0c0  | `[u64; 0xae]` | #VMEXIT counts by [`stats::exit_slot`]         |
+//! | 0x630  | 64 x 16       | Recent #VMEXITs, oldest first                  |
+//! | 0xa30  | 16            | Reserved                                       |
+//! | 0xa40  | 0x1000        | The guest VMCB                                 |
+//!
+//! Recent #VMEXITs are entries of the [`crate::amd::trace`] export format, and
+//! unused ones are zero. The registers are those of the guest at the last
+//! #VMEXIT. Readers must ignore bytes beyond the known header and record
+//! layouts rather than reject them, so that fields can be appended without a
+//! version change. The constants and the writers of the layout are in
+//! `hv_interface::dump`, shared with the `hv_dump` decoder.
+//!
+//! # Limitations
+//!
+//! The processors stay virtualized while the system bug checks and writes the
+//! dump. Devirtualizing them would take a hypercall on each processor, but the
+//! bug check stops the other processors with interrupts disabled, and a bug
+//! check caused by the hypervisor may leave it unable to devirtualize at all.
+//! The dump is therefore written by the guest on top of the nested page
+//! tables, which map all memory as it is without the hypervisor, and the
+//! hypervisor keeps handling the #VMEXITs of the dump path.
+
+use hv_interface::dump::{dump_size, DumpHeader, RecordWriter};
+
+use crate::amd::guest::support::apic_id;
+use crate::amd::processors::{self, MAX_PROCESSORS};
+use crate::amd::stats::{self, EXIT_SLOT_COUNT};
+use crate::amd::trace::{self, TRACE_ENTRY_SIZE};
+
+pub use hv_interface::dump::{
+    DUMP_FLAG_TRUNCATED, DUMP_GUID, DUMP_HEADER_SIZE, DUMP_MAGIC, DUMP_RECORD_SIZE, DUMP_VERSION,
+    RECENT_EXIT_COUNT,
+};
+
+const _: () = assert!(EXIT_SLOT_COUNT == hv_interface::EXIT_SLOT_COUNT);
+const _: () = assert!(TRACE_ENTRY_SIZE == hv_interface::dump::TRACE_ENTRY_SIZE);
+
+/// Returns the size of the data for `processor_count` virtualized processors.
+pub const fn size(processor_count: usize) -> usize {
+    dump_size(processor_count)
+}
+
+/// Writes a snapshot of every virtualized processor into `buffer` in the
+/// format above and returns the number of bytes written. Records that do not
+/// fit are left out. Called while the system bug checks, at any IRQL.
+pub fn write(buffer: &mut [u8]) -> usize {
+    if buffer.len() < DUMP_HEADER_SIZE {
+        return 0;
+    }
+    let current = apic_id::processor_id_from(apic_id::get()).map_or(u32::MAX, |id| id as u32);
+    let capacity = (buffer.len() - DUMP_HEADER_SIZE) / DUMP_RECORD_SIZE;
+
+    let mut count = 0;
+    let mut flags = 0;
+    for processor_id in 0..MAX_PROCESSORS {
+        // SAFETY: the other processors are stopped in the guest.
+        let Some(vcpu) = (unsafe { processors::vcpu(processor_id) }) else {
+            continue;
+        };
+        if count == capacity {
+            flags |= DUMP_FLAG_TRUNCATED;
+            break;
+        }
+        let mut record = RecordWriter::new(&mut buffer[size(count)..], processor_id as u32);
+
+        if let Some(trace) = trace::get(processor_id) {
+            let (recent_count, total_exits) =
+                trace.write_entries(RECENT_EXIT_COUNT, record.recent_exits());
+            record.set_trace(recent_count as u32, total_exits);
+        }
+        if let Some(statistics) = stats::get(processor_id) {
+            record.set_counters(statistics.totals());
+            for slot in 0..EXIT_SLOT_COUNT {
+                record.set_exit_count(slot, statistics.exit_count(slot));
+            }
+        }
+
+        let registers = vcpu.saved_regs();
+        record.set_gprs(core::array::from_fn(|index| registers.gpr(index as u8)));
+        record.set_rip(registers.rip);
+        record.set_rflags(registers.rflags);
+        let vmcb = vcpu.guest_vmcb_bytes();
+        record.vmcb()[..vmcb.len()].copy_from_slice(vmcb);
+        count += 1;
+    }
+
+    let header = DumpHeader {
+        record_count: count as u32,
+        flags,
+        bug_check_processor: current,
+        tsc: unsafe { x86::time::rdtsc() },
+    };
+    header.write(buffer);
+    size(count)
+}
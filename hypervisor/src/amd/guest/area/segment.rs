@@ -1,49 +1,83 @@
-
 use x86::{
     dtables::DescriptorTablePointer,
-    segmentation::{SegmentSelector},
+    segmentation::{SegmentSelector, SystemDescriptorTypes64},
 };
 
 use crate::amd::guest::SegmentDescriptorRaw;
 use crate::amd::guest::support::error::SegmentError;
 
+/// A segment descriptor read from the GDT or an LDT.
+/// See: 3.4.5 Segment Descriptors
 pub struct SegmentDescriptor {
     low64: SegmentDescriptorRaw,
     upper_base: Option<u32>,
 }
 
 impl SegmentDescriptor {
+    /// Reads the descriptor `selector` refers to from the GDT, or from the LDT
+    /// the current LDTR selects if its TI flag is set.
     pub fn try_from_gdtr(
         gdtr: &DescriptorTablePointer<u64>,
         selector: SegmentSelector,
     ) -> Result<Self, SegmentError> {
-        if selector.contains(SegmentSelector::TI_LDT) {
-            return Err(SegmentError::LdtAccess { selector });
-        }
+        let ldtr = if selector.contains(SegmentSelector::TI_LDT) {
+            unsafe { x86::dtables::ldtr() }
+        } else {
+            SegmentSelector::from_raw(0)
+        };
+        Self::try_from_tables(gdtr, ldtr, selector)
+    }
+
+    /// Reads the descriptor `selector` refers to from the GDT, or from the LDT
+    /// `ldtr` selects in the GDT if its TI flag is set.
+    pub fn try_from_tables(
+        gdtr: &DescriptorTablePointer<u64>,
+        ldtr: SegmentSelector,
+        selector: SegmentSelector,
+    ) -> Result<Self, SegmentError> {
+        let gdt = unsafe { table(gdtr.base as u64, u32::from(gdtr.limit)) };
+        let index = usize::from(selector.index());
 
-        let index = selector.index() as usize;
-        if index == 0 {
-            return Err(SegmentError::NullDescriptor { selector });
+        if !selector.contains(SegmentSelector::TI_LDT) {
+            if index == 0 {
+                return Err(SegmentError::NullDescriptor { selector });
+            }
+            return Self::try_from_table(gdt, index);
         }
 
-        let gdt = unsafe {
-            core::slice::from_raw_parts(gdtr.base.cast::<u64>(), usize::from(gdtr.limit + 1) / 8)
-        };
+        // "The LDT is located in a system segment of the LDT type. The GDT must
+        //  contain a segment descriptor for the LDT segment."
+        // See: 3.5.1 Segment Descriptor Tables
+        if ldtr.index() == 0 || ldtr.contains(SegmentSelector::TI_LDT) {
+            return Err(SegmentError::NoLdt { selector, ldtr });
+        }
+        let ldt = Self::try_from_table(gdt, usize::from(ldtr.index()))?;
+        if !ldt.is_system() || ldt.descriptor_type() != SystemDescriptorTypes64::LDT as u8 || !ldt.is_present() {
+            return Err(SegmentError::NoLdt { selector, ldtr });
+        }
+        let ldt = unsafe { table(ldt.base(), ldt.limit()) };
+        Self::try_from_table(ldt, index)
+    }
 
-        let raw = gdt
+    /// Parses the descriptor at `index` in `table`, which is 16 bytes long for
+    /// system descriptors.
+    pub fn try_from_table(table: &[u64], index: usize) -> Result<Self, SegmentError> {
+        let raw = table
             .get(index)
-            .ok_or(SegmentError::OutOfGdtAccess { index })?;
+            .ok_or(SegmentError::OutOfTableAccess { index })?;
 
         let low64 = SegmentDescriptorRaw::from(*raw);
         let upper_base = if low64.is_16byte() {
             let index: usize = index + 1;
 
-            let raw = gdt
+            let raw = table
                 .get(index)
-                .ok_or(SegmentError::OutOfGdtAccess { index })?;
+                .ok_or(SegmentError::OutOfTableAccess { index })?;
 
+            // The type field of the upper half must be zero, and the rest is
+            // reserved.
             let Ok(upper_base) = u32::try_from(*raw) else {
-                return Err(SegmentError::InvalidGdtEntry { index, entry: *raw });
+                return Err(SegmentError::InvalidEntry { index, entry: *raw });
             };
 
             Some(upper_base)
@@ -60,5 +94,222 @@ impl SegmentDescriptor {
             self.low64.base() as _
         }
     }
+
+    /// Returns the limit in bytes, with the granularity applied.
+    pub fn limit(&self) -> u32 {
+        let limit = self.low64.limit();
+        if self.is_page_granular() {
+            limit << 12 | 0xfff
+        } else {
+            limit
+        }
+    }
+
+    pub fn descriptor_type(&self) -> u8 {
+        self.low64.descriptor_type()
+    }
+
+    /// Whether this is a system segment or a gate, rather than a code or data
+    /// segment.
+    pub fn is_system(&self) -> bool {
+        self.low64.is_system()
+    }
+
+    pub fn dpl(&self) -> u8 {
+        self.low64.dpl()
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.low64.is_present()
+    }
+
+    pub fn avl(&self) -> bool {
+        self.low64.avl()
+    }
+
+    /// Whether this is a 64-bit code segment.
+    pub fn is_long(&self) -> bool {
+        self.low64.is_long()
+    }
+
+    /// The D/B flag, set for 32-bit segments.
+    pub fn db(&self) -> bool {
+        self.low64.db()
+    }
+
+    /// The G flag, set when the limit is in 4KB units.
+    pub fn is_page_granular(&self) -> bool {
+        self.low64.is_page_granular()
+    }
+
+    pub fn is_16byte(&self) -> bool {
+        self.upper_base.is_some()
+    }
+
+    /// Returns the attributes in the format of the VMCB segment registers:
+    /// type, S, DPL and P in bits 7:0, and AVL, L, D/B and G in bits 11:8.
+    /// See: 15.5.1 Basic Operation
+    pub fn attrib(&self) -> u16 {
+        self.low64.attributes()
+    }
 }
 
+/// Returns the descriptor table at `base` whose limit is `limit`. Only whole
+/// descriptors within the limit are included.
+///
+/// # Safety
+/// The table must be mapped and not be modified while the result is used.
+unsafe fn table<'a>(base: u64, limit: u32) -> &'a [u64] {
+    let len = (limit as usize + 1) / 8;
+    if len == 0 {
+        return &[];
+    }
+    core::slice::from_raw_parts(base as *const u64, len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    /// The 64-bit kernel code segment of Windows.
+    const KERNEL_CODE64: u64 = 0x0020_9b00_0000_0000;
+    /// The 32-bit user data segment of Windows.
+    const USER_DATA32: u64 = 0x00cf_f300_0000_ffff;
+    /// The 32-bit user code segment, byte granular with a 0x3c00 byte limit.
+    const USER_CODE32_SMALL: u64 = 0x0040_fb00_0000_3c00;
+    /// A busy TSS at 0xffff_f800_1234_5678 with a limit of 0x67.
+    const TSS: [u64; 2] = [0x1200_8b34_5678_0067, 0xffff_f800];
+
+    fn ldt_descriptor(ldt: &[u64]) -> [u64; 2] {
+        let base = ldt.as_ptr() as u64;
+        let limit = (ldt.len() * 8 - 1) as u64;
+        let low = limit & 0xffff
+            | (base & 0xff_ffff) << 16
+            | 0x82 << 40
+            | (limit >> 16 & 0xf) << 48
+            | (base >> 24 & 0xff) << 56;
+        [low, base >> 32]
+    }
+
+    fn selector(index: u16, ldt: bool) -> SegmentSelector {
+        let selector = SegmentSelector::new(index, x86::Ring::Ring3);
+        if ldt {
+            selector | SegmentSelector::TI_LDT
+        } else {
+            selector
+        }
+    }
+
+    #[test]
+    fn parses_long_mode_code_segment() {
+        let descriptor = SegmentDescriptor::try_from_table(&[0, KERNEL_CODE64], 1).unwrap();
+        assert_eq!(descriptor.base(), 0);
+        assert_eq!(descriptor.limit(), 0);
+        assert_eq!(descriptor.descriptor_type(), 0xb);
+        assert!(!descriptor.is_system());
+        assert_eq!(descriptor.dpl(), 0);
+        assert!(descriptor.is_present());
+        assert!(!descriptor.avl());
+        assert!(descriptor.is_long());
+        assert!(!descriptor.db());
+        assert!(!descriptor.is_page_granular());
+        assert!(!descriptor.is_16byte());
+        assert_eq!(descriptor.attrib(), 0x029b);
+    }
+
+    #[test]
+    fn applies_granularity_to_limit() {
+        let descriptor = SegmentDescriptor::try_from_table(&[USER_DATA32], 0).unwrap();
+        assert_eq!(descriptor.limit(), 0xffff_ffff);
+        assert_eq!(descriptor.dpl(), 3);
+        assert!(descriptor.db());
+        assert!(descriptor.is_page_granular());
+        assert_eq!(descriptor.attrib(), 0x0cf3);
+
+        let descriptor = SegmentDescriptor::try_from_table(&[USER_CODE32_SMALL], 0).unwrap();
+        assert_eq!(descriptor.limit(), 0x3c00);
+        assert_eq!(descriptor.attrib(), 0x04fb);
+    }
+
+    #[test]
+    fn parses_16_byte_system_descriptor() {
+        let descriptor = SegmentDescriptor::try_from_table(&TSS, 0).unwrap();
+        assert!(descriptor.is_16byte());
+        assert!(descriptor.is_system());
+        assert_eq!(descriptor.descriptor_type(), SystemDescriptorTypes64::TssBusy as u8);
+        assert_eq!(descriptor.base(), 0xffff_f800_1234_5678);
+        assert_eq!(descriptor.limit(), 0x67);
+        assert_eq!(descriptor.attrib(), 0x008b);
+    }
+
+    #[test]
+    fn checks_table_bounds() {
+        assert!(matches!(
+            SegmentDescriptor::try_from_table(&[0, KERNEL_CODE64], 2),
+            Err(SegmentError::OutOfTableAccess { index: 2 })
+        ));
+        // The upper half of the TSS descriptor is outside the table.
+        assert!(matches!(
+            SegmentDescriptor::try_from_table(&TSS[..1], 0),
+            Err(SegmentError::OutOfTableAccess { index: 1 })
+        ));
+        assert!(matches!(
+            SegmentDescriptor::try_from_table(&[TSS[0], KERNEL_CODE64], 0),
+            Err(SegmentError::InvalidEntry { index: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn limits_gdt_to_gdtr_limit() {
+        let gdt = [0, KERNEL_CODE64, USER_DATA32];
+        let gdtr = DescriptorTablePointer { base: gdt.as_ptr(), limit: 0xf };
+        assert!(SegmentDescriptor::try_from_tables(&gdtr, selector(0, false), selector(1, false)).is_ok());
+        assert!(matches!(
+            SegmentDescriptor::try_from_tables(&gdtr, selector(0, false), selector(2, false)),
+            Err(SegmentError::OutOfTableAccess { index: 2 })
+        ));
+        assert!(matches!(
+            SegmentDescriptor::try_from_tables(&gdtr, selector(0, false), selector(0, false)),
+            Err(SegmentError::NullDescriptor { .. })
+        ));
+    }
+
+    #[test]
+    fn resolves_selectors_through_ldt() {
+        let ldt = [USER_CODE32_SMALL, USER_DATA32];
+        let mut gdt = Vec::from([0, KERNEL_CODE64]);
+        gdt.extend(ldt_descriptor(&ldt));
+        let gdtr = DescriptorTablePointer::new_from_slice(&gdt);
+        let ldtr = selector(2, false);
+
+        // The first LDT entry is usable, unlike the first GDT entry.
+        let descriptor = SegmentDescriptor::try_from_tables(&gdtr, ldtr, selector(0, true)).unwrap();
+        assert_eq!(descriptor.attrib(), 0x04fb);
+        let descriptor = SegmentDescriptor::try_from_tables(&gdtr, ldtr, selector(1, true)).unwrap();
+        assert_eq!(descriptor.attrib(), 0x0cf3);
+        assert!(matches!(
+            SegmentDescriptor::try_from_tables(&gdtr, ldtr, selector(2, true)),
+            Err(SegmentError::OutOfTableAccess { index: 2 })
+        ));
+
+        let descriptor = SegmentDescriptor::try_from_tables(&gdtr, ldtr, ldtr).unwrap();
+        assert_eq!(descriptor.base(), ldt.as_ptr() as u64);
+        assert_eq!(descriptor.limit(), 0xf);
+        assert_eq!(descriptor.attrib(), 0x0082);
+    }
+
+    #[test]
+    fn rejects_missing_ldt() {
+        let gdt = [0, KERNEL_CODE64];
+        let gdtr = DescriptorTablePointer::new_from_slice(&gdt);
+        assert!(matches!(
+            SegmentDescriptor::try_from_tables(&gdtr, selector(0, false), selector(0, true)),
+            Err(SegmentError::NoLdt { .. })
+        ));
+        assert!(matches!(
+            SegmentDescriptor::try_from_tables(&gdtr, selector(1, false), selector(0, true)),
+            Err(SegmentError::NoLdt { .. })
+        ));
+    }
+}
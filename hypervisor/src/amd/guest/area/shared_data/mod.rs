@@ -1,14 +1,22 @@
 use alloc::vec::Vec;
-use core::sync::atomic::AtomicU8;
+use core::sync::atomic::{AtomicU32, AtomicU8};
 use spin::{Lazy, Once, RwLock};
 
 use crate::amd::guest::area::{GdtTss, NestedPageTables, PagingStructures};
 use crate::amd::guest::area::interrupt_handlers::InterruptDescriptorTable;
 use crate::amd::guest::support;
+use crate::amd::guest::PagingStructuresRaw;
+use crate::amd::processors::MAX_PROCESSORS;
+use crate::amd::stats;
 
 pub struct SharedGuestData {
     pub npt: RwLock<NestedPageTables>,
-    pub activity_states: [AtomicU8; 0xff],
+    pub activity_states: [AtomicU8; MAX_PROCESSORS],
+
+    /// The last known LDR and DFR values of each processor's local APIC. Used
+    /// to resolve IPIs sent in the logical destination mode.
+    pub apic_ldrs: [AtomicU32; MAX_PROCESSORS],
+    pub apic_dfrs: [AtomicU32; MAX_PROCESSORS],
 }
 
 impl SharedGuestData {
@@ -16,12 +24,15 @@ impl SharedGuestData {
         let mut npt = NestedPageTables::new();
         npt.build_identity();
         npt.split_apic_page();
+        stats::account_npt(core::mem::size_of::<PagingStructuresRaw>());
 
         Self {
             npt: RwLock::new(npt),
             activity_states: core::array::from_fn(|_| {
                 AtomicU8::new(support::GuestActivityState::Active as u8)
             }),
+            apic_ldrs: core::array::from_fn(|_| AtomicU32::new(0)),
+            apic_dfrs: core::array::from_fn(|_| AtomicU32::new(support::icr::APIC_DFR_RESET_VALUE)),
         }
     }
 }
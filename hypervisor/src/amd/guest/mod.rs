@@ -4,6 +4,7 @@ mod vcpu;
 
 
 pub use raw::*;
+pub mod state;
 pub mod support;
 pub mod vmexit;
 
@@ -5,150 +5,299 @@ use x86::dtables::{lgdt, DescriptorTablePointer};
 use x86::segmentation::{cs, BuildDescriptor, Descriptor, DescriptorBuilder, GateDescriptorBuilder, SegmentSelector, SystemDescriptorTypes64};
 use x86::task::{load_tr, tr};
 use crate::amd::guest::area::SegmentDescriptor;
+use crate::amd::guest::state::{DescriptorTableState, GuestState, SegmentState};
 use crate::amd::guest::support::error::GdtTssError;
 
 ///! c style structure define
 
-/// vmcb control area
-#[derive(derivative::Derivative)]
-#[derivative(Debug, Default)]
-#[repr(C)]
-pub struct ControlArea {
-    intercept_cr_read: u16,              // +0x000
-    intercept_cr_write: u16,             // +0x002
-    pub(crate) intercept_dr_read: u16,   // +0x004
-    intercept_dr_write: u16,             // +0x006
-    pub(crate) intercept_exception: u32, // +0x008
-    pub(crate) intercept_misc1: u32,     // +0x00c
-    pub(crate) intercept_misc2: u32,     // +0x010
-    intercept_misc3: u32,                // +0x014
-    #[derivative(Debug = "ignore", Default(value = "[0; 36]"))]
-    _padding1: [u8; 0x03c - 0x018], // +0x018
-    pause_filter_threshold: u16,         // +0x03c
-    pub(crate) pause_filter_count: u16,  // +0x03e
-    iopm_base_pa: u64,                   // +0x040
-    msrpm_base_pa: u64,                  // +0x048
-    tsc_offset: u64,                     // +0x050
-    pub(crate) guest_asid: u32,          // +0x058
-    pub(crate) tlb_control: u32,         // +0x05c
-    vintr: u64,                          // +0x060
-    interrupt_shadow: u64,               // +0x068
-    pub(crate) exit_code: u64,           // +0x070
-    pub(crate) exit_info1: u64,          // +0x078
-    pub(crate) exit_info2: u64,          // +0x080
-    exit_int_info: u64,                  // +0x088
-    pub(crate) np_enable: u64,           // +0x090
-    avic_apic_bar: u64,                  // +0x098
-    guest_pa_pf_ghcb: u64,               // +0x0a0
-    event_inj: u64,                      // +0x0a8
-    pub(crate) ncr3: u64,                // +0x0b0
-    lbr_virtualization_enable: u64,      // +0x0b8
-    pub(crate) vmcb_clean: u32,          // +0x0c0
-    _reserved: u32,                      // +0x0c4
-    pub(crate) nrip: u64,                // +0x0c8
-    pub(crate) num_of_bytes_fetched: u8, // +0x0d0
-    pub(crate) guest_instruction_bytes: [u8; 15], // +0x0d1
-    avic_apic_backing_page_pointer: u64, // +0x0e0
-    #[derivative(Debug = "ignore")]
-    _padding2: u64, // +0x0e8
-    avic_logical_table_pointer: u64,     // +0x0f0
-    avic_physical_table_pointer: u64,    // +0x0f8
-    #[derivative(Debug = "ignore")]
-    _padding3: u64, // +0x100
-    vmcb_save_state_pointer: u64,        // +0x108
-    #[derivative(Debug = "ignore", Default(value = "[0; 720]"))]
-    _padding4: [u8; 0x3e0 - 0x110], // +0x110
-    reserved_for_host: [u8; 0x20],       // +0x3e0
+/// Defines a VMCB area from its layout table. Every offset and the total size
+/// are checked at compile time, and `Debug` prints each field with its offset
+/// and APM name. Fields starting with `_` are reserved and not printed.
+/// See: Appendix B Layout of VMCB
+macro_rules! vmcb_layout {
+    (
+        $(#[$meta:meta])*
+        pub struct $name:ident, size $size:literal {
+            $($offset:literal $field:ident: $ty:ty => $apm:literal,)*
+        }
+    ) => {
+        $(#[$meta])*
+        #[repr(C)]
+        pub struct $name {
+            $($field: $ty,)*
+        }
+
+        $(const _: () = assert!(core::mem::offset_of!($name, $field) == $offset);)*
+        const _: () = assert!(core::mem::size_of::<$name>() == $size);
+
+        impl Default for $name {
+            fn default() -> Self {
+                // Safety: every field is an integer or an array of integers.
+                unsafe { core::mem::zeroed() }
+            }
+        }
+
+        impl core::fmt::Debug for $name {
+            fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+                let mut s = f.debug_struct(stringify!($name));
+                $(
+                    if !stringify!($field).starts_with('_') {
+                        s.field(concat!("+", stringify!($offset), " ", stringify!($field), " (", $apm, ")"), &self.$field);
+                    }
+                )*
+                s.finish()
+            }
+        }
+    };
 }
 
+vmcb_layout! {
+    /// vmcb control area
+    pub struct ControlArea, size 0x400 {
+        0x000 intercept_cr_read: u16 => "Intercept reads of CR0-15",
+        0x002 intercept_cr_write: u16 => "Intercept writes of CR0-15",
+        0x004 intercept_dr_read: u16 => "Intercept reads of DR0-15",
+        0x006 intercept_dr_write: u16 => "Intercept writes of DR0-15",
+        0x008 intercept_exception: u32 => "Intercept exception vectors 0-31",
+        0x00c intercept_misc1: u32 => "Intercept vector 3",
+        0x010 intercept_misc2: u32 => "Intercept vector 4",
+        0x014 intercept_misc3: u32 => "Intercept vector 5",
+        0x018 _reserved1: [u8; 0x24] => "Reserved",
+        0x03c pause_filter_threshold: u16 => "PAUSE_FILTER_THRESHOLD",
+        0x03e pause_filter_count: u16 => "PAUSE_FILTER_COUNT",
+        0x040 iopm_base_pa: u64 => "IOPM_BASE_PA",
+        0x048 msrpm_base_pa: u64 => "MSRPM_BASE_PA",
+        0x050 tsc_offset: u64 => "TSC_OFFSET",
+        0x058 guest_asid: u32 => "Guest ASID",
+        0x05c tlb_control: u8 => "TLB_CONTROL",
+        0x05d _reserved2: [u8; 3] => "Reserved",
+        0x060 vintr: u64 => "V_TPR, V_IRQ, VGIF, V_NMI, V_INTR_PRIO, V_IGN_TPR, V_INTR_MASKING, AVIC_ENABLE, V_INTR_VECTOR",
+        0x068 interrupt_shadow: u64 => "INTERRUPT_SHADOW, GUEST_INTERRUPT_MASK",
+        0x070 exit_code: u64 => "EXITCODE",
+        0x078 exit_info1: u64 => "EXITINFO1",
+        0x080 exit_info2: u64 => "EXITINFO2",
+        0x088 exit_int_info: u64 => "EXITINTINFO",
+        0x090 np_enable: u64 => "NP_ENABLE, SEV, SEV-ES, GMET, SSS, VTE",
+        0x098 avic_apic_bar: u64 => "AVIC_APIC_BAR",
+        0x0a0 ghcb_pa: u64 => "Guest physical address of GHCB",
+        0x0a8 event_inj: u64 => "EVENTINJ",
+        0x0b0 ncr3: u64 => "N_CR3",
+        0x0b8 lbr_virtualization_enable: u64 => "LBR_VIRTUALIZATION_ENABLE, Virtualized VMSAVE/VMLOAD, IBS virtualization",
+        0x0c0 vmcb_clean: u32 => "VMCB Clean Bits",
+        0x0c4 _reserved3: u32 => "Reserved",
+        0x0c8 nrip: u64 => "nRIP",
+        0x0d0 num_of_bytes_fetched: u8 => "Number of bytes fetched",
+        0x0d1 guest_instruction_bytes: [u8; 15] => "Guest instruction bytes",
+        0x0e0 avic_apic_backing_page_pointer: u64 => "AVIC_APIC_BACKING_PAGE pointer",
+        0x0e8 _reserved4: u64 => "Reserved",
+        0x0f0 avic_logical_table_pointer: u64 => "AVIC_LOGICAL_TABLE pointer",
+        0x0f8 avic_physical_table_pointer: u64 => "AVIC_PHYSICAL_TABLE pointer",
+        0x100 _reserved5: u64 => "Reserved",
+        0x108 vmsa_pointer: u64 => "VMSA pointer",
+        0x110 vmgexit_rax: u64 => "VMGEXIT_RAX",
+        0x118 vmgexit_cpl: u8 => "VMGEXIT_CPL",
+        0x119 _reserved6: [u8; 7] => "Reserved",
+        0x120 bus_lock_threshold_counter: u16 => "Bus Lock Threshold Counter",
+        0x122 _reserved7: [u8; 0x16] => "Reserved",
+        0x138 allowed_sev_features: u64 => "ALLOWED_SEV_FEATURES",
+        0x140 guest_sev_features: u64 => "GUEST_SEV_FEATURES",
+        0x148 _reserved8: [u8; 0x298] => "Reserved",
+        0x3e0 reserved_for_host: [u8; 0x20] => "Reserved for host usage",
+    }
+}
 
-/// vmcb state save area
-#[derive(derivative::Derivative)]
-#[derivative(Debug, Default)]
-#[repr(C)]
-pub struct StateSaveArea {
-    pub(crate) es_selector: u16, // +0x000
-    pub(crate) es_attrib: u16,   // +0x002
-    pub(crate) es_limit: u32,    // +0x004
-    pub(crate) es_base: u64,                // +0x008
-    pub(crate) cs_selector: u16, // +0x010
-    pub(crate) cs_attrib: u16,   // +0x012
-    pub(crate) cs_limit: u32,    // +0x014
-    pub(crate) cs_base: u64,                // +0x018
-    pub(crate) ss_selector: u16, // +0x020
-    pub(crate) ss_attrib: u16,   // +0x022
-    pub(crate) ss_limit: u32,    // +0x024
-    pub(crate) ss_base: u64,                // +0x028
-    pub(crate) ds_selector: u16, // +0x030
-    pub(crate) ds_attrib: u16,   // +0x032
-    pub(crate) ds_limit: u32,    // +0x034
-    pub(crate) ds_base: u64,                // +0x038
-    pub(crate) fs_selector: u16,            // +0x040
-    pub(crate) fs_attrib: u16,              // +0x042
-    pub(crate) fs_limit: u32,               // +0x044
-    pub(crate) fs_base: u64,                // +0x048
-    pub(crate) gs_selector: u16,            // +0x050
-    pub(crate) gs_attrib: u16,              // +0x052
-    pub(crate) gs_limit: u32,               // +0x054
-    pub(crate) gs_base: u64,                // +0x058
-    pub(crate) gdtr_selector: u16,          // +0x060 (Reserved)
-    pub(crate) gdtr_attrib: u16,            // +0x062 (Reserved)
-    pub(crate) gdtr_limit: u32,  // +0x064
-    pub(crate) gdtr_base: u64,   // +0x068
-    pub(crate) ldtr_selector: u16,          // +0x070 (Reserved)
-    pub(crate) ldtr_attrib: u16,            // +0x072 (Reserved)
-    pub(crate) ldtr_limit: u32,             // +0x074
-    pub(crate) ldtr_base: u64,              // +0x078
-    pub(crate) idtr_selector: u16,          // +0x080
-    pub(crate) idtr_attrib: u16,            // +0x082
-    pub(crate) idtr_limit: u32,  // +0x084
-    pub(crate) idtr_base: u64,   // +0x088
-    pub(crate) tr_selector: u16,            // +0x090
-    pub(crate) tr_attrib: u16,              // +0x092
-    pub(crate) tr_limit: u32,               // +0x094
-    pub(crate) tr_base: u64,                // +0x098
-    #[derivative(Debug = "ignore", Default(value = "[0; 43]"))]
-    _padding1: [u8; 0x0cb - 0x0a0], // +0x0a0
-    pub(crate) cpl: u8,                     // +0x0cb
-    #[derivative(Debug = "ignore")]
-    _padding2: u32, // +0x0cc
-    pub(crate) efer: u64,        // +0x0d0
-    #[derivative(Debug = "ignore", Default(value = "[0; 112]"))]
-    _padding3: [u8; 0x148 - 0x0d8], // +0x0d8
-    pub(crate) cr4: u64,         // +0x148
-    pub(crate) cr3: u64,         // +0x150
-    pub(crate) cr0: u64,         // +0x158
-    pub(crate) dr7: u64,                    // +0x160
-    pub(crate) dr6: u64,                    // +0x168
-    pub(crate) rflags: u64,      // +0x170
-    pub(crate) rip: u64,         // +0x178
-    #[derivative(Debug = "ignore", Default(value = "[0; 88]"))]
-    _padding4: [u8; 0x1d8 - 0x180], // +0x180
-    pub(crate) rsp: u64,         // +0x1d8
-    pub(crate) s_cet: u64,                  // +0x1e0
-    pub(crate) ssp: u64,                    // +0x1e8
-    pub(crate) isst_addr: u64,              // +0x1f0
-    pub(crate) rax: u64,         // +0x1f8
-    pub(crate) star: u64,                   // +0x200
-    pub(crate) lstar: u64,                  // +0x208
-    pub(crate) cstar: u64,                  // +0x210
-    pub(crate) sf_mask: u64,                // +0x218
-    pub(crate) kernel_gs_base: u64,         // +0x220
-    pub(crate) sysenter_cs: u64,            // +0x228
-    pub(crate) sysenter_esp: u64,           // +0x230
-    pub(crate) sysenter_eip: u64,           // +0x238
-    pub(crate) cr2: u64,                    // +0x240
-    #[derivative(Debug = "ignore", Default(value = "[0; 32]"))]
-    _padding5: [u8; 0x268 - 0x248], // +0x248
-    pub(crate) gpat: u64,        // +0x268
-    pub(crate) dbg_ctl: u64,                // +0x270
-    pub(crate) br_from: u64,                // +0x278
-    pub(crate) br_to: u64,                  // +0x280
-    pub(crate) last_excep_from: u64,        // +0x288
-    pub(crate) last_excep_to: u64,          // +0x290
-    #[derivative(Debug = "ignore", Default(value = "[0; 71]"))]
-    _padding6: [u8; 0x2df - 0x298], // +0x298
-    pub(crate) spec_ctl: u64,               // +0x2e0
+vmcb_layout! {
+    /// vmcb state save area
+    pub struct StateSaveArea, size 0xc00 {
+        0x000 es_selector: u16 => "ES selector",
+        0x002 es_attrib: u16 => "ES attrib",
+        0x004 es_limit: u32 => "ES limit",
+        0x008 es_base: u64 => "ES base",
+        0x010 cs_selector: u16 => "CS selector",
+        0x012 cs_attrib: u16 => "CS attrib",
+        0x014 cs_limit: u32 => "CS limit",
+        0x018 cs_base: u64 => "CS base",
+        0x020 ss_selector: u16 => "SS selector",
+        0x022 ss_attrib: u16 => "SS attrib",
+        0x024 ss_limit: u32 => "SS limit",
+        0x028 ss_base: u64 => "SS base",
+        0x030 ds_selector: u16 => "DS selector",
+        0x032 ds_attrib: u16 => "DS attrib",
+        0x034 ds_limit: u32 => "DS limit",
+        0x038 ds_base: u64 => "DS base",
+        0x040 fs_selector: u16 => "FS selector",
+        0x042 fs_attrib: u16 => "FS attrib",
+        0x044 fs_limit: u32 => "FS limit",
+        0x048 fs_base: u64 => "FS base",
+        0x050 gs_selector: u16 => "GS selector",
+        0x052 gs_attrib: u16 => "GS attrib",
+        0x054 gs_limit: u32 => "GS limit",
+        0x058 gs_base: u64 => "GS base",
+        0x060 _gdtr_reserved: u32 => "Reserved",
+        0x064 gdtr_limit: u32 => "GDTR limit",
+        0x068 gdtr_base: u64 => "GDTR base",
+        0x070 ldtr_selector: u16 => "LDTR selector",
+        0x072 ldtr_attrib: u16 => "LDTR attrib",
+        0x074 ldtr_limit: u32 => "LDTR limit",
+        0x078 ldtr_base: u64 => "LDTR base",
+        0x080 _idtr_reserved: u32 => "Reserved",
+        0x084 idtr_limit: u32 => "IDTR limit",
+        0x088 idtr_base: u64 => "IDTR base",
+        0x090 tr_selector: u16 => "TR selector",
+        0x092 tr_attrib: u16 => "TR attrib",
+        0x094 tr_limit: u32 => "TR limit",
+        0x098 tr_base: u64 => "TR base",
+        0x0a0 _reserved1: [u8; 0x2a] => "Reserved",
+        0x0ca vmpl: u8 => "VMPL",
+        0x0cb cpl: u8 => "CPL",
+        0x0cc _reserved2: u32 => "Reserved",
+        0x0d0 efer: u64 => "EFER",
+        0x0d8 _reserved3: [u8; 0x68] => "Reserved",
+        0x140 xss: u64 => "XSS",
+        0x148 cr4: u64 => "CR4",
+        0x150 cr3: u64 => "CR3",
+        0x158 cr0: u64 => "CR0",
+        0x160 dr7: u64 => "DR7",
+        0x168 dr6: u64 => "DR6",
+        0x170 rflags: u64 => "RFLAGS",
+        0x178 rip: u64 => "RIP",
+        0x180 _reserved4: [u8; 0x58] => "Reserved",
+        0x1d8 rsp: u64 => "RSP",
+        0x1e0 s_cet: u64 => "S_CET",
+        0x1e8 ssp: u64 => "SSP",
+        0x1f0 isst_addr: u64 => "ISST_ADDR",
+        0x1f8 rax: u64 => "RAX",
+        0x200 star: u64 => "STAR",
+        0x208 lstar: u64 => "LSTAR",
+        0x210 cstar: u64 => "CSTAR",
+        0x218 sf_mask: u64 => "SFMASK",
+        0x220 kernel_gs_base: u64 => "KernelGsBase",
+        0x228 sysenter_cs: u64 => "SYSENTER_CS",
+        0x230 sysenter_esp: u64 => "SYSENTER_ESP",
+        0x238 sysenter_eip: u64 => "SYSENTER_EIP",
+        0x240 cr2: u64 => "CR2",
+        0x248 _reserved5: [u8; 0x20] => "Reserved",
+        0x268 gpat: u64 => "G_PAT",
+        0x270 dbg_ctl: u64 => "DBGCTL",
+        0x278 br_from: u64 => "BR_FROM",
+        0x280 br_to: u64 => "BR_TO",
+        0x288 last_excep_from: u64 => "LASTEXCPFROM",
+        0x290 last_excep_to: u64 => "LASTEXCPTO",
+        0x298 _reserved6: [u8; 0x48] => "Reserved",
+        0x2e0 spec_ctl: u64 => "SPEC_CTRL",
+        0x2e8 pkru: u32 => "PKRU",
+        0x2ec tsc_aux: u32 => "TSC_AUX",
+        0x2f0 tsc_scale: u64 => "Guest TSC ratio",
+        0x2f8 _reserved7: [u8; 0x378] => "Reserved",
+        0x670 lbr_stack_from_to: [u64; 32] => "LBR_STACK_FROM/TO 0-15",
+        0x770 lbr_select: u64 => "LBR_SELECT",
+        0x778 ibs_fetch_ctl: u64 => "IBS_FETCH_CTL",
+        0x780 ibs_fetch_linear_addr: u64 => "IBS_FETCH_LINADDR",
+        0x788 ibs_op_ctl: u64 => "IBS_OP_CTL",
+        0x790 ibs_op_rip: u64 => "IBS_OP_RIP",
+        0x798 ibs_op_data: u64 => "IBS_OP_DATA",
+        0x7a0 ibs_op_data2: u64 => "IBS_OP_DATA2",
+        0x7a8 ibs_op_data3: u64 => "IBS_OP_DATA3",
+        0x7b0 ibs_dc_linear_addr: u64 => "IBS_DC_LINADDR",
+        0x7b8 bp_ibstgt_rip: u64 => "BP_IBSTGT_RIP",
+        0x7c0 ic_ibs_extd_ctl: u64 => "IC_IBS_EXTD_CTL",
+        0x7c8 _reserved8: [u8; 0x438] => "Reserved",
+    }
+}
+
+
+impl StateSaveArea {
+    /// Returns the guest state held in this area.
+    pub fn guest_state(&self) -> GuestState {
+        let segment = |selector, attrib, limit, base| SegmentState { selector, attrib, limit, base };
+        GuestState {
+            es: segment(self.es_selector, self.es_attrib, self.es_limit, self.es_base),
+            cs: segment(self.cs_selector, self.cs_attrib, self.cs_limit, self.cs_base),
+            ss: segment(self.ss_selector, self.ss_attrib, self.ss_limit, self.ss_base),
+            ds: segment(self.ds_selector, self.ds_attrib, self.ds_limit, self.ds_base),
+            fs: segment(self.fs_selector, self.fs_attrib, self.fs_limit, self.fs_base),
+            gs: segment(self.gs_selector, self.gs_attrib, self.gs_limit, self.gs_base),
+            gdtr: DescriptorTableState { base: self.gdtr_base, limit: self.gdtr_limit },
+            ldtr: segment(self.ldtr_selector, self.ldtr_attrib, self.ldtr_limit, self.ldtr_base),
+            idtr: DescriptorTableState { base: self.idtr_base, limit: self.idtr_limit },
+            tr: segment(self.tr_selector, self.tr_attrib, self.tr_limit, self.tr_base),
+            cpl: self.cpl,
+            efer: self.efer,
+            cr0: self.cr0,
+            cr2: self.cr2,
+            cr3: self.cr3,
+            cr4: self.cr4,
+            dr6: self.dr6,
+            dr7: self.dr7,
+            rflags: self.rflags,
+            rip: self.rip,
+            rsp: self.rsp,
+            rax: self.rax,
+            star: self.star,
+            lstar: self.lstar,
+            cstar: self.cstar,
+            sf_mask: self.sf_mask,
+            kernel_gs_base: self.kernel_gs_base,
+            sysenter_cs: self.sysenter_cs,
+            sysenter_esp: self.sysenter_esp,
+            sysenter_eip: self.sysenter_eip,
+            gpat: self.gpat,
+            dbg_ctl: self.dbg_ctl,
+            br_from: self.br_from,
+            br_to: self.br_to,
+            last_excep_from: self.last_excep_from,
+            last_excep_to: self.last_excep_to,
+            s_cet: self.s_cet,
+            ssp: self.ssp,
+            isst_addr: self.isst_addr,
+            spec_ctrl: self.spec_ctl,
+        }
+    }
+
+    /// Writes `state` into this area. Fields that are not part of
+    /// [`GuestState`] are left unchanged.
+    pub fn set_guest_state(&mut self, state: &GuestState) {
+        SegmentState { selector: self.es_selector, attrib: self.es_attrib, limit: self.es_limit, base: self.es_base } = state.es;
+        SegmentState { selector: self.cs_selector, attrib: self.cs_attrib, limit: self.cs_limit, base: self.cs_base } = state.cs;
+        SegmentState { selector: self.ss_selector, attrib: self.ss_attrib, limit: self.ss_limit, base: self.ss_base } = state.ss;
+        SegmentState { selector: self.ds_selector, attrib: self.ds_attrib, limit: self.ds_limit, base: self.ds_base } = state.ds;
+        SegmentState { selector: self.fs_selector, attrib: self.fs_attrib, limit: self.fs_limit, base: self.fs_base } = state.fs;
+        SegmentState { selector: self.gs_selector, attrib: self.gs_attrib, limit: self.gs_limit, base: self.gs_base } = state.gs;
+        DescriptorTableState { base: self.gdtr_base, limit: self.gdtr_limit } = state.gdtr;
+        SegmentState { selector: self.ldtr_selector, attrib: self.ldtr_attrib, limit: self.ldtr_limit, base: self.ldtr_base } = state.ldtr;
+        DescriptorTableState { base: self.idtr_base, limit: self.idtr_limit } = state.idtr;
+        SegmentState { selector: self.tr_selector, attrib: self.tr_attrib, limit: self.tr_limit, base: self.tr_base } = state.tr;
+        self.cpl = state.cpl;
+        self.efer = state.efer;
+        self.cr0 = state.cr0;
+        self.cr2 = state.cr2;
+        self.cr3 = state.cr3;
+        self.cr4 = state.cr4;
+        self.dr6 = state.dr6;
+        self.dr7 = state.dr7;
+        self.rflags = state.rflags;
+        self.rip = state.rip;
+        self.rsp = state.rsp;
+        self.rax = state.rax;
+        self.star = state.star;
+        self.lstar = state.lstar;
+        self.cstar = state.cstar;
+        self.sf_mask = state.sf_mask;
+        self.kernel_gs_base = state.kernel_gs_base;
+        self.sysenter_cs = state.sysenter_cs;
+        self.sysenter_esp = state.sysenter_esp;
+        self.sysenter_eip = state.sysenter_eip;
+        self.gpat = state.gpat;
+        self.dbg_ctl = state.dbg_ctl;
+        self.br_from = state.br_from;
+        self.br_to = state.br_to;
+        self.last_excep_from = state.last_excep_from;
+        self.last_excep_to = state.last_excep_to;
+        self.s_cet = state.s_cet;
+        self.ssp = state.ssp;
+        self.isst_addr = state.isst_addr;
+        self.spec_ctl = state.spec_ctrl;
+    }
 }
 
 
@@ -156,8 +305,262 @@ pub struct StateSaveArea {
 #[derive(Debug, Default)]
 #[repr(C, align(4096))]
 pub struct VmcbRaw {
-    pub(crate) control_area: ControlArea,
-    pub(crate) state_save_area: StateSaveArea,
+    control_area: ControlArea,
+    state_save_area: StateSaveArea,
+}
+
+const _: () = assert!(core::mem::offset_of!(VmcbRaw, state_save_area) == 0x400);
+const _: () = assert!(core::mem::size_of::<VmcbRaw>() == 0x1000);
+
+
+/// The groups of VMCB fields the processor may cache across VMRUN. A group
+/// whose bit is clear in VMCB_CLEAN is reloaded from memory on the next VMRUN.
+/// See: 15.15.3 VMCB Clean Bits
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VmcbClean(u32);
+
+impl VmcbClean {
+    /// The field is always loaded from memory.
+    pub const NONE: Self = Self(0);
+    /// Intercept vectors, TSC offset and pause filter count.
+    pub const I: Self = Self(1 << 0);
+    /// IOPM_BASE_PA and MSRPM_BASE_PA.
+    pub const IOPM: Self = Self(1 << 1);
+    pub const ASID: Self = Self(1 << 2);
+    /// V_TPR, V_IRQ, V_INTR_PRIO, V_IGN_TPR, V_INTR_MASKING and V_INTR_VECTOR.
+    pub const TPR: Self = Self(1 << 3);
+    /// NP_ENABLE, N_CR3 and G_PAT.
+    pub const NP: Self = Self(1 << 4);
+    /// CR0, CR3, CR4 and EFER.
+    pub const CRX: Self = Self(1 << 5);
+    /// DR6 and DR7.
+    pub const DRX: Self = Self(1 << 6);
+    /// GDT and IDT base and limit.
+    pub const DT: Self = Self(1 << 7);
+    /// CS, DS, SS and ES selector, base, limit and attributes, and CPL.
+    pub const SEG: Self = Self(1 << 8);
+    pub const CR2: Self = Self(1 << 9);
+    /// DbgCtlMsr, br_from, br_to, lastint_from and lastint_to.
+    pub const LBR: Self = Self(1 << 10);
+    /// AVIC APIC_BAR, APIC backing page, physical table and logical table
+    /// pointers.
+    pub const AVIC: Self = Self(1 << 11);
+    /// S_CET, SSP and ISST_ADDR.
+    pub const CET: Self = Self(1 << 12);
+    pub const ALL: Self = Self((1 << 13) - 1);
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+}
+
+/// Defines a getter and a setter on `VmcbRaw` for each field. The setter
+/// clears the clean bit of the field so that the processor does not keep using
+/// a stale cached value.
+macro_rules! vmcb_fields {
+    ($($area:ident.$field:ident, $setter:ident: $ty:ty => $clean:ident;)*) => {
+        impl VmcbRaw {
+            $(
+                #[allow(dead_code)]
+                pub(crate) fn $field(&self) -> $ty {
+                    self.$area.$field
+                }
+
+                #[allow(dead_code)]
+                pub(crate) fn $setter(&mut self, value: $ty) {
+                    self.$area.$field = value;
+                    self.control_area.vmcb_clean &= !VmcbClean::$clean.bits();
+                }
+            )*
+        }
+    };
+}
+
+/// Defines a getter on `VmcbRaw` for each field written only by the processor.
+macro_rules! vmcb_exit_fields {
+    ($($area:ident.$field:ident: $ty:ty;)*) => {
+        impl VmcbRaw {
+            $(
+                #[allow(dead_code)]
+                pub(crate) fn $field(&self) -> $ty {
+                    self.$area.$field
+                }
+            )*
+        }
+    };
+}
+
+vmcb_fields! {
+    control_area.intercept_cr_read, set_intercept_cr_read: u16 => I;
+    control_area.intercept_cr_write, set_intercept_cr_write: u16 => I;
+    control_area.intercept_dr_read, set_intercept_dr_read: u16 => I;
+    control_area.intercept_dr_write, set_intercept_dr_write: u16 => I;
+    control_area.intercept_exception, set_intercept_exception: u32 => I;
+    control_area.intercept_misc1, set_intercept_misc1: u32 => I;
+    control_area.intercept_misc2, set_intercept_misc2: u32 => I;
+    control_area.intercept_misc3, set_intercept_misc3: u32 => I;
+    control_area.pause_filter_threshold, set_pause_filter_threshold: u16 => I;
+    control_area.pause_filter_count, set_pause_filter_count: u16 => I;
+    control_area.iopm_base_pa, set_iopm_base_pa: u64 => IOPM;
+    control_area.msrpm_base_pa, set_msrpm_base_pa: u64 => IOPM;
+    control_area.tsc_offset, set_tsc_offset: u64 => I;
+    control_area.guest_asid, set_guest_asid: u32 => ASID;
+    control_area.tlb_control, set_tlb_control: u8 => NONE;
+    control_area.vintr, set_vintr: u64 => TPR;
+    control_area.interrupt_shadow, set_interrupt_shadow: u64 => NONE;
+    control_area.np_enable, set_np_enable: u64 => NP;
+    control_area.avic_apic_bar, set_avic_apic_bar: u64 => AVIC;
+    control_area.ghcb_pa, set_ghcb_pa: u64 => NONE;
+    control_area.event_inj, set_event_inj: u64 => NONE;
+    control_area.ncr3, set_ncr3: u64 => NP;
+    control_area.lbr_virtualization_enable, set_lbr_virtualization_enable: u64 => LBR;
+    control_area.avic_apic_backing_page_pointer, set_avic_apic_backing_page_pointer: u64 => AVIC;
+    control_area.avic_logical_table_pointer, set_avic_logical_table_pointer: u64 => AVIC;
+    control_area.avic_physical_table_pointer, set_avic_physical_table_pointer: u64 => AVIC;
+    control_area.vmsa_pointer, set_vmsa_pointer: u64 => NONE;
+    control_area.vmgexit_rax, set_vmgexit_rax: u64 => NONE;
+    control_area.vmgexit_cpl, set_vmgexit_cpl: u8 => NONE;
+    control_area.bus_lock_threshold_counter, set_bus_lock_threshold_counter: u16 => NONE;
+    control_area.allowed_sev_features, set_allowed_sev_features: u64 => NONE;
+    control_area.guest_sev_features, set_guest_sev_features: u64 => NONE;
+
+    state_save_area.es_selector, set_es_selector: u16 => SEG;
+    state_save_area.es_attrib, set_es_attrib: u16 => SEG;
+    state_save_area.es_limit, set_es_limit: u32 => SEG;
+    state_save_area.es_base, set_es_base: u64 => SEG;
+    state_save_area.cs_selector, set_cs_selector: u16 => SEG;
+    state_save_area.cs_attrib, set_cs_attrib: u16 => SEG;
+    state_save_area.cs_limit, set_cs_limit: u32 => SEG;
+    state_save_area.cs_base, set_cs_base: u64 => SEG;
+    state_save_area.ss_selector, set_ss_selector: u16 => SEG;
+    state_save_area.ss_attrib, set_ss_attrib: u16 => SEG;
+    state_save_area.ss_limit, set_ss_limit: u32 => SEG;
+    state_save_area.ss_base, set_ss_base: u64 => SEG;
+    state_save_area.ds_selector, set_ds_selector: u16 => SEG;
+    state_save_area.ds_attrib, set_ds_attrib: u16 => SEG;
+    state_save_area.ds_limit, set_ds_limit: u32 => SEG;
+    state_save_area.ds_base, set_ds_base: u64 => SEG;
+    // FS, GS, LDTR and TR are loaded by VMLOAD, not by VMRUN.
+    state_save_area.fs_selector, set_fs_selector: u16 => NONE;
+    state_save_area.fs_attrib, set_fs_attrib: u16 => NONE;
+    state_save_area.fs_limit, set_fs_limit: u32 => NONE;
+    state_save_area.fs_base, set_fs_base: u64 => NONE;
+    state_save_area.gs_selector, set_gs_selector: u16 => NONE;
+    state_save_area.gs_attrib, set_gs_attrib: u16 => NONE;
+    state_save_area.gs_limit, set_gs_limit: u32 => NONE;
+    state_save_area.gs_base, set_gs_base: u64 => NONE;
+    state_save_area.gdtr_limit, set_gdtr_limit: u32 => DT;
+    state_save_area.gdtr_base, set_gdtr_base: u64 => DT;
+    state_save_area.ldtr_selector, set_ldtr_selector: u16 => NONE;
+    state_save_area.ldtr_attrib, set_ldtr_attrib: u16 => NONE;
+    state_save_area.ldtr_limit, set_ldtr_limit: u32 => NONE;
+    state_save_area.ldtr_base, set_ldtr_base: u64 => NONE;
+    state_save_area.idtr_limit, set_idtr_limit: u32 => DT;
+    state_save_area.idtr_base, set_idtr_base: u64 => DT;
+    state_save_area.tr_selector, set_tr_selector: u16 => NONE;
+    state_save_area.tr_attrib, set_tr_attrib: u16 => NONE;
+    state_save_area.tr_limit, set_tr_limit: u32 => NONE;
+    state_save_area.tr_base, set_tr_base: u64 => NONE;
+    state_save_area.vmpl, set_vmpl: u8 => NONE;
+    state_save_area.cpl, set_cpl: u8 => SEG;
+    state_save_area.efer, set_efer: u64 => CRX;
+    state_save_area.xss, set_xss: u64 => NONE;
+    state_save_area.cr4, set_cr4: u64 => CRX;
+    state_save_area.cr3, set_cr3: u64 => CRX;
+    state_save_area.cr0, set_cr0: u64 => CRX;
+    state_save_area.dr7, set_dr7: u64 => DRX;
+    state_save_area.dr6, set_dr6: u64 => DRX;
+    state_save_area.rflags, set_rflags: u64 => NONE;
+    state_save_area.rip, set_rip: u64 => NONE;
+    state_save_area.rsp, set_rsp: u64 => NONE;
+    state_save_area.s_cet, set_s_cet: u64 => CET;
+    state_save_area.ssp, set_ssp: u64 => CET;
+    state_save_area.isst_addr, set_isst_addr: u64 => CET;
+    state_save_area.rax, set_rax: u64 => NONE;
+    state_save_area.star, set_star: u64 => NONE;
+    state_save_area.lstar, set_lstar: u64 => NONE;
+    state_save_area.cstar, set_cstar: u64 => NONE;
+    state_save_area.sf_mask, set_sf_mask: u64 => NONE;
+    state_save_area.kernel_gs_base, set_kernel_gs_base: u64 => NONE;
+    state_save_area.sysenter_cs, set_sysenter_cs: u64 => NONE;
+    state_save_area.sysenter_esp, set_sysenter_esp: u64 => NONE;
+    state_save_area.sysenter_eip, set_sysenter_eip: u64 => NONE;
+    state_save_area.cr2, set_cr2: u64 => CR2;
+    state_save_area.gpat, set_gpat: u64 => NP;
+    state_save_area.dbg_ctl, set_dbg_ctl: u64 => LBR;
+    state_save_area.br_from, set_br_from: u64 => LBR;
+    state_save_area.br_to, set_br_to: u64 => LBR;
+    state_save_area.last_excep_from, set_last_excep_from: u64 => LBR;
+    state_save_area.last_excep_to, set_last_excep_to: u64 => LBR;
+    state_save_area.spec_ctl, set_spec_ctl: u64 => NONE;
+    state_save_area.pkru, set_pkru: u32 => NONE;
+    state_save_area.tsc_aux, set_tsc_aux: u32 => NONE;
+    state_save_area.tsc_scale, set_tsc_scale: u64 => NONE;
+    state_save_area.lbr_stack_from_to, set_lbr_stack_from_to: [u64; 32] => LBR;
+    state_save_area.lbr_select, set_lbr_select: u64 => LBR;
+    state_save_area.ibs_fetch_ctl, set_ibs_fetch_ctl: u64 => NONE;
+    state_save_area.ibs_fetch_linear_addr, set_ibs_fetch_linear_addr: u64 => NONE;
+    state_save_area.ibs_op_ctl, set_ibs_op_ctl: u64 => NONE;
+    state_save_area.ibs_op_rip, set_ibs_op_rip: u64 => NONE;
+    state_save_area.ibs_op_data, set_ibs_op_data: u64 => NONE;
+    state_save_area.ibs_op_data2, set_ibs_op_data2: u64 => NONE;
+    state_save_area.ibs_op_data3, set_ibs_op_data3: u64 => NONE;
+    state_save_area.ibs_dc_linear_addr, set_ibs_dc_linear_addr: u64 => NONE;
+    state_save_area.bp_ibstgt_rip, set_bp_ibstgt_rip: u64 => NONE;
+    state_save_area.ic_ibs_extd_ctl, set_ic_ibs_extd_ctl: u64 => NONE;
+}
+
+vmcb_exit_fields! {
+    control_area.exit_code: u64;
+    control_area.exit_info1: u64;
+    control_area.exit_info2: u64;
+    control_area.exit_int_info: u64;
+    control_area.nrip: u64;
+}
+
+impl VmcbRaw {
+    /// Returns the guest instruction bytes fetched on #VMEXIT(NPF) or #PF with
+    /// decode assists.
+    /// See: 15.33.4 Nested and intercepted #PF
+    pub(crate) fn guest_instruction_bytes(&self) -> &[u8] {
+        let count = usize::from(self.control_area.num_of_bytes_fetched)
+            .min(self.control_area.guest_instruction_bytes.len());
+        &self.control_area.guest_instruction_bytes[..count]
+    }
+
+    pub(crate) fn guest_state(&self) -> GuestState {
+        self.state_save_area.guest_state()
+    }
+
+    /// Writes `state` into the state save area and clears the clean bits of
+    /// every group it covers.
+    pub(crate) fn set_guest_state(&mut self, state: &GuestState) {
+        const DIRTY: u32 = VmcbClean::SEG.bits()
+            | VmcbClean::DT.bits()
+            | VmcbClean::CRX.bits()
+            | VmcbClean::DRX.bits()
+            | VmcbClean::CR2.bits()
+            | VmcbClean::NP.bits()
+            | VmcbClean::LBR.bits()
+            | VmcbClean::CET.bits();
+        self.state_save_area.set_guest_state(state);
+        self.control_area.vmcb_clean &= !DIRTY;
+    }
+
+    pub(crate) fn vmcb_clean(&self) -> VmcbClean {
+        VmcbClean(self.control_area.vmcb_clean)
+    }
+
+    /// Tells the processor that it may use its cached copy of every field on
+    /// the next VMRUN. Setters clear the bits of the fields they modify.
+    pub(crate) fn mark_all_clean(&mut self) {
+        self.control_area.vmcb_clean = VmcbClean::ALL.bits();
+    }
+
+    /// Makes the processor reload every field from memory on the next VMRUN.
+    pub(crate) fn mark_all_dirty(&mut self) {
+        self.control_area.vmcb_clean = VmcbClean::NONE.bits();
+    }
 }
 
 
@@ -178,15 +581,20 @@ pub struct SegmentDescriptorRaw {
 }
 
 impl SegmentDescriptorRaw {
-    // "In 64-bit mode, the TSS descriptor is expanded to 16 bytes (...)."
+    // "In 64-bit mode, the TSS descriptor is expanded to 16 bytes (...)." So
+    // are the LDT descriptor and the gate descriptors.
     // See: 8.2.3 TSS Descriptor in 64-bit mode
+    // See: Table 3-2. System-Segment and Gate-Descriptor Types
     pub(crate) fn is_16byte(&self) -> bool {
-        let high32 = self.raw.get_bits(32..);
-        let system = high32.get_bit(12); // descriptor type
-        let type_ = high32.get_bits(8..=11) as u8;
-        !system
-            && (type_ == SystemDescriptorTypes64::TssAvailable as u8
-            || type_ == SystemDescriptorTypes64::TssBusy as u8)
+        const TYPES: [SystemDescriptorTypes64; 6] = [
+            SystemDescriptorTypes64::LDT,
+            SystemDescriptorTypes64::TssAvailable,
+            SystemDescriptorTypes64::TssBusy,
+            SystemDescriptorTypes64::CallGate,
+            SystemDescriptorTypes64::InterruptGate,
+            SystemDescriptorTypes64::TrapGate,
+        ];
+        self.is_system() && TYPES.iter().any(|&type_| self.descriptor_type() == type_ as u8)
     }
 
     pub(crate) fn base(&self) -> u32 {
@@ -198,6 +606,49 @@ impl SegmentDescriptorRaw {
         let base_low = low32.get_bits(16..=31);
         u32::try_from(base_high | base_middle | base_low).unwrap()
     }
+
+    /// Returns the 20-bit limit field, before the granularity is applied.
+    pub(crate) fn limit(&self) -> u32 {
+        (self.raw.get_bits(0..16) | self.raw.get_bits(48..52) << 16) as u32
+    }
+
+    pub(crate) fn descriptor_type(&self) -> u8 {
+        self.raw.get_bits(40..44) as u8
+    }
+
+    /// Whether the S flag is clear.
+    pub(crate) fn is_system(&self) -> bool {
+        !self.raw.get_bit(44)
+    }
+
+    pub(crate) fn dpl(&self) -> u8 {
+        self.raw.get_bits(45..47) as u8
+    }
+
+    pub(crate) fn is_present(&self) -> bool {
+        self.raw.get_bit(47)
+    }
+
+    pub(crate) fn avl(&self) -> bool {
+        self.raw.get_bit(52)
+    }
+
+    pub(crate) fn is_long(&self) -> bool {
+        self.raw.get_bit(53)
+    }
+
+    pub(crate) fn db(&self) -> bool {
+        self.raw.get_bit(54)
+    }
+
+    pub(crate) fn is_page_granular(&self) -> bool {
+        self.raw.get_bit(55)
+    }
+
+    /// Returns the type, S, DPL, P, AVL, L, D/B and G fields.
+    pub(crate) fn attributes(&self) -> u16 {
+        (self.raw.get_bits(40..48) | self.raw.get_bits(52..56) << 8) as u16
+    }
 }
 
 impl From<u64> for SegmentDescriptorRaw {
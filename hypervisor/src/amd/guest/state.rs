@@ -0,0 +1,283 @@
+//! This module implements a snapshot of the guest state the VMCB holds.
+//!
+//! [`GuestState`] covers every field VMRUN and VMLOAD load into the processor
+//! for a guest that is not encrypted, so it can be captured, inspected and
+//! restored without VMSAVE. The fields only used with SEV-ES, IBS or the LBR
+//! stack are not part of it.
+//! See: Appendix B Layout of VMCB
+
+use core::arch::asm;
+
+use x86::controlregs::{cr0, cr2, cr3, cr4};
+use x86::dtables::ldtr;
+use x86::msr::{self, rdmsr};
+use x86::segmentation::{cs, ds, es, fs, gs, ss, SegmentSelector};
+use x86::task::tr;
+
+use crate::amd::guest::area::SegmentDescriptor;
+use crate::amd::guest::support::{sgdt, sidt};
+use crate::amd::guest::support::speculation::{self, SpeculationSupport};
+
+const MSR_LAST_BRANCH_FROM_IP: u32 = 0x1db;
+const MSR_LAST_BRANCH_TO_IP: u32 = 0x1dc;
+const MSR_LAST_EXCEPTION_FROM_IP: u32 = 0x1dd;
+const MSR_LAST_EXCEPTION_TO_IP: u32 = 0x1de;
+
+/// A segment register in the VMCB format.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SegmentState {
+    pub selector: u16,
+    pub attrib: u16,
+    pub limit: u32,
+    pub base: u64,
+}
+
+/// GDTR or IDTR in the VMCB format.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DescriptorTableState {
+    pub base: u64,
+    pub limit: u32,
+}
+
+/// The guest state held in the VMCB state save area.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GuestState {
+    pub es: SegmentState,
+    pub cs: SegmentState,
+    pub ss: SegmentState,
+    pub ds: SegmentState,
+    pub fs: SegmentState,
+    pub gs: SegmentState,
+    pub gdtr: DescriptorTableState,
+    pub ldtr: SegmentState,
+    pub idtr: DescriptorTableState,
+    pub tr: SegmentState,
+    pub cpl: u8,
+    pub efer: u64,
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub dr6: u64,
+    pub dr7: u64,
+    pub rflags: u64,
+    pub rip: u64,
+    pub rsp: u64,
+    pub rax: u64,
+    pub star: u64,
+    pub lstar: u64,
+    pub cstar: u64,
+    pub sf_mask: u64,
+    pub kernel_gs_base: u64,
+    pub sysenter_cs: u64,
+    pub sysenter_esp: u64,
+    pub sysenter_eip: u64,
+    pub gpat: u64,
+    pub dbg_ctl: u64,
+    pub br_from: u64,
+    pub br_to: u64,
+    pub last_excep_from: u64,
+    pub last_excep_to: u64,
+    pub s_cet: u64,
+    pub ssp: u64,
+    pub isst_addr: u64,
+    pub spec_ctrl: u64,
+}
+
+impl GuestState {
+    /// Captures the state of the current processor. RIP, RSP, RFLAGS and RAX
+    /// are left zero, as they are taken from the caller's registers. S_CET,
+    /// SSP and ISST_ADDR are left zero too, since the shadow stack pointer at
+    /// the point the registers were captured is not known, so the guest starts
+    /// without supervisor shadow stacks.
+    pub fn capture_current() -> Self {
+        let gdtr = sgdt();
+        let idtr = sidt();
+        let ldtr = unsafe { ldtr() };
+        // The null selector and selectors of unusable descriptors leave the
+        // segment unusable. FS and GS bases are in MSRs instead.
+        let segment = |selector: SegmentSelector, base: Option<u64>| {
+            match SegmentDescriptor::try_from_tables(&gdtr, ldtr, selector) {
+                Ok(descriptor) => SegmentState {
+                    selector: selector.bits(),
+                    attrib: descriptor.attrib(),
+                    limit: descriptor.limit(),
+                    base: base.unwrap_or_else(|| descriptor.base()),
+                },
+                Err(_) => SegmentState { selector: selector.bits(), base: base.unwrap_or(0), ..Default::default() },
+            }
+        };
+
+        unsafe {
+            Self {
+                es: segment(es(), None),
+                cs: segment(cs(), None),
+                ss: segment(ss(), None),
+                ds: segment(ds(), None),
+                fs: segment(fs(), Some(rdmsr(msr::IA32_FS_BASE))),
+                gs: segment(gs(), Some(rdmsr(msr::IA32_GS_BASE))),
+                gdtr: DescriptorTableState { base: gdtr.base as u64, limit: u32::from(gdtr.limit) },
+                ldtr: segment(ldtr, None),
+                idtr: DescriptorTableState { base: idtr.base as u64, limit: u32::from(idtr.limit) },
+                tr: segment(tr(), None),
+                cpl: (cs().bits() & 0b11) as u8,
+                efer: rdmsr(msr::IA32_EFER),
+                cr0: cr0().bits() as u64,
+                cr2: cr2() as u64,
+                cr3: cr3(),
+                cr4: cr4().bits() as u64,
+                dr6: dr6(),
+                dr7: x86::debugregs::dr7().0 as u64,
+                rflags: 0,
+                rip: 0,
+                rsp: 0,
+                rax: 0,
+                star: rdmsr(msr::IA32_STAR),
+                lstar: rdmsr(msr::IA32_LSTAR),
+                cstar: rdmsr(msr::IA32_CSTAR),
+                sf_mask: rdmsr(msr::IA32_FMASK),
+                kernel_gs_base: rdmsr(msr::IA32_KERNEL_GSBASE),
+                sysenter_cs: rdmsr(msr::IA32_SYSENTER_CS),
+                sysenter_esp: rdmsr(msr::IA32_SYSENTER_ESP),
+                sysenter_eip: rdmsr(msr::IA32_SYSENTER_EIP),
+                gpat: rdmsr(msr::IA32_PAT),
+                dbg_ctl: rdmsr(msr::IA32_DEBUGCTL),
+                br_from: rdmsr(MSR_LAST_BRANCH_FROM_IP),
+                br_to: rdmsr(MSR_LAST_BRANCH_TO_IP),
+                last_excep_from: rdmsr(MSR_LAST_EXCEPTION_FROM_IP),
+                last_excep_to: rdmsr(MSR_LAST_EXCEPTION_TO_IP),
+                s_cet: 0,
+                ssp: 0,
+                isst_addr: 0,
+                spec_ctrl: if SpeculationSupport::current().spec_ctrl { speculation::spec_ctrl() } else { 0 },
+            }
+        }
+    }
+}
+
+fn dr6() -> u64 {
+    let value: u64;
+    unsafe { asm!("mov {}, dr6", out(reg) value, options(nomem, nostack, preserves_flags)) };
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amd::guest::StateSaveArea;
+
+    /// Returns a state whose fields all hold different values.
+    fn distinct_state() -> GuestState {
+        let mut next = 0u64;
+        let mut value = || {
+            next += 1;
+            next * 0x0101_0101
+        };
+        let mut segment = || SegmentState {
+            selector: value() as u16,
+            attrib: value() as u16,
+            limit: value() as u32,
+            base: value(),
+        };
+        let (es, cs, ss, ds, fs, gs, ldtr, tr) =
+            (segment(), segment(), segment(), segment(), segment(), segment(), segment(), segment());
+        GuestState {
+            es,
+            cs,
+            ss,
+            ds,
+            fs,
+            gs,
+            gdtr: DescriptorTableState { base: value(), limit: value() as u32 },
+            ldtr,
+            idtr: DescriptorTableState { base: value(), limit: value() as u32 },
+            tr,
+            cpl: value() as u8,
+            efer: value(),
+            cr0: value(),
+            cr2: value(),
+            cr3: value(),
+            cr4: value(),
+            dr6: value(),
+            dr7: value(),
+            rflags: value(),
+            rip: value(),
+            rsp: value(),
+            rax: value(),
+            star: value(),
+            lstar: value(),
+            cstar: value(),
+            sf_mask: value(),
+            kernel_gs_base: value(),
+            sysenter_cs: value(),
+            sysenter_esp: value(),
+            sysenter_eip: value(),
+            gpat: value(),
+            dbg_ctl: value(),
+            br_from: value(),
+            br_to: value(),
+            last_excep_from: value(),
+            last_excep_to: value(),
+            s_cet: value(),
+            ssp: value(),
+            isst_addr: value(),
+            spec_ctrl: value(),
+        }
+    }
+
+    fn read_u64(area: &StateSaveArea, offset: usize) -> u64 {
+        assert!(offset + 8 <= core::mem::size_of::<StateSaveArea>());
+        unsafe { (area as *const StateSaveArea).cast::<u8>().add(offset).cast::<u64>().read_unaligned() }
+    }
+
+    fn write_u64(area: &mut StateSaveArea, offset: usize, value: u64) {
+        assert!(offset + 8 <= core::mem::size_of::<StateSaveArea>());
+        unsafe { (area as *mut StateSaveArea).cast::<u8>().add(offset).cast::<u64>().write_unaligned(value) }
+    }
+
+    #[test]
+    fn round_trips_through_state_save_area() {
+        let state = distinct_state();
+        let mut area = StateSaveArea::default();
+        area.set_guest_state(&state);
+        assert_eq!(area.guest_state(), state);
+    }
+
+    #[test]
+    fn round_trips_default_state() {
+        let mut area = StateSaveArea::default();
+        area.set_guest_state(&distinct_state());
+        area.set_guest_state(&GuestState::default());
+        assert_eq!(area.guest_state(), GuestState::default());
+    }
+
+    #[test]
+    fn writes_fields_at_their_vmcb_offsets() {
+        let state = distinct_state();
+        let mut area = StateSaveArea::default();
+        area.set_guest_state(&state);
+
+        // Offsets are relative to the state save area at VMCB offset 0x400.
+        assert_eq!(read_u64(&area, 0x048), state.fs.base);
+        assert_eq!(read_u64(&area, 0x098), state.tr.base);
+        assert_eq!(read_u64(&area, 0x0d0), state.efer);
+        assert_eq!(read_u64(&area, 0x158), state.cr0);
+        assert_eq!(read_u64(&area, 0x1f8), state.rax);
+        assert_eq!(read_u64(&area, 0x208), state.lstar);
+        assert_eq!(read_u64(&area, 0x220), state.kernel_gs_base);
+        assert_eq!(read_u64(&area, 0x268), state.gpat);
+        assert_eq!(read_u64(&area, 0x2e0), state.spec_ctrl);
+    }
+
+    #[test]
+    fn leaves_other_fields_alone() {
+        // XSS and the guest TSC ratio are not part of the guest state.
+        let mut area = StateSaveArea::default();
+        write_u64(&mut area, 0x140, 0x1800);
+        write_u64(&mut area, 0x2f0, 0x1_0000_0000);
+        area.set_guest_state(&distinct_state());
+        assert_eq!(read_u64(&area, 0x140), 0x1800);
+        assert_eq!(read_u64(&area, 0x2f0), 0x1_0000_0000);
+    }
+}
@@ -1,13 +1,24 @@
+//! This module implements the mapping between APIC IDs and processor IDs.
+//!
+//! The map is read on #VMEXIT and grows when a processor is added at runtime,
+//! so it is a table of atomics indexed by the APIC ID rather than a locked map.
+//! A writer preempted while holding a lock could otherwise stall the host on
+//! the processors reading it.
+
 use core::sync::atomic::{AtomicUsize, Ordering};
 
-use alloc::collections::BTreeMap;
-use spin::RwLock;
 use kernelutils::nt::platform_ops;
 
+use crate::amd::processors::MAX_PROCESSORS;
 
 type ApicId = u8;
 type ProcessorId = usize;
-pub(crate) static APIC_ID_MAP: RwLock<BTreeMap<ApicId, ProcessorId>> = RwLock::new(BTreeMap::new());
+
+const UNKNOWN: ProcessorId = usize::MAX;
+
+/// The processor ID of each APIC ID, or [`UNKNOWN`].
+static PROCESSOR_IDS: [AtomicUsize; 0x100] = [const { AtomicUsize::new(UNKNOWN) }; 0x100];
+/// The number of processors in the map.
 pub(crate) static PROCESSOR_COUNT: AtomicUsize = AtomicUsize::new(0);
 
 /// Gets an APIC ID.
@@ -17,17 +28,58 @@ pub(crate) fn get() -> ApicId {
     (x86::cpuid::cpuid!(0x1).ebx >> 24) as _
 }
 
+/// Adds every active processor to the map.
 pub(crate) fn init() {
-    assert!(PROCESSOR_COUNT.load(Ordering::Relaxed) == 0);
-    platform_ops::get().run_on_all_processors(|| {
-        let mut map = APIC_ID_MAP.write();
-        assert!(map
-            .insert(get(), PROCESSOR_COUNT.fetch_add(1, Ordering::Relaxed))
-            .is_none());
-    });
+    let ops = platform_ops::get();
+    for index in 0..ops.processor_count() {
+        ops.run_on_processor(index, &mut || {
+            register_current(index as ProcessorId);
+        });
+    }
+}
+
+/// Adds the current processor to the map with `processor_id`, its system
+/// processor index. Returns `false` if it cannot be tracked, including when its
+/// APIC ID is already mapped to another processor ID.
+pub(crate) fn register_current(processor_id: ProcessorId) -> bool {
+    if processor_id >= MAX_PROCESSORS {
+        log::error!("Processor {processor_id} exceeds the supported {MAX_PROCESSORS} processors");
+        return false;
+    }
+
+    let apic_id = get();
+    match PROCESSOR_IDS[apic_id as usize].compare_exchange(
+        UNKNOWN,
+        processor_id,
+        Ordering::AcqRel,
+        Ordering::Acquire,
+    ) {
+        Ok(_) => {
+            PROCESSOR_COUNT.fetch_add(1, Ordering::Relaxed);
+        }
+        // A processor keeps its index while the system runs.
+        Err(known) if known == processor_id => {}
+        Err(known) => {
+            log::error!("APIC ID {apic_id} is processor {known}, not processor {processor_id}");
+            return false;
+        }
+    }
+    true
 }
 
 pub(crate) fn processor_id_from(apic_id: ApicId) -> Option<ProcessorId> {
-    let map = APIC_ID_MAP.read();
-    map.get(&apic_id).copied()
+    Some(PROCESSOR_IDS[apic_id as usize].load(Ordering::Acquire)).filter(|&id| id != UNKNOWN)
+}
+
+/// Returns the APIC ID of the processor, or `None` if it is not known.
+pub(crate) fn apic_id_of(processor_id: ProcessorId) -> Option<ApicId> {
+    processors().find(|&(_, id)| id == processor_id).map(|(apic_id, _)| apic_id)
+}
+
+/// Returns the APIC ID and processor ID of every processor in the map.
+pub(crate) fn processors() -> impl Iterator<Item = (ApicId, ProcessorId)> {
+    PROCESSOR_IDS.iter().enumerate().filter_map(|(apic_id, processor_id)| {
+        let processor_id = processor_id.load(Ordering::Acquire);
+        (processor_id != UNKNOWN).then_some((apic_id as ApicId, processor_id))
+    })
 }
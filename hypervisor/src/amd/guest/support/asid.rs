@@ -0,0 +1,38 @@
+//! This module implements the allocation of address space identifiers (ASIDs).
+//!
+//! ASIDs tag the TLB entries of each guest so that they do not need to be
+//! flushed on every world switch. ASID 0 is the host. The processor reports how
+//! many ASIDs it supports, and a vCPU gets the next one in that range. When the
+//! range is exhausted, the allocation restarts from 1. A vCPU flushes all
+//! entries of its ASID before its first VMRUN, so reusing an ASID is safe.
+//! See: 15.16 TLB Control
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use bit_field::BitField;
+use x86::cpuid::cpuid;
+
+static NEXT_ASID: AtomicU32 = AtomicU32::new(1);
+
+/// Returns the number of ASIDs the processor supports, including the ASID 0.
+/// See: E.4.10 Function 8000_000Ah—SVM Features
+pub fn count() -> u32 {
+    cpuid!(0x8000_000a).ebx
+}
+
+/// Returns whether TLB_CONTROL can flush the entries of a single ASID.
+/// See: E.4.10 Function 8000_000Ah—SVM Features
+pub fn is_flush_by_asid_supported() -> bool {
+    cpuid!(0x8000_000a).edx.get_bit(6)
+}
+
+/// Allocates an ASID for a vCPU.
+pub(crate) fn allocate() -> u32 {
+    let count = count().max(2);
+    let asid = NEXT_ASID
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |asid| {
+            Some(if asid + 1 >= count { 1 } else { asid + 1 })
+        })
+        .unwrap();
+    if asid >= count { 1 } else { asid }
+}
@@ -0,0 +1,37 @@
+//! This module runs the consistency checks VMRUN performs, implemented in
+//! `hv_core`, on a VMCB.
+//! See: 15.5.1 Basic Operation
+
+use alloc::vec::Vec;
+
+pub use hv_core::consistency::{CpuLimits, VmcbFields, VmcbViolation, CR4_VALID_BITS};
+
+use crate::amd::guest::VmcbRaw;
+
+/// Returns every field of `vmcb` that makes VMRUN fail with #VMEXIT(INVALID).
+/// An empty result means the VMCB passes all checks known to `hv_core`.
+pub fn check(vmcb: &VmcbRaw, limits: &CpuLimits) -> Vec<VmcbViolation> {
+    let fields = VmcbFields {
+        intercept_misc1: vmcb.intercept_misc1(),
+        intercept_misc2: vmcb.intercept_misc2(),
+        iopm_base_pa: vmcb.iopm_base_pa(),
+        msrpm_base_pa: vmcb.msrpm_base_pa(),
+        guest_asid: vmcb.guest_asid(),
+        event_inj: vmcb.event_inj(),
+        es_attrib: vmcb.es_attrib(),
+        cs_attrib: vmcb.cs_attrib(),
+        ss_attrib: vmcb.ss_attrib(),
+        ds_attrib: vmcb.ds_attrib(),
+        fs_attrib: vmcb.fs_attrib(),
+        gs_attrib: vmcb.gs_attrib(),
+        ldtr_attrib: vmcb.ldtr_attrib(),
+        tr_attrib: vmcb.tr_attrib(),
+        efer: vmcb.efer(),
+        cr4: vmcb.cr4(),
+        cr3: vmcb.cr3(),
+        cr0: vmcb.cr0(),
+        dr7: vmcb.dr7(),
+        dr6: vmcb.dr6(),
+    };
+    hv_core::consistency::check(&fields, limits)
+}
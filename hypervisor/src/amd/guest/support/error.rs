@@ -1,18 +1,18 @@
 use x86::segmentation::SegmentSelector;
 
-#[derive(thiserror_no_std::Error, Debug)]
+#[derive(thiserror_no_std::Error, Clone, Copy, Debug, PartialEq, Eq)]
 pub(crate) enum SegmentError {
     #[error("`{selector}` points to the null descriptor")]
     NullDescriptor { selector: SegmentSelector },
 
-    #[error("`{selector}` points to LDT where parsing is unimplemented")]
-    LdtAccess { selector: SegmentSelector },
+    #[error("`{selector}` points to LDT, but `{ldtr}` does not select a present LDT")]
+    NoLdt { selector: SegmentSelector, ldtr: SegmentSelector },
 
-    #[error("`{index}` points to outside GDT")]
-    OutOfGdtAccess { index: usize },
+    #[error("`{index}` points to outside the descriptor table")]
+    OutOfTableAccess { index: usize },
 
     #[error("`{index}` points to `{entry}`, which is invalid as a descriptor")]
-    InvalidGdtEntry { index: usize, entry: u64 },
+    InvalidEntry { index: usize, entry: u64 },
 }
 
 #[derive(thiserror_no_std::Error, Clone, Copy, Debug)]
@@ -0,0 +1,53 @@
+//! This module implements access to the local APIC registers of the current
+//! processor through the xAPIC MMIO page.
+
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use bit_field::BitField;
+use x86::bits64::paging::BASE_PAGE_SIZE;
+use x86::msr::rdmsr;
+use kernelutils::nt::platform_ops;
+
+/// The linear address the local APIC page is mapped at. All processors share
+/// the same APIC base, and each of them sees its own local APIC through it.
+static APIC_PAGE: AtomicPtr<u8> = AtomicPtr::new(null_mut());
+
+/// Returns the physical address of the local APIC page of the current processor.
+pub(crate) fn base() -> u64 {
+    unsafe { rdmsr(x86::msr::IA32_APIC_BASE) } & !0xfff
+}
+
+/// Returns whether the current processor is the bootstrap processor (BSP).
+/// See: 16.3.1 Local APIC Enable
+pub(crate) fn is_bsp() -> bool {
+    unsafe { rdmsr(x86::msr::IA32_APIC_BASE) }.get_bit(8)
+}
+
+/// Maps the local APIC page. Must be called before `read` and `write`.
+pub(crate) fn init() {
+    if !APIC_PAGE.load(Ordering::Relaxed).is_null() {
+        return;
+    }
+    let va = platform_ops::get().map_io_space(base(), BASE_PAGE_SIZE);
+    assert!(!va.is_null());
+    APIC_PAGE.store(va.cast(), Ordering::Relaxed);
+}
+
+fn register(offset: u64) -> *mut u32 {
+    let page = APIC_PAGE.load(Ordering::Relaxed);
+    assert!(!page.is_null() && offset < BASE_PAGE_SIZE as u64);
+    unsafe { page.add(offset as usize) }.cast()
+}
+
+/// Reads the local APIC register at `offset`.
+pub(crate) fn read(offset: u64) -> u32 {
+    // Safety: the page is mapped by `init` and `offset` is within the page.
+    unsafe { register(offset).read_volatile() }
+}
+
+/// Writes `value` to the local APIC register at `offset`.
+pub(crate) fn write(offset: u64, value: u32) {
+    // Safety: the page is mapped by `init` and `offset` is within the page.
+    unsafe { register(offset).write_volatile(value) }
+}
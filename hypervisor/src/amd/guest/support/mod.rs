@@ -1,52 +1,19 @@
 pub mod apic_id;
+pub mod asid;
+pub mod consistency;
 pub mod error;
+pub use hv_core::icr;
+pub mod local_apic;
+pub mod speculation;
+pub mod xsave;
 
 use alloc::alloc::handle_alloc_error;
 use alloc::boxed::Box;
 use core::alloc::Layout;
 use core::arch::{asm, global_asm};
-use x86::bits64::paging::BASE_PAGE_SHIFT;
 use x86::dtables::DescriptorTablePointer;
 use kernelutils::Registers;
 
-/// Returns the access rights of the given segment for SVM.
-pub fn get_segment_access_right(table_base: u64, selector: u16) -> u16 {
-    let descriptor_value = get_segment_descriptor_value(table_base, selector);
-
-    // First, get the AVL, L, D/B and G bits, while excluding the "Seg. Limit 19:16"
-    // bits. Then, get the Type, S, DPL and P bits. Finally, return those bits
-    // without the "Seg. Limit 19:16" bits.
-    // See: Figure 3-8. Segment Descriptor
-    let ar = (descriptor_value >> 40) as u16;
-    let upper_ar = (ar >> 4) & 0b1111_0000_0000;
-    let lower_ar = ar & 0b1111_1111;
-    lower_ar | upper_ar
-}
-
-/// Returns the segment descriptor casted as a 64bit integer for the given
-/// selector.
-pub fn get_segment_descriptor_value(table_base: u64, selector: u16) -> u64 {
-    let sel = x86::segmentation::SegmentSelector::from_raw(selector);
-    let descriptor_addr = table_base + u64::from(sel.index() * 8);
-    let ptr = descriptor_addr as *const u64;
-    unsafe { *ptr }
-}
-
-/// Returns the limit of the given segment.
-pub fn get_segment_limit(table_base: u64, selector: u16) -> u32 {
-    let sel = x86::segmentation::SegmentSelector::from_raw(selector);
-    if sel.index() == 0 && (sel.bits() >> 2) == 0 {
-        return 0; // unusable
-    }
-    let descriptor_value = get_segment_descriptor_value(table_base, selector);
-    let limit_low = descriptor_value & 0xffff;
-    let limit_high = (descriptor_value >> (32 + 16)) & 0xF;
-    let mut limit = limit_low | (limit_high << 16);
-    if ((descriptor_value >> (32 + 23)) & 0x01) != 0 {
-        limit = ((limit + 1) << BASE_PAGE_SHIFT) - 1;
-    }
-    limit as u32
-}
 #[allow(dead_code)]
 const SVM_DEBUG_CONTROL_0: u16 = 1 << 0;
 #[allow(dead_code)]
@@ -55,13 +22,15 @@ const SVM_DEBUG_CONTROL_6: u16 = 1 << 6;
 
 const SVM_DEBUG_CONTROL_7: u16 = 1 << 7;
 
+/// The lowest address of the system address space. The host runs with the page
+/// tables that were current when the processor was virtualized, so only the
+/// system address space is shared with the guest.
+pub(crate) const SYSTEM_ADDRESS_START: u64 = 0xffff_8000_0000_0000;
+
 #[allow(dead_code)]
 pub const SVM_NP_ENABLE_NP_ENABLE: u64 = 1 << 0;
-#[allow(dead_code)]
-pub const SECURITY_EXCEPTION: u32 = 1 << 30;
 
 
-#[allow(dead_code)]
 #[repr(u32)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum TlbControl {
@@ -70,6 +39,31 @@ pub enum TlbControl {
     FlushGuests = 0x3,
     FlushGuestsNonGlobal = 0x7,
 }
+
+impl TlbControl {
+    /// Returns the control that flushes at least what `self` and `other` flush.
+    pub fn merge(self, other: Self) -> Self {
+        let rank = |control| match control {
+            Self::DoNotFlush => 0,
+            Self::FlushGuestsNonGlobal => 1,
+            Self::FlushGuests => 2,
+            Self::FlushAll => 3,
+        };
+        if rank(other) > rank(self) { other } else { self }
+    }
+}
+
+/// A TLB flush requested for a vCPU. See [`crate::amd::VCpu::flush_tlb`].
+/// See: 15.16 TLB Control
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TlbFlush {
+    /// All entries of the ASID of the vCPU, including nested translations.
+    Guest,
+    /// The non-global entries of the ASID of the vCPU.
+    GuestNonGlobal,
+    /// The entry of one guest linear address in the ASID of the vCPU.
+    Address(u64),
+}
 pub fn zeroed_box<T>() -> Box<T> {
     let layout = Layout::new::<T>();
     let ptr = unsafe { alloc::alloc::alloc_zeroed(layout) }.cast::<T>();
@@ -81,12 +75,23 @@ pub fn zeroed_box<T>() -> Box<T> {
 
 
 extern "C" {
-    /// Runs the guest until #VMEXIT occurs.
-    pub fn run_svm_guest(registers: &mut Registers, vmcb_pa: u64, host_vmcb_pa: u64);
+    /// Runs the guest until #VMEXIT occurs. Fills the return stack buffer
+    /// after #VMEXIT if `fill_rsb` is set.
+    pub fn run_svm_guest(registers: &mut Registers, vmcb_pa: u64, host_vmcb_pa: u64, fill_rsb: bool);
 }
 
 global_asm!(include_str!("run_guest.s"));
 
+extern "C" {
+    /// Loads the general purpose, XMM and MXCSR registers from `registers`,
+    /// DS and ES from bits 47:32 and 63:48 of `selectors`, and returns to
+    /// RIP with RSP and RFLAGS from `registers`, and CS and SS from bits 15:0
+    /// and 31:16 of `selectors`. The return frame is built below the new RSP.
+    pub fn resume_unvirtualized(registers: &Registers, selectors: u64) -> !;
+}
+
+global_asm!(include_str!("resume_unvirtualized.s"));
+
 pub fn lidt(idtr: &DescriptorTablePointer<u64>) {
     unsafe { x86::dtables::lidt(idtr) };
 }
@@ -99,6 +104,48 @@ pub fn vmsave(vmcb_pa: u64) {
         )
     };
 }
+pub fn vmload(vmcb_pa: u64) {
+    unsafe {
+        asm!("vmload rax", in("rax") vmcb_pa, options(nostack, preserves_flags))
+    };
+}
+/// Sets the global interrupt flag (GIF).
+/// See: 15.17 Global Interrupt Flag, STGI and CLGI Instructions
+pub fn stgi() {
+    unsafe { asm!("stgi", options(nomem, nostack, preserves_flags)) };
+}
+pub fn cr8() -> u64 {
+    let value: u64;
+    unsafe { asm!("mov {}, cr8", out(reg) value, options(nomem, nostack, preserves_flags)) };
+    value
+}
+pub fn cr8_write(value: u64) {
+    unsafe { asm!("mov cr8, {}", in(reg) value, options(nomem, nostack, preserves_flags)) };
+}
+pub fn xsetbv(index: u32, value: u64) {
+    unsafe {
+        asm!(
+        "xsetbv",
+        in("ecx") index, in("eax") value as u32, in("edx") (value >> 32) as u32,
+        options(nomem, nostack, preserves_flags),
+        )
+    };
+}
+pub fn xgetbv(index: u32) -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        asm!(
+        "xgetbv",
+        in("ecx") index, out("eax") low, out("edx") high,
+        options(nomem, nostack, preserves_flags),
+        )
+    };
+    u64::from(high) << 32 | u64::from(low)
+}
+/// Invalidates the TLB entry of the guest linear address `address` in `asid`.
+pub fn invlpga(address: u64, asid: u32) {
+    unsafe { asm!("invlpga rax, ecx", in("rax") address, in("ecx") asid, options(nostack, preserves_flags)) };
+}
 pub fn sidt() -> DescriptorTablePointer<u64> {
     let mut idtr = DescriptorTablePointer::<u64>::default();
     unsafe { x86::dtables::sidt(&mut idtr) };
@@ -0,0 +1,83 @@
+# The module implements the `resume_unvirtualized` function.
+
+# Resumes the guest outside of SVM after devirtualization.
+#
+# This function works as follows:
+# 1. builds an IRETQ frame with guest RIP, CS, RFLAGS, RSP and SS right below
+#    the guest RSP. The guest stack can be written below RSP at any time by
+#    interrupts, so nothing is stored there.
+# 2. switches to the frame, and loads guest DS and ES.
+# 3. loads guest XMM, MXCSR and general purpose register values from
+#    `registers`.
+# 4. executes IRETQ, which loads CS, SS and RSP consistently and restores
+#    RFLAGS, including IF, at the same time as it jumps to RIP.
+#
+# The caller must have loaded the guest GDT, as the selectors refer to it.
+#
+# extern "C" fn resume_unvirtualized(registers: &Registers, selectors: u64) -> !;
+.align 16
+.global resume_unvirtualized
+resume_unvirtualized:
+    xchg    bx, bx
+
+    # Build the IRETQ frame below the guest RSP.
+    mov     rax, [rcx + registers_rsp]
+    sub     rax, 0x28
+    mov     r8, [rcx + registers_rip]
+    mov     [rax], r8               # RIP
+    movzx   r8, dx
+    mov     [rax + 0x8], r8         # CS
+    mov     r8, [rcx + registers_rflags]
+    mov     [rax + 0x10], r8        # RFLAGS
+    mov     r8, [rcx + registers_rsp]
+    mov     [rax + 0x18], r8        # RSP
+    mov     r8, rdx
+    shr     r8, 16
+    movzx   r8, r8w
+    mov     [rax + 0x20], r8        # SS
+    mov     rsp, rax
+
+    # Load DS and ES. FS and GS are left as VMLOAD set them, as loading their
+    # selectors would clear the upper halves of their bases.
+    mov     r8, rdx
+    shr     r8, 32
+    mov     ds, r8w
+    shr     r8, 16
+    mov     es, r8w
+
+    # Load guest XMM, MXCSR and general purpose registers. RCX goes last.
+    ldmxcsr dword ptr [rcx + registers_mxcsr]
+    movaps  xmm0, [rcx + registers_xmm0]
+    movaps  xmm1, [rcx + registers_xmm1]
+    movaps  xmm2, [rcx + registers_xmm2]
+    movaps  xmm3, [rcx + registers_xmm3]
+    movaps  xmm4, [rcx + registers_xmm4]
+    movaps  xmm5, [rcx + registers_xmm5]
+    movaps  xmm6, [rcx + registers_xmm6]
+    movaps  xmm7, [rcx + registers_xmm7]
+    movaps  xmm8, [rcx + registers_xmm8]
+    movaps  xmm9, [rcx + registers_xmm9]
+    movaps  xmm10, [rcx + registers_xmm10]
+    movaps  xmm11, [rcx + registers_xmm11]
+    movaps  xmm12, [rcx + registers_xmm12]
+    movaps  xmm13, [rcx + registers_xmm13]
+    movaps  xmm14, [rcx + registers_xmm14]
+    movaps  xmm15, [rcx + registers_xmm15]
+
+    mov     rax, [rcx + registers_rax]
+    mov     rbx, [rcx + registers_rbx]
+    mov     rdx, [rcx + registers_rdx]
+    mov     rdi, [rcx + registers_rdi]
+    mov     rsi, [rcx + registers_rsi]
+    mov     rbp, [rcx + registers_rbp]
+    mov      r8, [rcx + registers_r8]
+    mov      r9, [rcx + registers_r9]
+    mov     r10, [rcx + registers_r10]
+    mov     r11, [rcx + registers_r11]
+    mov     r12, [rcx + registers_r12]
+    mov     r13, [rcx + registers_r13]
+    mov     r14, [rcx + registers_r14]
+    mov     r15, [rcx + registers_r15]
+    mov     rcx, [rcx + registers_rcx]
+
+    iretq
@@ -3,8 +3,8 @@
 # Runs the guest until #VMEXIT occurs.
 #
 # This function works as follows:
-# 1. saves host general purpose and XMM register values to stack.
-# 2. loads guest general purpose and XMM register values from `GuestRegisters`.
+# 1. saves host general purpose, XMM and MXCSR register values to stack.
+# 2. loads guest general purpose, XMM and MXCSR register values from `GuestRegisters`.
 # 3. executes the VMRUN instruction that
 #     1. saves host register values to the host state area, as specified by
 #        the VM_HSAVE_PA MSR.
@@ -17,15 +17,18 @@
 #        are always disabled.
 #     3. updates VMCB's EXITCODE field with the reason of #VMEXIT.
 #     4. starts running code in host-mode.
-# 5. saves guest general purpose and XMM register values to `GuestRegisters`.
-# 6. loads host general purpose and XMM register values from stack.
+# 5. saves guest general purpose, XMM and MXCSR register values to `GuestRegisters`.
+# 6. fills the return stack buffer if requested.
+# 7. loads host general purpose, XMM and MXCSR register values from stack.
 #
 # Saving XMM registers are only required for the Windows version because the UEFI
 # version is compiled with "-mmx,-sse,+soft-float", preventing the compiler from
 # using those registers. For the Windows version, XMM0-5 needs care as they are
-# volatile.
+# volatile, and XMM6-15 as the caller expects them to be preserved. These are
+# all the extended registers code compiled with SSE modifies. The rest of the
+# extended state, if any, is switched by the caller with XSAVE and XRSTOR.
 #
-# extern "C" fn run_svm_guest(registers: &mut Registers, vmcb_pa: u64, host_vmcb_pa: u64);
+# extern "C" fn run_svm_guest(registers: &mut Registers, vmcb_pa: u64, host_vmcb_pa: u64, fill_rsb: bool);
 .align 16
 .global run_svm_guest
 run_svm_guest:
@@ -48,28 +51,51 @@ run_svm_guest:
     push    r14
     push    r15
 
-    # Save current (host) XMM registers onto stack too.
-    sub     rsp, 0x60
+    # Save current (host) XMM and MXCSR registers onto stack too.
+    sub     rsp, 0x110
     movaps  xmmword ptr [rsp], xmm0
     movaps  xmmword ptr [rsp + 0x10], xmm1
     movaps  xmmword ptr [rsp + 0x20], xmm2
     movaps  xmmword ptr [rsp + 0x30], xmm3
     movaps  xmmword ptr [rsp + 0x40], xmm4
     movaps  xmmword ptr [rsp + 0x50], xmm5
+    movaps  xmmword ptr [rsp + 0x60], xmm6
+    movaps  xmmword ptr [rsp + 0x70], xmm7
+    movaps  xmmword ptr [rsp + 0x80], xmm8
+    movaps  xmmword ptr [rsp + 0x90], xmm9
+    movaps  xmmword ptr [rsp + 0xa0], xmm10
+    movaps  xmmword ptr [rsp + 0xb0], xmm11
+    movaps  xmmword ptr [rsp + 0xc0], xmm12
+    movaps  xmmword ptr [rsp + 0xd0], xmm13
+    movaps  xmmword ptr [rsp + 0xe0], xmm14
+    movaps  xmmword ptr [rsp + 0xf0], xmm15
+    stmxcsr dword ptr [rsp + 0x100]
 
-    # Copy `registers` and `vmcb_pa` for use. Then, save
-    # `registers` at the top of stack so that after #VMEXIT, we can find it.
+    # Copy `registers` and `vmcb_pa` for use. Then, save `fill_rsb` and
+    # `registers` at the top of stack so that after #VMEXIT, we can find them.
     mov     r15, rcx    # r15 <= `registers`
     mov     rax, rdx    # rax <= `vmcb_pa`
+    push    r9          # [rsp] <= `fill_rsb` (#2)
     push    rcx         # [rsp] <= `registers` (#1)
 
-    # Restore guest general purpose and XMM registers from `registers` and try VMRESUME.
+    # Restore guest general purpose, XMM and MXCSR registers from `registers` and try VMRESUME.
+    ldmxcsr dword ptr [r15 + registers_mxcsr]
     movaps  xmm0, [r15 + registers_xmm0]
     movaps  xmm1, [r15 + registers_xmm1]
     movaps  xmm2, [r15 + registers_xmm2]
     movaps  xmm3, [r15 + registers_xmm3]
     movaps  xmm4, [r15 + registers_xmm4]
     movaps  xmm5, [r15 + registers_xmm5]
+    movaps  xmm6, [r15 + registers_xmm6]
+    movaps  xmm7, [r15 + registers_xmm7]
+    movaps  xmm8, [r15 + registers_xmm8]
+    movaps  xmm9, [r15 + registers_xmm9]
+    movaps  xmm10, [r15 + registers_xmm10]
+    movaps  xmm11, [r15 + registers_xmm11]
+    movaps  xmm12, [r15 + registers_xmm12]
+    movaps  xmm13, [r15 + registers_xmm13]
+    movaps  xmm14, [r15 + registers_xmm14]
+    movaps  xmm15, [r15 + registers_xmm15]
 
     mov     rbx, [r15 + registers_rbx]
     mov     rcx, [r15 + registers_rcx]
@@ -92,7 +118,7 @@ run_svm_guest:
     vmrun   rax
     vmsave  rax
 
-    # #VMEXIT occurred. Save current (guest) general purpose and XMM registers.
+    # #VMEXIT occurred. Save current (guest) general purpose, XMM and MXCSR registers.
     xchg    bx, bx
     xchg    r15, [rsp]  # r15 <= `registers` and [rsp] <= guest r15
     mov     [r15 + registers_rbx], rbx
@@ -116,17 +142,61 @@ run_svm_guest:
     movaps  [r15 + registers_xmm3], xmm3
     movaps  [r15 + registers_xmm4], xmm4
     movaps  [r15 + registers_xmm5], xmm5
+    movaps  [r15 + registers_xmm6], xmm6
+    movaps  [r15 + registers_xmm7], xmm7
+    movaps  [r15 + registers_xmm8], xmm8
+    movaps  [r15 + registers_xmm9], xmm9
+    movaps  [r15 + registers_xmm10], xmm10
+    movaps  [r15 + registers_xmm11], xmm11
+    movaps  [r15 + registers_xmm12], xmm12
+    movaps  [r15 + registers_xmm13], xmm13
+    movaps  [r15 + registers_xmm14], xmm14
+    movaps  [r15 + registers_xmm15], xmm15
+    stmxcsr dword ptr [r15 + registers_mxcsr]
 
-    # Discard the stack value pushed at #1.
+    # Overwrite the return stack buffer with 32 entries pointing to speculation
+    # traps if `fill_rsb` is set, before the first return after #VMEXIT. Return
+    # predictions left by the guest are never used then. Each iteration makes
+    # two calls and discards their return addresses.
+    test    byte ptr [rsp + 8], 1
+    jz      5f
+    mov     ecx, 16
+2:
+    call    3f
+    int3
+3:
+    call    4f
+    int3
+4:
+    add     rsp, 0x10
+    dec     ecx
+    jnz     2b
+    # Do not speculatively run past the loop.
+    lfence
+5:
+
+    # Discard the stack values pushed at #1 and #2.
+    pop     rax
     pop     rax
 
+    ldmxcsr dword ptr [rsp + 0x100]
+    movaps  xmm15, xmmword ptr [rsp + 0xf0]
+    movaps  xmm14, xmmword ptr [rsp + 0xe0]
+    movaps  xmm13, xmmword ptr [rsp + 0xd0]
+    movaps  xmm12, xmmword ptr [rsp + 0xc0]
+    movaps  xmm11, xmmword ptr [rsp + 0xb0]
+    movaps  xmm10, xmmword ptr [rsp + 0xa0]
+    movaps  xmm9, xmmword ptr [rsp + 0x90]
+    movaps  xmm8, xmmword ptr [rsp + 0x80]
+    movaps  xmm7, xmmword ptr [rsp + 0x70]
+    movaps  xmm6, xmmword ptr [rsp + 0x60]
     movaps  xmm5, xmmword ptr [rsp + 0x50]
     movaps  xmm4, xmmword ptr [rsp + 0x40]
     movaps  xmm3, xmmword ptr [rsp + 0x30]
     movaps  xmm2, xmmword ptr [rsp + 0x20]
     movaps  xmm1, xmmword ptr [rsp + 0x10]
     movaps  xmm0, xmmword ptr [rsp]
-    add     rsp, 0x60
+    add     rsp, 0x110
 
     # Restore host general purpose registers from stack.
     pop     r15
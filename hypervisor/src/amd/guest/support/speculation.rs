@@ -0,0 +1,170 @@
+//! This module implements the mitigations against speculative execution
+//! attacks applied on the world switch.
+//!
+//! - The SPEC_CTRL MSR is switched between the guest and host values, so that
+//!   neither runs with the controls the other chose. Processors with SPEC_CTRL
+//!   virtualization switch it on VMRUN and #VMEXIT through the VMCB. Otherwise
+//!   the MSR is written around `run_svm_guest`.
+//! - The return stack buffer (RSB) is filled with benign entries right after
+//!   #VMEXIT, before the host executes any return, so that the host does not
+//!   speculatively return to targets the guest trained.
+//! - IBPB is issued after #VMEXIT, so that no indirect branch prediction the
+//!   guest trained is used by the host.
+//!
+//! Each is configured with [`crate::amd::config::SpeculationMitigations`], and
+//! [`Mitigation::Auto`] enables it according to the vulnerabilities the
+//! processor reports.
+//! See: Software Techniques for Managing Speculation on AMD Processors
+
+use bit_field::BitField;
+use x86::cpuid::cpuid;
+use x86::msr::{rdmsr, wrmsr, IA32_EFER};
+
+use crate::amd::config::{Mitigation, SpeculationMitigations};
+
+const MSR_SPEC_CTRL: u32 = 0x48;
+const MSR_PRED_CMD: u32 = 0x49;
+const PRED_CMD_IBPB: u64 = 1 << 0;
+const EFER_AIBRSE: u64 = 1 << 21;
+
+/// The speculation controls and vulnerabilities the processor reports.
+/// See: CPUID Fn8000_0008_EBX, Fn8000_000A_EDX and Fn8000_0021_EAX
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct SpeculationSupport {
+    /// Whether the SPEC_CTRL MSR exists, that is, whether any of IBRS, STIBP
+    /// and SSBD is supported.
+    pub(crate) spec_ctrl: bool,
+    /// Whether VMRUN and #VMEXIT switch SPEC_CTRL through the VMCB.
+    pub(crate) spec_ctrl_virtualization: bool,
+    pub(crate) ibpb: bool,
+    /// Whether the host runs with automatic IBRS, which keeps the predictions
+    /// made at lower privilege, including in the guest, from being used.
+    pub(crate) automatic_ibrs: bool,
+    /// Whether the processor is not vulnerable to Speculative Return Stack
+    /// Overflow, where the guest can make host returns mispredict.
+    pub(crate) srso_no: bool,
+}
+
+impl SpeculationSupport {
+    pub(crate) fn current() -> Self {
+        let ebx = cpuid!(0x8000_0008).ebx;
+        let extended2 = if cpuid!(0x8000_0000).eax >= 0x8000_0021 { cpuid!(0x8000_0021).eax } else { 0 };
+        let automatic_ibrs = extended2.get_bit(8) && unsafe { rdmsr(IA32_EFER) } & EFER_AIBRSE != 0;
+        Self {
+            spec_ctrl: ebx.get_bit(14) || ebx.get_bit(15) || ebx.get_bit(24),
+            spec_ctrl_virtualization: cpuid!(0x8000_000a).edx.get_bit(20),
+            ibpb: ebx.get_bit(12),
+            automatic_ibrs,
+            srso_no: extended2.get_bit(29),
+        }
+    }
+
+    /// Returns the mitigations to apply for `config`. A mitigation the
+    /// processor cannot perform is not applied, even if enabled.
+    pub(crate) fn resolve(self, config: &SpeculationMitigations) -> Mitigations {
+        let enabled = |mitigation, vulnerable| match mitigation {
+            Mitigation::Auto => vulnerable,
+            Mitigation::Enabled => true,
+            Mitigation::Disabled => false,
+        };
+        Mitigations {
+            switch_spec_ctrl: self.spec_ctrl_virtualization || (self.spec_ctrl && enabled(config.spec_ctrl, true)),
+            spec_ctrl_virtualization: self.spec_ctrl_virtualization,
+            fill_rsb: enabled(config.rsb_fill, !self.automatic_ibrs),
+            ibpb_on_exit: self.ibpb && enabled(config.ibpb_on_exit, !self.srso_no),
+        }
+    }
+}
+
+/// The mitigations applied to a vCPU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Mitigations {
+    pub(crate) switch_spec_ctrl: bool,
+    pub(crate) spec_ctrl_virtualization: bool,
+    pub(crate) fill_rsb: bool,
+    pub(crate) ibpb_on_exit: bool,
+}
+
+pub(crate) fn spec_ctrl() -> u64 {
+    unsafe { rdmsr(MSR_SPEC_CTRL) }
+}
+
+pub(crate) fn set_spec_ctrl(value: u64) {
+    unsafe { wrmsr(MSR_SPEC_CTRL, value) };
+}
+
+/// Flushes the indirect branch predictions.
+pub(crate) fn ibpb() {
+    unsafe { wrmsr(MSR_PRED_CMD, PRED_CMD_IBPB) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NONE: SpeculationSupport = SpeculationSupport {
+        spec_ctrl: false,
+        spec_ctrl_virtualization: false,
+        ibpb: false,
+        automatic_ibrs: false,
+        srso_no: false,
+    };
+
+    const FULL: SpeculationSupport = SpeculationSupport {
+        spec_ctrl: true,
+        spec_ctrl_virtualization: false,
+        ibpb: true,
+        automatic_ibrs: false,
+        srso_no: false,
+    };
+
+    fn config(mitigation: Mitigation) -> SpeculationMitigations {
+        SpeculationMitigations { spec_ctrl: mitigation, rsb_fill: mitigation, ibpb_on_exit: mitigation }
+    }
+
+    #[test]
+    fn auto_follows_vulnerabilities() {
+        let mitigations = FULL.resolve(&config(Mitigation::Auto));
+        assert!(mitigations.switch_spec_ctrl);
+        assert!(mitigations.fill_rsb);
+        assert!(mitigations.ibpb_on_exit);
+
+        let support = SpeculationSupport { automatic_ibrs: true, srso_no: true, ..FULL };
+        let mitigations = support.resolve(&config(Mitigation::Auto));
+        assert!(mitigations.switch_spec_ctrl);
+        assert!(!mitigations.fill_rsb);
+        assert!(!mitigations.ibpb_on_exit);
+    }
+
+    #[test]
+    fn applies_only_supported_mitigations() {
+        let mitigations = NONE.resolve(&config(Mitigation::Enabled));
+        assert!(!mitigations.switch_spec_ctrl);
+        assert!(!mitigations.ibpb_on_exit);
+        // Filling the RSB needs no processor support.
+        assert!(mitigations.fill_rsb);
+
+        let support = SpeculationSupport { automatic_ibrs: true, srso_no: true, ..FULL };
+        let mitigations = support.resolve(&config(Mitigation::Enabled));
+        assert!(mitigations.fill_rsb);
+        assert!(mitigations.ibpb_on_exit);
+    }
+
+    #[test]
+    fn disabled_applies_nothing_but_virtualization() {
+        assert_eq!(
+            FULL.resolve(&config(Mitigation::Disabled)),
+            Mitigations {
+                switch_spec_ctrl: false,
+                spec_ctrl_virtualization: false,
+                fill_rsb: false,
+                ibpb_on_exit: false,
+            }
+        );
+        // SPEC_CTRL virtualization switches it on VMRUN whatever the config.
+        let support = SpeculationSupport { spec_ctrl_virtualization: true, ..FULL };
+        let mitigations = support.resolve(&config(Mitigation::Disabled));
+        assert!(mitigations.switch_spec_ctrl);
+        assert!(mitigations.spec_ctrl_virtualization);
+    }
+}
@@ -0,0 +1,288 @@
+//! This module implements the switching of the extended processor state
+//! between the guest and the host with XSAVE and XRSTOR.
+//!
+//! `run_svm_guest` switches XMM0-XMM15 and MXCSR through [`Registers`] on
+//! every world switch, which is all host code compiled with SSE modifies. The
+//! rest of the state enabled in XCR0 and IA32_XSS, such as the upper halves of
+//! YMM and ZMM and the AVX-512 mask registers, stays in the processor unless
+//! [`ExtendedStateSwitch`] opts in to switching it:
+//!
+//! - [`ExtendedStateSwitch::Eager`] saves the host state and restores the guest
+//!   state before VMRUN, and does the reverse after #VMEXIT. The host may use
+//!   any extended register.
+//! - [`ExtendedStateSwitch::Lazy`] leaves the guest state in the processor
+//!   after #VMEXIT. Host code that uses more than SSE, or that reads the guest
+//!   state, calls [`crate::amd::VCpu::extended_state`] first, which saves the
+//!   guest state. It is restored before the next VMRUN.
+//!
+//! XCR0 and IA32_XSS are not switched by VMRUN, so the values the guest enabled
+//! are current in the host too and select the components to switch. The x87
+//! and SSE components are never switched with XSAVE: XMM0-XMM15 and MXCSR are
+//! in [`Registers`], and the host does not use x87, so [`XsaveArea`] does not
+//! hold them. XSAVE still writes MXCSR into the area along with AVX, but the
+//! value is the host's: the guest MXCSR is switched out before the area is
+//! saved and in after it is restored.
+//! See: 11.5 XSAVE/XRSTOR Instructions
+//!
+//! [`Registers`]: kernelutils::Registers
+
+use alloc::boxed::Box;
+use alloc::vec;
+use core::arch::asm;
+
+use bit_field::BitField;
+use x86::controlregs::{cr4, Cr4};
+use x86::cpuid::cpuid;
+use x86::msr::rdmsr;
+
+use crate::amd::config::ExtendedStateSwitch;
+use crate::amd::guest::support;
+
+const MSR_IA32_XSS: u32 = 0xda0;
+
+/// The size of the legacy region and the XSAVE header.
+const XSAVE_HEADER_END: usize = 576;
+
+/// The x87 and SSE components, which are not switched with XSAVE.
+const LEGACY_COMPONENTS: u64 = 0b11;
+
+#[derive(thiserror_no_std::Error, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum XsaveError {
+    #[error("the processor does not support XSAVE or CR4.OSXSAVE is clear")]
+    NotSupported,
+
+    #[error("the vCPU was virtualized with ExtendedStateSwitch::Sse")]
+    NotEnabled,
+}
+
+/// How the processor saves the extended state.
+/// See: E.3.8 Function Dh—Processor Extended State Enumeration
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct XsaveSupport {
+    /// Whether XSAVES and XRSTORS are supported. They save the supervisor
+    /// components enabled in IA32_XSS too, in the compacted format.
+    pub(crate) compacted: bool,
+    /// The size of an area that holds every supported component.
+    pub(crate) size: usize,
+}
+
+impl XsaveSupport {
+    /// Returns the support of the current processor, or `None` if the host
+    /// cannot execute XSAVE.
+    pub(crate) fn current() -> Option<Self> {
+        if !cpuid!(0x1).ecx.get_bit(26) || !unsafe { cr4() }.contains(Cr4::CR4_ENABLE_OS_XSAVE) {
+            return None;
+        }
+
+        let leaf0 = cpuid!(0xd, 0);
+        let leaf1 = cpuid!(0xd, 1);
+        let compacted = leaf1.eax.get_bit(3);
+        let size = if compacted {
+            let user = u64::from(leaf0.edx) << 32 | u64::from(leaf0.eax);
+            let supervisor = u64::from(leaf1.edx) << 32 | u64::from(leaf1.ecx);
+            compacted_size(user | supervisor, |component| {
+                let leaf = cpuid!(0xd, component);
+                (leaf.eax as usize, leaf.ecx.get_bit(1))
+            })
+        } else {
+            leaf0.ecx as usize
+        };
+        Some(Self { compacted, size })
+    }
+
+    /// Returns the components to switch: those enabled in XCR0, and in
+    /// IA32_XSS if they are saved by XSAVES, except x87 and SSE, whose
+    /// registers `run_svm_guest` switches. XRSTOR still loads MXCSR when AVX is
+    /// among them, which is why the area is restored before `run_svm_guest`
+    /// loads the guest MXCSR.
+    pub(crate) fn enabled_components(self) -> u64 {
+        let xss = if self.compacted { unsafe { rdmsr(MSR_IA32_XSS) } } else { 0 };
+        (support::xgetbv(0) | xss) & !LEGACY_COMPONENTS
+    }
+}
+
+/// Returns the size of a compacted area that holds `components`, given the
+/// size of each component and whether it is aligned to 64 bytes.
+/// See: 11.5.5 XSAVE Area
+pub(crate) fn compacted_size(components: u64, describe: impl Fn(u32) -> (usize, bool)) -> usize {
+    (2..63)
+        .filter(|&component| components.get_bit(component as usize))
+        .fold(XSAVE_HEADER_END, |offset, component| {
+            let (size, aligned) = describe(component);
+            let offset = if aligned { (offset + 63) & !63 } else { offset };
+            offset + size
+        })
+}
+
+#[repr(C, align(64))]
+#[derive(Clone, Copy)]
+struct XsaveBlock([u8; 64]);
+
+/// A 64-byte aligned XSAVE area. It is in the compacted format if XSAVES is
+/// supported, or in the standard format otherwise. Its MXCSR field holds the
+/// host value; the guest MXCSR is in [`kernelutils::Registers`].
+pub struct XsaveArea {
+    blocks: Box<[XsaveBlock]>,
+}
+
+impl XsaveArea {
+    fn new(size: usize) -> Self {
+        Self {
+            blocks: vec![XsaveBlock([0; 64]); size.div_ceil(64)].into_boxed_slice(),
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        let len = self.blocks.len() * 64;
+        unsafe { core::slice::from_raw_parts(self.blocks.as_ptr().cast(), len) }
+    }
+
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        let len = self.blocks.len() * 64;
+        unsafe { core::slice::from_raw_parts_mut(self.blocks.as_mut_ptr().cast(), len) }
+    }
+
+    /// Returns XSTATE_BV, the components whose state is not the initial one.
+    pub fn xstate_bv(&self) -> u64 {
+        u64::from_le_bytes(self.as_bytes()[512..520].try_into().unwrap())
+    }
+
+    fn save(&mut self, components: u64, compacted: bool) {
+        let area = self.blocks.as_mut_ptr();
+        let (low, high) = (components as u32, (components >> 32) as u32);
+        unsafe {
+            if compacted {
+                asm!("xsaves64 [{}]", in(reg) area, in("eax") low, in("edx") high, options(nostack, preserves_flags));
+            } else {
+                asm!("xsave64 [{}]", in(reg) area, in("eax") low, in("edx") high, options(nostack, preserves_flags));
+            }
+        }
+    }
+
+    fn restore(&self, components: u64, compacted: bool) {
+        let area = self.blocks.as_ptr();
+        let (low, high) = (components as u32, (components >> 32) as u32);
+        unsafe {
+            if compacted {
+                asm!("xrstors64 [{}]", in(reg) area, in("eax") low, in("edx") high, options(nostack, preserves_flags));
+            } else {
+                asm!("xrstor64 [{}]", in(reg) area, in("eax") low, in("edx") high, options(nostack, preserves_flags));
+            }
+        }
+    }
+}
+
+/// The extended state of a vCPU that opted in to switching it.
+pub(crate) struct ExtendedState {
+    switch: ExtendedStateSwitch,
+    support: XsaveSupport,
+    host: XsaveArea,
+    guest: XsaveArea,
+    /// The components saved into `guest`, and restored from it on the next
+    /// VMRUN. Zero when the guest state is in the processor.
+    saved: u64,
+    /// The components saved into `host` before VMRUN.
+    host_saved: u64,
+}
+
+impl ExtendedState {
+    /// Returns the extended state for `switch`, or `None` if it is not switched
+    /// with XSAVE.
+    pub(crate) fn new(switch: ExtendedStateSwitch) -> Result<Option<Self>, XsaveError> {
+        if switch == ExtendedStateSwitch::Sse {
+            return Ok(None);
+        }
+        let support = XsaveSupport::current().ok_or(XsaveError::NotSupported)?;
+        Ok(Some(Self {
+            switch,
+            support,
+            host: XsaveArea::new(support.size),
+            guest: XsaveArea::new(support.size),
+            saved: 0,
+            host_saved: 0,
+        }))
+    }
+
+    pub(crate) fn switch(&self) -> ExtendedStateSwitch {
+        self.switch
+    }
+
+    pub(crate) fn set_switch(&mut self, switch: ExtendedStateSwitch) {
+        // The eager switch restores the guest state from the area on VMRUN, so
+        // it must be there.
+        if switch == ExtendedStateSwitch::Eager {
+            self.save_guest();
+        }
+        self.switch = switch;
+    }
+
+    /// Saves the guest state if it is still in the processor, and returns it.
+    pub(crate) fn save_guest(&mut self) -> &mut XsaveArea {
+        if self.saved == 0 {
+            self.saved = self.support.enabled_components();
+            self.guest.save(self.saved, self.support.compacted);
+        }
+        &mut self.guest
+    }
+
+    /// Switches to the guest state right before VMRUN.
+    pub(crate) fn enter(&mut self) {
+        if self.switch == ExtendedStateSwitch::Eager {
+            self.host_saved = self.support.enabled_components();
+            self.host.save(self.host_saved, self.support.compacted);
+        }
+        if self.saved != 0 {
+            self.guest.restore(self.saved, self.support.compacted);
+            self.saved = 0;
+        }
+    }
+
+    /// Switches to the host state right after #VMEXIT.
+    pub(crate) fn exit(&mut self) {
+        if self.switch == ExtendedStateSwitch::Eager {
+            self.save_guest();
+            self.host.restore(self.host_saved, self.support.compacted);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// AVX, the AVX-512 components and PKRU as a typical processor describes
+    /// them: (size, aligned to 64 bytes).
+    fn describe(component: u32) -> (usize, bool) {
+        match component {
+            2 => (256, false),
+            5 => (64, false),
+            6 => (512, false),
+            7 => (1024, false),
+            9 => (8, false),
+            11 => (16, true),
+            _ => (0, false),
+        }
+    }
+
+    #[test]
+    fn legacy_components_take_the_header_only() {
+        assert_eq!(compacted_size(0, describe), XSAVE_HEADER_END);
+        assert_eq!(compacted_size(LEGACY_COMPONENTS, describe), XSAVE_HEADER_END);
+    }
+
+    #[test]
+    fn packs_components_in_order() {
+        assert_eq!(compacted_size(1 << 2, describe), 576 + 256);
+        assert_eq!(compacted_size(0b111 << 5, describe), 576 + 64 + 512 + 1024);
+        // Components that are not enabled take no space.
+        assert_eq!(compacted_size(1 << 2 | 1 << 9, describe), 576 + 256 + 8);
+    }
+
+    #[test]
+    fn aligns_components() {
+        // PKRU ends at 840, so the aligned component starts at 896.
+        assert_eq!(compacted_size(1 << 2 | 1 << 9 | 1 << 11, describe), 896 + 16);
+        // An aligned component already on a boundary does not move.
+        assert_eq!(compacted_size(1 << 2 | 1 << 11, describe), 832 + 16);
+    }
+}
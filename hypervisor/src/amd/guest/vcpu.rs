@@ -1,5 +1,5 @@
 use bit_field::BitField;
-use core::arch::{asm};
+use core::arch::asm;
 use core::ptr::addr_of;
 use core::sync::atomic::{AtomicU8, Ordering};
 
@@ -7,20 +7,36 @@ use x86::controlregs::{cr0,  cr3_write };
 use x86::cpuid::cpuid;
 use x86::current::rflags::RFlags;
 
-use x86::msr::{rdmsr, wrmsr};
+use alloc::vec::Vec;
+use x86::dtables::DescriptorTablePointer;
+use x86::msr::{self, wrmsr};
 
 
 use crate::amd::guest::{ support};
-use crate::amd::{InstructionInfo, VmExitReason};
+use crate::amd::guest::state::GuestState;
+use crate::amd::guest::vmexit::{exception, shutdown, tsc, xsetbv};
+use crate::amd::guest::vmexit::exception::Delivery;
+use crate::amd::guest::vmexit::tsc::TscError;
+use crate::amd::guest::vmexit::unknown::UnknownExit;
+use crate::amd::{config, processors, stats, trace, DebugBreaks, ExtendedStateSwitch, IcrInterceptPolicy, InstructionInfo, VmExitReason};
+use crate::amd::stats::VcpuStatistics;
+use crate::amd::trace::{TraceAction, TraceBuffer, TraceRecord};
+use crate::amd::intercept::{Intercept, InterceptError, InterceptSet};
 use kernelutils::Registers;
 use kernelutils::nt::platform_ops;
 use crate::amd::guest::area::{HostStateArea, Vmcb, SHARED_GUEST_DATA, SHARED_HOST_DATA};
-use crate::amd::guest::support::apic_id;
+use crate::amd::guest::{HostStateAreaRaw, VmcbRaw};
+use crate::amd::guest::support::{apic_id, asid, consistency, local_apic, icr, TlbFlush};
+use crate::amd::guest::support::speculation::{self, Mitigations, SpeculationSupport};
+use crate::amd::guest::support::xsave::{ExtendedState, XsaveArea, XsaveError};
+use crate::amd::guest::support::consistency::{CpuLimits, VmcbViolation};
+use crate::amd::guest::support::icr::{ApicDestination, DeliveryMode, InterruptCommand};
 
 #[derive( derivative::Derivative)]
 pub struct VCpu {
-    #[allow(dead_code)]
     id: usize,
+    /// Whether this is the bootstrap processor (BSP).
+    is_bsp: bool,
     pub guest_vmcb: Vmcb,
 
     #[allow(dead_code)]
@@ -32,30 +48,80 @@ pub struct VCpu {
     host_state: HostStateArea,
     registers: Registers,
     activity_state: &'static AtomicU8,
+    statistics: &'static VcpuStatistics,
+    trace: &'static TraceBuffer,
+    /// Whether the processor supports decode assists.
+    decode_assists: bool,
+    /// The value of TSC_RATIO while this vCPU runs.
+    tsc_ratio: u64,
+    asid: u32,
+    /// Whether TLB_CONTROL can flush the entries of a single ASID.
+    flush_by_asid: bool,
+    /// The TLB flush to perform on the next VMRUN.
+    pending_flush: support::TlbControl,
+    /// The extended state beyond SSE, if it is switched with XSAVE.
+    extended_state: Option<ExtendedState>,
+    /// The mitigations applied on the world switch.
+    mitigations: Mitigations,
+    /// The SPEC_CTRL value of the host, and of the guest when SPEC_CTRL is not
+    /// switched through the VMCB.
+    host_spec_ctrl: u64,
+    guest_spec_ctrl: u64,
+
+    /// The TSC value and the statistics slot of the last #VMEXIT, if any.
+    last_exit: Option<(u64, usize)>,
+    /// Whether the guest asked to be devirtualized.
+    devirtualization_requested: bool,
+    /// The interrupt or NMI whose delivery was interrupted by an exception
+    /// injected in its place. It is injected on the VMRUN after.
+    deferred_event: Option<u64>,
 }
 
 
 impl VCpu {
     fn handle_security_exception(&mut self) {
-        assert!(self.id != 0);
+        // #SX pushes the error code 1 when it is caused by INIT redirected with
+        // VM_CR.R_INIT.
+        // See: 8.2 Vectors
+        const SX_ERROR_CODE_INIT: u64 = 1;
+
+        let error_code = self.guest_vmcb.exit_info1();
+        if error_code != SX_ERROR_CODE_INIT {
+            log::warn!("#SX with an unexpected error code {error_code:#x?} is handled as INIT");
+        }
+
         self.handle_init_signal();
-        self.handle_sipi(self.wait_for_sipi());
+
+        // Unlike APs, the BSP does not enter the Wait-for-SIPI state. It starts
+        // fetching code from the reset vector right after INIT.
+        if !self.is_bsp {
+            self.handle_sipi(self.wait_for_sipi());
+        }
     }
 
     fn handle_init_signal(&mut self) {
         const EFER_SVME: u64 = 1 << 12;
 
-        assert!(self.id != 0);
-
-        // Update the state to Wait-for-SIPI as soon as possible since we are
-        // racing against BSP sending SIPI.
-        assert!(
+        if self.is_bsp {
+            // Someone may have marked us Wait-for-SIPI while emulating an INIT
+            // sent with a broadcast shorthand. The BSP never waits for SIPI.
             self.activity_state
-                .swap(support::GuestActivityState::WaitForSipi as u8, Ordering::Relaxed)
-                == support::GuestActivityState::Active as u8
-        );
+                .store(support::GuestActivityState::Active as u8, Ordering::Relaxed);
+        } else {
+            // Update the state to Wait-for-SIPI as soon as possible since we are
+            // racing against BSP sending SIPI. The state may already be updated if
+            // the sender of INIT did it while emulating the IPI, or may even hold
+            // the vector if SIPI was emulated before we got here. Keep it as-is in
+            // those cases.
+            let _ = self.activity_state.compare_exchange(
+                support::GuestActivityState::Active as u8,
+                support::GuestActivityState::WaitForSipi as u8,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
+        }
 
-        log::debug!("INIT");
+        log::debug!("INIT on {} {}", if self.is_bsp { "BSP" } else { "AP" }, self.id);
 
         // Extension Type
         // Not Write-through
@@ -64,55 +130,63 @@ impl VCpu {
         let new_cr0 = 1u64 << 4
             | (previous_cr0.get_bit(29) as u64) << 29
             | (previous_cr0.get_bit(30) as u64) << 30;
-        self.guest_vmcb.state_save_area.cr0 = new_cr0;
-        self.guest_vmcb.state_save_area.cr2 = 0;
-        self.guest_vmcb.state_save_area.cr3 = 0;
-        self.guest_vmcb.state_save_area.cr4 = 0;
-        self.guest_vmcb.state_save_area.rflags = RFlags::FLAGS_A1.bits();
-        self.guest_vmcb.state_save_area.efer = EFER_SVME;
-        self.guest_vmcb.state_save_area.rip = 0xfff0;
-        self.guest_vmcb.state_save_area.cs_selector = 0xf000;
-        self.guest_vmcb.state_save_area.cs_base = 0xffff0000;
-        self.guest_vmcb.state_save_area.cs_limit = 0xffff;
-        self.guest_vmcb.state_save_area.cs_attrib = 0x9b;
-        self.guest_vmcb.state_save_area.ds_selector = 0;
-        self.guest_vmcb.state_save_area.ds_base = 0;
-        self.guest_vmcb.state_save_area.ds_limit = 0xffff;
-        self.guest_vmcb.state_save_area.ds_attrib = 0x93;
-        self.guest_vmcb.state_save_area.es_selector = 0;
-        self.guest_vmcb.state_save_area.es_base = 0;
-        self.guest_vmcb.state_save_area.es_limit = 0xffff;
-        self.guest_vmcb.state_save_area.es_attrib = 0x93;
-        self.guest_vmcb.state_save_area.fs_selector = 0;
-        self.guest_vmcb.state_save_area.fs_base = 0;
-        self.guest_vmcb.state_save_area.fs_limit = 0xffff;
-        self.guest_vmcb.state_save_area.fs_attrib = 0x93;
-        self.guest_vmcb.state_save_area.gs_selector = 0;
-        self.guest_vmcb.state_save_area.gs_base = 0;
-        self.guest_vmcb.state_save_area.gs_limit = 0xffff;
-        self.guest_vmcb.state_save_area.gs_attrib = 0x93;
-        self.guest_vmcb.state_save_area.ds_selector = 0;
-        self.guest_vmcb.state_save_area.ds_base = 0;
-        self.guest_vmcb.state_save_area.ds_limit = 0xffff;
-        self.guest_vmcb.state_save_area.ds_attrib = 0x93;
-        self.guest_vmcb.state_save_area.gdtr_base = 0;
-        self.guest_vmcb.state_save_area.gdtr_limit = 0xffff;
-        self.guest_vmcb.state_save_area.idtr_base = 0;
-        self.guest_vmcb.state_save_area.idtr_limit = 0xffff;
-        self.guest_vmcb.state_save_area.ldtr_selector = 0;
-        self.guest_vmcb.state_save_area.ldtr_base = 0;
-        self.guest_vmcb.state_save_area.ldtr_limit = 0xffff;
-        self.guest_vmcb.state_save_area.ldtr_attrib = 0x82;
-        self.guest_vmcb.state_save_area.tr_selector = 0;
-        self.guest_vmcb.state_save_area.tr_base = 0;
-        self.guest_vmcb.state_save_area.tr_limit = 0xffff;
-        self.guest_vmcb.state_save_area.tr_attrib = 0x8b;
+        self.guest_vmcb.set_cr0(new_cr0);
+        self.guest_vmcb.set_cr2(0);
+        self.guest_vmcb.set_cr3(0);
+        self.guest_vmcb.set_cr4(0);
+        self.guest_vmcb.set_rflags(RFlags::FLAGS_A1.bits());
+        self.guest_vmcb.set_efer(EFER_SVME);
+        self.guest_vmcb.set_rip(0xfff0);
+        // RIP, RSP and RFLAGS are copied from `registers` on VMRUN. Reset them
+        // there too.
+        self.registers.rip = 0xfff0;
+        self.registers.rflags = RFlags::FLAGS_A1.bits();
+        self.registers.rsp = 0;
+        // INIT discards the event that was being delivered.
+        self.guest_vmcb.set_event_inj(0);
+        self.deferred_event = None;
+        self.guest_vmcb.set_cs_selector(0xf000);
+        self.guest_vmcb.set_cs_base(0xffff0000);
+        self.guest_vmcb.set_cs_limit(0xffff);
+        self.guest_vmcb.set_cs_attrib(0x9b);
+        self.guest_vmcb.set_ds_selector(0);
+        self.guest_vmcb.set_ds_base(0);
+        self.guest_vmcb.set_ds_limit(0xffff);
+        self.guest_vmcb.set_ds_attrib(0x93);
+        self.guest_vmcb.set_es_selector(0);
+        self.guest_vmcb.set_es_base(0);
+        self.guest_vmcb.set_es_limit(0xffff);
+        self.guest_vmcb.set_es_attrib(0x93);
+        self.guest_vmcb.set_fs_selector(0);
+        self.guest_vmcb.set_fs_base(0);
+        self.guest_vmcb.set_fs_limit(0xffff);
+        self.guest_vmcb.set_fs_attrib(0x93);
+        self.guest_vmcb.set_gs_selector(0);
+        self.guest_vmcb.set_gs_base(0);
+        self.guest_vmcb.set_gs_limit(0xffff);
+        self.guest_vmcb.set_gs_attrib(0x93);
+        self.guest_vmcb.set_ds_selector(0);
+        self.guest_vmcb.set_ds_base(0);
+        self.guest_vmcb.set_ds_limit(0xffff);
+        self.guest_vmcb.set_ds_attrib(0x93);
+        self.guest_vmcb.set_gdtr_base(0);
+        self.guest_vmcb.set_gdtr_limit(0xffff);
+        self.guest_vmcb.set_idtr_base(0);
+        self.guest_vmcb.set_idtr_limit(0xffff);
+        self.guest_vmcb.set_ldtr_selector(0);
+        self.guest_vmcb.set_ldtr_base(0);
+        self.guest_vmcb.set_ldtr_limit(0xffff);
+        self.guest_vmcb.set_ldtr_attrib(0x82);
+        self.guest_vmcb.set_tr_selector(0);
+        self.guest_vmcb.set_tr_base(0);
+        self.guest_vmcb.set_tr_limit(0xffff);
+        self.guest_vmcb.set_tr_attrib(0x8b);
         self.registers.rax = 0;
         self.registers.rdx = cpuid!(0x1).eax as _;
         self.registers.rbx = 0;
         self.registers.rcx = 0;
         self.registers.rbp = 0;
-        self.guest_vmcb.state_save_area.rsp = 0;
+        self.guest_vmcb.set_rsp(0);
         self.registers.rdi = 0;
         self.registers.rsi = 0;
         self.registers.r8 = 0;
@@ -129,16 +203,15 @@ impl VCpu {
             x86::debugregs::dr2_write(0);
             x86::debugregs::dr3_write(0);
         };
-        self.guest_vmcb.state_save_area.dr6 = 0xffff0ff0;
-        self.guest_vmcb.state_save_area.dr7 = 0x400;
+        self.guest_vmcb.set_dr6(0xffff0ff0);
+        self.guest_vmcb.set_dr7(0x400);
 
-        self.guest_vmcb.control_area.tlb_control = support::TlbControl::FlushAll as _;
-        self.guest_vmcb.control_area.vmcb_clean = 0;
+        // INIT resets more state than the fields set above. Reload everything.
+        self.flush_tlb(TlbFlush::Guest);
+        self.guest_vmcb.mark_all_dirty();
     }
 
     fn wait_for_sipi(&self) -> u8 {
-        assert!(self.id != 0);
-
         // Wait for SIPI sent from BSP.
         while self.activity_state.load(Ordering::Relaxed) == support::GuestActivityState::WaitForSipi as u8 {
             core::hint::spin_loop();
@@ -150,19 +223,16 @@ impl VCpu {
     }
 
     fn handle_sipi(&mut self, vector: u8) {
-        assert!(self.id != 0);
-        assert!(self.activity_state.load(Ordering::Relaxed) == support::GuestActivityState::Active as u8);
         log::debug!("SIPI vector {vector:#x?}");
 
-        self.guest_vmcb.state_save_area.cs_selector = (vector as u16) << 8;
-        self.guest_vmcb.state_save_area.cs_base = (vector as u64) << 12;
-        self.guest_vmcb.state_save_area.rip = 0;
+        self.guest_vmcb.set_cs_selector((vector as u16) << 8);
+        self.guest_vmcb.set_cs_base((vector as u64) << 12);
+        self.guest_vmcb.set_rip(0);
         self.registers.rip = 0;
     }
 
     fn intercept_apic_write(&mut self, enable: bool) {
-        let apic_base_raw = unsafe { rdmsr(x86::msr::IA32_APIC_BASE) };
-        let apic_base = apic_base_raw & !0xfff;
+        let apic_base = local_apic::base();
         let pt_index = apic_base.get_bits(12..=20) as usize; // [20:12]
 
         let mut npt = SHARED_GUEST_DATA.npt.write();
@@ -173,22 +243,13 @@ impl VCpu {
         // shootdown. It is fine because APIC writes we want to see are done by
         // this processors. We need to handle #VMEXIT(NFP) on other processors
         // if it happens.
-        self.guest_vmcb.control_area.tlb_control = support::TlbControl::FlushAll as _;
+        self.flush_tlb(TlbFlush::Guest);
     }
 
     fn handle_nested_page_fault(&mut self) {
-        unsafe { asm!("int 3") }
-        if self.id == apic_id::PROCESSOR_COUNT.load(Ordering::Relaxed) - 1 {
-            log::debug!("Stopping APIC write interception");
-            self.intercept_apic_write(false);
-        }
+        config::debug_break(DebugBreaks::NESTED_PAGE_FAULT);
 
-        let instructions = unsafe {
-            core::slice::from_raw_parts(
-                self.guest_vmcb.control_area.guest_instruction_bytes.as_ptr(),
-                self.guest_vmcb.control_area.num_of_bytes_fetched as _,
-            )
-        };
+        let instructions = self.guest_vmcb.guest_instruction_bytes();
 
         // This one is by far the most frequent one. Micro-optimize this path by
         // checking this pattern first.
@@ -237,74 +298,153 @@ impl VCpu {
 
         self.registers.rip += instr_len;
 
-        let message_type = value.get_bits(8..=10);
-        let faulting_gpa = self.guest_vmcb.control_area.exit_info2;
+        let faulting_gpa = self.guest_vmcb.exit_info2();
         let apic_register = faulting_gpa & 0xfff;
         if apic_register != 0xb0 && self.id == 0 {
             log::trace!("APIC reg:{apic_register:#x} <= {value:#x}");
         }
 
-        // If the faulting access is not because of sending Startup IPI (0b110)
-        // via the Interrupt Command Register Low (0x300), do the write access
-        // the guest wanted to do and bail out.
+        // Keep track of the logical destination of this processor so that IPIs
+        // sent in the logical destination mode can be resolved.
+        match apic_register {
+            icr::APIC_REG_LDR => SHARED_GUEST_DATA.apic_ldrs[self.id].store(value, Ordering::Relaxed),
+            icr::APIC_REG_DFR => SHARED_GUEST_DATA.apic_dfrs[self.id].store(value, Ordering::Relaxed),
+            _ => {}
+        }
+
+        // Do the write access the guest wanted to do, unless it is writing to
+        // the Interrupt Command Register Low (0x300) to send an IPI that needs
+        // to be emulated.
         // Table 16-2. APIC Registers
-        if message_type != 0b110 || apic_register != 0x300 {
-            // Safety: GPA is same as PA in our NTPs, and the faulting address
-            // is always the local APIC page, which is writable in the host
-            // address space.
-            let apic_reg = faulting_gpa as *mut u32;
-            unsafe { apic_reg.write_volatile(value) };
-            return;
-        }
-
-        // The BSP is trying to send Startup IPI. This must not be allowed because
-        // SVM does not intercept it or deliver #VMEXIT. We need to prevent the
-        // BSP from sending it and emulate the effect in software instead.
-
-        // Figure 16-18. Interrupt Command Register (APIC Offset 300h–310h)
-        assert!(!value.get_bit(11), "Destination Mode must be 'Physical'");
-        assert!(
-            value.get_bits(18..=19) == 0b00,
-            "Destination Shorthand must be 'Destination'"
-        );
-
-        // Safety: GPA is same as PA in our NTPs, and the faulting address
-        // is always the local APIC page, which is writable in the host
-        // address space.
-        let icr_high_addr = (faulting_gpa & !0xfff) | 0x310;
-        let icr_high_value = unsafe { *(icr_high_addr as *mut u32) };
-
-        // Collect necessary bits to emulate, that is, vector and destination.
-        let vector = value.get_bits(0..=7) as u8;
-        let apic_id = icr_high_value.get_bits(24..=31) as u8;
-        let processor_id = apic_id::processor_id_from(apic_id).unwrap();
-        log::debug!("SIPI to {apic_id} with vector {vector:#x?}");
-        assert!(vector != support::GuestActivityState::WaitForSipi as u8);
-
-        // Update the activity state of the target processor with the obtained
-        // vector value. The target processor should get out from the busy loop
-        // after this. Note that it is possible that the target processor is not
-        // yet in the WaitForSipi state when #VMEXIT(#SX) has not been processed.
-        // It is fine, as SIPI will be sent twice, and almost certain that 2nd
-        // SIPI is late enough.
-        let activity_state = &SHARED_GUEST_DATA.activity_states[processor_id];
-        let _ = activity_state.compare_exchange(
-            support::GuestActivityState::WaitForSipi as u8,
-            vector,
-            Ordering::Relaxed,
-            Ordering::Relaxed,
-        );
+        if apic_register != icr::APIC_REG_ICR_LOW || !self.emulate_ipi(value) {
+            local_apic::write(apic_register, value);
+        }
+    }
+
+    /// Emulates the effect of the IPI requested by writing `icr_low` to ICR.
+    /// Returns `true` if the write must not reach the local APIC.
+    fn emulate_ipi(&mut self, icr_low: u32) -> bool {
+        let command = InterruptCommand::new(icr_low, local_apic::read(icr::APIC_REG_ICR_HIGH));
+        match command.delivery_mode() {
+            // INIT is delivered as #SX to the target processors, as we set
+            // VM_CR.R_INIT. Let it reach the local APIC, but update the activity
+            // state of the targets right away so that SIPI following INIT is
+            // not missed even if it is sent before the targets process #SX.
+            DeliveryMode::Init => {
+                if command.is_init_level_deassert() {
+                    return false;
+                }
+                for processor_id in self.ipi_targets(&command) {
+                    log::debug!("INIT to processor {processor_id}");
+                    let _ = SHARED_GUEST_DATA.activity_states[processor_id].compare_exchange(
+                        support::GuestActivityState::Active as u8,
+                        support::GuestActivityState::WaitForSipi as u8,
+                        Ordering::Relaxed,
+                        Ordering::Relaxed,
+                    );
+                }
+                false
+            }
+            // The processor is trying to send Startup IPI. This must not be
+            // allowed because SVM does not intercept it or deliver #VMEXIT. We
+            // need to prevent it from sending it and emulate the effect in
+            // software instead.
+            DeliveryMode::Startup => {
+                let vector = command.vector();
+                if vector == support::GuestActivityState::WaitForSipi as u8 {
+                    log::warn!("Dropping SIPI with the unsupported vector {vector:#x?}");
+                    return true;
+                }
+
+                // Update the activity state of the target processors with the
+                // vector value. The target processors should get out from the
+                // busy loop after this. Note that it is possible that a target
+                // processor is not yet in the WaitForSipi state when
+                // #VMEXIT(#SX) has not been processed. It is fine, as SIPI will
+                // be sent twice, and almost certain that 2nd SIPI is late enough.
+                let mut emulated = false;
+                for processor_id in self.ipi_targets(&command) {
+                    log::debug!("SIPI to processor {processor_id} with vector {vector:#x?}");
+                    let _ = SHARED_GUEST_DATA.activity_states[processor_id].compare_exchange(
+                        support::GuestActivityState::WaitForSipi as u8,
+                        vector,
+                        Ordering::Relaxed,
+                        Ordering::Relaxed,
+                    );
+                    emulated = true;
+                }
+                if emulated {
+                    self.update_apic_write_interception();
+                }
+
+                // Processors outside of SVM, such as those added at runtime and
+                // not virtualized yet, are started for real, so let SIPI reach
+                // the local APIC unless the only processor that can accept it
+                // is virtualized. Virtualized processors ignore SIPI from the
+                // local APIC, as INIT is redirected to #SX and never puts them
+                // into the Wait-for-SIPI state.
+                match command.single_destination(apic_id::get()) {
+                    Some(destination) => {
+                        apic_id::processor_id_from(destination).is_some_and(processors::is_virtualized)
+                    }
+                    None => false,
+                }
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the IDs of the virtualized processors that accept the IPI
+    /// described by `command` when it is sent from this processor.
+    fn ipi_targets<'a>(&self, command: &'a InterruptCommand) -> impl Iterator<Item = usize> + 'a {
+        let sender_apic_id = apic_id::get();
+        apic_id::processors()
+            .filter(move |&(apic_id, processor_id)| {
+                let destination = ApicDestination {
+                    apic_id,
+                    ldr: SHARED_GUEST_DATA.apic_ldrs[processor_id].load(Ordering::Relaxed),
+                    dfr: SHARED_GUEST_DATA.apic_dfrs[processor_id].load(Ordering::Relaxed),
+                };
+                processors::is_virtualized(processor_id) && destination.accepts(command, sender_apic_id)
+            })
+            .map(|(_, processor_id)| processor_id)
+    }
+
+    /// Stops intercepting APIC writes if the configured policy no longer
+    /// requires it. Evaluated after SIPI is emulated, which is what ends
+    /// [`IcrInterceptPolicy::UntilApsStarted`].
+    fn update_apic_write_interception(&mut self) {
+        if !Self::apic_write_interception_required() {
+            log::debug!("Stopping APIC write interception");
+            self.intercept_apic_write(false);
+        }
+    }
+
+    /// Returns whether the configured policy requires intercepting APIC
+    /// writes now.
+    fn apic_write_interception_required() -> bool {
+        match config::read().icr_intercept {
+            IcrInterceptPolicy::Disabled => false,
+            // Processors that were running when virtualized are started
+            // already, so only those waiting for SIPI keep the interception.
+            IcrInterceptPolicy::UntilApsStarted => apic_id::processors().any(|(_, processor_id)| {
+                SHARED_GUEST_DATA.activity_states[processor_id].load(Ordering::Relaxed)
+                    == support::GuestActivityState::WaitForSipi as u8
+            }),
+            IcrInterceptPolicy::Always => true,
+        }
     }
 }
 impl VCpu {
     fn initialize_control(&mut self) {
-        self.guest_vmcb.initialize_control();
+        self.guest_vmcb.initialize_control(self.asid);
     }
 
     fn initialize_guest(&mut self) {
         self.guest_vmcb.initialize_guest(&self.registers);
-
-        support:: vmsave(self.guest_vmcb_pa);
+        if self.mitigations.spec_ctrl_virtualization {
+            self.guest_vmcb.set_spec_ctl(self.guest_spec_ctrl);
+        }
     }
 
     fn initialize_host(&mut self) {
@@ -330,9 +470,13 @@ impl VCpu {
 }
 
 impl VCpu {
+    /// The size of the guest and host VMCBs and the host state area.
+    const VMCB_BYTES: usize = 2 * core::mem::size_of::<VmcbRaw>() + core::mem::size_of::<HostStateAreaRaw>();
+
     pub(crate) fn new(id: usize) -> Self {
         let mut vm = Self {
             id,
+            is_bsp: local_apic::is_bsp(),
             registers: Registers::default(),
             guest_vmcb: Vmcb::new(),
             guest_vmcb_pa: 0,
@@ -341,13 +485,46 @@ impl VCpu {
 
             host_state: HostStateArea::new(),
             activity_state: &SHARED_GUEST_DATA.activity_states[id],
+            statistics: stats::for_processor(id),
+            trace: trace::for_processor(id),
+            decode_assists: cpuid!(0x8000_000a).edx.get_bit(7),
+            tsc_ratio: tsc::TSC_RATIO_ONE,
+            asid: asid::allocate(),
+            flush_by_asid: asid::is_flush_by_asid_supported(),
+            pending_flush: support::TlbControl::DoNotFlush,
+            extended_state: None,
+            mitigations: SpeculationSupport::current().resolve(&config::read().speculation),
+            host_spec_ctrl: 0,
+            guest_spec_ctrl: 0,
+            last_exit: None,
+            devirtualization_requested: false,
+            deferred_event: None,
         };
+        if vm.mitigations.switch_spec_ctrl {
+            // The guest starts with the controls the system was running with.
+            vm.host_spec_ctrl = speculation::spec_ctrl();
+            vm.guest_spec_ctrl = vm.host_spec_ctrl;
+        }
+        log::debug!("Speculation mitigations: {:?}", vm.mitigations);
+        xsetbv::clear_hidden_components();
+        let switch = config::read().extended_state_switch;
+        vm.extended_state = ExtendedState::new(switch).unwrap_or_else(|error| {
+            log::warn!("Switching SSE state only: {error}");
+            None
+        });
+        // The ASID may have been used by another vCPU before.
+        vm.flush_tlb(TlbFlush::Guest);
+        stats::account_vmcb(Self::VMCB_BYTES);
 
         vm.guest_vmcb_pa = platform_ops::get().pa(addr_of!(*vm.guest_vmcb.as_ref()) as _);
         vm.host_vmcb_pa = platform_ops::get().pa(addr_of!(*vm.host_vmcb.as_ref()) as _);
-        // if cfg!(feature = "uefi") && vm.id == 0 {
-        //     vm.intercept_apic_write(true);
-        // }
+
+        SHARED_GUEST_DATA.apic_ldrs[id].store(local_apic::read(icr::APIC_REG_LDR), Ordering::Relaxed);
+        SHARED_GUEST_DATA.apic_dfrs[id].store(local_apic::read(icr::APIC_REG_DFR), Ordering::Relaxed);
+        if vm.is_bsp && Self::apic_write_interception_required() {
+            log::debug!("Starting APIC write interception");
+            vm.intercept_apic_write(true);
+        }
         vm
     }
     pub(crate) fn activate(&mut self) {
@@ -373,32 +550,119 @@ impl VCpu {
     }
 
     pub(crate) fn run(&mut self) -> VmExitReason {
+        const VMEXIT_CR0_READ: u64 = 0x00;
+        const VMEXIT_CR15_WRITE: u64 = 0x1f;
+        const VMEXIT_DR0_READ: u64 = 0x20;
+        const VMEXIT_DR15_WRITE: u64 = 0x3f;
+        const VMEXIT_EXCP0: u64 = 0x40;
         const VMEXIT_EXCEPTION_SX: u64 = 0x5e;
+        const VMEXIT_EXCP31: u64 = 0x5f;
+        const VMEXIT_RDTSC: u64 = 0x6e;
         const VMEXIT_CPUID: u64 = 0x72;
+        const VMEXIT_PAUSE: u64 = 0x77;
+        const VMEXIT_HLT: u64 = 0x78;
+        const VMEXIT_SHUTDOWN: u64 = 0x7f;
+        const VMEXIT_VMMCALL: u64 = 0x81;
+        const VMEXIT_RDTSCP: u64 = 0x87;
+        const VMEXIT_XSETBV: u64 = 0x8d;
         const VMEXIT_NPF: u64 = 0x400;
+        const VMEXIT_INVALID: u64 = u64::MAX;
+        const EVENT_VALID: u64 = 1 << 31;
+
+        // Record how the previous #VMEXIT was handled. The VMCB still holds RIP
+        // at the #VMEXIT unless the handler replaced it.
+        if self.last_exit.is_some() {
+            let action = if self.guest_vmcb.event_inj() & EVENT_VALID != 0 {
+                TraceAction::Injected
+            } else if self.registers.rip != self.guest_vmcb.rip() {
+                TraceAction::Emulated
+            } else {
+                TraceAction::Resumed
+            };
+            self.trace.complete(action);
+        }
 
-        self.guest_vmcb.state_save_area.rax = self.registers.rax;
-        self.guest_vmcb.state_save_area.rip = self.registers.rip;
-        self.guest_vmcb.state_save_area.rsp = self.registers.rsp;
-        self.guest_vmcb.state_save_area.rflags = self.registers.rflags;
+        if self.guest_vmcb.event_inj() & EVENT_VALID == 0 {
+            if let Some(event) = self.deferred_event.take() {
+                self.guest_vmcb.set_event_inj(event);
+            }
+        }
+
+        self.guest_vmcb.set_rax(self.registers.rax);
+        self.guest_vmcb.set_rip(self.registers.rip);
+        self.guest_vmcb.set_rsp(self.registers.rsp);
+        self.guest_vmcb.set_rflags(self.registers.rflags);
+        self.guest_vmcb.set_tlb_control(self.pending_flush as _);
+        self.pending_flush = support::TlbControl::DoNotFlush;
 
         log::trace!("Entering the guest");
 
+        // Account the time spent handling the previous #VMEXIT.
+        let entry_tsc = unsafe { x86::time::rdtsc() };
+        if let Some((exit_tsc, slot)) = self.last_exit.take() {
+            self.statistics.record_handling(slot, entry_tsc.wrapping_sub(exit_tsc));
+        }
+
         // Run the guest until the #VMEXIT occurs.
-        unsafe { support::run_svm_guest(&mut self.registers, self.guest_vmcb_pa, self.host_vmcb_pa) };
+        if let Some(extended_state) = &mut self.extended_state {
+            extended_state.enter();
+        }
+        let swap_spec_ctrl = self.mitigations.switch_spec_ctrl && !self.mitigations.spec_ctrl_virtualization;
+        if swap_spec_ctrl && self.guest_spec_ctrl != self.host_spec_ctrl {
+            speculation::set_spec_ctrl(self.guest_spec_ctrl);
+        }
+        unsafe {
+            support::run_svm_guest(
+                &mut self.registers,
+                self.guest_vmcb_pa,
+                self.host_vmcb_pa,
+                self.mitigations.fill_rsb,
+            )
+        };
+        // The guest writes SPEC_CTRL without #VMEXIT, so read its value back.
+        if swap_spec_ctrl {
+            self.guest_spec_ctrl = speculation::spec_ctrl();
+            if self.guest_spec_ctrl != self.host_spec_ctrl {
+                speculation::set_spec_ctrl(self.host_spec_ctrl);
+            }
+        }
+        if self.mitigations.ibpb_on_exit {
+            speculation::ibpb();
+        }
+        if let Some(extended_state) = &mut self.extended_state {
+            extended_state.exit();
+        }
+
+        let exit_tsc = unsafe { x86::time::rdtsc() };
+        let slot = stats::exit_slot(self.guest_vmcb.exit_code());
+        self.statistics.record_exit(slot, exit_tsc.wrapping_sub(entry_tsc));
+        self.last_exit = Some((exit_tsc, slot));
+        self.trace.record(&TraceRecord {
+            tsc: exit_tsc,
+            exit_code: self.guest_vmcb.exit_code(),
+            exit_info1: self.guest_vmcb.exit_info1(),
+            exit_info2: self.guest_vmcb.exit_info2(),
+            rip: self.guest_vmcb.rip(),
+            cr3: self.guest_vmcb.cr3(),
+        });
 
         log::trace!("Exited the guest");
 
         // #VMEXIT occurred. Copy the guest register values from VMCB so that
         // `self.registers` is complete and up to date.
-        self.registers.rax = self.guest_vmcb.state_save_area.rax;
-        self.registers.rip = self.guest_vmcb.state_save_area.rip;
-        self.registers.rsp = self.guest_vmcb.state_save_area.rsp;
-        self.registers.rflags = self.guest_vmcb.state_save_area.rflags;
+        self.registers.rax = self.guest_vmcb.rax();
+        self.registers.rip = self.guest_vmcb.rip();
+        self.registers.rsp = self.guest_vmcb.rsp();
+        self.registers.rflags = self.guest_vmcb.rflags();
 
-        // We might have requested flushing TLB. Clear the request.
-        self.guest_vmcb.control_area.tlb_control = support::TlbControl::DoNotFlush as _;
-        self.guest_vmcb.control_area.vmcb_clean = u32::MAX;
+        self.guest_vmcb.mark_all_clean();
+        // We might have requested event injection. Clear the request. An event
+        // that could not be delivered is reported in EXITINTINFO instead, and
+        // is injected again unless the guest raises it again by itself.
+        // See: 15.7.2 Intercepts During IDT Interrupt Delivery
+        let interrupted = self.guest_vmcb.exit_int_info();
+        self.guest_vmcb
+            .set_event_inj(if exception::needs_reinjection(interrupted) { interrupted } else { 0 });
 
         // Handle #VMEXIT by translating it to the `VmExitReason` type.
         //
@@ -409,32 +673,373 @@ impl VCpu {
         //
         // For the list of possible exit codes,
         // See: Appendix C SVM Intercept Exit Codes
-        match self.guest_vmcb.control_area.exit_code {
+        match self.guest_vmcb.exit_code() {
+            VMEXIT_CR0_READ..=VMEXIT_CR15_WRITE => VmExitReason::CrAccess(InstructionInfo {
+                next_rip: self.guest_vmcb.nrip(),
+            }),
+            VMEXIT_DR0_READ..=VMEXIT_DR15_WRITE => VmExitReason::DrAccess(InstructionInfo {
+                next_rip: self.guest_vmcb.nrip(),
+            }),
             VMEXIT_EXCEPTION_SX => {
                 self.handle_security_exception();
                 VmExitReason::InitSignal
             }
+            VMEXIT_EXCP0..=VMEXIT_EXCP31 => VmExitReason::Exception(exception::exception_info(self)),
+            VMEXIT_RDTSC => VmExitReason::Rdtsc(InstructionInfo {
+                next_rip: self.guest_vmcb.nrip(),
+            }),
             VMEXIT_CPUID => VmExitReason::Cpuid(InstructionInfo {
-                next_rip: self.guest_vmcb.control_area.nrip,
+                next_rip: self.guest_vmcb.nrip(),
+            }),
+            VMEXIT_PAUSE => VmExitReason::Pause(InstructionInfo {
+                next_rip: self.guest_vmcb.nrip(),
+            }),
+            VMEXIT_HLT => VmExitReason::Hlt(InstructionInfo {
+                next_rip: self.guest_vmcb.nrip(),
+            }),
+            VMEXIT_SHUTDOWN => VmExitReason::Shutdown,
+            VMEXIT_VMMCALL => VmExitReason::Vmmcall(InstructionInfo {
+                next_rip: self.guest_vmcb.nrip(),
+            }),
+            VMEXIT_RDTSCP => VmExitReason::Rdtscp(InstructionInfo {
+                next_rip: self.guest_vmcb.nrip(),
+            }),
+            VMEXIT_XSETBV => VmExitReason::XSetBv(InstructionInfo {
+                next_rip: self.guest_vmcb.nrip(),
             }),
             VMEXIT_NPF => {
-                unsafe { asm!("int 3") };
                 self.handle_nested_page_fault();
                 VmExitReason::NestedPageFault
             }
-            _ => {
-                log::error!("{:#x?}", self.guest_vmcb_pa);
-                panic!(
-                    "Unhandled #VMEXIT reason: {:?}",
-                    self.guest_vmcb.control_area.exit_code
-                )
+            VMEXIT_INVALID => {
+                for violation in self.vmcb_violations() {
+                    log::error!("Illegal VMCB state: {violation}");
+                }
+                log::error!("{:#x?}", self.guest_vmcb);
+                panic!("VMRUN failed the consistency checks");
+            }
+            exit_code => VmExitReason::Unknown(UnknownExit {
+                exit_code,
+                exit_info1: self.guest_vmcb.exit_info1(),
+                exit_info2: self.guest_vmcb.exit_info2(),
+                next_rip: self.guest_vmcb.nrip(),
+            }),
+        }
+    }
+
+    /// Makes the processor leave the guest after the current #VMEXIT is
+    /// handled. See [`Self::devirtualize`].
+    pub(crate) fn request_devirtualization(&mut self) {
+        self.devirtualization_requested = true;
+    }
+
+    pub(crate) fn is_devirtualization_requested(&self) -> bool {
+        self.devirtualization_requested
+    }
+
+    /// Loads the guest state into the processor, disables SVM and continues
+    /// running the guest code outside of SVM. The resources of the vCPU are
+    /// freed. The host stack is not, as this runs on it. The TSC is set to the
+    /// value the guest reads, so that it continues from there.
+    pub(crate) fn devirtualize(mut self) -> ! {
+        const SVM_MSR_VM_HSAVE_PA: u32 = 0xc001_0117;
+        const EFER_SVME: u64 = 1 << 12;
+
+        log::info!("Devirtualizing processor {}", self.id);
+        // `self` was moved out of the published location.
+        processors::set_vcpu(self.id, core::ptr::null_mut());
+        self.trace.complete(TraceAction::Emulated);
+        if let Some((exit_tsc, slot)) = self.last_exit.take() {
+            self.statistics
+                .record_handling(slot, unsafe { x86::time::rdtsc() }.wrapping_sub(exit_tsc));
+        }
+
+        let state = self.guest_state();
+        let registers = self.registers;
+
+        // Load the state VMRUN and VMLOAD would have loaded, starting with FS,
+        // GS, TR, LDTR, KernelGsBase and the SYSCALL and SYSENTER MSRs.
+        // See: 15.5.2 VMSAVE and VMLOAD Instructions
+        support::vmload(self.guest_vmcb_pa);
+        unsafe {
+            x86::dtables::lgdt(&DescriptorTablePointer::<u64> {
+                limit: state.gdtr.limit as u16,
+                base: state.gdtr.base as *const u64,
+            });
+            support::lidt(&DescriptorTablePointer::<u64> {
+                limit: state.idtr.limit as u16,
+                base: state.idtr.base as *const u64,
+            });
+            // Written as-is, as the x86 crate drops bits it does not define.
+            asm!("mov cr4, {}", in(reg) state.cr4, options(nostack, preserves_flags));
+            asm!("mov cr0, {}", in(reg) state.cr0, options(nostack, preserves_flags));
+            cr3_write(state.cr3);
+            x86::controlregs::cr2_write(state.cr2);
+            asm!("mov dr6, {}", in(reg) state.dr6, options(nomem, nostack, preserves_flags));
+            x86::debugregs::dr7_write(x86::debugregs::Dr7(state.dr7 as usize));
+            wrmsr(msr::IA32_PAT, state.gpat);
+            wrmsr(msr::IA32_DEBUGCTL, state.dbg_ctl);
+        }
+        if self.mitigations.switch_spec_ctrl {
+            speculation::set_spec_ctrl(state.spec_ctrl);
+        }
+        if self.tsc_ratio != tsc::TSC_RATIO_ONE {
+            let _ = tsc::set_ratio(tsc::TSC_RATIO_ONE);
+        }
+        // Without the offset and the ratio, the guest would read the host TSC
+        // and see it jump, possibly backwards. Write the TSC it reads instead.
+        // This is skipped when the two are the same, as the write would move
+        // the TSC of this processor apart from the others by its latency.
+        // See: 15.30.5 TSC Ratio MSR (C000_0104h)
+        if self.tsc_ratio != tsc::TSC_RATIO_ONE || self.guest_vmcb.tsc_offset() != 0 {
+            unsafe { wrmsr(msr::IA32_TIME_STAMP_COUNTER, self.guest_tsc()) };
+        }
+        // The guest may not have used the extended state since the last world
+        // switch, in which case it is in the processor already.
+        if let Some(extended_state) = &mut self.extended_state {
+            extended_state.enter();
+        }
+
+        let id = self.id;
+        let selectors = u64::from(state.cs.selector)
+            | u64::from(state.ss.selector) << 16
+            | u64::from(state.ds.selector) << 32
+            | u64::from(state.es.selector) << 48;
+        stats::release_vmcb(Self::VMCB_BYTES);
+        drop(self);
+
+        // GIF is clear since #VMEXIT, and STGI is #UD once EFER.SVME is clear.
+        // Interrupts stay disabled by RFLAGS.IF until IRETQ.
+        // See: 15.17 Global Interrupt Flag, STGI and CLGI Instructions
+        support::stgi();
+        unsafe {
+            wrmsr(SVM_MSR_VM_HSAVE_PA, 0);
+            wrmsr(msr::IA32_EFER, state.efer & !EFER_SVME);
+        }
+        processors::set_virtualized(id, false);
+        unsafe { support::resume_unvirtualized(&registers, selectors) }
+    }
+
+    /// Returns every field of the guest VMCB that makes VMRUN fail with
+    /// #VMEXIT(INVALID).
+    pub fn vmcb_violations(&self) -> Vec<VmcbViolation> {
+        consistency::check(&self.guest_vmcb, &CpuLimits::current())
+    }
+
+    /// Panics if VMRUN would fail with the current guest VMCB. Debug builds
+    /// check the VMCB before the first VMRUN, and handlers can call this after
+    /// modifying the VMCB while debugging them.
+    #[cfg(debug_assertions)]
+    pub fn assert_vmcb_consistent(&self) {
+        let violations = self.vmcb_violations();
+        for violation in &violations {
+            log::error!("Illegal VMCB state: {violation}");
+        }
+        assert!(violations.is_empty(), "VMRUN would fail the consistency checks");
+    }
+
+    /// Returns the intercepts in effect for this vCPU.
+    pub fn intercepts(&self) -> InterceptSet {
+        InterceptSet::read_from(&self.guest_vmcb)
+    }
+
+    /// Replaces the intercepts of this vCPU. Takes effect on the next VMRUN.
+    pub fn set_intercepts(&mut self, intercepts: &InterceptSet) {
+        intercepts.write_to(&mut self.guest_vmcb);
+    }
+
+    /// Starts intercepting `intercepts` in addition to the current ones.
+    pub fn enable_intercepts<I: Intercept>(&mut self, intercepts: I) -> Result<(), InterceptError> {
+        let intercepts = self.intercepts().to_builder().enable(intercepts).build()?;
+        self.set_intercepts(&intercepts);
+        Ok(())
+    }
+
+    /// Stops intercepting `intercepts`.
+    pub fn disable_intercepts<I: Intercept>(&mut self, intercepts: I) -> Result<(), InterceptError> {
+        let intercepts = self.intercepts().to_builder().disable(intercepts).build()?;
+        self.set_intercepts(&intercepts);
+        Ok(())
+    }
+
+    /// Whether EXITINFO1 holds the decoded operands of intercepted instructions.
+    /// See: 15.33 Decode Assists
+    pub(crate) fn has_decode_assists(&self) -> bool {
+        self.decode_assists
+    }
+
+    /// Returns the index of the processor this vCPU runs on.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Returns the current guest CR3.
+    pub fn guest_cr3(&self) -> u64 {
+        self.guest_vmcb.cr3()
+    }
+
+    /// Returns the architectural state of the guest. RAX, RIP, RSP and RFLAGS
+    /// are taken from [`Self::regs`], which the host modifies instead of the
+    /// VMCB.
+    pub fn guest_state(&self) -> GuestState {
+        let mut state = self.guest_vmcb.guest_state();
+        state.rax = self.registers.rax;
+        state.rip = self.registers.rip;
+        state.rsp = self.registers.rsp;
+        state.rflags = self.registers.rflags;
+        if !self.mitigations.spec_ctrl_virtualization {
+            state.spec_ctrl = self.guest_spec_ctrl;
+        }
+        state
+    }
+
+    /// Replaces the architectural state of the guest with `state`. Takes effect
+    /// on the next VMRUN, which checks it for consistency.
+    /// See: 15.5.1 Basic Operation
+    pub fn set_guest_state(&mut self, state: &GuestState) {
+        self.guest_vmcb.set_guest_state(state);
+        self.registers.rax = state.rax;
+        self.registers.rip = state.rip;
+        self.registers.rsp = state.rsp;
+        self.registers.rflags = state.rflags;
+        if self.mitigations.switch_spec_ctrl && !self.mitigations.spec_ctrl_virtualization {
+            self.guest_spec_ctrl = state.spec_ctrl;
+        }
+    }
+
+    /// Returns the value added to the scaled host TSC to get the guest TSC.
+    pub fn tsc_offset(&self) -> i64 {
+        self.guest_vmcb.tsc_offset() as i64
+    }
+
+    /// Sets the value added to the scaled host TSC to get the guest TSC. Takes
+    /// effect on the next VMRUN.
+    pub fn set_tsc_offset(&mut self, offset: i64) {
+        self.guest_vmcb.set_tsc_offset(offset as u64);
+    }
+
+    /// Returns the TSC ratio of the guest. See [`Self::set_tsc_ratio`].
+    pub fn tsc_ratio(&self) -> u64 {
+        self.tsc_ratio
+    }
+
+    /// Sets the TSC ratio of the guest, a fixed-point number with 8 integer and
+    /// 32 fraction bits. [`crate::amd::TSC_RATIO_ONE`] runs the guest TSC at the
+    /// host rate. Must be called on the processor of this vCPU, as hooks are.
+    pub fn set_tsc_ratio(&mut self, ratio: u64) -> Result<(), TscError> {
+        tsc::set_ratio(ratio)?;
+        self.tsc_ratio = ratio;
+        Ok(())
+    }
+
+    /// Returns the TSC the guest reads now.
+    pub fn guest_tsc(&self) -> u64 {
+        tsc::guest_tsc(unsafe { x86::time::rdtsc() }, self.tsc_ratio, self.guest_vmcb.tsc_offset())
+    }
+
+    /// Returns the ASID of this vCPU.
+    pub fn asid(&self) -> u32 {
+        self.asid
+    }
+
+    /// Flushes the TLB entries of this guest described by `flush` before the
+    /// next VMRUN. Without flush-by-ASID support, flushing the ASID flushes the
+    /// entries of all ASIDs.
+    pub fn flush_tlb(&mut self, flush: TlbFlush) {
+        let control = match flush {
+            TlbFlush::Guest if self.flush_by_asid => support::TlbControl::FlushGuests,
+            TlbFlush::GuestNonGlobal if self.flush_by_asid => support::TlbControl::FlushGuestsNonGlobal,
+            TlbFlush::Guest | TlbFlush::GuestNonGlobal => support::TlbControl::FlushAll,
+            TlbFlush::Address(address) => {
+                // The guest does not run until the next VMRUN, so invalidating
+                // the entry now is the same as on the next VMRUN.
+                support::invlpga(address, self.asid);
+                return;
+            }
+        };
+        self.pending_flush = self.pending_flush.merge(control);
+    }
+
+    /// Returns how the extended state of this vCPU is switched.
+    pub fn extended_state_switch(&self) -> ExtendedStateSwitch {
+        self.extended_state.as_ref().map_or(ExtendedStateSwitch::Sse, ExtendedState::switch)
+    }
+
+    /// Changes how the extended state of this vCPU is switched. The XSAVE areas
+    /// are only allocated when the processor is virtualized with a mode other
+    /// than [`ExtendedStateSwitch::Sse`], so switching from it fails, and
+    /// switching to it switches to [`ExtendedStateSwitch::Lazy`].
+    pub fn set_extended_state_switch(&mut self, switch: ExtendedStateSwitch) -> Result<(), XsaveError> {
+        match &mut self.extended_state {
+            Some(extended_state) => {
+                let switch = if switch == ExtendedStateSwitch::Sse { ExtendedStateSwitch::Lazy } else { switch };
+                extended_state.set_switch(switch);
+                Ok(())
             }
+            None if switch == ExtendedStateSwitch::Sse => Ok(()),
+            None => Err(XsaveError::NotEnabled),
         }
     }
 
+    /// Saves the extended state of the guest, except XMM0-XMM15 and MXCSR which
+    /// are in [`Self::regs`], and returns it. Modifications are loaded on the
+    /// next VMRUN. Host code must call this before using extended registers
+    /// beyond SSE when the state is switched lazily. Returns `None` if the
+    /// state is not switched with XSAVE.
+    pub fn extended_state(&mut self) -> Option<&mut XsaveArea> {
+        self.extended_state.as_mut().map(ExtendedState::save_guest)
+    }
+
     pub fn regs(&mut self) -> &mut Registers {
         &mut self.registers
     }
+
+    /// Returns the registers of the guest that the VMCB does not hold, and
+    /// RAX, RIP, RSP and RFLAGS. See [`Self::guest_state`].
+    pub(crate) fn saved_regs(&self) -> &Registers {
+        &self.registers
+    }
+
+    /// Returns the guest VMCB as bytes.
+    pub(crate) fn guest_vmcb_bytes(&self) -> &[u8] {
+        let vmcb: &VmcbRaw = &self.guest_vmcb;
+        unsafe { core::slice::from_raw_parts((vmcb as *const VmcbRaw).cast(), core::mem::size_of::<VmcbRaw>()) }
+    }
+
+    /// Injects an exception into the guest on the next VMRUN.
+    ///
+    /// If an event is already to be injected, such as the event whose delivery
+    /// the #VMEXIT interrupted, the exception is combined with it as if raised
+    /// while delivering it: the two become #DF, or a triple fault that is
+    /// handled as #VMEXIT(SHUTDOWN), or the exception is delivered first.
+    /// See: 15.20 Event Injection
+    pub fn inject_exception(&mut self, vector: u8, error_code: Option<u32>) {
+        const DF: u8 = 8;
+        const EVENT_TYPE_EXCEPTION: u64 = 3;
+        const EVENT_ERROR_CODE_VALID: u64 = 1 << 11;
+        const EVENT_VALID: u64 = 1 << 31;
+
+        let (vector, error_code) = match exception::combine(self.guest_vmcb.event_inj(), vector) {
+            Delivery::Serial { deferred } => {
+                if deferred.is_some() {
+                    self.deferred_event = deferred;
+                }
+                (vector, error_code)
+            }
+            Delivery::DoubleFault => (DF, Some(0)),
+            Delivery::TripleFault => {
+                log::error!("Exception {vector:#x} while delivering #DF");
+                shutdown::handle_shutdown(self);
+            }
+        };
+
+        let mut event = u64::from(vector) | EVENT_TYPE_EXCEPTION << 8 | EVENT_VALID;
+        if let Some(error_code) = error_code {
+            event |= EVENT_ERROR_CODE_VALID | u64::from(error_code) << 32;
+        }
+        self.guest_vmcb.set_event_inj(event);
+        self.statistics.record_injection();
+    }
 }
 
 
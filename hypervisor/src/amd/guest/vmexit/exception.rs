@@ -0,0 +1,256 @@
+//! This module implements the handling of intercepted guest exceptions.
+//!
+//! Any exception vector can be intercepted per vCPU with
+//! [`crate::amd::VCpu::enable_intercepts`] and
+//! [`crate::amd::intercept::ExceptionIntercepts`]. Each intercepted exception
+//! is reported to [`crate::amd::hooks::VmExitHooks::exception`], which decides
+//! whether it is delivered to the guest as-is, delivered modified, or dropped.
+//! #SX is handled separately as it carries INIT.
+//! See: 15.12 Exception Intercepts
+//!
+//! An exception intercepted while the processor delivered another event leaves
+//! that event in EXITINTINFO. It is injected again on the next VMRUN, and an
+//! exception injected meanwhile is combined with it as the processor would:
+//! into #DF, into shutdown, or delivered first.
+//! See: 15.7.2 Intercepts During IDT Interrupt Delivery
+
+use bit_field::BitField;
+
+use crate::amd::guest::support;
+use crate::amd::hooks;
+use crate::amd::VCpu;
+
+const VMEXIT_EXCP0: u64 = 0x40;
+
+const BP: u8 = 3;
+const OF: u8 = 4;
+const DF: u8 = 8;
+const PF: u8 = 14;
+
+const EVENT_VALID: u64 = 1 << 31;
+const EVENT_TYPE_INTERRUPT: u64 = 0;
+const EVENT_TYPE_NMI: u64 = 2;
+const EVENT_TYPE_EXCEPTION: u64 = 3;
+
+/// The exceptions that push an error code.
+/// See: 8.2 Vectors
+const ERROR_CODE_VECTORS: u32 =
+    1 << 8 | 1 << 10 | 1 << 11 | 1 << 12 | 1 << 13 | 1 << 14 | 1 << 17 | 1 << 21 | 1 << 29 | 1 << 30;
+
+/// The exceptions that cause #DF when raised while delivering one of them.
+/// See: 8.2.9 Double-Fault Exception (#DF)
+const CONTRIBUTORY_VECTORS: u32 = 1 << 0 | 1 << 10 | 1 << 11 | 1 << 12 | 1 << 13;
+
+/// How an exception raised while delivering another event is delivered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Delivery {
+    /// The exception is delivered. The interrupt or NMI that was being
+    /// delivered, if any, is delivered after it. An exception that was being
+    /// delivered is raised again when the guest resumes.
+    Serial { deferred: Option<u64> },
+    /// #DF is delivered instead of both.
+    DoubleFault,
+    /// The processor shuts down.
+    TripleFault,
+}
+
+/// An intercepted guest exception, or an exception to deliver to the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExceptionInfo {
+    pub vector: u8,
+    /// The error code, for the exceptions that push one.
+    pub error_code: Option<u32>,
+    /// The faulting linear address, for #PF. It is loaded into CR2 when the
+    /// exception is delivered.
+    pub fault_address: Option<u64>,
+}
+
+/// What to do with an intercepted exception.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExceptionAction {
+    /// Delivers the exception to the guest as it was raised.
+    Reinject,
+    /// Delivers the given exception instead.
+    Inject(ExceptionInfo),
+    /// Resumes the guest without delivering the exception. RIP still points to
+    /// the faulting instruction unless the hook changes it.
+    /// The event whose delivery raised it, if any, is delivered again.
+    Swallow,
+}
+
+/// Handles #VMEXIT(EXCP0) to #VMEXIT(EXCP31), except #SX.
+pub fn handle_exception(guest: &mut VCpu, exception: &ExceptionInfo) {
+    match hooks::get().exception(guest, exception) {
+        ExceptionAction::Reinject => inject(guest, exception),
+        ExceptionAction::Inject(exception) => inject(guest, &exception),
+        ExceptionAction::Swallow => {}
+    }
+}
+
+/// Returns the intercepted exception described by the VMCB of `guest`.
+pub(crate) fn exception_info(guest: &VCpu) -> ExceptionInfo {
+    // "EXITINFO1 contains the error code if the exception pushes one. For #PF,
+    //  EXITINFO2 contains the faulting address (the value that would have been
+    //  loaded into CR2)."
+    // See: 15.12 Exception Intercepts
+    let vector = (guest.guest_vmcb.exit_code() - VMEXIT_EXCP0) as u8;
+    let error_code = (ERROR_CODE_VECTORS & 1 << vector != 0).then(|| guest.guest_vmcb.exit_info1() as u32);
+    let fault_address = (vector == PF).then(|| guest.guest_vmcb.exit_info2());
+    ExceptionInfo { vector, error_code, fault_address }
+}
+
+fn inject(guest: &mut VCpu, exception: &ExceptionInfo) {
+    // The processor does not update CR2 on an intercepted #PF.
+    if let Some(fault_address) = exception.fault_address {
+        guest.guest_vmcb.set_cr2(fault_address);
+    }
+
+    // #BP and #OF are intercepted with RIP pointing to INT3, INT 3 or INTO,
+    // and are delivered with RIP pointing to the next instruction.
+    if exception.vector == BP || exception.vector == OF {
+        guest.regs().rip = next_rip(guest);
+    }
+
+    guest.inject_exception(exception.vector, exception.error_code);
+}
+
+/// Returns whether `event` from EXITINTINFO must be injected again. Software
+/// interrupts and the #BP and #OF raised by INT3 and INTO are not, as RIP
+/// still points to the instruction, which raises them again.
+pub(crate) fn needs_reinjection(event: u64) -> bool {
+    if event & EVENT_VALID == 0 {
+        return false;
+    }
+    match event.get_bits(8..11) {
+        EVENT_TYPE_INTERRUPT | EVENT_TYPE_NMI => true,
+        EVENT_TYPE_EXCEPTION => !matches!(event.get_bits(0..8) as u8, BP | OF),
+        _ => false,
+    }
+}
+
+/// Returns how the exception `vector` is delivered when it is raised while
+/// the processor delivers `pending`, an event in the EVENTINJ format.
+/// See: 8.2.9 Double-Fault Exception (#DF)
+pub(crate) fn combine(pending: u64, vector: u8) -> Delivery {
+    if pending & EVENT_VALID == 0 {
+        return Delivery::Serial { deferred: None };
+    }
+    let contributory = |vector: u8| CONTRIBUTORY_VECTORS & 1 << vector != 0;
+    let pending_vector = pending.get_bits(0..8) as u8;
+    match pending.get_bits(8..11) {
+        EVENT_TYPE_EXCEPTION if pending_vector == DF && (contributory(vector) || vector == PF) => Delivery::TripleFault,
+        EVENT_TYPE_EXCEPTION
+            if contributory(pending_vector) && contributory(vector)
+                || pending_vector == PF && (contributory(vector) || vector == PF) =>
+        {
+            Delivery::DoubleFault
+        }
+        EVENT_TYPE_INTERRUPT | EVENT_TYPE_NMI => Delivery::Serial { deferred: Some(pending) },
+        _ => Delivery::Serial { deferred: None },
+    }
+}
+
+/// Returns the RIP after the instruction that raised the intercepted #BP or #OF.
+fn next_rip(guest: &mut VCpu) -> u64 {
+    // The processor saves nRIP for the exceptions INT3, INTO and BOUND raise.
+    // See: 15.7.1 State Saved on Exit
+    let rip = guest.regs().rip;
+    let nrip = guest.guest_vmcb.nrip();
+    let exit_vector = guest.guest_vmcb.exit_code().wrapping_sub(VMEXIT_EXCP0);
+    if (exit_vector == u64::from(BP) || exit_vector == u64::from(OF)) && nrip > rip {
+        return nrip;
+    }
+
+    // Otherwise, decode the length from the instruction bytes if they can be
+    // read. See `register_access` for when they can. INT3 is assumed if not.
+    let address = guest.guest_vmcb.cs_base().wrapping_add(rip);
+    let fetched = guest.guest_vmcb.guest_instruction_bytes();
+    let length = if !fetched.is_empty() {
+        soft_exception_length(fetched)
+    } else if guest.guest_vmcb.cpl() == 0 && address >= support::SYSTEM_ADDRESS_START {
+        // Read up to the end of the page, which is enough for these
+        // instructions unless they have redundant prefixes.
+        let in_page = (0x1000 - (address & 0xfff)).min(15) as usize;
+        // Safety: the instruction is in the system address space, which is
+        // mapped the same way in the host, and was just executed by the guest.
+        soft_exception_length(unsafe { core::slice::from_raw_parts(address as *const u8, in_page) })
+    } else {
+        None
+    };
+    rip + length.unwrap_or(1)
+}
+
+/// Returns the length of the INT3, INT 3 or INTO at the start of `bytes`.
+fn soft_exception_length(bytes: &[u8]) -> Option<u64> {
+    // See: 1.2 Instruction Prefixes
+    let prefixes = bytes
+        .iter()
+        .take_while(|&&byte| matches!(byte, 0x26 | 0x2e | 0x36 | 0x3e | 0x64 | 0x65 | 0x66 | 0x67 | 0xf0 | 0xf2 | 0xf3))
+        .count();
+    let length = match bytes[prefixes..] {
+        // INT3 and INTO.
+        [0xcc, ..] | [0xce, ..] => 1,
+        // INT 3 and INT 4.
+        [0xcd, 3 | 4, ..] => 2,
+        _ => return None,
+    };
+    Some((prefixes + length) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measures_soft_exceptions() {
+        assert_eq!(soft_exception_length(&[0xcc, 0x90]), Some(1));
+        assert_eq!(soft_exception_length(&[0xce]), Some(1));
+        assert_eq!(soft_exception_length(&[0xcd, 0x03]), Some(2));
+        assert_eq!(soft_exception_length(&[0x66, 0xcd, 0x04]), Some(3));
+        assert_eq!(soft_exception_length(&[0xcd, 0x2e]), None);
+        assert_eq!(soft_exception_length(&[0xcd]), None);
+        assert_eq!(soft_exception_length(&[0x66]), None);
+        assert_eq!(soft_exception_length(&[]), None);
+    }
+
+    fn exception(vector: u8) -> u64 {
+        EVENT_VALID | EVENT_TYPE_EXCEPTION << 8 | u64::from(vector)
+    }
+
+    #[test]
+    fn reinjects_only_events_not_raised_again() {
+        assert!(!needs_reinjection(0));
+        assert!(needs_reinjection(EVENT_VALID | EVENT_TYPE_INTERRUPT << 8 | 0x2f));
+        assert!(needs_reinjection(EVENT_VALID | EVENT_TYPE_NMI << 8 | 2));
+        assert!(needs_reinjection(exception(PF) | 1 << 11 | 2 << 32));
+        assert!(!needs_reinjection(exception(BP)));
+        assert!(!needs_reinjection(exception(OF)));
+        // Software interrupt.
+        assert!(!needs_reinjection(EVENT_VALID | 4 << 8 | 0x2e));
+    }
+
+    #[test]
+    fn combines_exceptions() {
+        const DE: u8 = 0;
+        const UD: u8 = 6;
+        const GP: u8 = 13;
+
+        assert_eq!(combine(0, GP), Delivery::Serial { deferred: None });
+        // Contributory, then contributory.
+        assert_eq!(combine(exception(DE), GP), Delivery::DoubleFault);
+        assert_eq!(combine(exception(GP), PF), Delivery::Serial { deferred: None });
+        // #PF, then contributory or #PF.
+        assert_eq!(combine(exception(PF), GP), Delivery::DoubleFault);
+        assert_eq!(combine(exception(PF), PF), Delivery::DoubleFault);
+        // Benign.
+        assert_eq!(combine(exception(UD), GP), Delivery::Serial { deferred: None });
+        assert_eq!(combine(exception(PF), UD), Delivery::Serial { deferred: None });
+        // #DF, then contributory or #PF.
+        assert_eq!(combine(exception(DF), PF), Delivery::TripleFault);
+        assert_eq!(combine(exception(DF), GP), Delivery::TripleFault);
+        assert_eq!(combine(exception(DF), UD), Delivery::Serial { deferred: None });
+
+        let interrupt = EVENT_VALID | EVENT_TYPE_INTERRUPT << 8 | 0xd1;
+        assert_eq!(combine(interrupt, PF), Delivery::Serial { deferred: Some(interrupt) });
+    }
+}
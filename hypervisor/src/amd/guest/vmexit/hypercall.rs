@@ -0,0 +1,207 @@
+//! This module implements the hypercall interface.
+//!
+//! The guest issues a hypercall by executing VMMCALL at CPL 0 with:
+//! - RCX: the command. See [`HypercallCommand`].
+//! - RDX: the linear address of the output buffer, if the command has output.
+//! - R8:  the size of the output buffer in bytes.
+//! - R9:  the command specific argument.
+//!
+//! On return, RAX holds the status. See [`HypercallStatus`]. VMMCALL at CPL > 0
+//! causes #UD as if the hypervisor was not present.
+//!
+//! Buffers are accessed by their linear address from the host, which cannot
+//! handle a page fault. They must be in nonpaged system memory, such as the
+//! nonpaged pool or a locked MDL mapping, and stay mapped until the hypercall
+//! returns. Only the range is checked to be in the system address space; a
+//! paged out or unmapped buffer brings down the system.
+
+use crate::amd::{stats, trace};
+use crate::amd::guest::support;
+use crate::amd::intercept::{InterceptChange, InterceptVectors};
+use crate::amd::{ExtendedStateSwitch, InstructionInfo, VCpu};
+
+/// The processor ID to pass to [`HypercallCommand::QueryStatistics`] to get the
+/// statistics summed over all processors.
+pub const ALL_PROCESSORS: u64 = u64::MAX;
+
+#[repr(u64)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HypercallCommand {
+    /// Writes [`stats::ExitStatistics`] of the processor specified by R9, or of
+    /// all processors if R9 is [`ALL_PROCESSORS`].
+    QueryStatistics = 1,
+    /// Clears the statistics of all processors.
+    ResetStatistics = 2,
+    /// Writes [`stats::MemoryUsage`].
+    QueryMemoryUsage = 3,
+    /// Writes the #VMEXIT trace of the processor specified by R9 in the format
+    /// described in [`trace`]. The buffer must be at least
+    /// [`trace::TRACE_EXPORT_SIZE`] bytes.
+    ExportTrace = 4,
+    /// Changes how the extended state of the calling processor is switched to
+    /// the mode specified by R9: 0 for [`ExtendedStateSwitch::Sse`], 1 for
+    /// [`ExtendedStateSwitch::Eager`] and 2 for [`ExtendedStateSwitch::Lazy`].
+    /// See [`VCpu::set_extended_state_switch`].
+    SetExtendedStateSwitch = 5,
+    /// Changes the intercepts of the calling processor as the
+    /// [`InterceptChange`] at the linear address in R9 specifies. The change
+    /// must be in nonpaged system memory, as buffers are. Only the intercepts
+    /// in [`InterceptVectors::RUNTIME`] can be changed.
+    ChangeIntercepts = 6,
+    /// Devirtualizes the calling processor. The guest continues after VMMCALL
+    /// outside of SVM, with RAX holding [`HypercallStatus::Success`].
+    Devirtualize = 7,
+}
+
+impl TryFrom<u64> for HypercallCommand {
+    type Error = HypercallStatus;
+
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Self::QueryStatistics),
+            2 => Ok(Self::ResetStatistics),
+            3 => Ok(Self::QueryMemoryUsage),
+            4 => Ok(Self::ExportTrace),
+            5 => Ok(Self::SetExtendedStateSwitch),
+            6 => Ok(Self::ChangeIntercepts),
+            7 => Ok(Self::Devirtualize),
+            _ => Err(HypercallStatus::InvalidCommand),
+        }
+    }
+}
+
+#[repr(u64)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HypercallStatus {
+    Success = 0,
+    InvalidCommand = 1,
+    InvalidParameter = 2,
+    BufferTooSmall = 3,
+}
+
+pub fn handle_vmmcall(guest: &mut VCpu, info: &InstructionInfo) {
+    const UD: u8 = 6;
+
+    if guest.guest_vmcb.cpl() != 0 {
+        guest.inject_exception(UD, None);
+        return;
+    }
+
+    let command = guest.regs().rcx;
+    let buffer = guest.regs().rdx;
+    let size = guest.regs().r8;
+    let argument = guest.regs().r9;
+    log::trace!("VMMCALL {command:#x?} {buffer:#x?} {size:#x?} {argument:#x?}");
+
+    let status = match HypercallCommand::try_from(command) {
+        Ok(HypercallCommand::QueryStatistics) => {
+            let statistics = if argument == ALL_PROCESSORS {
+                Some(stats::total())
+            } else {
+                stats::snapshot(argument as usize)
+            };
+            match statistics {
+                Some(statistics) => write_output(buffer, size, &statistics),
+                None => HypercallStatus::InvalidParameter,
+            }
+        }
+        Ok(HypercallCommand::ResetStatistics) => {
+            stats::reset();
+            HypercallStatus::Success
+        }
+        Ok(HypercallCommand::QueryMemoryUsage) => {
+            write_output(buffer, size, &stats::memory_usage())
+        }
+        Ok(HypercallCommand::ExportTrace) => export_trace(buffer, size, argument),
+        Ok(HypercallCommand::SetExtendedStateSwitch) => set_extended_state_switch(guest, argument),
+        Ok(HypercallCommand::ChangeIntercepts) => change_intercepts(guest, argument),
+        Ok(HypercallCommand::Devirtualize) => {
+            guest.request_devirtualization();
+            HypercallStatus::Success
+        }
+        Err(status) => status,
+    };
+
+    guest.regs().rax = status as u64;
+    guest.regs().rip = info.next_rip;
+}
+
+fn export_trace(buffer: u64, size: u64, processor_id: u64) -> HypercallStatus {
+    if let Err(status) = check_buffer(buffer, size, trace::TRACE_EXPORT_SIZE) {
+        return status;
+    }
+
+    // Safety: checked by `check_buffer` as in `write_output`.
+    let output = unsafe { core::slice::from_raw_parts_mut(buffer as *mut u8, size as usize) };
+    match trace::export(processor_id as usize, output) {
+        Ok(_) => HypercallStatus::Success,
+        Err(trace::TraceError::NotTraced { .. }) => HypercallStatus::InvalidParameter,
+        Err(trace::TraceError::BufferTooSmall { .. }) => HypercallStatus::BufferTooSmall,
+    }
+}
+
+fn set_extended_state_switch(guest: &mut VCpu, mode: u64) -> HypercallStatus {
+    let switch = match mode {
+        0 => ExtendedStateSwitch::Sse,
+        1 => ExtendedStateSwitch::Eager,
+        2 => ExtendedStateSwitch::Lazy,
+        _ => return HypercallStatus::InvalidParameter,
+    };
+    match guest.set_extended_state_switch(switch) {
+        Ok(()) => HypercallStatus::Success,
+        Err(_) => HypercallStatus::InvalidParameter,
+    }
+}
+
+fn change_intercepts(guest: &mut VCpu, address: u64) -> HypercallStatus {
+    let size = core::mem::size_of::<InterceptChange>();
+    if check_buffer(address, size as u64, size).is_err() {
+        return HypercallStatus::InvalidParameter;
+    }
+
+    // Safety: the change is in the system address space. The caller at CPL 0
+    // is responsible for it being nonpaged, as in `write_output`. Every bit
+    // pattern is a valid `InterceptChange`.
+    let change = unsafe { (address as *const InterceptChange).read_unaligned() };
+    if !InterceptVectors::RUNTIME.contains(&change.enable) || !InterceptVectors::RUNTIME.contains(&change.disable) {
+        return HypercallStatus::InvalidParameter;
+    }
+    match guest.intercepts().to_builder().apply(&change).build() {
+        Ok(intercepts) => {
+            guest.set_intercepts(&intercepts);
+            HypercallStatus::Success
+        }
+        Err(_) => HypercallStatus::InvalidParameter,
+    }
+}
+
+/// Checks that the guest buffer at `buffer` of `size` bytes can hold
+/// `required` bytes and lies entirely in the system address space.
+fn check_buffer(buffer: u64, size: u64, required: usize) -> Result<(), HypercallStatus> {
+    if buffer < support::SYSTEM_ADDRESS_START || buffer.checked_add(size).is_none() {
+        return Err(HypercallStatus::InvalidParameter);
+    }
+    if size < required as u64 {
+        return Err(HypercallStatus::BufferTooSmall);
+    }
+    Ok(())
+}
+
+/// Copies `value` into the guest buffer at `buffer`.
+fn write_output<T>(buffer: u64, size: u64, value: &T) -> HypercallStatus {
+    if let Err(status) = check_buffer(buffer, size, core::mem::size_of::<T>()) {
+        return status;
+    }
+
+    // Safety: the buffer is in the system address space and large enough. The
+    // caller at CPL 0 is responsible for it being nonpaged and writable. See
+    // the module documentation.
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            (value as *const T).cast::<u8>(),
+            buffer as *mut u8,
+            core::mem::size_of::<T>(),
+        )
+    };
+    HypercallStatus::Success
+}
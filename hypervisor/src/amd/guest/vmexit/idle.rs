@@ -0,0 +1,69 @@
+//! This module implements the handling of PAUSE and HLT, the instructions a
+//! guest executes while it waits.
+//!
+//! Neither is intercepted by default. PAUSE is intercepted when
+//! [`crate::amd::HypervisorConfig::pause_filter`] is set, in which case
+//! #VMEXIT(PAUSE) means the guest is spinning on a contended lock at the
+//! reported RIP. HLT is intercepted when
+//! [`crate::amd::HypervisorConfig::intercept_hlt`] is set, which is meant for
+//! tracing only: the processor does not halt, so an idle guest keeps the
+//! processor busy, and HLT cannot be intercepted at runtime.
+//! See: 15.14.4 Pause Intercept Filtering
+
+use bit_field::BitField;
+use x86::cpuid::cpuid;
+
+use crate::amd::config::PauseFilter;
+use crate::amd::hooks;
+use crate::amd::{InstructionInfo, VCpu};
+
+/// The pause filter supported by the processor.
+/// See: E.4.10 Function 8000_000Ah—SVM Features
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct PauseFilterSupport {
+    pub(crate) count: bool,
+    pub(crate) threshold: bool,
+}
+
+impl PauseFilterSupport {
+    pub(crate) fn current() -> Self {
+        let edx = cpuid!(0x8000_000a).edx;
+        Self {
+            count: edx.get_bit(10),
+            threshold: edx.get_bit(12),
+        }
+    }
+
+    /// Returns the filter to program, or `None` if the processor cannot filter
+    /// PAUSE. Intercepting every PAUSE would slow down every spin loop of the
+    /// guest, so PAUSE is left alone in that case.
+    pub(crate) fn apply(self, filter: PauseFilter) -> Option<PauseFilter> {
+        if !self.count {
+            return None;
+        }
+        Some(PauseFilter {
+            count: filter.count,
+            threshold: if self.threshold { filter.threshold } else { 0 },
+        })
+    }
+}
+
+/// Handles #VMEXIT(PAUSE). The guest executed more PAUSE instructions in a row
+/// than the filter count.
+pub fn handle_pause(guest: &mut VCpu, info: &InstructionInfo) {
+    let rip = guest.regs().rip;
+    hooks::get().spin_loop(guest, rip);
+
+    // PAUSE is only a hint, so completing it without delay is correct.
+    guest.regs().rip = info.next_rip;
+}
+
+/// Handles #VMEXIT(HLT).
+pub fn handle_hlt(guest: &mut VCpu, info: &InstructionInfo) {
+    hooks::get().halt(guest);
+
+    // Resume the guest after HLT as if an interrupt woke it up. HLT may end on
+    // any interrupt, so the guest already expects spurious wake-ups, but the
+    // processor does not actually halt while HLT is intercepted.
+    guest.regs().rip = info.next_rip;
+}
@@ -1,17 +1,37 @@
+pub mod exception;
+pub mod hypercall;
+pub mod idle;
+pub mod register_access;
+pub mod shutdown;
+pub mod tsc;
+pub mod unknown;
+pub mod xsetbv;
 
-use core::arch::asm;
+use crate::amd::config::{self, DebugBreaks};
 use x86::cpuid::cpuid;
 use crate::amd::VCpu;
+use exception::ExceptionInfo;
+use unknown::UnknownExit;
 
 pub enum VmExitReason {
     Cpuid(InstructionInfo),
-    Rdmsr(InstructionInfo),
-    Wrmsr(InstructionInfo),
     XSetBv(InstructionInfo),
+    Rdtsc(InstructionInfo),
+    Rdtscp(InstructionInfo),
+    /// The guest is spinning on PAUSE. See [`idle`].
+    Pause(InstructionInfo),
+    Hlt(InstructionInfo),
+    CrAccess(InstructionInfo),
+    DrAccess(InstructionInfo),
+    Exception(ExceptionInfo),
+    Vmmcall(InstructionInfo),
     InitSignal,
     StartupIpi,
     NestedPageFault,
-    Unknown,
+    /// The guest triple faulted. See [`shutdown`].
+    Shutdown,
+    /// An exit code the hypervisor has no handler for. See [`unknown`].
+    Unknown(UnknownExit),
 }
 
 pub struct InstructionInfo {
@@ -27,11 +47,15 @@ pub fn handle_cpuid(guest: &mut VCpu, info: &InstructionInfo) {
     if leaf == 1 {
         cpuid_result.ecx &= !(1 << 5);
     }
+    xsetbv::mask_cpuid(leaf, sub_leaf, &mut cpuid_result);
+    for cpuid_override in &config::read().cpuid_overrides {
+        cpuid_override.apply(leaf, sub_leaf, &mut cpuid_result);
+    }
     guest.regs().rax = u64::from(cpuid_result.eax);
     guest.regs().rbx = u64::from(cpuid_result.ebx);
     guest.regs().rcx = u64::from(cpuid_result.ecx);
     guest.regs().rdx = u64::from(cpuid_result.edx);
     guest.regs().rip = info.next_rip;
 
-    unsafe { asm!("int 3") }
+    config::debug_break(DebugBreaks::CPUID);
 }
@@ -0,0 +1,667 @@
+//! This module implements the emulation of intercepted accesses to the control
+//! registers CR0, CR3, CR4 and CR8, and to the debug registers DR0-DR7.
+//!
+//! The general purpose register of MOV is taken from EXITINFO1 when the
+//! processor supports decode assists. Otherwise, and for CLTS, LMSW and SMSW,
+//! the instruction is decoded. Its bytes are taken from the VMCB when the
+//! processor fetched them, or read from the guest when it runs at CPL 0 in the
+//! system address space. SMSW is legal at any CPL, so a user mode SMSW may have
+//! no bytes to decode. Instructions that cannot be fetched or decoded, such as
+//! the memory forms of LMSW and SMSW, and accesses to control registers other
+//! than CR0, CR3, CR4 and CR8, cause #UD instead of being emulated.
+//! See: 15.33 Decode Assists
+
+use bit_field::BitField;
+use spin::Once;
+use x86::cpuid::{cpuid, CpuIdResult};
+
+use crate::amd::guest::support;
+use crate::amd::guest::support::TlbFlush;
+use crate::amd::guest::support::consistency::CR4_VALID_BITS;
+use crate::amd::hooks::{self, HookAction};
+use crate::amd::{InstructionInfo, VCpu};
+
+const VMEXIT_CR0_WRITE: u64 = 0x10;
+const VMEXIT_DR0_WRITE: u64 = 0x30;
+
+const GP: u8 = 13;
+const UD: u8 = 6;
+
+const CR0_PE: u64 = 1 << 0;
+const CR0_TS: u64 = 1 << 3;
+const CR0_ET: u64 = 1 << 4;
+const CR0_WP: u64 = 1 << 16;
+const CR0_NW: u64 = 1 << 29;
+const CR0_CD: u64 = 1 << 30;
+const CR0_PG: u64 = 1 << 31;
+// The bits LMSW loads: PE, MP, EM and TS.
+const CR0_MSW_BITS: u64 = 0xf;
+
+const CR3_NO_FLUSH: u64 = 1 << 63;
+// See: 3.1.2 CR3 Register
+const CR3_LONG_MODE_RESERVED_BITS: u64 = 0x7ff << 52;
+
+const CR4_DE: u64 = 1 << 3;
+const CR4_PSE: u64 = 1 << 4;
+const CR4_PAE: u64 = 1 << 5;
+const CR4_PGE: u64 = 1 << 7;
+const CR4_LA57: u64 = 1 << 12;
+const CR4_PCIDE: u64 = 1 << 17;
+const CR4_SMEP: u64 = 1 << 20;
+const CR4_SMAP: u64 = 1 << 21;
+const CR4_PKE: u64 = 1 << 22;
+
+const EFER_LME: u64 = 1 << 8;
+const EFER_LMA: u64 = 1 << 10;
+
+const SEGMENT_ATTRIB_L: u16 = 1 << 9;
+
+const EVENT_VALID: u64 = 1 << 31;
+
+/// Whether the guest reads or writes the register.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessDirection {
+    Read,
+    Write,
+}
+
+/// An instruction that accesses a control or debug register.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegisterInstruction {
+    /// MOV from or to CR`cr` with the general purpose register `gpr`.
+    MovCr { cr: u8, gpr: u8, direction: AccessDirection },
+    /// MOV from or to DR`dr` with the general purpose register `gpr`.
+    MovDr { dr: u8, gpr: u8, direction: AccessDirection },
+    /// CLTS, which clears CR0.TS.
+    Clts,
+    /// LMSW with the general purpose register `gpr` as the source.
+    Lmsw { gpr: u8 },
+    /// SMSW with the general purpose register `gpr` as the destination.
+    Smsw { gpr: u8, operand_size: u8 },
+}
+
+/// An intercepted access to a control register.
+#[derive(Clone, Copy, Debug)]
+pub struct CrAccess {
+    /// The number of the control register.
+    pub register: u8,
+    pub direction: AccessDirection,
+    pub instruction: RegisterInstruction,
+    /// The value read from, or written to, the control register. For CLTS and
+    /// LMSW, this is the resulting value of CR0.
+    pub value: u64,
+}
+
+/// An intercepted access to a debug register.
+#[derive(Clone, Copy, Debug)]
+pub struct DrAccess {
+    /// The number of the debug register. DR4 and DR5 are reported as DR6 and
+    /// DR7 they alias.
+    pub register: u8,
+    pub direction: AccessDirection,
+    /// The general purpose register the value is moved from or to.
+    pub gpr: u8,
+    /// The value read from, or written to, the debug register.
+    pub value: u64,
+}
+
+/// Handles #VMEXIT(CRx_READ) and #VMEXIT(CRx_WRITE).
+pub fn handle_cr_access(guest: &mut VCpu, info: &InstructionInfo) {
+    let exit_code = guest.guest_vmcb.exit_code();
+    let exit_info1 = guest.guest_vmcb.exit_info1();
+    let register = exit_code.get_bits(0..4) as u8;
+    let direction = if exit_code >= VMEXIT_CR0_WRITE {
+        AccessDirection::Write
+    } else {
+        AccessDirection::Read
+    };
+
+    // "EXITINFO1[63] (...) is set if the instruction is MOV CR, and cleared
+    //  if it is CLTS, LMSW or SMSW. EXITINFO1[3:0] holds the number of the GPR."
+    // See: 15.33.1 MOV CRx/DRx Intercepts
+    let instruction = if guest.has_decode_assists() && exit_info1.get_bit(63) {
+        Some(RegisterInstruction::MovCr { cr: register, gpr: exit_info1.get_bits(0..4) as u8, direction })
+    } else {
+        decode_guest_instruction(guest)
+    };
+    let matches_exit = |instruction: &RegisterInstruction| match *instruction {
+        RegisterInstruction::MovCr { cr, direction: d, .. } => cr == register && d == direction,
+        RegisterInstruction::Clts | RegisterInstruction::Lmsw { .. } => {
+            register == 0 && direction == AccessDirection::Write
+        }
+        RegisterInstruction::Smsw { .. } => register == 0 && direction == AccessDirection::Read,
+        RegisterInstruction::MovDr { .. } => false,
+    };
+    let Some(instruction) = instruction.filter(matches_exit) else {
+        log::warn!("Unsupported access to CR{register} at {:#x?}", guest.regs().rip);
+        guest.inject_exception(UD, None);
+        return;
+    };
+    if !is_supported_cr(register) {
+        guest.inject_exception(UD, None);
+        return;
+    }
+
+    let value = match (direction, instruction) {
+        (AccessDirection::Read, _) => read_cr(guest, register),
+        (AccessDirection::Write, RegisterInstruction::MovCr { gpr, .. }) => operand(guest, gpr),
+        (AccessDirection::Write, RegisterInstruction::Clts) => guest.guest_vmcb.cr0() & !CR0_TS,
+        (AccessDirection::Write, RegisterInstruction::Lmsw { gpr }) => {
+            // LMSW can set CR0.PE but not clear it.
+            let cr0 = guest.guest_vmcb.cr0();
+            cr0 & !CR0_MSW_BITS | guest.regs().gpr(gpr) & CR0_MSW_BITS | cr0 & CR0_PE
+        }
+        (AccessDirection::Write, _) => unreachable!(),
+    };
+
+    let mut access = CrAccess { register, direction, instruction, value };
+    if hooks::get().cr_access(guest, &mut access) == HookAction::Emulate {
+        let emulated = match access.direction {
+            AccessDirection::Read => store_cr(guest, &access),
+            AccessDirection::Write => write_cr(guest, access.register, access.value),
+        };
+        if !emulated {
+            guest.inject_exception(GP, Some(0));
+        }
+    }
+    complete(guest, info);
+}
+
+/// Handles #VMEXIT(DRx_READ) and #VMEXIT(DRx_WRITE).
+pub fn handle_dr_access(guest: &mut VCpu, info: &InstructionInfo) {
+    let exit_code = guest.guest_vmcb.exit_code();
+    let mut register = exit_code.get_bits(0..4) as u8;
+    let direction = if exit_code >= VMEXIT_DR0_WRITE {
+        AccessDirection::Write
+    } else {
+        AccessDirection::Read
+    };
+
+    // DR8-DR15 do not exist, and DR4 and DR5 are only aliases of DR6 and DR7
+    // while CR4.DE is clear.
+    // See: 13.1.1 Debug Registers
+    if register > 7 || (register == 4 || register == 5) && guest.guest_vmcb.cr4() & CR4_DE != 0 {
+        guest.inject_exception(UD, None);
+        return;
+    }
+    if register == 4 || register == 5 {
+        register += 2;
+    }
+
+    // "EXITINFO1[3:0] holds the number of the GPR."
+    // See: 15.33.1 MOV CRx/DRx Intercepts
+    let gpr = if guest.has_decode_assists() {
+        guest.guest_vmcb.exit_info1().get_bits(0..4) as u8
+    } else {
+        match decode_guest_instruction(guest) {
+            Some(RegisterInstruction::MovDr { gpr, .. }) => gpr,
+            _ => {
+                log::warn!("Unsupported access to DR{register} at {:#x?}", guest.regs().rip);
+                guest.inject_exception(UD, None);
+                return;
+            }
+        }
+    };
+
+    let value = match direction {
+        AccessDirection::Read => read_dr(guest, register),
+        AccessDirection::Write => Some(operand(guest, gpr)),
+    };
+    let Some(value) = value else {
+        guest.inject_exception(UD, None);
+        return;
+    };
+
+    let mut access = DrAccess { register, direction, gpr, value };
+    if hooks::get().dr_access(guest, &mut access) == HookAction::Emulate {
+        match access.direction {
+            AccessDirection::Read => store(guest, access.gpr, access.value),
+            AccessDirection::Write => {
+                if !write_dr(guest, access.register, access.value) {
+                    guest.inject_exception(GP, Some(0));
+                }
+            }
+        }
+    }
+    complete(guest, info);
+}
+
+/// Moves the guest past the instruction unless it raised an exception.
+fn complete(guest: &mut VCpu, info: &InstructionInfo) {
+    if guest.guest_vmcb.event_inj() & EVENT_VALID == 0 {
+        guest.regs().rip = info.next_rip;
+    }
+}
+
+fn is_64bit_mode(guest: &VCpu) -> bool {
+    guest.guest_vmcb.efer() & EFER_LMA != 0 && guest.guest_vmcb.cs_attrib() & SEGMENT_ATTRIB_L != 0
+}
+
+/// Returns the value of the source operand `gpr`. Outside 64-bit mode the
+/// operand is 32-bit.
+fn operand(guest: &mut VCpu, gpr: u8) -> u64 {
+    let value = guest.regs().gpr(gpr);
+    if is_64bit_mode(guest) { value } else { value & u64::from(u32::MAX) }
+}
+
+/// Stores `value` into the destination operand `gpr`.
+fn store(guest: &mut VCpu, gpr: u8, value: u64) {
+    let value = if is_64bit_mode(guest) { value } else { value & u64::from(u32::MAX) };
+    guest.regs().set_gpr(gpr, value);
+}
+
+/// Stores the value of a control register read. Returns `false` if the
+/// instruction does not read one, which happens if a hook changed the
+/// direction of CLTS or LMSW.
+fn store_cr(guest: &mut VCpu, access: &CrAccess) -> bool {
+    match access.instruction {
+        RegisterInstruction::Smsw { gpr, operand_size: 2 } => {
+            let value = guest.regs().gpr(gpr) & !0xffff | access.value & 0xffff;
+            guest.regs().set_gpr(gpr, value);
+        }
+        RegisterInstruction::Smsw { gpr, operand_size: 4 } => {
+            guest.regs().set_gpr(gpr, access.value & u64::from(u32::MAX));
+        }
+        RegisterInstruction::Smsw { gpr, .. } => guest.regs().set_gpr(gpr, access.value),
+        RegisterInstruction::MovCr { gpr, .. } => store(guest, gpr, access.value),
+        RegisterInstruction::Clts | RegisterInstruction::Lmsw { .. } | RegisterInstruction::MovDr { .. } => {
+            return false;
+        }
+    }
+    true
+}
+
+/// Returns whether accesses to the control register are emulated. Others
+/// either do not exist or are never intercepted, as
+/// [`crate::amd::intercept::InterceptSetBuilder::build`] rejects them.
+pub(crate) fn is_supported_cr(register: u8) -> bool {
+    matches!(register, 0 | 3 | 4 | 8)
+}
+
+/// Reads one of the control registers [`is_supported_cr`] accepts.
+fn read_cr(guest: &VCpu, register: u8) -> u64 {
+    match register {
+        0 => guest.guest_vmcb.cr0(),
+        3 => guest.guest_vmcb.cr3(),
+        4 => guest.guest_vmcb.cr4(),
+        // Without V_INTR_MASKING, the guest uses the physical TPR.
+        // See: 15.21.1 Virtualizing APIC.TPR
+        8 => support::cr8(),
+        _ => unreachable!(),
+    }
+}
+
+/// Writes `value` to one of the control registers [`is_supported_cr`] accepts.
+/// Returns `false` if the write must cause #GP, including to an unsupported
+/// control register a hook redirected the write to.
+/// See: 3.1 System-Control Registers
+fn write_cr(guest: &mut VCpu, register: u8, value: u64) -> bool {
+    let vmcb = &mut guest.guest_vmcb;
+    let mut flush = None;
+    match register {
+        0 => {
+            let value = value | CR0_ET;
+            let old = vmcb.cr0();
+            if value.get_bits(32..) != 0
+                || value & CR0_NW != 0 && value & CR0_CD == 0
+                || value & CR0_PG != 0 && value & CR0_PE == 0
+            {
+                return false;
+            }
+
+            // Setting CR0.PG with EFER.LME activates long mode, and clearing it
+            // deactivates long mode, unless in 64-bit mode.
+            // See: 14.6.1 Activating Long Mode
+            let efer = vmcb.efer();
+            if efer & EFER_LME != 0 && old & CR0_PG == 0 && value & CR0_PG != 0 {
+                if vmcb.cr4() & CR4_PAE == 0 {
+                    return false;
+                }
+                vmcb.set_efer(efer | EFER_LMA);
+            }
+            if efer & EFER_LMA != 0 && value & CR0_PG == 0 {
+                if vmcb.cs_attrib() & SEGMENT_ATTRIB_L != 0 {
+                    return false;
+                }
+                vmcb.set_efer(efer & !EFER_LMA);
+            }
+
+            vmcb.set_cr0(value);
+            if (old ^ value) & (CR0_PG | CR0_WP | CR0_CD | CR0_NW) != 0 {
+                flush = Some(TlbFlush::Guest);
+            }
+        }
+        3 => {
+            let long_mode = vmcb.efer() & EFER_LMA != 0;
+            if long_mode && value & CR3_LONG_MODE_RESERVED_BITS != 0 {
+                return false;
+            }
+
+            // With CR4.PCIDE, bit 63 requests not to flush the TLB entries of
+            // the PCID and is not stored.
+            // See: 5.5.1 Process Context Identifier
+            if vmcb.cr4() & CR4_PCIDE != 0 && value & CR3_NO_FLUSH != 0 {
+                vmcb.set_cr3(value & !CR3_NO_FLUSH);
+            } else {
+                vmcb.set_cr3(value);
+                flush = Some(TlbFlush::GuestNonGlobal);
+            }
+        }
+        4 => {
+            let old = vmcb.cr4();
+            let long_mode = vmcb.efer() & EFER_LMA != 0;
+            // LA57 cannot be changed while long mode is active.
+            if value & !supported_cr4_bits() != 0
+                || long_mode && value & CR4_PAE == 0
+                || long_mode && (old ^ value) & CR4_LA57 != 0
+                || !long_mode && value & CR4_PCIDE != 0
+                || old & CR4_PCIDE == 0 && value & CR4_PCIDE != 0 && vmcb.cr3().get_bits(0..12) != 0
+            {
+                return false;
+            }
+
+            vmcb.set_cr4(value);
+            let flush_bits = CR4_PSE | CR4_PAE | CR4_PGE | CR4_PCIDE | CR4_SMEP | CR4_SMAP | CR4_PKE;
+            if (old ^ value) & flush_bits != 0 {
+                flush = Some(TlbFlush::Guest);
+            }
+        }
+        8 => {
+            // Bits 63:4 are reserved.
+            if value.get_bits(4..) != 0 {
+                return false;
+            }
+            support::cr8_write(value);
+        }
+        _ => return false,
+    }
+    if let Some(flush) = flush {
+        guest.flush_tlb(flush);
+    }
+    true
+}
+
+/// Returns the CR4 bits the processor supports. Setting any other bit causes
+/// #GP, even if the bit is defined.
+fn supported_cr4_bits() -> u64 {
+    static SUPPORTED_CR4_BITS: Once<u64> = Once::new();
+    *SUPPORTED_CR4_BITS.call_once(|| {
+        let leaf7 = if cpuid!(0).eax >= 7 { cpuid!(7, 0) } else { CpuIdResult { eax: 0, ebx: 0, ecx: 0, edx: 0 } };
+        cr4_bits_from_cpuid(cpuid!(1), leaf7) & CR4_VALID_BITS
+    })
+}
+
+/// Maps the features CPUID Fn0000_0001 and Fn0000_0007_x0 report to the CR4
+/// bits that enable them.
+/// See: 3.1.3 CR4 Register
+fn cr4_bits_from_cpuid(leaf1: CpuIdResult, leaf7: CpuIdResult) -> u64 {
+    let features = [
+        (0, leaf1.edx.get_bit(1)),   // VME
+        (1, leaf1.edx.get_bit(1)),   // PVI
+        (2, leaf1.edx.get_bit(4)),   // TSD
+        (3, leaf1.edx.get_bit(2)),   // DE
+        (4, leaf1.edx.get_bit(3)),   // PSE
+        (5, leaf1.edx.get_bit(6)),   // PAE
+        (6, leaf1.edx.get_bit(7)),   // MCE
+        (7, leaf1.edx.get_bit(13)),  // PGE
+        (8, true),                   // PCE
+        (9, leaf1.edx.get_bit(24)),  // OSFXSR
+        (10, leaf1.edx.get_bit(25)), // OSXMMEXCPT
+        (11, leaf7.ecx.get_bit(2)),  // UMIP
+        (12, leaf7.ecx.get_bit(16)), // LA57
+        (16, leaf7.ebx.get_bit(0)),  // FSGSBASE
+        (17, leaf1.ecx.get_bit(17)), // PCIDE
+        (18, leaf1.ecx.get_bit(26)), // OSXSAVE
+        (20, leaf7.ebx.get_bit(7)),  // SMEP
+        (21, leaf7.ebx.get_bit(20)), // SMAP
+        (22, leaf7.ecx.get_bit(3)),  // PKE
+        (23, leaf7.ecx.get_bit(7)),  // CET
+        (24, leaf7.ecx.get_bit(31)), // PKS
+    ];
+    features.iter().filter(|(_, supported)| *supported).fold(0, |bits, (bit, _)| bits | 1 << bit)
+}
+
+/// Reads the debug register, or returns `None` for DR4, DR5 and DR8-DR15.
+/// DR0-DR3 are not part of the VMCB and hold the guest values while the host
+/// runs.
+fn read_dr(guest: &VCpu, register: u8) -> Option<u64> {
+    let value = unsafe {
+        match register {
+            0 => x86::debugregs::dr0() as u64,
+            1 => x86::debugregs::dr1() as u64,
+            2 => x86::debugregs::dr2() as u64,
+            3 => x86::debugregs::dr3() as u64,
+            6 => guest.guest_vmcb.dr6(),
+            7 => guest.guest_vmcb.dr7(),
+            _ => return None,
+        }
+    };
+    Some(value)
+}
+
+/// Writes `value` to the debug register. Returns `false` if the write must
+/// cause #GP, including to DR4, DR5 and DR8-DR15 a hook redirected the write
+/// to.
+/// See: 13.1.1 Debug Registers
+fn write_dr(guest: &mut VCpu, register: u8, value: u64) -> bool {
+    unsafe {
+        match register {
+            0 => x86::debugregs::dr0_write(value as usize),
+            1 => x86::debugregs::dr1_write(value as usize),
+            2 => x86::debugregs::dr2_write(value as usize),
+            3 => x86::debugregs::dr3_write(value as usize),
+            6 | 7 if value.get_bits(32..) != 0 => return false,
+            6 => guest.guest_vmcb.set_dr6(value),
+            7 => guest.guest_vmcb.set_dr7(value),
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// Fetches and decodes the instruction at the guest RIP. Returns `None` if the
+/// instruction cannot be fetched or is not supported.
+fn decode_guest_instruction(guest: &mut VCpu) -> Option<RegisterInstruction> {
+    let fetched = guest.guest_vmcb.guest_instruction_bytes();
+    if !fetched.is_empty() {
+        return decode(fetched);
+    }
+
+    // MOV CRx, MOV DRx, CLTS and LMSW raise #GP at CPL > 0 before the
+    // intercept is checked, but SMSW does not. Only the system address space
+    // is mapped the same way in the host, so an instruction elsewhere cannot
+    // be read.
+    // See: 15.9 Instruction Intercepts
+    let address = guest.guest_vmcb.cs_base().wrapping_add(guest.regs().rip);
+    if guest.guest_vmcb.cpl() != 0
+        || address < support::SYSTEM_ADDRESS_START
+        || address.checked_add(MAX_INSTRUCTION_LENGTH as u64).is_none()
+    {
+        return None;
+    }
+
+    // Read up to the end of the page first, as the next page may not be
+    // mapped. The instruction only continues there if it did not decode.
+    let in_page = (PAGE_SIZE - (address as usize & (PAGE_SIZE - 1))).min(MAX_INSTRUCTION_LENGTH);
+    for length in [in_page, MAX_INSTRUCTION_LENGTH] {
+        // Safety: the instruction is in the system address space, which is
+        // mapped the same way in the host, and was just executed by the guest
+        // at CPL 0. Bytes past the first page are only read if the
+        // instruction extends into the next page.
+        let bytes = unsafe { core::slice::from_raw_parts(address as *const u8, length) };
+        if let Ok(instruction) = decode_partial(bytes) {
+            return instruction;
+        }
+    }
+    None
+}
+
+// See: 1.1 Instruction Byte Order
+const MAX_INSTRUCTION_LENGTH: usize = 15;
+const PAGE_SIZE: usize = 0x1000;
+
+/// The bytes ended before the instruction could be decoded.
+struct Incomplete;
+
+/// Decodes the register forms of MOV CRx, MOV DRx, CLTS, LMSW and SMSW.
+/// Returns `None` for other instructions, including the memory forms of LMSW
+/// and SMSW.
+/// See: 3 General-Purpose Instruction Reference and 4 System Instruction Reference
+pub fn decode(bytes: &[u8]) -> Option<RegisterInstruction> {
+    decode_partial(bytes).ok().flatten()
+}
+
+/// Decodes as [`decode`], but tells apart bytes that end too early.
+fn decode_partial(bytes: &[u8]) -> Result<Option<RegisterInstruction>, Incomplete> {
+    const LOCK: u8 = 0xf0;
+    const OPERAND_SIZE: u8 = 0x66;
+
+    let mut lock = false;
+    let mut operand_size = 4;
+    let mut rex = 0u8;
+    let mut bytes = bytes.iter().copied();
+    let mut byte = bytes.next().ok_or(Incomplete)?;
+
+    // Legacy prefixes, then REX.
+    // See: 1.2 Instruction Prefixes
+    while matches!(byte, 0x26 | 0x2e | 0x36 | 0x3e | 0x64 | 0x65 | 0x66 | 0x67 | 0xf0 | 0xf2 | 0xf3) {
+        match byte {
+            LOCK => lock = true,
+            OPERAND_SIZE => operand_size = 2,
+            _ => {}
+        }
+        byte = bytes.next().ok_or(Incomplete)?;
+    }
+    if byte & 0xf0 == 0x40 {
+        rex = byte;
+        byte = bytes.next().ok_or(Incomplete)?;
+    }
+    if rex.get_bit(3) {
+        operand_size = 8;
+    }
+    if byte != 0x0f {
+        return Ok(None);
+    }
+
+    let opcode = bytes.next().ok_or(Incomplete)?;
+    if opcode == 0x06 {
+        return Ok(Some(RegisterInstruction::Clts));
+    }
+
+    // The ModRM byte. MOV CRx and MOV DRx always use the register form
+    // regardless of ModRM.mod.
+    let modrm = bytes.next().ok_or(Incomplete)?;
+    let register_form = modrm.get_bits(6..8) == 0b11;
+    let reg = modrm.get_bits(3..6) | u8::from(rex.get_bit(2)) << 3;
+    let rm = modrm.get_bits(0..3) | u8::from(rex.get_bit(0)) << 3;
+    Ok(match opcode {
+        0x20 | 0x22 => {
+            // "LOCK MOV CR0" accesses CR8 on AMD processors.
+            // See: MOV CRn in 4 System Instruction Reference
+            let cr = if lock && reg == 0 { 8 } else { reg };
+            let direction = if opcode == 0x20 { AccessDirection::Read } else { AccessDirection::Write };
+            Some(RegisterInstruction::MovCr { cr, gpr: rm, direction })
+        }
+        0x21 | 0x23 => {
+            let direction = if opcode == 0x21 { AccessDirection::Read } else { AccessDirection::Write };
+            Some(RegisterInstruction::MovDr { dr: reg, gpr: rm, direction })
+        }
+        0x01 if register_form && reg & 0b111 == 6 => Some(RegisterInstruction::Lmsw { gpr: rm }),
+        0x01 if register_form && reg & 0b111 == 4 => Some(RegisterInstruction::Smsw { gpr: rm, operand_size }),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use AccessDirection::{Read, Write};
+    use RegisterInstruction::*;
+
+    #[test]
+    fn decodes_supported_forms() {
+        let table: &[(&[u8], RegisterInstruction)] = &[
+            // mov rax, cr0
+            (&[0x0f, 0x20, 0xc0], MovCr { cr: 0, gpr: 0, direction: Read }),
+            // mov cr3, rcx
+            (&[0x0f, 0x22, 0xd9], MovCr { cr: 3, gpr: 1, direction: Write }),
+            // mov r9, cr4
+            (&[0x41, 0x0f, 0x20, 0xe1], MovCr { cr: 4, gpr: 9, direction: Read }),
+            // mov cr8, rax
+            (&[0x44, 0x0f, 0x22, 0xc0], MovCr { cr: 8, gpr: 0, direction: Write }),
+            // lock mov cr0, eax, which is mov cr8, eax
+            (&[0xf0, 0x0f, 0x22, 0xc0], MovCr { cr: 8, gpr: 0, direction: Write }),
+            // mov rdx, dr7
+            (&[0x0f, 0x21, 0xfa], MovDr { dr: 7, gpr: 2, direction: Read }),
+            // mov dr0, r15
+            (&[0x41, 0x0f, 0x23, 0xc7], MovDr { dr: 0, gpr: 15, direction: Write }),
+            // clts
+            (&[0x0f, 0x06], Clts),
+            // lmsw si
+            (&[0x0f, 0x01, 0xf6], Lmsw { gpr: 6 }),
+            // smsw eax
+            (&[0x0f, 0x01, 0xe0], Smsw { gpr: 0, operand_size: 4 }),
+            // smsw ax
+            (&[0x66, 0x0f, 0x01, 0xe0], Smsw { gpr: 0, operand_size: 2 }),
+            // smsw r10
+            (&[0x49, 0x0f, 0x01, 0xe2], Smsw { gpr: 10, operand_size: 8 }),
+            // MOV CRx ignores ModRM.mod: mov rbx, cr0 encoded with mod 00
+            (&[0x0f, 0x20, 0x03], MovCr { cr: 0, gpr: 3, direction: Read }),
+        ];
+        for &(bytes, expected) in table {
+            assert_eq!(decode(bytes), Some(expected), "{bytes:02x?}");
+        }
+    }
+
+    #[test]
+    fn rejects_other_forms() {
+        let table: &[&[u8]] = &[
+            // smsw [rax]
+            &[0x0f, 0x01, 0x20],
+            // lmsw [rsi]
+            &[0x0f, 0x01, 0x36],
+            // sgdt [rax]
+            &[0x0f, 0x01, 0x00],
+            // nop
+            &[0x90],
+            // cpuid
+            &[0x0f, 0xa2],
+            // Truncated
+            &[],
+            &[0x66],
+            &[0x0f],
+            &[0x0f, 0x20],
+        ];
+        for bytes in table {
+            assert_eq!(decode(bytes), None, "{bytes:02x?}");
+        }
+    }
+
+    #[test]
+    fn tells_incomplete_apart() {
+        assert!(matches!(decode_partial(&[0xf0, 0x0f, 0x22]), Err(Incomplete)));
+        assert!(matches!(decode_partial(&[0x0f, 0x01, 0x20]), Ok(None)));
+        assert!(matches!(decode_partial(&[0x90]), Ok(None)));
+    }
+
+    #[test]
+    fn supports_only_emulated_control_registers() {
+        let supported: [u8; 4] = [0, 3, 4, 8];
+        for register in 0..16 {
+            assert_eq!(is_supported_cr(register), supported.contains(&register), "CR{register}");
+        }
+    }
+
+    #[test]
+    fn maps_cpuid_features_to_cr4_bits() {
+        let none = CpuIdResult { eax: 0, ebx: 0, ecx: 0, edx: 0 };
+        assert_eq!(cr4_bits_from_cpuid(none, none), 1 << 8);
+
+        // VME, PAE, PGE, OSFXSR and OSXSAVE, then FSGSBASE, SMEP and LA57.
+        let leaf1 = CpuIdResult { edx: 1 << 1 | 1 << 6 | 1 << 13 | 1 << 24, ecx: 1 << 26, ..none };
+        let leaf7 = CpuIdResult { ebx: 1 << 0 | 1 << 7, ecx: 1 << 16, ..none };
+        let expected = 0b11 | CR4_PAE | CR4_PGE | 1 << 8 | 1 << 9 | 1 << 18 | 1 << 16 | CR4_SMEP | CR4_LA57;
+        assert_eq!(cr4_bits_from_cpuid(leaf1, leaf7), expected);
+        assert_eq!(cr4_bits_from_cpuid(leaf1, leaf7) & !CR4_VALID_BITS, 0);
+    }
+}
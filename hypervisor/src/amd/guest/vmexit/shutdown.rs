@@ -0,0 +1,119 @@
+//! This module implements the handling of a guest triple fault.
+//!
+//! Without the SHUTDOWN intercept, a triple fault puts the processor into the
+//! shutdown state and the system resets with no trace of the cause. The guest
+//! cannot continue after shutdown either way. Its state is captured into a
+//! [`CrashReport`], then [`crate::amd::HypervisorConfig::shutdown_action`] is
+//! performed.
+
+use kernelutils::nt::platform_ops;
+
+use crate::amd::config::{self, ShutdownAction};
+use crate::amd::crash::{self, CrashReport, CRASH_REPORT_MAGIC, CRASH_REPORT_SIZE, CRASH_REPORT_VERSION};
+use crate::amd::guest::support;
+use crate::amd::VCpu;
+
+/// The bug check code used for [`ShutdownAction::BugCheck`].
+pub const HYPERVISOR_ERROR: u32 = 0x0002_0001;
+
+/// Handles #VMEXIT(SHUTDOWN).
+pub fn handle_shutdown(guest: &mut VCpu) -> ! {
+    let report = crash::record(capture(guest));
+
+    match config::read().shutdown_action {
+        ShutdownAction::Reset => {
+            log::error!("Resetting the system");
+            reset();
+        }
+        ShutdownAction::BugCheck => {
+            log::error!("Bug checking the system");
+            platform_ops::get().bug_check(
+                HYPERVISOR_ERROR,
+                [
+                    u64::from(report.processor_id),
+                    report.exit_int_info,
+                    report.rip,
+                    report as *const CrashReport as u64,
+                ],
+            );
+        }
+        ShutdownAction::Halt => {
+            log::error!("Halting processor {}", report.processor_id);
+            halt();
+        }
+    }
+}
+
+/// Returns the guest state at #VMEXIT.
+fn capture(guest: &mut VCpu) -> CrashReport {
+    let vmcb = &guest.guest_vmcb;
+    let state = guest.guest_state();
+    let mut report = CrashReport {
+        magic: CRASH_REPORT_MAGIC,
+        version: CRASH_REPORT_VERSION,
+        size: CRASH_REPORT_SIZE as u16,
+        processor_id: guest.id() as u32,
+        reserved: 0,
+        tsc: unsafe { x86::time::rdtsc() },
+        exit_code: vmcb.exit_code(),
+        exit_info1: vmcb.exit_info1(),
+        exit_info2: vmcb.exit_info2(),
+        exit_int_info: vmcb.exit_int_info(),
+        gprs: [0; 16],
+        rip: state.rip,
+        rflags: state.rflags,
+        cr0: state.cr0,
+        cr2: state.cr2,
+        cr3: state.cr3,
+        cr4: state.cr4,
+        cr8: support::cr8(),
+        efer: state.efer,
+        // DR0-DR3 are not switched by VMRUN and still hold the guest values.
+        dr: unsafe {
+            [
+                x86::debugregs::dr0() as u64,
+                x86::debugregs::dr1() as u64,
+                x86::debugregs::dr2() as u64,
+                x86::debugregs::dr3() as u64,
+                state.dr6,
+                state.dr7,
+            ]
+        },
+        gdtr_base: state.gdtr.base,
+        idtr_base: state.idtr.base,
+        gdtr_limit: state.gdtr.limit,
+        idtr_limit: state.idtr.limit,
+        segments: [state.es, state.cs, state.ss, state.ds, state.fs, state.gs, state.ldtr, state.tr],
+        kernel_gs_base: state.kernel_gs_base,
+    };
+    for (index, gpr) in report.gprs.iter_mut().enumerate() {
+        *gpr = guest.regs().gpr(index as u8);
+    }
+    report
+}
+
+/// Resets the system through the reset control register, then through the
+/// keyboard controller. The reset control register resets on the transition of
+/// the reset bit, so the reset type is written first without it.
+fn reset() -> ! {
+    const RESET_CONTROL: u16 = 0xcf9;
+    const RESET_CONTROL_SYSTEM_RESET: u8 = 0x02;
+    const RESET_CONTROL_FULL_RESET: u8 = 0x06;
+    const KEYBOARD_COMMAND: u16 = 0x64;
+    const KEYBOARD_PULSE_RESET: u8 = 0xfe;
+
+    unsafe {
+        x86::io::outb(RESET_CONTROL, RESET_CONTROL_SYSTEM_RESET);
+        x86::io::outb(RESET_CONTROL, RESET_CONTROL_FULL_RESET);
+        x86::io::outb(KEYBOARD_COMMAND, KEYBOARD_PULSE_RESET);
+    }
+    halt();
+}
+
+/// Stops this processor. Interrupts are disabled while the host runs, so it
+/// never resumes.
+fn halt() -> ! {
+    loop {
+        unsafe { x86::halt() };
+    }
+}
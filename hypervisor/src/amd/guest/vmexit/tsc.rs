@@ -0,0 +1,119 @@
+//! This module implements the control of the guest time-stamp counter and the
+//! emulation of RDTSC and RDTSCP.
+//!
+//! The guest reads `(host TSC * ratio) + offset`, where the offset is in the
+//! VMCB and the ratio is the TSC_RATIO MSR of the processor. Both are set per
+//! vCPU with [`crate::amd::VCpu::set_tsc_offset`] and
+//! [`crate::amd::VCpu::set_tsc_ratio`]. RDTSC and RDTSCP are only intercepted
+//! when enabled with [`crate::amd::VCpu::enable_intercepts`], in which case the
+//! value is reported to [`crate::amd::hooks::VmExitHooks::tsc_read`].
+//! See: 15.30.5 TSC Ratio MSR (C000_0104h)
+
+use bit_field::BitField;
+use x86::cpuid::cpuid;
+use x86::msr::{rdmsr, wrmsr};
+
+use crate::amd::hooks;
+use crate::amd::{InstructionInfo, VCpu};
+
+const MSR_TSC_AUX: u32 = 0xc000_0103;
+const MSR_TSC_RATIO: u32 = 0xc000_0104;
+
+/// The TSC ratio of 1.0, which is the reset value of TSC_RATIO.
+pub const TSC_RATIO_ONE: u64 = 1 << 32;
+
+#[derive(thiserror_no_std::Error, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TscError {
+    #[error("the processor does not support the TSC ratio MSR")]
+    RatioNotSupported,
+
+    #[error("TSC ratio {0:#x} sets reserved bits 63:40")]
+    InvalidRatio(u64),
+}
+
+/// A time-stamp counter read by RDTSC or RDTSCP.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TscRead {
+    /// Whether the instruction is RDTSCP.
+    pub rdtscp: bool,
+    /// The value loaded into EDX:EAX.
+    pub tsc: u64,
+    /// The value loaded into ECX by RDTSCP.
+    pub aux: u32,
+}
+
+/// Returns whether the processor supports the TSC ratio MSR.
+/// See: E.4.10 Function 8000_000Ah—SVM Features
+pub fn is_ratio_supported() -> bool {
+    cpuid!(0x8000_000a).edx.get_bit(4)
+}
+
+/// Sets the TSC ratio of the current processor. The ratio is a fixed-point
+/// number with 8 integer bits (39:32) and 32 fraction bits (31:0).
+pub(crate) fn set_ratio(ratio: u64) -> Result<(), TscError> {
+    if !is_ratio_supported() {
+        return Err(TscError::RatioNotSupported);
+    }
+    if ratio.get_bits(40..) != 0 {
+        return Err(TscError::InvalidRatio(ratio));
+    }
+    unsafe { wrmsr(MSR_TSC_RATIO, ratio) };
+    Ok(())
+}
+
+/// Returns the TSC a guest with `ratio` and `offset` reads when the host TSC is
+/// `host_tsc`.
+pub fn guest_tsc(host_tsc: u64, ratio: u64, offset: u64) -> u64 {
+    let scaled = (u128::from(host_tsc) * u128::from(ratio)) >> 32;
+    (scaled as u64).wrapping_add(offset)
+}
+
+/// Handles #VMEXIT(RDTSC) and #VMEXIT(RDTSCP).
+pub fn handle_tsc_read(guest: &mut VCpu, info: &InstructionInfo, rdtscp: bool) {
+    // TSC_AUX is not switched by VMRUN, so the current value is what RDPID and
+    // RDTSCP without interception return to the guest.
+    let mut read = TscRead {
+        rdtscp,
+        tsc: guest.guest_tsc(),
+        aux: unsafe { rdmsr(MSR_TSC_AUX) } as u32,
+    };
+    hooks::get().tsc_read(guest, &mut read);
+
+    guest.regs().rax = read.tsc & u64::from(u32::MAX);
+    guest.regs().rdx = read.tsc >> 32;
+    if read.rdtscp {
+        guest.regs().rcx = u64::from(read.aux);
+    }
+    guest.regs().rip = info.next_rip;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guest_tsc_with_ratio_one_adds_offset() {
+        assert_eq!(guest_tsc(1000, TSC_RATIO_ONE, 0), 1000);
+        assert_eq!(guest_tsc(1000, TSC_RATIO_ONE, 24), 1024);
+        // The offset is added modulo 2^64, so a negative offset subtracts.
+        assert_eq!(guest_tsc(1000, TSC_RATIO_ONE, (-1000i64) as u64), 0);
+        assert_eq!(guest_tsc(u64::MAX, TSC_RATIO_ONE, 1), 0);
+    }
+
+    #[test]
+    fn guest_tsc_scales_by_ratio() {
+        assert_eq!(guest_tsc(1000, TSC_RATIO_ONE * 2, 0), 2000);
+        assert_eq!(guest_tsc(1000, TSC_RATIO_ONE / 2, 0), 500);
+        // 1.5 with the fraction truncated.
+        assert_eq!(guest_tsc(3, TSC_RATIO_ONE + (TSC_RATIO_ONE >> 1), 0), 4);
+        assert_eq!(guest_tsc(1000, 0, 7), 7);
+    }
+
+    #[test]
+    fn guest_tsc_does_not_overflow_before_shifting() {
+        // The largest ratio, 255.99..., on a large TSC.
+        let ratio = (1 << 40) - 1;
+        let host_tsc = 1 << 48;
+        assert_eq!(guest_tsc(host_tsc, ratio, 0), (1 << 56) - (1 << 16));
+    }
+}
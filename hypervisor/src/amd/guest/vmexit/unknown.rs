@@ -0,0 +1,52 @@
+//! This module implements the handling of #VMEXIT the hypervisor has no
+//! handler for.
+//!
+//! Such #VMEXITs are caused by intercepts hooks enabled, such as
+//! [`crate::amd::intercept::Misc1Intercepts::MSR_PROT`], and are reported to
+//! [`crate::amd::hooks::VmExitHooks::unknown_exit`]. If the hooks do not handle
+//! one, the intercept that caused it is disabled on the vCPU and the guest
+//! resumes where it was, so that the processor completes the intercepted
+//! operation as if it had never been intercepted.
+
+use crate::amd::hooks;
+use crate::amd::intercept::{InterceptChange, InterceptVectors};
+use crate::amd::VCpu;
+
+const UD: u8 = 6;
+
+/// A #VMEXIT the hypervisor has no handler for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UnknownExit {
+    /// EXITCODE.
+    /// See: Appendix C SVM Intercept Exit Codes
+    pub exit_code: u64,
+    pub exit_info1: u64,
+    pub exit_info2: u64,
+    /// The RIP of the next instruction, for intercepted instructions.
+    pub next_rip: u64,
+}
+
+/// Handles a #VMEXIT with an exit code the hypervisor has no handler for.
+pub fn handle_unknown_exit(guest: &mut VCpu, exit: &UnknownExit) {
+    if hooks::get().unknown_exit(guest, exit) {
+        return;
+    }
+
+    let Some(intercept) = InterceptVectors::from_exit_code(exit.exit_code) else {
+        panic!("Unhandled #VMEXIT reason: {:#x?}", exit.exit_code);
+    };
+    log::warn!("Disabling the intercept of unhandled #VMEXIT {:#x?}", exit.exit_code);
+    let change = InterceptChange {
+        disable: intercept,
+        ..InterceptChange::default()
+    };
+    match guest.intercepts().to_builder().apply(&change).build() {
+        Ok(intercepts) => guest.set_intercepts(&intercepts),
+        // The intercept is required, which leaves VMRUN, as the guest cannot
+        // use SVM under the hypervisor.
+        Err(error) => {
+            log::warn!("Injecting #UD as the intercept cannot be disabled: {error}");
+            guest.inject_exception(UD, None);
+        }
+    }
+}
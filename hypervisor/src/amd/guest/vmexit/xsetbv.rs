@@ -0,0 +1,299 @@
+//! This module implements the emulation of XSETBV and the policy hiding XCR0
+//! state components from the guest.
+//!
+//! XCR0 is not switched by VMRUN, so the guest and the host share it. XSETBV is
+//! always intercepted so that the value is validated before it reaches the
+//! processor, and so that the components hidden with
+//! [`crate::amd::HypervisorConfig::hidden_xcr0`] cannot be enabled. Hidden
+//! components the system already enabled are cleared from XCR0 when each
+//! processor is virtualized, and CPUID reports neither them nor the features
+//! depending on them, such as FMA and F16C on AVX.
+//! See: 11.5.2 XFEATURE_ENABLED_MASK
+
+use bit_field::BitField;
+use x86::controlregs::{cr4, Cr4};
+use x86::cpuid::{cpuid, CpuIdResult};
+
+use crate::amd::guest::support;
+use crate::amd::{config, InstructionInfo, VCpu};
+
+const GP: u8 = 13;
+const UD: u8 = 6;
+
+const CR4_OSXSAVE: u64 = 1 << 18;
+
+pub const XCR0_X87: u64 = 1 << 0;
+pub const XCR0_SSE: u64 = 1 << 1;
+pub const XCR0_AVX: u64 = 1 << 2;
+pub const XCR0_MPX: u64 = 0b11 << 3;
+pub const XCR0_AVX512: u64 = 0b111 << 5;
+pub const XCR0_PKRU: u64 = 1 << 9;
+pub const XCR0_AMX: u64 = 0b11 << 17;
+
+/// Handles #VMEXIT(XSETBV).
+pub fn handle_xsetbv(guest: &mut VCpu, info: &InstructionInfo) {
+    let index = guest.regs().rcx as u32;
+    let value = guest.regs().rdx << 32 | guest.regs().rax & u64::from(u32::MAX);
+    log::trace!("XSETBV {index:#x?} {value:#x?}");
+
+    if guest.guest_vmcb.cr4() & CR4_OSXSAVE == 0 {
+        guest.inject_exception(UD, None);
+        return;
+    }
+    // XCR0 is the only XCR that can be written.
+    if index != 0 || guest.guest_vmcb.cpl() != 0 || !is_valid_xcr0(value, guest_supported_xcr0()) {
+        guest.inject_exception(GP, Some(0));
+        return;
+    }
+
+    support::xsetbv(index, value);
+    guest.regs().rip = info.next_rip;
+}
+
+/// Returns the XCR0 bits the guest may set: those supported by the processor
+/// and not hidden by the configuration.
+/// See: E.3.8 Function Dh—Processor Extended State Enumeration
+pub fn guest_supported_xcr0() -> u64 {
+    let result = cpuid!(0xd, 0);
+    let supported = u64::from(result.edx) << 32 | u64::from(result.eax);
+    supported & !hidden_xcr0()
+}
+
+/// Returns whether XSETBV accepts `value` for XCR0, given the `supported` bits.
+/// See: XSETBV in 4 System Instruction Reference
+pub fn is_valid_xcr0(value: u64, supported: u64) -> bool {
+    let all_or_none = |bits: u64| value & bits == 0 || value & bits == bits;
+
+    value & !supported == 0
+        && value & XCR0_X87 != 0
+        && (value & XCR0_AVX == 0 || value & XCR0_SSE != 0)
+        && (value & XCR0_AVX512 == 0 || value & XCR0_AVX != 0)
+        && all_or_none(XCR0_AVX512)
+        && all_or_none(XCR0_MPX)
+        && all_or_none(XCR0_AMX)
+}
+
+/// Clears the hidden components from XCR0, as the system may have enabled
+/// them before the processor is virtualized. Called on each processor before
+/// it is virtualized.
+///
+/// Extended state the system saved with those components is rejected by
+/// XRSTOR afterwards, so components should only be hidden by a driver loaded
+/// at boot, before they are used.
+pub(crate) fn clear_hidden_components() {
+    if !unsafe { cr4() }.contains(Cr4::CR4_ENABLE_OS_XSAVE) {
+        return;
+    }
+    let xcr0 = support::xgetbv(0);
+    let cleared = xcr0 & !hidden_xcr0();
+    if cleared != xcr0 {
+        log::info!("Clearing hidden components from XCR0 {xcr0:#x?}");
+        support::xsetbv(0, cleared);
+    }
+}
+
+/// Clears the CPUID bits describing the hidden XCR0 components and the
+/// features depending on them, so that the guest does not try to use them.
+pub fn mask_cpuid(leaf: u32, sub_leaf: u32, result: &mut CpuIdResult) {
+    let hidden = hidden_xcr0();
+    if hidden == 0 {
+        return;
+    }
+
+    match (leaf, sub_leaf) {
+        (0xd, 0) => {
+            result.eax &= !(hidden as u32);
+            result.edx &= !((hidden >> 32) as u32);
+            // EBX is the size for XCR0, which has no hidden components, and
+            // ECX is the size for every supported component.
+            let supported = u64::from(result.edx) << 32 | u64::from(result.eax);
+            result.ecx = standard_size(supported, component_extent);
+        }
+        // Sub-leaves 2 to 62 describe each component.
+        (0xd, 2..=62) if hidden.get_bit(sub_leaf as usize) => {
+            *result = CpuIdResult { eax: 0, ebx: 0, ecx: 0, edx: 0 };
+        }
+        _ => mask_features(leaf, sub_leaf, hidden, result),
+    }
+}
+
+/// The CPUID feature bits that require an XCR0 component: the leaf, the
+/// sub-leaf, the register index (EAX, EBX, ECX, EDX), the bit, and the
+/// component.
+const DEPENDENT_FEATURES: &[(u32, u32, usize, usize, u64)] = &[
+    (0x1, 0, 2, 12, XCR0_AVX),          // FMA
+    (0x1, 0, 2, 28, XCR0_AVX),          // AVX
+    (0x1, 0, 2, 29, XCR0_AVX),          // F16C
+    (0x7, 0, 1, 5, XCR0_AVX),           // AVX2
+    (0x7, 0, 2, 9, XCR0_AVX),           // VAES
+    (0x7, 0, 2, 10, XCR0_AVX),          // VPCLMULQDQ
+    (0x7, 1, 0, 4, XCR0_AVX),           // AVX-VNNI
+    (0x8000_0001, 0, 2, 11, XCR0_AVX),  // XOP
+    (0x8000_0001, 0, 2, 16, XCR0_AVX),  // FMA4
+    (0x7, 0, 1, 14, XCR0_MPX),          // MPX
+    (0x7, 0, 1, 16, XCR0_AVX512),       // AVX512F
+    (0x7, 0, 1, 17, XCR0_AVX512),       // AVX512DQ
+    (0x7, 0, 1, 21, XCR0_AVX512),       // AVX512_IFMA
+    (0x7, 0, 1, 26, XCR0_AVX512),       // AVX512PF
+    (0x7, 0, 1, 27, XCR0_AVX512),       // AVX512ER
+    (0x7, 0, 1, 28, XCR0_AVX512),       // AVX512CD
+    (0x7, 0, 1, 30, XCR0_AVX512),       // AVX512BW
+    (0x7, 0, 1, 31, XCR0_AVX512),       // AVX512VL
+    (0x7, 0, 2, 1, XCR0_AVX512),        // AVX512_VBMI
+    (0x7, 0, 2, 6, XCR0_AVX512),        // AVX512_VBMI2
+    (0x7, 0, 2, 11, XCR0_AVX512),       // AVX512_VNNI
+    (0x7, 0, 2, 12, XCR0_AVX512),       // AVX512_BITALG
+    (0x7, 0, 2, 14, XCR0_AVX512),       // AVX512_VPOPCNTDQ
+    (0x7, 0, 3, 2, XCR0_AVX512),        // AVX512_4VNNIW
+    (0x7, 0, 3, 3, XCR0_AVX512),        // AVX512_4FMAPS
+    (0x7, 0, 3, 8, XCR0_AVX512),        // AVX512_VP2INTERSECT
+    (0x7, 0, 3, 23, XCR0_AVX512),       // AVX512_FP16
+    (0x7, 1, 0, 5, XCR0_AVX512),        // AVX512_BF16
+    (0x7, 0, 2, 3, XCR0_PKRU),          // PKU
+    (0x7, 0, 3, 22, XCR0_AMX),          // AMX-BF16
+    (0x7, 0, 3, 24, XCR0_AMX),          // AMX-TILE
+    (0x7, 0, 3, 25, XCR0_AMX),          // AMX-INT8
+];
+
+/// Clears the bits of [`DEPENDENT_FEATURES`] whose component is in `hidden`.
+fn mask_features(leaf: u32, sub_leaf: u32, hidden: u64, result: &mut CpuIdResult) {
+    for &(_, _, register, bit, component) in DEPENDENT_FEATURES
+        .iter()
+        .filter(|feature| feature.0 == leaf && (leaf != 0x7 || feature.1 == sub_leaf))
+    {
+        if hidden & component != 0 {
+            let value = match register {
+                0 => &mut result.eax,
+                1 => &mut result.ebx,
+                2 => &mut result.ecx,
+                _ => &mut result.edx,
+            };
+            value.set_bit(bit, false);
+        }
+    }
+}
+
+/// Returns the size of the standard format XSAVE area holding `components`,
+/// given the size and offset of each component from `extent`.
+/// See: E.3.8 Function Dh—Processor Extended State Enumeration
+fn standard_size(components: u64, extent: impl Fn(u32) -> (u32, u32)) -> u32 {
+    // The legacy region and the XSAVE header.
+    const LEGACY_AND_HEADER_SIZE: u32 = 512 + 64;
+
+    (2..63)
+        .filter(|&component| components.get_bit(component))
+        .map(|component| {
+            let (size, offset) = extent(component as u32);
+            offset + size
+        })
+        .fold(LEGACY_AND_HEADER_SIZE, u32::max)
+}
+
+/// Returns the size and the standard format offset of the XSAVE component.
+fn component_extent(component: u32) -> (u32, u32) {
+    let result = cpuid!(0xd, component);
+    (result.eax, result.ebx)
+}
+
+/// Returns the XCR0 bits hidden from the guest. x87 state cannot be hidden.
+fn hidden_xcr0() -> u64 {
+    with_dependents(config::read().hidden_xcr0)
+}
+
+/// Returns `hidden` with the components that cannot be enabled without a
+/// hidden one, as [`is_valid_xcr0`] requires. x87 state is never hidden.
+fn with_dependents(hidden: u64) -> u64 {
+    let mut hidden = hidden & !XCR0_X87;
+    if hidden & XCR0_SSE != 0 {
+        hidden |= XCR0_AVX;
+    }
+    if hidden & XCR0_AVX != 0 {
+        hidden |= XCR0_AVX512;
+    }
+    if hidden & XCR0_AVX512 != 0 {
+        hidden |= XCR0_AVX512;
+    }
+    hidden
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUPPORTED: u64 = XCR0_X87 | XCR0_SSE | XCR0_AVX | XCR0_AVX512 | XCR0_PKRU;
+
+    #[test]
+    fn requires_x87() {
+        assert!(is_valid_xcr0(XCR0_X87, SUPPORTED));
+        assert!(!is_valid_xcr0(XCR0_SSE, SUPPORTED));
+        assert!(!is_valid_xcr0(0, SUPPORTED));
+    }
+
+    #[test]
+    fn requires_sse_for_avx() {
+        assert!(is_valid_xcr0(XCR0_X87 | XCR0_SSE | XCR0_AVX, SUPPORTED));
+        assert!(!is_valid_xcr0(XCR0_X87 | XCR0_AVX, SUPPORTED));
+    }
+
+    #[test]
+    fn requires_all_or_none_of_avx512() {
+        let avx = XCR0_X87 | XCR0_SSE | XCR0_AVX;
+        assert!(is_valid_xcr0(avx | XCR0_AVX512, SUPPORTED));
+        assert!(!is_valid_xcr0(avx | 1 << 5, SUPPORTED));
+        assert!(!is_valid_xcr0(avx | 0b11 << 6, SUPPORTED));
+        // AVX-512 also requires AVX.
+        assert!(!is_valid_xcr0(XCR0_X87 | XCR0_SSE | XCR0_AVX512, SUPPORTED));
+    }
+
+    #[test]
+    fn rejects_unsupported() {
+        assert!(!is_valid_xcr0(XCR0_X87 | XCR0_SSE | XCR0_AVX, XCR0_X87 | XCR0_SSE));
+        assert!(!is_valid_xcr0(XCR0_X87 | XCR0_AMX, SUPPORTED | 1 << 17));
+    }
+
+    #[test]
+    fn hides_dependents() {
+        assert_eq!(with_dependents(XCR0_X87), 0);
+        assert_eq!(with_dependents(XCR0_AVX), XCR0_AVX | XCR0_AVX512);
+        assert_eq!(with_dependents(XCR0_SSE), XCR0_SSE | XCR0_AVX | XCR0_AVX512);
+        assert_eq!(with_dependents(1 << 6), XCR0_AVX512);
+        assert_eq!(with_dependents(XCR0_PKRU), XCR0_PKRU);
+    }
+
+    #[test]
+    fn masks_dependent_features() {
+        let mut result = CpuIdResult { eax: 0, ebx: u32::MAX, ecx: u32::MAX, edx: u32::MAX };
+        mask_features(0x1, 0, with_dependents(XCR0_AVX), &mut result);
+        assert_eq!(result.ecx, !(1 << 12 | 1 << 28 | 1 << 29));
+
+        let mut result = CpuIdResult { eax: u32::MAX, ebx: u32::MAX, ecx: u32::MAX, edx: u32::MAX };
+        mask_features(0x7, 0, with_dependents(XCR0_AVX512), &mut result);
+        assert_eq!(result.ebx, !(1 << 16 | 1 << 17 | 1 << 21 | 1 << 26 | 1 << 27 | 1 << 28 | 1 << 30 | 1 << 31));
+        assert!(result.ebx.get_bit(5));
+        assert_eq!(result.eax, u32::MAX);
+
+        // Sub-leaf 1 of leaf 7 has its own bits.
+        let mut result = CpuIdResult { eax: u32::MAX, ebx: u32::MAX, ecx: u32::MAX, edx: u32::MAX };
+        mask_features(0x7, 1, with_dependents(XCR0_AVX), &mut result);
+        assert_eq!(result.eax, !(1 << 4 | 1 << 5));
+        assert_eq!(result.ebx, u32::MAX);
+    }
+
+    #[test]
+    fn computes_standard_size() {
+        // The sizes and offsets of AVX, the AVX-512 components and PKRU on a
+        // typical processor.
+        let extent = |component| match component {
+            2 => (256, 576),
+            5 => (64, 1088),
+            6 => (512, 1152),
+            7 => (1024, 1664),
+            9 => (8, 2688),
+            _ => (0, 0),
+        };
+        assert_eq!(standard_size(XCR0_X87 | XCR0_SSE, extent), 576);
+        assert_eq!(standard_size(XCR0_X87 | XCR0_SSE | XCR0_AVX, extent), 832);
+        assert_eq!(standard_size(SUPPORTED, extent), 2696);
+        assert_eq!(standard_size(SUPPORTED & !XCR0_PKRU, extent), 2688);
+    }
+}
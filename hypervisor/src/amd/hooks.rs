@@ -0,0 +1,99 @@
+//! This module implements the extension point for policy handlers that want to
+//! observe or change how the hypervisor handles #VMEXIT.
+//!
+//! Handlers implement [`VmExitHooks`] and are registered once with
+//! [`register`], typically before [`crate::amd::virtualize_system`]. Each hook
+//! runs on the processor that caused the #VMEXIT, with interrupts disabled, and
+//! may be called concurrently from all processors. Hooks using extended
+//! registers beyond SSE call [`VCpu::extended_state`] first, unless the state
+//! is switched with [`crate::amd::ExtendedStateSwitch::Eager`].
+
+use spin::Once;
+
+use crate::amd::guest::vmexit::exception::{ExceptionAction, ExceptionInfo};
+use crate::amd::guest::vmexit::register_access::{CrAccess, DrAccess};
+use crate::amd::guest::vmexit::tsc::TscRead;
+use crate::amd::guest::vmexit::unknown::UnknownExit;
+use crate::amd::VCpu;
+
+/// What the hypervisor does after a hook returns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HookAction {
+    /// Performs the default emulation with the access as left by the hook.
+    Emulate,
+    /// Completes the instruction without the default emulation. The hook is
+    /// responsible for any effect the instruction should have. The instruction
+    /// is not completed if the hook injected an exception.
+    Skip,
+}
+
+/// The hooks called while handling #VMEXIT. Every method has a default
+/// implementation that requests the default handling.
+pub trait VmExitHooks: Sync {
+    /// Called on each processor once its vCPU is initialized, before the first
+    /// VMRUN. Hooks can set up the intercepts of the vCPU here.
+    fn initialize(&self, _guest: &mut VCpu) {}
+
+    /// Called on an intercepted access to a control register. For reads,
+    /// `access.value` holds the value the guest will read. For writes, it holds
+    /// the value the guest is writing.
+    fn cr_access(&self, _guest: &mut VCpu, _access: &mut CrAccess) -> HookAction {
+        HookAction::Emulate
+    }
+
+    /// Called on an intercepted access to a debug register. `access.value` is
+    /// as for [`Self::cr_access`].
+    fn dr_access(&self, _guest: &mut VCpu, _access: &mut DrAccess) -> HookAction {
+        HookAction::Emulate
+    }
+
+    /// Called on an intercepted guest exception other than #SX.
+    fn exception(&self, _guest: &mut VCpu, _exception: &ExceptionInfo) -> ExceptionAction {
+        ExceptionAction::Reinject
+    }
+
+    /// Called on an intercepted RDTSC or RDTSCP. `read` holds the values the
+    /// guest would have read without interception, and the hook may change them.
+    fn tsc_read(&self, _guest: &mut VCpu, _read: &mut TscRead) {}
+
+    /// Called when the pause filter detects a spin loop at the guest `rip`.
+    /// The guest resumes after the PAUSE instruction.
+    fn spin_loop(&self, _guest: &mut VCpu, _rip: u64) {}
+
+    /// Called on an intercepted HLT. The guest resumes after the HLT
+    /// instruction without halting, so the guest calls it again at once when
+    /// idle.
+    fn halt(&self, _guest: &mut VCpu) {}
+
+    /// Called on a #VMEXIT the hypervisor has no handler for, caused by an
+    /// intercept the hooks enabled. Returns `true` if the hook handled it,
+    /// including moving RIP to `exit.next_rip` after emulating an instruction.
+    /// Otherwise, the intercept is disabled on this vCPU and the guest resumes
+    /// as if it had not been intercepted.
+    fn unknown_exit(&self, _guest: &mut VCpu, _exit: &UnknownExit) -> bool {
+        false
+    }
+}
+
+/// The hooks used when none are registered.
+struct DefaultHooks;
+
+impl VmExitHooks for DefaultHooks {}
+
+static HOOKS: Once<&'static dyn VmExitHooks> = Once::new();
+
+/// Registers the hooks. Returns `false` if hooks have already been registered,
+/// in which case `hooks` is not used.
+pub fn register(hooks: &'static dyn VmExitHooks) -> bool {
+    let mut registered = false;
+    HOOKS.call_once(|| {
+        registered = true;
+        hooks
+    });
+    registered
+}
+
+/// Returns the registered hooks, or the default ones.
+pub(crate) fn get() -> &'static dyn VmExitHooks {
+    HOOKS.get().copied().unwrap_or(&DefaultHooks)
+}
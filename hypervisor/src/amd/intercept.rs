@@ -0,0 +1,553 @@
+//! This module implements typed intercept vectors of the VMCB control area.
+//!
+//! An [`InterceptSet`] holds every intercept vector and can only be built
+//! through [`InterceptSetBuilder`], which rejects combinations VMRUN or the
+//! hypervisor cannot handle, such as one without the intercepts in
+//! [`InterceptVectors::REQUIRED`]. The intercepts of a vCPU can be changed at any
+//! time, including from #VMEXIT handlers, with [`crate::amd::VCpu::enable_intercepts`]
+//! and [`crate::amd::VCpu::disable_intercepts`]. #VMEXITs the hypervisor has no
+//! handler for are reported to [`crate::amd::hooks::VmExitHooks::unknown_exit`].
+//!
+//! See: 15.9 Instruction Intercepts and Appendix B Layout of VMCB
+
+use crate::amd::guest::VmcbRaw;
+
+pub use hv_core::intercept::{
+    CrReadIntercepts, CrWriteIntercepts, DrReadIntercepts, DrWriteIntercepts, ExceptionIntercepts, Misc1Intercepts,
+    Misc2Intercepts, Misc3Intercepts,
+};
+
+#[derive(thiserror_no_std::Error, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InterceptError {
+    #[error("VMRUN must always be intercepted")]
+    VmrunNotIntercepted,
+
+    #[error("VMMCALL must always be intercepted for hypercalls")]
+    VmmcallNotIntercepted,
+
+    #[error("#SX must always be intercepted, as it carries INIT")]
+    SxNotIntercepted,
+
+    #[error("MSR_PROT requires an MSR permissions map")]
+    MissingMsrPermissionMap,
+
+    #[error("IOIO_PROT requires an I/O permissions map")]
+    MissingIoPermissionMap,
+
+    #[error("accesses to CR{register} cannot be emulated")]
+    UnsupportedControlRegister { register: u8 },
+}
+
+/// The complete set of intercepts of a vCPU, including the permission maps
+/// selecting which MSR and I/O accesses are intercepted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct InterceptSet {
+    cr_read: CrReadIntercepts,
+    cr_write: CrWriteIntercepts,
+    dr_read: DrReadIntercepts,
+    dr_write: DrWriteIntercepts,
+    exceptions: ExceptionIntercepts,
+    misc1: Misc1Intercepts,
+    misc2: Misc2Intercepts,
+    misc3: Misc3Intercepts,
+    msr_permission_map: Option<u64>,
+    io_permission_map: Option<u64>,
+}
+
+/// An intercept vector type that can be enabled in an [`InterceptSet`].
+pub trait Intercept: Copy {
+    #[doc(hidden)]
+    fn update(self, set: &mut InterceptSet, enable: bool);
+
+    #[doc(hidden)]
+    fn is_enabled_in(self, set: &InterceptSet) -> bool;
+}
+
+macro_rules! impl_intercept {
+    ($($ty:ty => $field:ident,)*) => {
+        $(
+            impl Intercept for $ty {
+                fn update(self, set: &mut InterceptSet, enable: bool) {
+                    set.$field.set(self, enable);
+                }
+
+                fn is_enabled_in(self, set: &InterceptSet) -> bool {
+                    set.$field.contains(self)
+                }
+            }
+        )*
+    };
+}
+
+impl_intercept! {
+    CrReadIntercepts => cr_read,
+    CrWriteIntercepts => cr_write,
+    DrReadIntercepts => dr_read,
+    DrWriteIntercepts => dr_write,
+    ExceptionIntercepts => exceptions,
+    Misc1Intercepts => misc1,
+    Misc2Intercepts => misc2,
+    Misc3Intercepts => misc3,
+}
+
+impl InterceptSet {
+    /// Returns a builder starting with only [`InterceptVectors::REQUIRED`]
+    /// intercepted.
+    pub fn builder() -> InterceptSetBuilder {
+        let required = InterceptVectors::REQUIRED;
+        InterceptSetBuilder {
+            set: Self {
+                exceptions: required.exceptions,
+                misc2: required.misc2,
+                ..Self::default()
+            },
+        }
+    }
+
+    /// Returns a builder starting with the intercepts of `self`.
+    pub fn to_builder(&self) -> InterceptSetBuilder {
+        InterceptSetBuilder { set: *self }
+    }
+
+    /// Returns whether all of `intercepts` are enabled.
+    pub fn contains<I: Intercept>(&self, intercepts: I) -> bool {
+        intercepts.is_enabled_in(self)
+    }
+
+    pub fn cr_read(&self) -> CrReadIntercepts {
+        self.cr_read
+    }
+
+    pub fn cr_write(&self) -> CrWriteIntercepts {
+        self.cr_write
+    }
+
+    pub fn dr_read(&self) -> DrReadIntercepts {
+        self.dr_read
+    }
+
+    pub fn dr_write(&self) -> DrWriteIntercepts {
+        self.dr_write
+    }
+
+    pub fn exceptions(&self) -> ExceptionIntercepts {
+        self.exceptions
+    }
+
+    pub fn misc1(&self) -> Misc1Intercepts {
+        self.misc1
+    }
+
+    pub fn misc2(&self) -> Misc2Intercepts {
+        self.misc2
+    }
+
+    pub fn misc3(&self) -> Misc3Intercepts {
+        self.misc3
+    }
+
+    /// Returns the physical address of the MSR permissions map, if any.
+    pub fn msr_permission_map(&self) -> Option<u64> {
+        self.msr_permission_map
+    }
+
+    /// Returns the physical address of the I/O permissions map, if any.
+    pub fn io_permission_map(&self) -> Option<u64> {
+        self.io_permission_map
+    }
+
+    /// Returns the intercepts currently set in `vmcb`. A permission map at
+    /// physical address zero is treated as absent.
+    pub(crate) fn read_from(vmcb: &VmcbRaw) -> Self {
+        Self {
+            cr_read: CrReadIntercepts::from_bits_retain(vmcb.intercept_cr_read()),
+            cr_write: CrWriteIntercepts::from_bits_retain(vmcb.intercept_cr_write()),
+            dr_read: DrReadIntercepts::from_bits_retain(vmcb.intercept_dr_read()),
+            dr_write: DrWriteIntercepts::from_bits_retain(vmcb.intercept_dr_write()),
+            exceptions: ExceptionIntercepts::from_bits_retain(vmcb.intercept_exception()),
+            misc1: Misc1Intercepts::from_bits_retain(vmcb.intercept_misc1()),
+            misc2: Misc2Intercepts::from_bits_retain(vmcb.intercept_misc2()),
+            misc3: Misc3Intercepts::from_bits_retain(vmcb.intercept_misc3()),
+            msr_permission_map: Some(vmcb.msrpm_base_pa()).filter(|&pa| pa != 0),
+            io_permission_map: Some(vmcb.iopm_base_pa()).filter(|&pa| pa != 0),
+        }
+    }
+
+    /// Writes the intercepts into `vmcb`.
+    pub(crate) fn write_to(&self, vmcb: &mut VmcbRaw) {
+        vmcb.set_intercept_cr_read(self.cr_read.bits());
+        vmcb.set_intercept_cr_write(self.cr_write.bits());
+        vmcb.set_intercept_dr_read(self.dr_read.bits());
+        vmcb.set_intercept_dr_write(self.dr_write.bits());
+        vmcb.set_intercept_exception(self.exceptions.bits());
+        vmcb.set_intercept_misc1(self.misc1.bits());
+        vmcb.set_intercept_misc2(self.misc2.bits());
+        vmcb.set_intercept_misc3(self.misc3.bits());
+        if vmcb.msrpm_base_pa() != self.msr_permission_map.unwrap_or(0) {
+            vmcb.set_msrpm_base_pa(self.msr_permission_map.unwrap_or(0));
+        }
+        if vmcb.iopm_base_pa() != self.io_permission_map.unwrap_or(0) {
+            vmcb.set_iopm_base_pa(self.io_permission_map.unwrap_or(0));
+        }
+    }
+}
+
+/// One value of each intercept vector. Its layout is that of
+/// [`hv_interface::InterceptVectors`], as [`HypercallCommand::ChangeIntercepts`]
+/// reads it from the guest.
+///
+/// [`HypercallCommand::ChangeIntercepts`]: crate::amd::HypercallCommand::ChangeIntercepts
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct InterceptVectors {
+    pub cr_read: CrReadIntercepts,
+    pub cr_write: CrWriteIntercepts,
+    pub dr_read: DrReadIntercepts,
+    pub dr_write: DrWriteIntercepts,
+    pub exceptions: ExceptionIntercepts,
+    pub misc1: Misc1Intercepts,
+    pub misc2: Misc2Intercepts,
+    pub misc3: Misc3Intercepts,
+}
+
+impl InterceptVectors {
+    /// The intercepts every [`InterceptSet`] must have. VMRUN is required by
+    /// the processor, VMMCALL carries hypercalls including devirtualization,
+    /// and #SX carries INIT, as VM_CR.R_INIT is set.
+    /// See: 15.5.1 Basic Operation
+    pub const REQUIRED: Self = Self {
+        cr_read: CrReadIntercepts::empty(),
+        cr_write: CrWriteIntercepts::empty(),
+        dr_read: DrReadIntercepts::empty(),
+        dr_write: DrWriteIntercepts::empty(),
+        exceptions: ExceptionIntercepts::SX,
+        misc1: Misc1Intercepts::empty(),
+        misc2: Misc2Intercepts::VMRUN.union(Misc2Intercepts::VMMCALL),
+        misc3: Misc3Intercepts::empty(),
+    };
+
+    /// The intercepts that can be changed while the guest runs with
+    /// [`HypercallCommand::ChangeIntercepts`], as the hypervisor handles their
+    /// #VMEXITs with any configuration. The others either have no handler or
+    /// are required by the hypervisor itself, such as VMMCALL.
+    ///
+    /// [`HypercallCommand::ChangeIntercepts`]: crate::amd::HypercallCommand::ChangeIntercepts
+    pub const RUNTIME: Self = Self {
+        cr_read: CrReadIntercepts::CR0
+            .union(CrReadIntercepts::CR3)
+            .union(CrReadIntercepts::CR4)
+            .union(CrReadIntercepts::CR8),
+        cr_write: CrWriteIntercepts::CR0
+            .union(CrWriteIntercepts::CR3)
+            .union(CrWriteIntercepts::CR4)
+            .union(CrWriteIntercepts::CR8),
+        dr_read: DrReadIntercepts::from_bits_retain(0xff),
+        dr_write: DrWriteIntercepts::from_bits_retain(0xff),
+        // The exceptions `handle_exception` can reflect to the guest. #SX
+        // carries INIT and must stay intercepted. NMI is delivered as an NMI,
+        // not as an exception, a machine check cannot be reinjected, and #HV
+        // and #VC only exist in SEV-SNP and SEV-ES guests.
+        exceptions: ExceptionIntercepts::DE
+            .union(ExceptionIntercepts::DB)
+            .union(ExceptionIntercepts::BP)
+            .union(ExceptionIntercepts::OF)
+            .union(ExceptionIntercepts::BR)
+            .union(ExceptionIntercepts::UD)
+            .union(ExceptionIntercepts::NM)
+            .union(ExceptionIntercepts::DF)
+            .union(ExceptionIntercepts::TS)
+            .union(ExceptionIntercepts::NP)
+            .union(ExceptionIntercepts::SS)
+            .union(ExceptionIntercepts::GP)
+            .union(ExceptionIntercepts::PF)
+            .union(ExceptionIntercepts::MF)
+            .union(ExceptionIntercepts::AC)
+            .union(ExceptionIntercepts::XF)
+            .union(ExceptionIntercepts::CP),
+        // HLT is left out, as the processor does not halt while it is
+        // intercepted. See `HypervisorConfig::intercept_hlt`.
+        misc1: Misc1Intercepts::RDTSC,
+        misc2: Misc2Intercepts::RDTSCP,
+        misc3: Misc3Intercepts::empty(),
+    };
+
+    /// Returns the intercept of intercept vectors 3 to 5 that causes #VMEXIT
+    /// with `exit_code`, or `None` for other exit codes. Their bits are in the
+    /// order of the exit codes.
+    /// See: Appendix C SVM Intercept Exit Codes
+    pub fn from_exit_code(exit_code: u64) -> Option<Self> {
+        let bit = |first: u64| 1 << (exit_code - first);
+        let vectors = match exit_code {
+            0x60..=0x7f => Self {
+                misc1: Misc1Intercepts::from_bits_retain(bit(0x60)),
+                ..Self::default()
+            },
+            0x80..=0x9f => Self {
+                misc2: Misc2Intercepts::from_bits_retain(bit(0x80)),
+                ..Self::default()
+            },
+            0xa0..=0xa6 => Self {
+                misc3: Misc3Intercepts::from_bits_retain(bit(0xa0)),
+                ..Self::default()
+            },
+            _ => return None,
+        };
+        Some(vectors)
+    }
+
+    /// Returns whether all intercepts of `other` are in `self`.
+    pub fn contains(&self, other: &Self) -> bool {
+        self.cr_read.contains(other.cr_read)
+            && self.cr_write.contains(other.cr_write)
+            && self.dr_read.contains(other.dr_read)
+            && self.dr_write.contains(other.dr_write)
+            && self.exceptions.contains(other.exceptions)
+            && self.misc1.contains(other.misc1)
+            && self.misc2.contains(other.misc2)
+            && self.misc3.contains(other.misc3)
+    }
+}
+
+/// Intercepts to start and to stop intercepting at once.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct InterceptChange {
+    pub enable: InterceptVectors,
+    /// Applied after `enable`, so an intercept in both ends up disabled.
+    pub disable: InterceptVectors,
+}
+
+macro_rules! assert_interface_layout {
+    ($($field:ident),*) => {
+        $(const _: () = assert!(
+            core::mem::offset_of!(InterceptVectors, $field) == core::mem::offset_of!(hv_interface::InterceptVectors, $field)
+        );)*
+    };
+}
+
+assert_interface_layout!(cr_read, cr_write, dr_read, dr_write, exceptions, misc1, misc2, misc3);
+const _: () = assert!(core::mem::size_of::<InterceptVectors>() == core::mem::size_of::<hv_interface::InterceptVectors>());
+const _: () = assert!(core::mem::size_of::<InterceptChange>() == 2 * core::mem::size_of::<InterceptVectors>());
+
+/// The control registers whose accesses can be intercepted, as their #VMEXITs
+/// are emulated.
+const SUPPORTED_CR_INTERCEPTS: u16 = CrReadIntercepts::CR0
+    .union(CrReadIntercepts::CR3)
+    .union(CrReadIntercepts::CR4)
+    .union(CrReadIntercepts::CR8)
+    .bits();
+
+/// Builds a validated [`InterceptSet`].
+#[derive(Clone, Copy, Debug)]
+pub struct InterceptSetBuilder {
+    set: InterceptSet,
+}
+
+impl InterceptSetBuilder {
+    pub fn enable<I: Intercept>(mut self, intercepts: I) -> Self {
+        intercepts.update(&mut self.set, true);
+        self
+    }
+
+    pub fn disable<I: Intercept>(mut self, intercepts: I) -> Self {
+        intercepts.update(&mut self.set, false);
+        self
+    }
+
+    /// Sets the physical address of the 8KB MSR permissions map used with
+    /// [`Misc1Intercepts::MSR_PROT`].
+    /// See: 15.11 MSR Intercepts
+    pub fn msr_permission_map(mut self, pa: Option<u64>) -> Self {
+        self.set.msr_permission_map = pa;
+        self
+    }
+
+    /// Sets the physical address of the 12KB I/O permissions map used with
+    /// [`Misc1Intercepts::IOIO_PROT`].
+    /// See: 15.10.1 I/O Permissions Map
+    pub fn io_permission_map(mut self, pa: Option<u64>) -> Self {
+        self.set.io_permission_map = pa;
+        self
+    }
+
+    /// Enables then disables the intercepts of `change`.
+    pub fn apply(self, change: &InterceptChange) -> Self {
+        let InterceptVectors { cr_read, cr_write, dr_read, dr_write, exceptions, misc1, misc2, misc3 } = change.enable;
+        let builder = self
+            .enable(cr_read)
+            .enable(cr_write)
+            .enable(dr_read)
+            .enable(dr_write)
+            .enable(exceptions)
+            .enable(misc1)
+            .enable(misc2)
+            .enable(misc3);
+        let InterceptVectors { cr_read, cr_write, dr_read, dr_write, exceptions, misc1, misc2, misc3 } = change.disable;
+        builder
+            .disable(cr_read)
+            .disable(cr_write)
+            .disable(dr_read)
+            .disable(dr_write)
+            .disable(exceptions)
+            .disable(misc1)
+            .disable(misc2)
+            .disable(misc3)
+    }
+
+    pub fn build(self) -> Result<InterceptSet, InterceptError> {
+        let set = self.set;
+        if !set.misc2.contains(Misc2Intercepts::VMRUN) {
+            return Err(InterceptError::VmrunNotIntercepted);
+        }
+        if !set.misc2.contains(Misc2Intercepts::VMMCALL) {
+            return Err(InterceptError::VmmcallNotIntercepted);
+        }
+        if !set.exceptions.contains(ExceptionIntercepts::SX) {
+            return Err(InterceptError::SxNotIntercepted);
+        }
+        if set.misc1.contains(Misc1Intercepts::MSR_PROT) && set.msr_permission_map.is_none() {
+            return Err(InterceptError::MissingMsrPermissionMap);
+        }
+        if set.misc1.contains(Misc1Intercepts::IOIO_PROT) && set.io_permission_map.is_none() {
+            return Err(InterceptError::MissingIoPermissionMap);
+        }
+        // Only CR0, CR3, CR4 and CR8 accesses are emulated.
+        let unsupported = (set.cr_read.bits() | set.cr_write.bits()) & !SUPPORTED_CR_INTERCEPTS;
+        if unsupported != 0 {
+            return Err(InterceptError::UnsupportedControlRegister {
+                register: unsupported.trailing_zeros() as u8,
+            });
+        }
+        Ok(set)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builder_starts_with_required() {
+        let set = InterceptSet::builder().build().unwrap();
+        assert_eq!(set.misc2(), Misc2Intercepts::VMRUN | Misc2Intercepts::VMMCALL);
+        assert_eq!(set.exceptions(), ExceptionIntercepts::SX);
+        assert_eq!(set.misc1(), Misc1Intercepts::empty());
+    }
+
+    #[test]
+    fn rejects_removing_required() {
+        let builder = InterceptSet::builder();
+        assert_eq!(
+            builder.disable(Misc2Intercepts::VMRUN).build(),
+            Err(InterceptError::VmrunNotIntercepted)
+        );
+        assert_eq!(
+            builder.disable(Misc2Intercepts::VMMCALL).build(),
+            Err(InterceptError::VmmcallNotIntercepted)
+        );
+        assert_eq!(
+            builder.disable(ExceptionIntercepts::SX).build(),
+            Err(InterceptError::SxNotIntercepted)
+        );
+        let change = InterceptChange {
+            disable: InterceptVectors::REQUIRED,
+            ..InterceptChange::default()
+        };
+        assert!(builder.apply(&change).build().is_err());
+    }
+
+    #[test]
+    fn requires_permission_maps() {
+        let builder = InterceptSet::builder();
+        assert_eq!(
+            builder.enable(Misc1Intercepts::MSR_PROT).build(),
+            Err(InterceptError::MissingMsrPermissionMap)
+        );
+        assert_eq!(
+            builder.enable(Misc1Intercepts::IOIO_PROT).build(),
+            Err(InterceptError::MissingIoPermissionMap)
+        );
+        let set = builder
+            .enable(Misc1Intercepts::MSR_PROT)
+            .msr_permission_map(Some(0x1000))
+            .build()
+            .unwrap();
+        assert_eq!(set.msr_permission_map(), Some(0x1000));
+    }
+
+    #[test]
+    fn rejects_unsupported_control_registers() {
+        let builder = InterceptSet::builder();
+        let supported = CrReadIntercepts::CR0 | CrReadIntercepts::CR3 | CrReadIntercepts::CR4 | CrReadIntercepts::CR8;
+        assert!(builder.enable(supported).build().is_ok());
+        assert_eq!(
+            builder.enable(CrReadIntercepts::CR2).build(),
+            Err(InterceptError::UnsupportedControlRegister { register: 2 })
+        );
+        assert_eq!(
+            builder.enable(CrWriteIntercepts::CR0 | CrWriteIntercepts::CR15).build(),
+            Err(InterceptError::UnsupportedControlRegister { register: 15 })
+        );
+    }
+
+    #[test]
+    fn applies_disable_after_enable() {
+        let change = InterceptChange {
+            enable: InterceptVectors {
+                misc1: Misc1Intercepts::RDTSC | Misc1Intercepts::HLT,
+                ..InterceptVectors::default()
+            },
+            disable: InterceptVectors {
+                misc1: Misc1Intercepts::HLT,
+                ..InterceptVectors::default()
+            },
+        };
+        let set = InterceptSet::builder().apply(&change).build().unwrap();
+        assert!(set.contains(Misc1Intercepts::RDTSC));
+        assert!(!set.contains(Misc1Intercepts::HLT));
+    }
+
+    #[test]
+    fn runtime_excludes_required() {
+        let runtime = InterceptVectors::RUNTIME;
+        let required = InterceptVectors::REQUIRED;
+        assert!(runtime.exceptions.intersection(required.exceptions).is_empty());
+        assert!(runtime.misc2.intersection(required.misc2).is_empty());
+        assert!(!runtime.misc1.contains(Misc1Intercepts::HLT));
+    }
+
+    #[test]
+    fn runtime_excludes_unsupported_exceptions() {
+        let exceptions = InterceptVectors::RUNTIME.exceptions;
+        for unsupported in [
+            ExceptionIntercepts::NMI,
+            ExceptionIntercepts::MC,
+            ExceptionIntercepts::HV,
+            ExceptionIntercepts::VC,
+            ExceptionIntercepts::SX,
+        ] {
+            assert!(!exceptions.intersects(unsupported), "{unsupported:?}");
+        }
+        assert!(exceptions.contains(ExceptionIntercepts::PF | ExceptionIntercepts::GP));
+    }
+
+    #[test]
+    fn maps_exit_codes() {
+        let misc1 = |exit_code| InterceptVectors::from_exit_code(exit_code).map(|vectors| vectors.misc1);
+        let misc2 = |exit_code| InterceptVectors::from_exit_code(exit_code).map(|vectors| vectors.misc2);
+        let misc3 = |exit_code| InterceptVectors::from_exit_code(exit_code).map(|vectors| vectors.misc3);
+        assert_eq!(misc1(0x60), Some(Misc1Intercepts::INTR));
+        assert_eq!(misc1(0x7b), Some(Misc1Intercepts::IOIO_PROT));
+        assert_eq!(misc1(0x7c), Some(Misc1Intercepts::MSR_PROT));
+        assert_eq!(misc2(0x80), Some(Misc2Intercepts::VMRUN));
+        assert_eq!(misc2(0x8d), Some(Misc2Intercepts::XSETBV));
+        assert_eq!(misc2(0x9f), Some(Misc2Intercepts::CR15_WRITE_TRAP));
+        assert_eq!(misc3(0xa2), Some(Misc3Intercepts::INVPCID));
+        assert_eq!(misc3(0xa6), Some(Misc3Intercepts::IDLE_HLT));
+        let vectors = InterceptVectors::from_exit_code(0x76).unwrap();
+        assert_eq!(vectors, InterceptVectors { misc1: Misc1Intercepts::INVD, ..InterceptVectors::default() });
+        for exit_code in [0x00, 0x5f, 0xa7, 0x400, u64::MAX] {
+            assert_eq!(InterceptVectors::from_exit_code(exit_code), None, "{exit_code:#x}");
+        }
+    }
+}
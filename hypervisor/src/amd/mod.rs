@@ -1,18 +1,50 @@
+pub mod bench;
+pub mod config;
+pub mod control;
+pub mod crash;
+pub mod dump;
+pub mod hooks;
+pub mod intercept;
+pub mod processors;
+pub mod stats;
+pub mod trace;
 mod guest;
+pub use hv_core::registry;
 pub use guest::VCpu;
+pub use config::{
+    CpuidOverride, CpuidRegister, DebugBreaks, ExtendedStateSwitch, HypervisorConfig, IcrInterceptPolicy, LogSink,
+    Mitigation, PauseFilter, ProcessorSet, ShutdownAction, SpeculationMitigations,
+};
+pub use intercept::{InterceptChange, InterceptError, InterceptSet, InterceptVectors};
+pub use processors::{is_virtualized, ProcessorStatus};
 
 use alloc::boxed::Box;
-use core::arch::asm;
 
+pub use guest::state::{DescriptorTableState, GuestState, SegmentState};
+pub use guest::support::TlbFlush;
+pub use guest::support::xsave::{XsaveArea, XsaveError};
 pub use guest::vmexit::InstructionInfo;
 pub use guest::vmexit::VmExitReason;
+pub use guest::vmexit::exception::{ExceptionAction, ExceptionInfo};
+pub use guest::vmexit::tsc::{TscError, TscRead, TSC_RATIO_ONE};
+pub use guest::vmexit::unknown::UnknownExit;
+pub use guest::vmexit::register_access::{AccessDirection, CrAccess, DrAccess, RegisterInstruction};
+pub use guest::vmexit::hypercall::{HypercallCommand, HypercallStatus};
 
 
 use guest::vmexit::handle_cpuid;
+use guest::vmexit::exception::handle_exception;
+use guest::vmexit::hypercall::handle_vmmcall;
+use guest::vmexit::idle::{handle_hlt, handle_pause};
+use guest::vmexit::register_access::{handle_cr_access, handle_dr_access};
+use guest::vmexit::shutdown::handle_shutdown;
+use guest::vmexit::tsc::handle_tsc_read;
+use guest::vmexit::unknown::handle_unknown_exit;
+use guest::vmexit::xsetbv::handle_xsetbv;
 use crate::arch::Architecture;
 use kernelutils::nt::{platform_ops, switch_stack};
 use kernelutils::Registers;
-use crate::amd::guest::support::apic_id;
+use crate::amd::guest::support::{apic_id, local_apic};
 
 pub(crate) fn main(registers: &Registers) -> ! {
     unsafe { x86::irq::disable() };
@@ -24,30 +56,72 @@ pub(crate) fn main(registers: &Registers) -> ! {
     guest.activate();
 
     guest.initialize(registers);
-
+    hooks::get().initialize(&mut guest);
+    #[cfg(debug_assertions)]
+    guest.assert_vmcb_consistent();
+    processors::set_vcpu(id, &mut guest);
+    processors::set_virtualized(id, true);
 
     loop {
         let reason = guest.run();
 
 
-        if let VmExitReason::Cpuid(info) = reason {
-            handle_cpuid(&mut guest, &info);
+        match reason {
+            VmExitReason::Cpuid(info) => handle_cpuid(&mut guest, &info),
+            VmExitReason::Vmmcall(info) => handle_vmmcall(&mut guest, &info),
+            VmExitReason::CrAccess(info) => handle_cr_access(&mut guest, &info),
+            VmExitReason::DrAccess(info) => handle_dr_access(&mut guest, &info),
+            VmExitReason::Exception(exception) => handle_exception(&mut guest, &exception),
+            VmExitReason::XSetBv(info) => handle_xsetbv(&mut guest, &info),
+            VmExitReason::Rdtsc(info) => handle_tsc_read(&mut guest, &info, false),
+            VmExitReason::Rdtscp(info) => handle_tsc_read(&mut guest, &info, true),
+            VmExitReason::Pause(info) => handle_pause(&mut guest, &info),
+            VmExitReason::Hlt(info) => handle_hlt(&mut guest, &info),
+            VmExitReason::Shutdown => handle_shutdown(&mut guest),
+            VmExitReason::Unknown(exit) => handle_unknown_exit(&mut guest, &exit),
+            _ => {}
+        }
+
+        if guest.is_devirtualization_requested() {
+            guest.devirtualize();
         }
     }
 }
 
-pub fn virtualize_system() {
+/// Virtualizes the processors selected by `config`, which becomes the current
+/// configuration. Must be called once, at PASSIVE_LEVEL.
+pub fn virtualize_system(config: HypervisorConfig) {
+    config::set(config);
     platform_ops::init(Box::new(platform_ops::WindowsOps));
 
     apic_id::init();
-    platform_ops::get().run_on_all_processors(|| {
-        let registers = Registers::capture_current();
+    local_apic::init();
+    let ops = platform_ops::get();
+    for index in 0..ops.processor_count() {
+        let number = ops.processor_number(index);
+        if !config::read().processors.contains(index as usize, number.group, number.number) {
+            log::info!("Leaving processor {index} unvirtualized");
+            continue;
+        }
+        ops.run_on_processor(index, &mut virtualize_current_processor);
+    }
+}
+
+/// Virtualizes the current processor, unless it already is, and returns as
+/// the guest.
+pub(crate) fn virtualize_current_processor() {
+    let Some(id) = apic_id::processor_id_from(apic_id::get()) else {
+        log::error!("Leaving untracked processor with APIC ID {} unvirtualized", apic_id::get());
+        return;
+    };
+    let registers = Registers::capture_current();
+    // The guest starts here with the captured registers, and returns.
+    if is_virtualized(id) {
+        return;
+    }
 
-        log::info!("Virtualizing the current processor");
+    log::info!("Virtualizing processor {id}");
 
-        unsafe { asm!("int 3") }
-        switch_stack::jump_with_new_stack(main, &registers);
-        #[allow(dead_code)]
-        log::info!("Virtualized the current processor");
-    });
+    config::debug_break(DebugBreaks::VIRTUALIZE);
+    switch_stack::jump_with_stack(main, &registers, processors::host_stack(id));
 }
@@ -0,0 +1,135 @@
+//! This module implements the tracking of each processor under the hypervisor.
+//!
+//! A processor is identified by its processor ID, which is its system processor
+//! index. It can be virtualized and devirtualized any number of times. Its host
+//! stack is allocated the first time and reused after, as nothing runs on it
+//! while the processor is not virtualized. Every table is sized for
+//! [`MAX_PROCESSORS`] up front, so that processors added at runtime need no
+//! reallocation while other processors read the tables.
+
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+
+use kernelutils::nt::platform_ops::{self, ProcessorNumber};
+use kernelutils::nt::switch_stack;
+
+use crate::amd::guest::support::apic_id;
+use crate::amd::{stats, VCpu};
+
+/// The number of processors that can be tracked, one per xAPIC ID. It is the
+/// number the control interface describes.
+pub const MAX_PROCESSORS: usize = hv_interface::MAX_PROCESSORS;
+
+const _: () = assert!(MAX_PROCESSORS == 1 << u8::BITS && MAX_PROCESSORS % 64 == 0);
+
+struct ProcessorEntry {
+    virtualized: AtomicBool,
+    hot_added: AtomicBool,
+    /// Whether the processor was devirtualized by [`crate::amd::control::suspend`].
+    suspended: AtomicBool,
+    /// The base of the host stack, or 0 if not allocated yet.
+    host_stack: AtomicU64,
+    /// The vCPU while the processor is virtualized, or null.
+    vcpu: AtomicPtr<VCpu>,
+}
+
+static PROCESSORS: [ProcessorEntry; MAX_PROCESSORS] = [const {
+    ProcessorEntry {
+        virtualized: AtomicBool::new(false),
+        hot_added: AtomicBool::new(false),
+        suspended: AtomicBool::new(false),
+        host_stack: AtomicU64::new(0),
+        vcpu: AtomicPtr::new(null_mut()),
+    }
+}; MAX_PROCESSORS];
+
+/// What is known about a processor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProcessorStatus {
+    pub processor_id: usize,
+    pub number: ProcessorNumber,
+    pub apic_id: u8,
+    /// Whether the processor runs as a guest of the hypervisor.
+    pub virtualized: bool,
+    /// Whether the processor was added after the hypervisor was loaded.
+    pub hot_added: bool,
+}
+
+/// Returns the status of the processor, or `None` if it does not exist.
+pub fn status(processor_id: usize) -> Option<ProcessorStatus> {
+    let apic_id = apic_id::apic_id_of(processor_id)?;
+    Some(ProcessorStatus {
+        processor_id,
+        number: platform_ops::get().processor_number(processor_id as u32),
+        apic_id,
+        virtualized: is_virtualized(processor_id),
+        hot_added: is_hot_added(processor_id),
+    })
+}
+
+/// Returns whether the processor is running as a guest of the hypervisor.
+pub fn is_virtualized(processor_id: usize) -> bool {
+    PROCESSORS
+        .get(processor_id)
+        .is_some_and(|processor| processor.virtualized.load(Ordering::Acquire))
+}
+
+pub(crate) fn set_virtualized(processor_id: usize, virtualized: bool) {
+    PROCESSORS[processor_id].virtualized.store(virtualized, Ordering::Release);
+}
+
+/// Returns whether the processor was added after the hypervisor was loaded.
+pub fn is_hot_added(processor_id: usize) -> bool {
+    PROCESSORS
+        .get(processor_id)
+        .is_some_and(|processor| processor.hot_added.load(Ordering::Relaxed))
+}
+
+pub(crate) fn set_hot_added(processor_id: usize) {
+    PROCESSORS[processor_id].hot_added.store(true, Ordering::Relaxed);
+}
+
+pub(crate) fn is_suspended(processor_id: usize) -> bool {
+    PROCESSORS
+        .get(processor_id)
+        .is_some_and(|processor| processor.suspended.load(Ordering::Relaxed))
+}
+
+pub(crate) fn set_suspended(processor_id: usize, suspended: bool) {
+    if let Some(processor) = PROCESSORS.get(processor_id) {
+        processor.suspended.store(suspended, Ordering::Relaxed);
+    }
+}
+
+/// Publishes the vCPU of the processor, or withdraws it with null. Called only
+/// on the processor itself, before the vCPU runs and before it is dropped.
+pub(crate) fn set_vcpu(processor_id: usize, vcpu: *mut VCpu) {
+    PROCESSORS[processor_id].vcpu.store(vcpu, Ordering::Release);
+}
+
+/// Returns the vCPU of the processor, if it is virtualized.
+///
+/// # Safety
+///
+/// The processor must not run the host while the vCPU is in use, as the host
+/// modifies it without synchronization. This holds when the system bug
+/// checks, as other processors are stopped in the guest then.
+pub(crate) unsafe fn vcpu(processor_id: usize) -> Option<&'static VCpu> {
+    let vcpu = PROCESSORS.get(processor_id)?.vcpu.load(Ordering::Acquire);
+    unsafe { vcpu.as_ref() }
+}
+
+/// Returns the host stack of the processor, allocating it on the first call.
+/// Called only on the processor itself.
+pub(crate) fn host_stack(processor_id: usize) -> u64 {
+    let host_stack = &PROCESSORS[processor_id].host_stack;
+    match host_stack.load(Ordering::Relaxed) {
+        0 => {
+            let stack_base = switch_stack::allocate_stack();
+            stats::account_host_stack(switch_stack::STACK_SIZE);
+            host_stack.store(stack_base, Ordering::Relaxed);
+            stack_base
+        }
+        stack_base => stack_base,
+    }
+}
@@ -0,0 +1,239 @@
+//! This module implements per-processor #VMEXIT statistics and accounting of
+//! host memory used by the hypervisor.
+//!
+//! Counters are updated only by the processor they belong to, and can be read
+//! from any processor at any time. Snapshots taken while the guest is running
+//! are consistent per counter but not across counters.
+
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Once;
+
+use crate::amd::processors::MAX_PROCESSORS;
+
+/// The number of distinct exit reasons counted.
+pub const EXIT_SLOT_COUNT: usize = 0xae;
+
+// Exit codes 0x0 - 0xa6 are counted as-is. The others are packed after them.
+// See: Appendix C SVM Intercept Exit Codes
+const LAST_CONTIGUOUS_EXIT_CODE: u64 = 0xa6;
+const VMEXIT_NPF: u64 = 0x400;
+const VMEXIT_VMGEXIT: u64 = 0x403;
+const VMEXIT_INVALID: u64 = u64::MAX;
+const SLOT_NPF: usize = 0xa8;
+const SLOT_INVALID: usize = 0xac;
+const SLOT_UNKNOWN: usize = 0xad;
+
+/// Returns the index into the per-exit-reason counters for `exit_code`.
+pub fn exit_slot(exit_code: u64) -> usize {
+    match exit_code {
+        0..=LAST_CONTIGUOUS_EXIT_CODE => exit_code as usize,
+        VMEXIT_NPF..=VMEXIT_VMGEXIT => SLOT_NPF + (exit_code - VMEXIT_NPF) as usize,
+        VMEXIT_INVALID => SLOT_INVALID,
+        _ => SLOT_UNKNOWN,
+    }
+}
+
+/// Returns the exit code counted in `slot`, or `None` for the slot counting
+/// unknown exit codes and unused slots.
+pub fn exit_code_of_slot(slot: usize) -> Option<u64> {
+    match slot {
+        0..=0xa6 => Some(slot as u64),
+        SLOT_NPF..=0xab => Some(VMEXIT_NPF + (slot - SLOT_NPF) as u64),
+        SLOT_INVALID => Some(VMEXIT_INVALID),
+        _ => None,
+    }
+}
+
+/// A snapshot of the statistics of a processor or, for [`total`], of all
+/// processors. The layout is shared with the hypercall and IOCTL interfaces.
+#[derive(Clone, Debug)]
+#[repr(C)]
+pub struct ExitStatistics {
+    /// The number of processors the snapshot covers.
+    pub processor_count: u32,
+    pub reserved: u32,
+    /// TSC ticks spent running the guest, that is, from VMRUN to #VMEXIT.
+    pub guest_cycles: u64,
+    /// TSC ticks spent in the host, that is, from #VMEXIT to the next VMRUN.
+    pub host_cycles: u64,
+    /// The number of events injected into the guest.
+    pub injected_events: u64,
+    /// The number of #VMEXITs per exit reason. See [`exit_slot`].
+    pub exit_counts: [u64; EXIT_SLOT_COUNT],
+    /// The longest time in TSC ticks the host spent handling a #VMEXIT, per
+    /// exit reason.
+    pub max_exit_cycles: [u64; EXIT_SLOT_COUNT],
+}
+
+impl ExitStatistics {
+    const fn new() -> Self {
+        Self {
+            processor_count: 0,
+            reserved: 0,
+            guest_cycles: 0,
+            host_cycles: 0,
+            injected_events: 0,
+            exit_counts: [0; EXIT_SLOT_COUNT],
+            max_exit_cycles: [0; EXIT_SLOT_COUNT],
+        }
+    }
+}
+
+/// Host memory allocated by the hypervisor, in bytes.
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct MemoryUsage {
+    pub vmcb_bytes: u64,
+    pub npt_bytes: u64,
+    pub host_stack_bytes: u64,
+}
+
+/// The live counters of a processor.
+pub(crate) struct VcpuStatistics {
+    guest_cycles: AtomicU64,
+    host_cycles: AtomicU64,
+    injected_events: AtomicU64,
+    exit_counts: [AtomicU64; EXIT_SLOT_COUNT],
+    max_exit_cycles: [AtomicU64; EXIT_SLOT_COUNT],
+}
+
+impl VcpuStatistics {
+    fn new() -> Self {
+        Self {
+            guest_cycles: AtomicU64::new(0),
+            host_cycles: AtomicU64::new(0),
+            injected_events: AtomicU64::new(0),
+            exit_counts: core::array::from_fn(|_| AtomicU64::new(0)),
+            max_exit_cycles: core::array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+
+    pub(crate) fn record_exit(&self, slot: usize, guest_cycles: u64) {
+        add(&self.exit_counts[slot], 1);
+        add(&self.guest_cycles, guest_cycles);
+    }
+
+    pub(crate) fn record_handling(&self, slot: usize, host_cycles: u64) {
+        add(&self.host_cycles, host_cycles);
+        // Only this processor updates the counter. No need for fetch_max.
+        if self.max_exit_cycles[slot].load(Ordering::Relaxed) < host_cycles {
+            self.max_exit_cycles[slot].store(host_cycles, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn record_injection(&self) {
+        add(&self.injected_events, 1);
+    }
+
+    /// Returns the guest cycles, the host cycles and the number of injected
+    /// events.
+    pub(crate) fn totals(&self) -> [u64; 3] {
+        [
+            self.guest_cycles.load(Ordering::Relaxed),
+            self.host_cycles.load(Ordering::Relaxed),
+            self.injected_events.load(Ordering::Relaxed),
+        ]
+    }
+
+    pub(crate) fn exit_count(&self, slot: usize) -> u64 {
+        self.exit_counts[slot].load(Ordering::Relaxed)
+    }
+
+    fn accumulate(&self, snapshot: &mut ExitStatistics) {
+        snapshot.processor_count += 1;
+        snapshot.guest_cycles += self.guest_cycles.load(Ordering::Relaxed);
+        snapshot.host_cycles += self.host_cycles.load(Ordering::Relaxed);
+        snapshot.injected_events += self.injected_events.load(Ordering::Relaxed);
+        for slot in 0..EXIT_SLOT_COUNT {
+            snapshot.exit_counts[slot] += self.exit_counts[slot].load(Ordering::Relaxed);
+            snapshot.max_exit_cycles[slot] = snapshot.max_exit_cycles[slot]
+                .max(self.max_exit_cycles[slot].load(Ordering::Relaxed));
+        }
+    }
+
+    fn reset(&self) {
+        self.guest_cycles.store(0, Ordering::Relaxed);
+        self.host_cycles.store(0, Ordering::Relaxed);
+        self.injected_events.store(0, Ordering::Relaxed);
+        for slot in 0..EXIT_SLOT_COUNT {
+            self.exit_counts[slot].store(0, Ordering::Relaxed);
+            self.max_exit_cycles[slot].store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+// Non-atomic read-modify-write is fine as each counter has a single writer.
+fn add(counter: &AtomicU64, value: u64) {
+    counter.store(counter.load(Ordering::Relaxed).wrapping_add(value), Ordering::Relaxed);
+}
+
+/// The counters of each processor, indexed by the processor ID. They are never
+/// freed so that they survive the processor being devirtualized.
+static STATISTICS: [Once<Box<VcpuStatistics>>; MAX_PROCESSORS] = [const { Once::new() }; MAX_PROCESSORS];
+
+static VMCB_BYTES: AtomicU64 = AtomicU64::new(0);
+static NPT_BYTES: AtomicU64 = AtomicU64::new(0);
+static HOST_STACK_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the counters of the processor, if it has ever been virtualized.
+pub(crate) fn get(processor_id: usize) -> Option<&'static VcpuStatistics> {
+    STATISTICS.get(processor_id).and_then(Once::get).map(|statistics| &**statistics)
+}
+
+/// Returns the counters of the processor, allocating them on the first call.
+pub(crate) fn for_processor(processor_id: usize) -> &'static VcpuStatistics {
+    STATISTICS[processor_id].call_once(|| Box::new(VcpuStatistics::new()))
+}
+
+pub(crate) fn account_vmcb(bytes: usize) {
+    VMCB_BYTES.fetch_add(bytes as u64, Ordering::Relaxed);
+}
+
+pub(crate) fn release_vmcb(bytes: usize) {
+    VMCB_BYTES.fetch_sub(bytes as u64, Ordering::Relaxed);
+}
+
+pub(crate) fn account_npt(bytes: usize) {
+    NPT_BYTES.fetch_add(bytes as u64, Ordering::Relaxed);
+}
+
+pub(crate) fn account_host_stack(bytes: usize) {
+    HOST_STACK_BYTES.fetch_add(bytes as u64, Ordering::Relaxed);
+}
+
+/// Returns the statistics of the processor, or `None` if the processor has
+/// never been virtualized.
+pub fn snapshot(processor_id: usize) -> Option<ExitStatistics> {
+    let statistics = get(processor_id)?;
+    let mut snapshot = ExitStatistics::new();
+    statistics.accumulate(&mut snapshot);
+    Some(snapshot)
+}
+
+/// Returns the statistics summed over all processors. `max_exit_cycles` is the
+/// maximum across processors.
+pub fn total() -> ExitStatistics {
+    let mut snapshot = ExitStatistics::new();
+    for statistics in STATISTICS.iter().filter_map(Once::get) {
+        statistics.accumulate(&mut snapshot);
+    }
+    snapshot
+}
+
+/// Returns the amount of host memory in use.
+pub fn memory_usage() -> MemoryUsage {
+    MemoryUsage {
+        vmcb_bytes: VMCB_BYTES.load(Ordering::Relaxed),
+        npt_bytes: NPT_BYTES.load(Ordering::Relaxed),
+        host_stack_bytes: HOST_STACK_BYTES.load(Ordering::Relaxed),
+    }
+}
+
+/// Clears the counters of all processors. Memory usage is not affected. An
+/// update racing with the reset on a running processor may survive it.
+pub fn reset() {
+    for statistics in STATISTICS.iter().filter_map(Once::get) {
+        statistics.reset();
+    }
+}
@@ -0,0 +1,217 @@
+//! This module implements the per-processor #VMEXIT trace.
+//!
+//! Each processor records its last [`TRACE_ENTRY_COUNT`] #VMEXITs into a ring
+//! buffer. The buffer has a single writer, the processor itself, and can be
+//! exported from any processor without stopping it.
+//!
+//! # Export format (version 1)
+//!
+//! All values are little endian. The export starts with a 32 byte header:
+//!
+//! | Offset | Type      | Field                                               |
+//! |--------|-----------|-----------------------------------------------------|
+//! | 0x00   | `[u8; 4]` | Magic, `b"HVTR"`                                    |
+//! | 0x04   | `u16`     | Format version, 1                                   |
+//! | 0x06   | `u16`     | Header size in bytes, 32                            |
+//! | 0x08   | `u16`     | Entry size in bytes, 64                             |
+//! | 0x0a   | `u16`     | Reserved                                            |
+//! | 0x0c   | `u32`     | Processor ID                                        |
+//! | 0x10   | `u32`     | Number of entries following the header              |
+//! | 0x14   | `u32`     | Reserved                                            |
+//! | 0x18   | `u64`     | Number of #VMEXITs recorded since the trace started |
+//!
+//! Entries follow the header, oldest first, each 64 bytes:
+//!
+//! | Offset | Type  | Field                                                  |
+//! |--------|-------|--------------------------------------------------------|
+//! | 0x00   | `u64` | Sequence number of the #VMEXIT, starting from 1        |
+//! | 0x08   | `u64` | TSC at #VMEXIT                                         |
+//! | 0x10   | `u64` | EXITCODE                                               |
+//! | 0x18   | `u64` | EXITINFO1                                              |
+//! | 0x20   | `u64` | EXITINFO2                                              |
+//! | 0x28   | `u64` | Guest RIP                                              |
+//! | 0x30   | `u64` | Guest CR3                                              |
+//! | 0x38   | `u32` | Action taken, see [`TraceAction`]                      |
+//! | 0x3c   | `u32` | Reserved                                               |
+//!
+//! Readers must ignore entries whose size exceeds the known layout rather than
+//! reject them, so that fields can be appended without a version change.
+
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Once;
+
+use crate::amd::processors::MAX_PROCESSORS;
+
+/// The number of #VMEXITs kept per processor.
+pub const TRACE_ENTRY_COUNT: usize = 256;
+
+pub const TRACE_MAGIC: [u8; 4] = *b"HVTR";
+pub const TRACE_VERSION: u16 = 1;
+pub const TRACE_HEADER_SIZE: usize = 32;
+pub const TRACE_ENTRY_SIZE: usize = 64;
+
+/// The size of the buffer [`export`] needs to export the whole trace.
+pub const TRACE_EXPORT_SIZE: usize = TRACE_HEADER_SIZE + TRACE_ENTRY_SIZE * TRACE_ENTRY_COUNT;
+
+/// What the hypervisor did in response to a #VMEXIT.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceAction {
+    /// The #VMEXIT is still being handled.
+    Pending = 0,
+    /// The guest was resumed at the same RIP.
+    Resumed = 1,
+    /// The instruction was emulated and the guest was resumed after it.
+    Emulated = 2,
+    /// An event was injected into the guest.
+    Injected = 3,
+}
+
+#[derive(thiserror_no_std::Error, Clone, Copy, Debug)]
+pub enum TraceError {
+    #[error("processor `{processor_id}` has never been virtualized")]
+    NotTraced { processor_id: usize },
+
+    #[error("the buffer is `{size}` bytes but at least `{required}` bytes are required")]
+    BufferTooSmall { size: usize, required: usize },
+}
+
+// Indexes of the fields within an entry.
+const SEQUENCE: usize = 0;
+const TSC: usize = 1;
+const EXIT_CODE: usize = 2;
+const EXIT_INFO1: usize = 3;
+const EXIT_INFO2: usize = 4;
+const RIP: usize = 5;
+const CR3: usize = 6;
+const ACTION: usize = 7;
+
+/// A #VMEXIT to record.
+pub(crate) struct TraceRecord {
+    pub(crate) tsc: u64,
+    pub(crate) exit_code: u64,
+    pub(crate) exit_info1: u64,
+    pub(crate) exit_info2: u64,
+    pub(crate) rip: u64,
+    pub(crate) cr3: u64,
+}
+
+/// The ring buffer of a processor. Each entry is guarded by its sequence
+/// number: it is zeroed while the entry is being written, and readers discard
+/// the entry if it changed while they copied it.
+pub(crate) struct TraceBuffer {
+    entries: [[AtomicU64; TRACE_ENTRY_SIZE / 8]; TRACE_ENTRY_COUNT],
+    total_exits: AtomicU64,
+}
+
+impl TraceBuffer {
+    fn new() -> Self {
+        Self {
+            entries: core::array::from_fn(|_| core::array::from_fn(|_| AtomicU64::new(0))),
+            total_exits: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn record(&self, record: &TraceRecord) {
+        let sequence = self.total_exits.load(Ordering::Relaxed) + 1;
+        let entry = &self.entries[(sequence - 1) as usize % TRACE_ENTRY_COUNT];
+
+        entry[SEQUENCE].store(0, Ordering::Relaxed);
+        core::sync::atomic::fence(Ordering::Release);
+        entry[TSC].store(record.tsc, Ordering::Relaxed);
+        entry[EXIT_CODE].store(record.exit_code, Ordering::Relaxed);
+        entry[EXIT_INFO1].store(record.exit_info1, Ordering::Relaxed);
+        entry[EXIT_INFO2].store(record.exit_info2, Ordering::Relaxed);
+        entry[RIP].store(record.rip, Ordering::Relaxed);
+        entry[CR3].store(record.cr3, Ordering::Relaxed);
+        entry[ACTION].store(TraceAction::Pending as u64, Ordering::Relaxed);
+        entry[SEQUENCE].store(sequence, Ordering::Release);
+        self.total_exits.store(sequence, Ordering::Release);
+    }
+
+    /// Updates the action of the most recently recorded #VMEXIT.
+    pub(crate) fn complete(&self, action: TraceAction) {
+        let sequence = self.total_exits.load(Ordering::Relaxed);
+        if sequence != 0 {
+            let entry = &self.entries[(sequence - 1) as usize % TRACE_ENTRY_COUNT];
+            entry[ACTION].store(action as u64, Ordering::Relaxed);
+        }
+    }
+
+    fn export(&self, processor_id: usize, buffer: &mut [u8]) -> usize {
+        let (count, total_exits) = self.write_entries(TRACE_ENTRY_COUNT, &mut buffer[TRACE_HEADER_SIZE..]);
+
+        let header = &mut buffer[..TRACE_HEADER_SIZE];
+        header.fill(0);
+        header[0x00..0x04].copy_from_slice(&TRACE_MAGIC);
+        header[0x04..0x06].copy_from_slice(&TRACE_VERSION.to_le_bytes());
+        header[0x06..0x08].copy_from_slice(&(TRACE_HEADER_SIZE as u16).to_le_bytes());
+        header[0x08..0x0a].copy_from_slice(&(TRACE_ENTRY_SIZE as u16).to_le_bytes());
+        header[0x0c..0x10].copy_from_slice(&(processor_id as u32).to_le_bytes());
+        header[0x10..0x14].copy_from_slice(&(count as u32).to_le_bytes());
+        header[0x18..0x20].copy_from_slice(&total_exits.to_le_bytes());
+
+        TRACE_HEADER_SIZE + count * TRACE_ENTRY_SIZE
+    }
+
+    /// Writes up to the last `max_count` entries into `buffer` in the export
+    /// format, oldest first, and returns the number of entries written and the
+    /// number of #VMEXITs recorded. `buffer` must hold `max_count` entries.
+    pub(crate) fn write_entries(&self, max_count: usize, buffer: &mut [u8]) -> (usize, u64) {
+        let total_exits = self.total_exits.load(Ordering::Acquire);
+        let first = total_exits.saturating_sub(max_count.min(TRACE_ENTRY_COUNT) as u64) + 1;
+
+        let mut count = 0;
+        for sequence in first..=total_exits {
+            let entry = &self.entries[(sequence - 1) as usize % TRACE_ENTRY_COUNT];
+            if entry[SEQUENCE].load(Ordering::Acquire) != sequence {
+                continue;
+            }
+            let fields: [u64; TRACE_ENTRY_SIZE / 8] =
+                core::array::from_fn(|i| entry[i].load(Ordering::Relaxed));
+            core::sync::atomic::fence(Ordering::Acquire);
+            if entry[SEQUENCE].load(Ordering::Relaxed) != sequence {
+                // Overwritten while being copied.
+                continue;
+            }
+
+            let offset = count * TRACE_ENTRY_SIZE;
+            let out = &mut buffer[offset..offset + TRACE_ENTRY_SIZE];
+            for (i, field) in fields.iter().enumerate().take(ACTION) {
+                out[i * 8..i * 8 + 8].copy_from_slice(&field.to_le_bytes());
+            }
+            out[ACTION * 8..ACTION * 8 + 4].copy_from_slice(&(fields[ACTION] as u32).to_le_bytes());
+            out[ACTION * 8 + 4..].fill(0);
+            count += 1;
+        }
+        (count, total_exits)
+    }
+}
+
+/// The trace of each processor, indexed by the processor ID.
+static TRACES: [Once<Box<TraceBuffer>>; MAX_PROCESSORS] = [const { Once::new() }; MAX_PROCESSORS];
+
+/// Returns the trace of the processor, allocating it on the first call.
+pub(crate) fn for_processor(processor_id: usize) -> &'static TraceBuffer {
+    TRACES[processor_id].call_once(|| Box::new(TraceBuffer::new()))
+}
+
+/// Returns the trace of the processor, if it has ever been virtualized.
+pub(crate) fn get(processor_id: usize) -> Option<&'static TraceBuffer> {
+    TRACES.get(processor_id).and_then(Once::get).map(|trace| &**trace)
+}
+
+/// Writes the trace of the processor into `buffer` in the export format, and
+/// returns the number of bytes written. `buffer` must be at least
+/// [`TRACE_EXPORT_SIZE`] bytes.
+pub fn export(processor_id: usize, buffer: &mut [u8]) -> Result<usize, TraceError> {
+    let trace = get(processor_id).ok_or(TraceError::NotTraced { processor_id })?;
+    if buffer.len() < TRACE_EXPORT_SIZE {
+        return Err(TraceError::BufferTooSmall {
+            size: buffer.len(),
+            required: TRACE_EXPORT_SIZE,
+        });
+    }
+    Ok(trace.export(processor_id, buffer))
+}
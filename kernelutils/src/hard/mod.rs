@@ -43,8 +43,22 @@ pub struct Registers {
     pub xmm3: Xmm,
     pub xmm4: Xmm,
     pub xmm5: Xmm,
+    pub xmm6: Xmm,
+    pub xmm7: Xmm,
+    pub xmm8: Xmm,
+    pub xmm9: Xmm,
+    pub xmm10: Xmm,
+    pub xmm11: Xmm,
+    pub xmm12: Xmm,
+    pub xmm13: Xmm,
+    pub xmm14: Xmm,
+    pub xmm15: Xmm,
+    /// Loaded with LDMXCSR, so reserved bits must be clear.
+    pub mxcsr: u32,
 }
-const _: () = assert!(core::mem::size_of::<Registers>() == 0xf0);
+const _: () = assert!(core::mem::offset_of!(Registers, xmm15) == 0x180);
+const _: () = assert!(core::mem::offset_of!(Registers, mxcsr) == 0x190);
+const _: () = assert!(core::mem::size_of::<Registers>() == 0x1a0);
 
 impl Registers {
     #[inline(always)]
@@ -53,6 +67,61 @@ impl Registers {
         unsafe { capture_registers(&mut registers) };
         registers
     }
+
+    /// Returns the general purpose register numbered `index` as in the ModRM
+    /// and REX encodings: RAX, RCX, RDX, RBX, RSP, RBP, RSI, RDI, then R8-R15.
+    pub fn gpr(&self, index: u8) -> u64 {
+        *self.gpr_ref(index)
+    }
+
+    /// Sets the general purpose register numbered `index`. See [`Self::gpr`].
+    pub fn set_gpr(&mut self, index: u8, value: u64) {
+        *self.gpr_mut(index) = value;
+    }
+
+    fn gpr_ref(&self, index: u8) -> &u64 {
+        match index {
+            0 => &self.rax,
+            1 => &self.rcx,
+            2 => &self.rdx,
+            3 => &self.rbx,
+            4 => &self.rsp,
+            5 => &self.rbp,
+            6 => &self.rsi,
+            7 => &self.rdi,
+            8 => &self.r8,
+            9 => &self.r9,
+            10 => &self.r10,
+            11 => &self.r11,
+            12 => &self.r12,
+            13 => &self.r13,
+            14 => &self.r14,
+            15 => &self.r15,
+            _ => panic!("Invalid general purpose register number {index}"),
+        }
+    }
+
+    fn gpr_mut(&mut self, index: u8) -> &mut u64 {
+        match index {
+            0 => &mut self.rax,
+            1 => &mut self.rcx,
+            2 => &mut self.rdx,
+            3 => &mut self.rbx,
+            4 => &mut self.rsp,
+            5 => &mut self.rbp,
+            6 => &mut self.rsi,
+            7 => &mut self.rdi,
+            8 => &mut self.r8,
+            9 => &mut self.r9,
+            10 => &mut self.r10,
+            11 => &mut self.r11,
+            12 => &mut self.r12,
+            13 => &mut self.r13,
+            14 => &mut self.r14,
+            15 => &mut self.r15,
+            _ => panic!("Invalid general purpose register number {index}"),
+        }
+    }
 }
 
 #[repr(C, align(16))]
@@ -17,7 +17,9 @@ pub use kernel_alloc::KernelAlloc;
 
 pub use crate::misc::OwnedUnicodeString;
 pub use crate::misc::str_to_unicode;
+pub use crate::misc::unicode_to_string;
 pub use crate::misc::HypervisorError;
+pub use crate::misc::RegistryValue;
 pub use hard::CPUVersion;
 pub use hard::get_cpu_version;
 pub use hard::Registers;
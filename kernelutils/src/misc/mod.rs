@@ -3,4 +3,7 @@ mod error;
 
 pub use uni::OwnedUnicodeString;
 pub use uni::str_to_unicode;
+pub use uni::unicode_to_string;
 pub use error::HypervisorError;
+pub use hv_core::RegistryValue;
+pub(crate) use hv_core::registry_value::utf16;
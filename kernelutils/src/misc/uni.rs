@@ -1,3 +1,4 @@
+use alloc::string::String;
 use alloc::vec::Vec;
 use wdk_sys::UNICODE_STRING;
 
@@ -61,3 +62,22 @@ pub fn str_to_unicode(s: &str) -> OwnedUnicodeString {
         _phantompinned: core::marker::PhantomPinned,
     }
 }
+
+/// Converts a `UNICODE_STRING` to a Rust `String`, replacing invalid UTF-16.
+///
+/// # Arguments
+///
+/// - `s`: The `UNICODE_STRING` to be converted. Its buffer must be valid for
+///   `Length` bytes.
+///
+/// # Returns
+///
+/// - `String`: The converted string, without a null terminator.
+///
+pub fn unicode_to_string(s: &UNICODE_STRING) -> String {
+    if s.Buffer.is_null() {
+        return String::new();
+    }
+    let wide_string = unsafe { core::slice::from_raw_parts(s.Buffer, usize::from(s.Length) / 2) };
+    String::from_utf16_lossy(wide_string)
+}
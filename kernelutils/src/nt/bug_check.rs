@@ -0,0 +1,148 @@
+//! This module implements adding secondary data to crash dumps through a bug
+//! check reason callback.
+
+use alloc::boxed::Box;
+use alloc::vec;
+use core::ffi::{c_void, CStr};
+use core::ptr::{addr_of_mut, null_mut};
+
+use wdk_sys::{LIST_ENTRY, NTSTATUS, STATUS_UNSUCCESSFUL};
+
+/// KbCallbackSecondaryDumpData of KBUGCHECK_CALLBACK_REASON.
+const KB_CALLBACK_SECONDARY_DUMP_DATA: u32 = 2;
+
+/// KBUGCHECK_REASON_CALLBACK_RECORD. Owned by the system while registered.
+#[repr(C)]
+struct ReasonCallbackRecord {
+    entry: LIST_ENTRY,
+    callback_routine: Option<ReasonCallback>,
+    component: *const u8,
+    checksum: usize,
+    reason: u32,
+    state: u8,
+}
+
+/// KBUGCHECK_SECONDARY_DUMP_DATA.
+#[repr(C)]
+struct SecondaryDumpData {
+    in_buffer: *mut c_void,
+    in_buffer_length: u32,
+    maximum_allowed: u32,
+    guid: [u8; 16],
+    out_buffer: *mut c_void,
+    out_buffer_length: u32,
+}
+
+struct Registration {
+    guid: [u8; 16],
+    /// Nonpaged, as it is written while the system bug checks.
+    buffer: &'static mut [u8],
+    write: fn(&mut [u8]) -> usize,
+}
+
+static mut RECORD: ReasonCallbackRecord = ReasonCallbackRecord {
+    entry: LIST_ENTRY {
+        Flink: null_mut(),
+        Blink: null_mut(),
+    },
+    callback_routine: None,
+    component: core::ptr::null(),
+    checksum: 0,
+    reason: 0,
+    state: 0,
+};
+static mut REGISTRATION: Option<Registration> = None;
+
+/// Adds up to `size` bytes of data tagged with `guid` to crash dumps. When the
+/// system bug checks, `write` fills a buffer allocated here and returns the
+/// number of bytes to add. It runs at HIGH_LEVEL with the other processors
+/// stopped, so it must not allocate, wait or take locks. Must be called at
+/// PASSIVE_LEVEL, and not again before [`unregister_secondary_dump_data`].
+pub fn register_secondary_dump_data(
+    component: &'static CStr,
+    guid: [u8; 16],
+    size: usize,
+    write: fn(&mut [u8]) -> usize,
+) -> Result<(), NTSTATUS> {
+    let buffer = Box::leak(vec![0u8; size].into_boxed_slice());
+    unsafe {
+        *addr_of_mut!(REGISTRATION) = Some(Registration {
+            guid,
+            buffer,
+            write,
+        });
+        let registered = KeRegisterBugCheckReasonCallback(
+            addr_of_mut!(RECORD),
+            secondary_dump_data,
+            KB_CALLBACK_SECONDARY_DUMP_DATA,
+            component.as_ptr().cast(),
+        );
+        if registered == 0 {
+            free_registration();
+            return Err(STATUS_UNSUCCESSFUL);
+        }
+    }
+    Ok(())
+}
+
+/// Stops adding the data registered with [`register_secondary_dump_data`], if
+/// any.
+pub fn unregister_secondary_dump_data() {
+    unsafe {
+        if (*addr_of_mut!(REGISTRATION)).is_some() {
+            KeDeregisterBugCheckReasonCallback(addr_of_mut!(RECORD));
+            free_registration();
+        }
+    }
+}
+
+unsafe fn free_registration() {
+    if let Some(registration) = unsafe { (*addr_of_mut!(REGISTRATION)).take() } {
+        drop(unsafe { Box::from_raw(registration.buffer as *mut [u8]) });
+    }
+}
+
+unsafe extern "system" fn secondary_dump_data(
+    reason: u32,
+    _record: *mut ReasonCallbackRecord,
+    reason_specific_data: *mut c_void,
+    reason_specific_data_length: u32,
+) {
+    if reason != KB_CALLBACK_SECONDARY_DUMP_DATA
+        || (reason_specific_data_length as usize) < size_of::<SecondaryDumpData>()
+    {
+        return;
+    }
+    let Some(registration) = (unsafe { (*addr_of_mut!(REGISTRATION)).as_mut() }) else {
+        return;
+    };
+    let data = unsafe { &mut *reason_specific_data.cast::<SecondaryDumpData>() };
+
+    let size = registration.buffer.len().min(data.maximum_allowed as usize);
+    let buffer = &mut registration.buffer[..size];
+    let written = (registration.write)(buffer);
+    data.guid = registration.guid;
+    data.out_buffer = buffer.as_mut_ptr().cast();
+    data.out_buffer_length = written.min(size) as u32;
+}
+
+type ReasonCallback = unsafe extern "system" fn(
+    reason: u32,
+    record: *mut ReasonCallbackRecord,
+    reason_specific_data: *mut c_void,
+    reason_specific_data_length: u32,
+);
+
+#[link(name = "ntoskrnl")]
+extern "system" {
+    /// https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdm/nf-wdm-keregisterbugcheckreasoncallback
+    fn KeRegisterBugCheckReasonCallback(
+        callback_record: *mut ReasonCallbackRecord,
+        callback_routine: ReasonCallback,
+        reason: u32,
+        component: *const u8,
+    ) -> u8;
+
+    /// https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdm/nf-wdm-kederegisterbugcheckreasoncallback
+    fn KeDeregisterBugCheckReasonCallback(callback_record: *mut ReasonCallbackRecord) -> u8;
+}
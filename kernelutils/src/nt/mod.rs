@@ -1,6 +1,12 @@
 mod addresses;
+pub mod bug_check;
 pub mod undocumented;
+pub mod registry;
+pub mod mutex;
+pub mod security;
 pub mod platform_ops;
+pub mod power_state;
+pub mod processor_change;
 pub mod switch_stack;
 
 
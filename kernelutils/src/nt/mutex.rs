@@ -0,0 +1,68 @@
+//! This module implements a mutex for code running at PASSIVE_LEVEL, on top of
+//! KGUARDED_MUTEX. Unlike a spin lock, a waiting thread sleeps, and the owner
+//! can block or switch processors while it holds the mutex, such as with
+//! `KeSetSystemGroupAffinityThread`.
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+
+use spin::Once;
+use wdk_sys::ntddk::{KeAcquireGuardedMutex, KeInitializeGuardedMutex, KeReleaseGuardedMutex};
+use wdk_sys::KGUARDED_MUTEX;
+
+/// A mutex protecting `T`. It is initialized on the first [`GuardedMutex::lock`]
+/// and must not move after, so it is meant for statics.
+pub struct GuardedMutex<T> {
+    mutex: UnsafeCell<KGUARDED_MUTEX>,
+    initialized: Once,
+    data: UnsafeCell<T>,
+}
+
+// Safety: the data is only accessed through a guard, while the mutex is held.
+unsafe impl<T: Send> Sync for GuardedMutex<T> {}
+unsafe impl<T: Send> Send for GuardedMutex<T> {}
+
+impl<T> GuardedMutex<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            // Safety: KGUARDED_MUTEX is plain data, initialized before use.
+            mutex: UnsafeCell::new(unsafe { core::mem::zeroed() }),
+            initialized: Once::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Acquires the mutex, waiting for it if another thread holds it. Must be
+    /// called at IRQL <= APC_LEVEL. Normal kernel APCs are disabled until the
+    /// guard is dropped. The mutex is not recursive.
+    pub fn lock(&self) -> GuardedMutexGuard<'_, T> {
+        self.initialized.call_once(|| unsafe { KeInitializeGuardedMutex(self.mutex.get()) });
+        unsafe { KeAcquireGuardedMutex(self.mutex.get()) };
+        GuardedMutexGuard { mutex: self }
+    }
+}
+
+/// Releases the mutex when dropped.
+pub struct GuardedMutexGuard<'a, T> {
+    mutex: &'a GuardedMutex<T>,
+}
+
+impl<T> Deref for GuardedMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for GuardedMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for GuardedMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { KeReleaseGuardedMutex(self.mutex.mutex.get()) };
+    }
+}
@@ -1,41 +1,80 @@
 use alloc::boxed::Box;
-use wdk_sys::{ALL_PROCESSOR_GROUPS, GROUP_AFFINITY, NT_SUCCESS, PROCESSOR_NUMBER};
-use wdk_sys::ntddk::{KeGetProcessorNumberFromIndex, KeQueryActiveProcessorCountEx, KeRevertToUserGroupAffinityThread, KeSetSystemGroupAffinityThread, MmGetPhysicalAddress};
+use wdk_sys::{ALL_PROCESSOR_GROUPS, GROUP_AFFINITY, NT_SUCCESS, PHYSICAL_ADDRESS, PROCESSOR_NUMBER};
+use wdk_sys::_MEMORY_CACHING_TYPE::MmNonCached;
+use wdk_sys::ntddk::{KeBugCheckEx, KeGetProcessorNumberFromIndex, KeQueryActiveProcessorCountEx, KeRevertToUserGroupAffinityThread, KeSetSystemGroupAffinityThread, MmGetPhysicalAddress, MmMapIoSpace};
 
 pub struct WindowsOps;
+
+/// The processor group of a logical processor and its number in the group.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ProcessorNumber {
+    pub group: u16,
+    pub number: u8,
+}
+
 pub trait PlatformOps {
     /// Runs `callback` on all logical processors one by one.
     // This function cannot be called in a nested manner.
     fn run_on_all_processors(&self, callback: fn());
 
+    /// Returns the number of active logical processors in all processor groups.
+    fn processor_count(&self) -> u32;
+
+    /// Runs `callback` on the logical processor at the system processor index
+    /// `index`, which counts the active processors of every group.
+    fn run_on_processor(&self, index: u32, callback: &mut dyn FnMut());
+
+    /// Returns the group and number of the logical processor at the system
+    /// processor index `index`.
+    fn processor_number(&self, index: u32) -> ProcessorNumber;
+
     // Returns a physical address of a linear address specified by `va`.
     fn pa(&self, va: *const core::ffi::c_void) -> u64;
+
+    // Maps `size` bytes of device memory at `pa` as uncached and returns the
+    // linear address of the mapping.
+    fn map_io_space(&self, pa: u64, size: usize) -> *mut core::ffi::c_void;
+
+    // Brings down the system with the bug check `code` and its parameters.
+    fn bug_check(&self, code: u32, parameters: [u64; 4]) -> !;
 }
 
 impl PlatformOps for WindowsOps {
-    fn run_on_all_processors(&self, callback: fn()) {
-        fn processor_count() -> u32 {
-            unsafe { KeQueryActiveProcessorCountEx(u16::try_from(ALL_PROCESSOR_GROUPS).unwrap()) }
+    fn run_on_all_processors(&self, mut callback: fn()) {
+        // PAGED_CODE!();
+
+        for index in 0..self.processor_count() {
+            self.run_on_processor(index, &mut callback);
         }
+    }
 
-        // PAGED_CODE!();
+    fn processor_count(&self) -> u32 {
+        unsafe { KeQueryActiveProcessorCountEx(u16::try_from(ALL_PROCESSOR_GROUPS).unwrap()) }
+    }
 
-        for index in 0..processor_count() {
-            let mut processor_number = PROCESSOR_NUMBER::default();
-            let status = unsafe { KeGetProcessorNumberFromIndex(index, &mut processor_number) };
-            assert!(NT_SUCCESS(status));
+    fn run_on_processor(&self, index: u32, callback: &mut dyn FnMut()) {
+        let processor_number = self.processor_number(index);
 
-            let mut old_affinity = GROUP_AFFINITY::default();
-            let mut affinity = GROUP_AFFINITY {
-                Group: processor_number.Group,
-                Mask: 1 << processor_number.Number,
-                Reserved: [0, 0, 0],
-            };
-            unsafe { KeSetSystemGroupAffinityThread(&mut affinity, &mut old_affinity) };
+        let mut old_affinity = GROUP_AFFINITY::default();
+        let mut affinity = GROUP_AFFINITY {
+            Group: processor_number.group,
+            Mask: 1 << processor_number.number,
+            Reserved: [0, 0, 0],
+        };
+        unsafe { KeSetSystemGroupAffinityThread(&mut affinity, &mut old_affinity) };
 
-            callback();
+        callback();
+
+        unsafe { KeRevertToUserGroupAffinityThread(&mut old_affinity) };
+    }
 
-            unsafe { KeRevertToUserGroupAffinityThread(&mut old_affinity) };
+    fn processor_number(&self, index: u32) -> ProcessorNumber {
+        let mut processor_number = PROCESSOR_NUMBER::default();
+        let status = unsafe { KeGetProcessorNumberFromIndex(index, &mut processor_number) };
+        assert!(NT_SUCCESS(status));
+        ProcessorNumber {
+            group: processor_number.Group,
+            number: processor_number.Number,
         }
     }
 
@@ -45,6 +84,16 @@ impl PlatformOps for WindowsOps {
             MmGetPhysicalAddress(va.cast_mut()).QuadPart as u64
         }
     }
+
+    fn map_io_space(&self, pa: u64, size: usize) -> *mut core::ffi::c_void {
+        let mut physical_address: PHYSICAL_ADDRESS = unsafe { core::mem::zeroed() };
+        physical_address.QuadPart = pa as i64;
+        unsafe { MmMapIoSpace(physical_address, size as _, MmNonCached) }.cast()
+    }
+
+    fn bug_check(&self, code: u32, parameters: [u64; 4]) -> ! {
+        unsafe { KeBugCheckEx(code, parameters[0], parameters[1], parameters[2], parameters[3]) }
+    }
 }
 pub fn init(ops: Box<dyn PlatformOps>) {
     unsafe { PLATFORM_OPS = Some(Box::leak(ops)) };
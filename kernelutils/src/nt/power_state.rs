@@ -0,0 +1,115 @@
+//! This module implements the notification of system power state transitions
+//! through the `\Callback\PowerState` callback object.
+
+use core::ffi::c_void;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use wdk_sys::{NTSTATUS, NT_SUCCESS, OBJ_CASE_INSENSITIVE, STATUS_UNSUCCESSFUL, UNICODE_STRING};
+
+use crate::misc;
+use crate::nt::undocumented::InitializeObjectAttributes;
+
+/// PO_CB_SYSTEM_STATE_LOCK, passed as the first argument of the callback.
+const PO_CB_SYSTEM_STATE_LOCK: usize = 3;
+
+/// A transition of the system to or from the working state (S0).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PowerTransition {
+    /// The system is about to leave S0 to sleep, hibernate or shut down.
+    LeavingWorkingState,
+    /// The system has returned to S0.
+    EnteredWorkingState,
+}
+
+/// The `\Callback\PowerState` object, or null.
+static CALLBACK_OBJECT: AtomicPtr<c_void> = AtomicPtr::new(null_mut());
+/// The registration of the callback, or null.
+static REGISTRATION: AtomicPtr<c_void> = AtomicPtr::new(null_mut());
+
+/// Calls `on_transition` when the system leaves or returns to S0. It is called
+/// at PASSIVE_LEVEL, before the processors lose their state and after they
+/// are running again. Must be called at PASSIVE_LEVEL, and not again before
+/// [`unregister`].
+pub fn register(on_transition: fn(PowerTransition)) -> Result<(), NTSTATUS> {
+    let path = misc::str_to_unicode(r"\Callback\PowerState");
+    let mut name = path.to_unicode();
+    let mut attributes = InitializeObjectAttributes(
+        Some(&mut name as *mut UNICODE_STRING),
+        OBJ_CASE_INSENSITIVE,
+        None,
+        None,
+        None,
+    );
+    let mut object = null_mut();
+    // The object is created by the system, so it is only opened.
+    let status = unsafe { ExCreateCallback(&mut object, (&mut attributes as *mut _).cast(), 0, 1) };
+    if !NT_SUCCESS(status) {
+        return Err(status);
+    }
+
+    let registration =
+        unsafe { ExRegisterCallback(object, power_state_changed, on_transition as *mut c_void) };
+    if registration.is_null() {
+        unsafe { ObfDereferenceObject(object) };
+        return Err(STATUS_UNSUCCESSFUL);
+    }
+    CALLBACK_OBJECT.store(object, Ordering::Release);
+    REGISTRATION.store(registration, Ordering::Release);
+    Ok(())
+}
+
+/// Stops the notifications started by [`register`], if any.
+pub fn unregister() {
+    let registration = REGISTRATION.swap(null_mut(), Ordering::AcqRel);
+    if !registration.is_null() {
+        unsafe { ExUnregisterCallback(registration) };
+    }
+    let object = CALLBACK_OBJECT.swap(null_mut(), Ordering::AcqRel);
+    if !object.is_null() {
+        unsafe { ObfDereferenceObject(object) };
+    }
+}
+
+unsafe extern "system" fn power_state_changed(
+    context: *mut c_void,
+    argument1: *mut c_void,
+    argument2: *mut c_void,
+) {
+    if argument1 as usize != PO_CB_SYSTEM_STATE_LOCK {
+        return;
+    }
+    let on_transition: fn(PowerTransition) = core::mem::transmute(context);
+    // 0 when about to leave S0, 1 when back in S0.
+    on_transition(match argument2 as usize {
+        0 => PowerTransition::LeavingWorkingState,
+        _ => PowerTransition::EnteredWorkingState,
+    });
+}
+
+type CallbackFunction =
+    unsafe extern "system" fn(context: *mut c_void, argument1: *mut c_void, argument2: *mut c_void);
+
+#[link(name = "ntoskrnl")]
+extern "system" {
+    /// https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdm/nf-wdm-excreatecallback
+    fn ExCreateCallback(
+        callback_object: *mut *mut c_void,
+        object_attributes: *mut c_void,
+        create: u8,
+        allow_multiple_callbacks: u8,
+    ) -> NTSTATUS;
+
+    /// https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdm/nf-wdm-exregistercallback
+    fn ExRegisterCallback(
+        callback_object: *mut c_void,
+        callback_function: CallbackFunction,
+        callback_context: *mut c_void,
+    ) -> *mut c_void;
+
+    /// https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdm/nf-wdm-exunregistercallback
+    fn ExUnregisterCallback(callback_registration: *mut c_void);
+
+    /// https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdm/nf-wdm-obdereferenceobject
+    fn ObfDereferenceObject(object: *mut c_void) -> isize;
+}
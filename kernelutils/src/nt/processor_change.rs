@@ -0,0 +1,85 @@
+//! This module implements the notification of processors added to the system
+//! at runtime.
+
+use core::ffi::c_void;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use wdk_sys::{NTSTATUS, PROCESSOR_NUMBER, STATUS_UNSUCCESSFUL};
+
+/// KeProcessorAddCompleteNotify of KE_PROCESSOR_CHANGE_NOTIFY_STATE.
+const PROCESSOR_ADD_COMPLETE_NOTIFY: u32 = 1;
+/// Calls the callback for the active processors on registration too.
+const KE_PROCESSOR_CHANGE_ADD_EXISTING: u32 = 1;
+
+/// KE_PROCESSOR_CHANGE_NOTIFY_CONTEXT.
+#[repr(C)]
+struct ProcessorChangeNotifyContext {
+    state: u32,
+    nt_number: u32,
+    status: NTSTATUS,
+    processor_number: PROCESSOR_NUMBER,
+}
+
+/// The handle of the registered callback, or null.
+static HANDLE: AtomicPtr<c_void> = AtomicPtr::new(null_mut());
+
+/// Calls `on_added` with the system processor index of every active processor
+/// before returning, then of each processor that starts after, once it is
+/// running. No processor added around the registration is missed, but
+/// `on_added` may be called for a processor the caller already knows.
+/// `on_added` is called at PASSIVE_LEVEL and can run code on the processor.
+/// Must be called at PASSIVE_LEVEL, and not again before [`unregister`].
+pub fn register(on_added: fn(index: u32)) -> Result<(), NTSTATUS> {
+    let handle = unsafe {
+        KeRegisterProcessorChangeCallback(
+            processor_change,
+            on_added as *mut c_void,
+            KE_PROCESSOR_CHANGE_ADD_EXISTING,
+        )
+    };
+    if handle.is_null() {
+        return Err(STATUS_UNSUCCESSFUL);
+    }
+    HANDLE.store(handle, Ordering::Release);
+    Ok(())
+}
+
+/// Stops the notifications started by [`register`], if any.
+pub fn unregister() {
+    let handle = HANDLE.swap(null_mut(), Ordering::AcqRel);
+    if !handle.is_null() {
+        unsafe { KeDeregisterProcessorChangeCallback(handle) };
+    }
+}
+
+unsafe extern "system" fn processor_change(
+    context: *mut c_void,
+    change: *mut ProcessorChangeNotifyContext,
+    _operation_status: *mut NTSTATUS,
+) {
+    let change = &*change;
+    if change.state == PROCESSOR_ADD_COMPLETE_NOTIFY {
+        let on_added: fn(u32) = core::mem::transmute(context);
+        on_added(change.nt_number);
+    }
+}
+
+type ProcessorCallback = unsafe extern "system" fn(
+    context: *mut c_void,
+    change: *mut ProcessorChangeNotifyContext,
+    operation_status: *mut NTSTATUS,
+);
+
+#[link(name = "ntoskrnl")]
+extern "system" {
+    /// https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdm/nf-wdm-keregisterprocessorchangecallback
+    fn KeRegisterProcessorChangeCallback(
+        callback_function: ProcessorCallback,
+        callback_context: *mut c_void,
+        flags: u32,
+    ) -> *mut c_void;
+
+    /// https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdm/nf-wdm-kederegisterprocessorchangecallback
+    fn KeDeregisterProcessorChangeCallback(callback_handle: *mut c_void);
+}
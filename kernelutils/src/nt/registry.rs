@@ -0,0 +1,92 @@
+//! This module implements reading the values of a registry key. The data is
+//! decoded by [`RegistryValue`], which does not depend on the WDK.
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ptr::null_mut;
+
+use wdk_sys::ntddk::{ZwClose, ZwEnumerateValueKey, ZwOpenKey};
+use wdk_sys::_KEY_VALUE_INFORMATION_CLASS::KeyValueFullInformation;
+use wdk_sys::{
+    HANDLE, KEY_READ, KEY_VALUE_FULL_INFORMATION, NTSTATUS, NT_SUCCESS, OBJ_CASE_INSENSITIVE,
+    OBJ_KERNEL_HANDLE, STATUS_BUFFER_OVERFLOW, STATUS_BUFFER_TOO_SMALL, STATUS_NO_MORE_ENTRIES,
+    UNICODE_STRING,
+};
+
+use crate::misc::{self, utf16};
+use crate::nt::undocumented::InitializeObjectAttributes;
+use crate::RegistryValue;
+
+/// Returns the name and data of every value of the key at `path`, such as
+/// `\Registry\Machine\System\CurrentControlSet\Services\<name>\Parameters`.
+/// Must be called at PASSIVE_LEVEL.
+pub fn read_values(path: &str) -> Result<Vec<(String, RegistryValue)>, NTSTATUS> {
+    let path = misc::str_to_unicode(path);
+    let mut name = path.to_unicode();
+    let mut attributes = InitializeObjectAttributes(
+        Some(&mut name as *mut UNICODE_STRING),
+        OBJ_KERNEL_HANDLE | OBJ_CASE_INSENSITIVE,
+        None,
+        None,
+        None,
+    );
+    let mut key: HANDLE = null_mut();
+    let status = unsafe { ZwOpenKey(&mut key, KEY_READ, &mut attributes) };
+    if !NT_SUCCESS(status) {
+        return Err(status);
+    }
+
+    let values = enumerate_values(key);
+    unsafe { ZwClose(key) };
+    values
+}
+
+fn enumerate_values(key: HANDLE) -> Result<Vec<(String, RegistryValue)>, NTSTATUS> {
+    const HEADER_SIZE: usize = core::mem::offset_of!(KEY_VALUE_FULL_INFORMATION, Name);
+
+    let mut values = Vec::new();
+    // KEY_VALUE_FULL_INFORMATION is 4-byte aligned.
+    let mut buffer: Vec<u32> = vec![0; 64];
+    for index in 0.. {
+        let mut size = 0;
+        let status = loop {
+            let length = (buffer.len() * 4) as u32;
+            let status = unsafe {
+                ZwEnumerateValueKey(
+                    key,
+                    index,
+                    KeyValueFullInformation,
+                    buffer.as_mut_ptr().cast(),
+                    length,
+                    &mut size,
+                )
+            };
+            if status != STATUS_BUFFER_OVERFLOW && status != STATUS_BUFFER_TOO_SMALL {
+                break status;
+            }
+            buffer.resize((size as usize).div_ceil(4), 0);
+        };
+        if status == STATUS_NO_MORE_ENTRIES {
+            break;
+        }
+        if !NT_SUCCESS(status) {
+            return Err(status);
+        }
+
+        let bytes =
+            unsafe { core::slice::from_raw_parts(buffer.as_ptr().cast::<u8>(), size as usize) };
+        let information = unsafe { &*buffer.as_ptr().cast::<KEY_VALUE_FULL_INFORMATION>() };
+        let name_end = HEADER_SIZE + information.NameLength as usize;
+        let data_start = information.DataOffset as usize;
+        let data_end = data_start + information.DataLength as usize;
+        let (Some(name), Some(data)) = (
+            bytes.get(HEADER_SIZE..name_end),
+            bytes.get(data_start..data_end),
+        ) else {
+            continue;
+        };
+        values.push((utf16(name), RegistryValue::from_raw(information.Type, data)));
+    }
+    Ok(values)
+}
@@ -0,0 +1,66 @@
+//! This module implements creating device objects whose access is restricted
+//! from the start, such as the control device of the driver.
+
+use core::ptr::null_mut;
+
+use wdk_sys::{DEVICE_OBJECT, DRIVER_OBJECT, GUID, NTSTATUS, NT_SUCCESS, UNICODE_STRING};
+
+use crate::misc;
+
+/// SDDL_DEVOBJ_SYS_ALL_ADM_ALL: GENERIC_ALL to SYSTEM and Administrators, and
+/// no access to anyone else.
+pub const SYSTEM_AND_ADMINISTRATORS_ONLY: &str = "D:P(A;;GA;;;SY)(A;;GA;;;BA)";
+
+/// Creates the device object `name`, such as `\Device\<name>`, with the
+/// security descriptor `sddl`. The descriptor is applied when the object is
+/// created, so the object is never reachable with the default one.
+/// `class_guid` identifies the device class, under which administrators can
+/// override the descriptor in the registry. Must be called at PASSIVE_LEVEL.
+pub fn create_device_secure(
+    driver: &mut DRIVER_OBJECT,
+    name: &str,
+    device_type: u32,
+    characteristics: u32,
+    sddl: &str,
+    class_guid: &GUID,
+) -> Result<*mut DEVICE_OBJECT, NTSTATUS> {
+    let name = misc::str_to_unicode(name);
+    let mut name = name.to_unicode();
+    let sddl = misc::str_to_unicode(sddl);
+    let sddl = sddl.to_unicode();
+    let mut device = null_mut();
+    let status = unsafe {
+        IoCreateDeviceSecure(
+            driver,
+            0,
+            &mut name,
+            device_type,
+            characteristics,
+            0,
+            &sddl,
+            class_guid,
+            &mut device,
+        )
+    };
+    if NT_SUCCESS(status) {
+        Ok(device)
+    } else {
+        Err(status)
+    }
+}
+
+#[link(name = "wdmsec")]
+extern "system" {
+    /// https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdmsec/nf-wdmsec-wdmlibiocreatedevicesecure
+    fn IoCreateDeviceSecure(
+        driver_object: *mut DRIVER_OBJECT,
+        device_extension_size: u32,
+        device_name: *mut UNICODE_STRING,
+        device_type: u32,
+        device_characteristics: u32,
+        exclusive: u8,
+        default_sddl_string: *const UNICODE_STRING,
+        device_class_guid: *const GUID,
+        device_object: *mut *mut DEVICE_OBJECT,
+    ) -> NTSTATUS;
+}
@@ -4,18 +4,35 @@ use x86::bits64::paging::BASE_PAGE_SIZE;
 
 use crate::Registers;
 
+/// The size of the stack allocated by `jump_with_new_stack`.
+pub const STACK_SIZE: usize = BASE_PAGE_SIZE * 0x10;
 
 /// Installs the hypervisor on the current processor.
 pub fn jump_with_new_stack(destination: fn(&Registers) -> !, registers: &Registers) -> ! {
-    // Allocate separate stack space. This is never freed.
-    let layout = Layout::array::<[u8; BASE_PAGE_SIZE]>(0x10).unwrap();
+    jump_with_stack(destination, registers, allocate_stack())
+}
+
+/// Allocates a stack of [`STACK_SIZE`] bytes and returns its base, the address
+/// the stack grows down from. This is never freed.
+pub fn allocate_stack() -> u64 {
+    let layout = Layout::array::<[u8; BASE_PAGE_SIZE]>(STACK_SIZE / BASE_PAGE_SIZE).unwrap();
     let stack = unsafe { alloc::alloc::alloc_zeroed(layout) };
     if stack.is_null() {
         handle_alloc_error(layout);
     }
     let stack_base = stack as u64 + layout.size() as u64 - 0x8;
     log::trace!("Stack range: {:#x?}", (stack as u64..stack_base));
+    stack_base
+}
 
+/// Installs the hypervisor on the current processor with the stack at
+/// `stack_base`, from [`allocate_stack`]. Nothing may be running on the stack,
+/// so it can be reused only once the processor left it.
+pub fn jump_with_stack(
+    destination: fn(&Registers) -> !,
+    registers: &Registers,
+    stack_base: u64,
+) -> ! {
     unsafe { switch_stack(registers, destination as *const () as _, stack_base) };
 }
 
@@ -0,0 +1,262 @@
+//! This module implements the control device. The requests and responses are
+//! defined in `hv_interface`, which user-mode clients share.
+
+use hv_interface::{
+    ChangeInterceptsRequest, DevirtualizeRequest, Header, InterfaceError, StatisticsRequest,
+    StatisticsResponse, StatusResponse, TraceRequest, VirtualizeRequest, ALL_PROCESSORS,
+    MAX_PROCESSORS,
+};
+use hypervisor::amd::control::{self, ControlError};
+use hypervisor::amd::intercept::{
+    CrReadIntercepts, CrWriteIntercepts, DrReadIntercepts, DrWriteIntercepts, ExceptionIntercepts,
+    Misc1Intercepts, Misc2Intercepts, Misc3Intercepts,
+};
+use hypervisor::amd::{is_virtualized, processors, stats, trace, InterceptChange, InterceptVectors};
+use wdk_sys::ntddk::{
+    IoCreateSymbolicLink, IoDeleteDevice, IoDeleteSymbolicLink, IofCompleteRequest,
+};
+use wdk_sys::{
+    DEVICE_OBJECT, DRIVER_OBJECT, FILE_DEVICE_SECURE_OPEN, GUID, IO_NO_INCREMENT, IRP,
+    IRP_MJ_CLOSE, IRP_MJ_CREATE, IRP_MJ_DEVICE_CONTROL, NTSTATUS, NT_SUCCESS,
+    STATUS_BUFFER_TOO_SMALL, STATUS_INVALID_DEVICE_REQUEST, STATUS_INVALID_DEVICE_STATE,
+    STATUS_INVALID_PARAMETER, STATUS_REVISION_MISMATCH, STATUS_SUCCESS,
+};
+
+const _: () = assert!(hv_interface::EXIT_SLOT_COUNT == stats::EXIT_SLOT_COUNT);
+
+/// The device class of the control device, under which its security
+/// descriptor can be overridden.
+const DEVICE_CLASS_GUID: GUID = GUID {
+    Data1: 0x152b_8103,
+    Data2: 0x63ec,
+    Data3: 0x49c7,
+    Data4: [0x8f, 0x9b, 0x69, 0xde, 0xb0, 0x58, 0x34, 0x31],
+};
+
+/// Creates the control device, accessible to SYSTEM and Administrators only,
+/// and its symbolic link.
+pub fn create(driver: &mut DRIVER_OBJECT) -> Result<(), NTSTATUS> {
+    let device = kernelutils::nt::security::create_device_secure(
+        driver,
+        hv_interface::DEVICE_NAME,
+        hv_interface::FILE_DEVICE_HYPERVISOR,
+        FILE_DEVICE_SECURE_OPEN,
+        kernelutils::nt::security::SYSTEM_AND_ADMINISTRATORS_ONLY,
+        &DEVICE_CLASS_GUID,
+    )?;
+
+    driver.MajorFunction[IRP_MJ_CREATE as usize] = Some(dispatch_create_close);
+    driver.MajorFunction[IRP_MJ_CLOSE as usize] = Some(dispatch_create_close);
+    driver.MajorFunction[IRP_MJ_DEVICE_CONTROL as usize] = Some(dispatch_device_control);
+
+    let device_name = kernelutils::str_to_unicode(hv_interface::DEVICE_NAME);
+    let mut device_name = device_name.to_unicode();
+    let link_name = kernelutils::str_to_unicode(hv_interface::SYMBOLIC_LINK_NAME);
+    let mut link_name = link_name.to_unicode();
+    let status = unsafe { IoCreateSymbolicLink(&mut link_name, &mut device_name) };
+    if !NT_SUCCESS(status) {
+        unsafe { IoDeleteDevice(device) };
+        return Err(status);
+    }
+    Ok(())
+}
+
+/// Deletes the control device and its symbolic link, if created.
+pub fn delete(driver: &mut DRIVER_OBJECT) {
+    if driver.DeviceObject.is_null() {
+        return;
+    }
+
+    let link_name = kernelutils::str_to_unicode(hv_interface::SYMBOLIC_LINK_NAME);
+    let mut link_name = link_name.to_unicode();
+    unsafe {
+        IoDeleteSymbolicLink(&mut link_name);
+        IoDeleteDevice(driver.DeviceObject);
+    }
+}
+
+unsafe extern "C" fn dispatch_create_close(_device: *mut DEVICE_OBJECT, irp: *mut IRP) -> NTSTATUS {
+    complete(&mut *irp, STATUS_SUCCESS, 0)
+}
+
+unsafe extern "C" fn dispatch_device_control(
+    _device: *mut DEVICE_OBJECT,
+    irp: *mut IRP,
+) -> NTSTATUS {
+    let irp = &mut *irp;
+    let stack = &*irp
+        .Tail
+        .Overlay
+        .__bindgen_anon_2
+        .__bindgen_anon_1
+        .CurrentStackLocation;
+    let parameters = &stack.Parameters.DeviceIoControl;
+    let code = parameters.IoControlCode;
+
+    // METHOD_BUFFERED passes the input and takes the output in the same system
+    // buffer, which is large enough for either. There is none if both are empty.
+    let system_buffer = irp.AssociatedIrp.SystemBuffer.cast::<u8>();
+    let (buffer, input_size, output_size) = if system_buffer.is_null() {
+        (&mut [][..], 0, 0)
+    } else {
+        let input_size = parameters.InputBufferLength as usize;
+        let output_size = parameters.OutputBufferLength as usize;
+        let buffer = core::slice::from_raw_parts_mut(system_buffer, input_size.max(output_size));
+        (buffer, input_size, output_size)
+    };
+
+    match device_control(code, buffer, input_size, output_size) {
+        Ok(size) => complete(irp, STATUS_SUCCESS, size),
+        Err(status) => {
+            log::debug!("IOCTL {code:#x} failed with {status:#x}");
+            complete(irp, status, 0)
+        }
+    }
+}
+
+fn complete(irp: &mut IRP, status: NTSTATUS, information: usize) -> NTSTATUS {
+    irp.IoStatus.__bindgen_anon_1.Status = status;
+    irp.IoStatus.Information = information as u64;
+    unsafe { IofCompleteRequest(irp, IO_NO_INCREMENT as i8) };
+    status
+}
+
+/// Handles the request in `buffer` and returns the size of the response written
+/// to it.
+fn device_control(
+    code: u32,
+    buffer: &mut [u8],
+    input_size: usize,
+    output_size: usize,
+) -> Result<usize, NTSTATUS> {
+    let input = &buffer[..input_size];
+    let output = ..output_size;
+    match code {
+        hv_interface::IOCTL_QUERY_STATUS => write(&query_status(), &mut buffer[output]),
+        hv_interface::IOCTL_QUERY_STATISTICS => {
+            let request: StatisticsRequest = read(input)?;
+            write(&query_statistics(request.processor)?, &mut buffer[output])
+        }
+        hv_interface::IOCTL_EXPORT_TRACE => {
+            let request: TraceRequest = read(input)?;
+            match trace::export(request.processor as usize, &mut buffer[output]) {
+                Ok(size) => Ok(size),
+                Err(trace::TraceError::NotTraced { .. }) => Err(STATUS_INVALID_PARAMETER),
+                Err(trace::TraceError::BufferTooSmall { .. }) => Err(STATUS_BUFFER_TOO_SMALL),
+            }
+        }
+        hv_interface::IOCTL_CHANGE_INTERCEPTS => {
+            let request: ChangeInterceptsRequest = read(input)?;
+            let change = InterceptChange {
+                enable: intercept_vectors(&request.enable),
+                disable: intercept_vectors(&request.disable),
+            };
+            control::change_intercepts(target(request.processor), &change)
+                .map_err(control_status)?;
+            Ok(0)
+        }
+        hv_interface::IOCTL_DEVIRTUALIZE => {
+            let request: DevirtualizeRequest = read(input)?;
+            control::devirtualize(target(request.processor)).map_err(control_status)?;
+            Ok(0)
+        }
+        hv_interface::IOCTL_VIRTUALIZE => {
+            let request: VirtualizeRequest = read(input)?;
+            control::virtualize(target(request.processor)).map_err(control_status)?;
+            Ok(0)
+        }
+        _ => Err(STATUS_INVALID_DEVICE_REQUEST),
+    }
+}
+
+fn query_status() -> StatusResponse {
+    let processor_count = control::processor_count();
+    let mut status = StatusResponse {
+        header: Header::of::<StatusResponse>(),
+        major: env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or_default(),
+        minor: env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or_default(),
+        patch: env!("CARGO_PKG_VERSION_PATCH").parse().unwrap_or_default(),
+        reserved: 0,
+        processor_count: processor_count as u32,
+        virtualized_count: 0,
+        virtualized: [0; MAX_PROCESSORS / 64],
+        hot_added: [0; MAX_PROCESSORS / 64],
+    };
+    for index in 0..processor_count.min(MAX_PROCESSORS) {
+        if is_virtualized(index) {
+            status.set_virtualized(index);
+            status.virtualized_count += 1;
+        }
+        if processors::is_hot_added(index) {
+            status.set_hot_added(index);
+        }
+    }
+    status
+}
+
+fn query_statistics(processor: u32) -> Result<StatisticsResponse, NTSTATUS> {
+    let statistics = if processor == ALL_PROCESSORS {
+        stats::total()
+    } else {
+        stats::snapshot(processor as usize).ok_or(STATUS_INVALID_PARAMETER)?
+    };
+    let memory = stats::memory_usage();
+    Ok(StatisticsResponse {
+        header: Header::of::<StatisticsResponse>(),
+        processor_count: statistics.processor_count,
+        reserved: 0,
+        guest_cycles: statistics.guest_cycles,
+        host_cycles: statistics.host_cycles,
+        injected_events: statistics.injected_events,
+        exit_counts: statistics.exit_counts,
+        max_exit_cycles: statistics.max_exit_cycles,
+        vmcb_bytes: memory.vmcb_bytes,
+        npt_bytes: memory.npt_bytes,
+        host_stack_bytes: memory.host_stack_bytes,
+    })
+}
+
+fn intercept_vectors(vectors: &hv_interface::InterceptVectors) -> InterceptVectors {
+    InterceptVectors {
+        cr_read: CrReadIntercepts::from_bits_retain(vectors.cr_read),
+        cr_write: CrWriteIntercepts::from_bits_retain(vectors.cr_write),
+        dr_read: DrReadIntercepts::from_bits_retain(vectors.dr_read),
+        dr_write: DrWriteIntercepts::from_bits_retain(vectors.dr_write),
+        exceptions: ExceptionIntercepts::from_bits_retain(vectors.exceptions),
+        misc1: Misc1Intercepts::from_bits_retain(vectors.misc1),
+        misc2: Misc2Intercepts::from_bits_retain(vectors.misc2),
+        misc3: Misc3Intercepts::from_bits_retain(vectors.misc3),
+    }
+}
+
+fn target(processor: u32) -> Option<usize> {
+    (processor != ALL_PROCESSORS).then_some(processor as usize)
+}
+
+fn read<T: hv_interface::Message>(input: &[u8]) -> Result<T, NTSTATUS> {
+    hv_interface::read(input).map_err(interface_status)
+}
+
+fn write<T: hv_interface::Message>(value: &T, output: &mut [u8]) -> Result<usize, NTSTATUS> {
+    hv_interface::write(value, output).map_err(interface_status)
+}
+
+fn interface_status(error: InterfaceError) -> NTSTATUS {
+    match error {
+        InterfaceError::BufferTooSmall { .. } => STATUS_BUFFER_TOO_SMALL,
+        InterfaceError::VersionMismatch { .. } | InterfaceError::SizeMismatch { .. } => {
+            STATUS_REVISION_MISMATCH
+        }
+    }
+}
+
+fn control_status(error: ControlError) -> NTSTATUS {
+    match error {
+        ControlError::InvalidProcessor { .. } | ControlError::Rejected { .. } => {
+            STATUS_INVALID_PARAMETER
+        }
+        ControlError::NotVirtualized { .. }
+        | ControlError::AlreadyVirtualized { .. }
+        | ControlError::Suspended => STATUS_INVALID_DEVICE_STATE,
+    }
+}
@@ -1,8 +1,18 @@
 #![no_std]
 
+use alloc::format;
+use core::fmt;
+use hypervisor::amd::registry::ConfigError;
+use hypervisor::amd::{dump, processors, HypervisorConfig, LogSink};
 use kernel_log::KernelLogger;
-use log::LevelFilter;
-use wdk_sys::{DRIVER_OBJECT, NTSTATUS, PUNICODE_STRING};
+use kernelutils::nt::power_state::PowerTransition;
+use wdk_sys::ntddk::ObfReferenceObject;
+use wdk_sys::{
+    DRIVER_OBJECT, NTSTATUS, PUNICODE_STRING, STATUS_INVALID_PARAMETER,
+    STATUS_OBJECT_NAME_NOT_FOUND, STATUS_SUCCESS,
+};
+
+mod device;
 
 extern crate alloc;
 #[cfg(not(test))]
@@ -19,19 +29,141 @@ static GLOBAL_ALLOCATOR: WDKAllocator = WDKAllocator;
 #[export_name = "DriverEntry"]
 pub unsafe extern "system" fn driver_entry(
     driver: &mut DRIVER_OBJECT,
-    _registry_path: PUNICODE_STRING,
+    registry_path: PUNICODE_STRING,
 ) -> NTSTATUS {
     driver.DriverUnload = Some(driver_unload);
-    KernelLogger::init(LevelFilter::Trace).expect("Failed to initialize logger");
+
+    let config = match read_config(registry_path) {
+        Ok(config) => config,
+        Err(error) => {
+            init_logger(&HypervisorConfig::new());
+            log::error!("Failed to read the configuration: {error}");
+            return error.status();
+        }
+    };
+    init_logger(&config);
 
     // spoof_test();
 
     log::trace!("com_logger Hello Gorgon");
 
-    hypervisor::amd::virtualize_system();
-    0 as NTSTATUS
+    hypervisor::amd::virtualize_system(config);
+
+    // Registered after `virtualize_system`, which `add_processor` needs. The
+    // processors started in between are reported with the existing ones.
+    if let Err(status) = kernelutils::nt::processor_change::register(processor_added) {
+        log::error!("Failed to register for processor changes: {status:#x}");
+    }
+    if let Err(status) = kernelutils::nt::power_state::register(power_transition) {
+        log::error!("Failed to register for power state changes: {status:#x}");
+    }
+
+    if let Err(status) = device::create(driver) {
+        log::error!("Failed to create the control device: {status:#x}");
+    }
+    // Sized for every processor that can be tracked, as processors added
+    // later are virtualized too.
+    if let Err(status) = kernelutils::nt::bug_check::register_secondary_dump_data(
+        c"Bluepill",
+        dump::DUMP_GUID,
+        dump::size(processors::MAX_PROCESSORS),
+        dump::write,
+    ) {
+        log::error!("Failed to register the crash dump data: {status:#x}");
+    }
+    STATUS_SUCCESS
+}
+
+/// Why the configuration could not be read.
+enum ReadConfigError {
+    /// The `Parameters` subkey exists but cannot be read.
+    Registry(NTSTATUS),
+    Invalid(ConfigError),
+}
+
+impl ReadConfigError {
+    /// Returns the status `DriverEntry` fails with.
+    fn status(&self) -> NTSTATUS {
+        match self {
+            Self::Registry(status) => *status,
+            Self::Invalid(_) => STATUS_INVALID_PARAMETER,
+        }
+    }
+}
+
+impl fmt::Display for ReadConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Registry(status) => write!(f, "failed to read the Parameters key: {status:#x}"),
+            Self::Invalid(error) => write!(f, "invalid configuration: {error}"),
+        }
+    }
+}
+
+/// Reads the configuration from the `Parameters` subkey of the service key.
+/// Without the subkey, the defaults are used.
+unsafe fn read_config(registry_path: PUNICODE_STRING) -> Result<HypervisorConfig, ReadConfigError> {
+    let path = format!(
+        "{}\\Parameters",
+        kernelutils::unicode_to_string(&*registry_path)
+    );
+    match kernelutils::nt::registry::read_values(&path) {
+        Ok(values) => hypervisor::amd::registry::parse(
+            values.iter().map(|(name, value)| (name.as_str(), value)),
+        )
+        .map_err(ReadConfigError::Invalid),
+        Err(STATUS_OBJECT_NAME_NOT_FOUND) => Ok(HypervisorConfig::new()),
+        Err(status) => Err(ReadConfigError::Registry(status)),
+    }
+}
+
+fn init_logger(config: &HypervisorConfig) {
+    match config.log_sink {
+        LogSink::Debugger => {
+            KernelLogger::init(config.log_level).expect("Failed to initialize logger")
+        }
+        LogSink::Serial { port } => com_logger::builder()
+            .base(port)
+            .filter(config.log_level)
+            .setup(),
+    }
+}
+
+/// Virtualizes a processor added at runtime, when the configuration selects it.
+/// Called for the processors active at registration too, which are already
+/// tracked.
+fn processor_added(index: u32) {
+    if let Err(error) = hypervisor::amd::control::add_processor(index as usize) {
+        log::error!("Failed to add processor {index}: {error}");
+    }
+}
+
+/// Keeps the processors virtualized across sleep and hibernation, during which
+/// they lose their SVM state.
+fn power_transition(transition: PowerTransition) {
+    match transition {
+        PowerTransition::LeavingWorkingState => {
+            if let Err(error) = hypervisor::amd::control::suspend() {
+                log::error!("Failed to devirtualize before sleeping: {error}");
+            }
+        }
+        PowerTransition::EnteredWorkingState => hypervisor::amd::control::resume(),
+    }
 }
 
-pub extern "C" fn driver_unload(_driver: *mut DRIVER_OBJECT) {
+pub extern "C" fn driver_unload(driver: *mut DRIVER_OBJECT) {
+    // No processor must be added or virtualized again past this point.
+    kernelutils::nt::processor_change::unregister();
+    kernelutils::nt::power_state::unregister();
+    kernelutils::nt::bug_check::unregister_secondary_dump_data();
+    // The hypervisor runs from the driver image, so no processor may remain
+    // virtualized once it is unloaded. If one does, the driver object is
+    // referenced so that the image is never unloaded, and the hypervisor keeps
+    // running on the processors that are still virtualized.
+    if let Err(error) = hypervisor::amd::control::devirtualize(None) {
+        log::error!("Failed to devirtualize, leaking the driver image: {error}");
+        unsafe { ObfReferenceObject(driver.cast()) };
+    }
+    device::delete(unsafe { &mut *driver });
     log::trace!("Driver unloaded successfully!");
 }